@@ -1,21 +1,110 @@
-use anyhow::{Context, Result};
 use base64::prelude::*;
+use futures::stream::{self, Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
-const BASE_URL: &str = "https://gmail.googleapis.com/gmail/v1";
-const MIN_REQUEST_INTERVAL: Duration = Duration::from_millis(100);
+use crate::auth::{StaticToken, TokenProvider};
+use crate::cache::{Cache, Format};
+use crate::contacts::{Contact, SearchResponse};
+use crate::error::{Context, GmailError, Result, bail, format_err};
+use crate::journal::{self, Target};
+use crate::quota::{self, TokenBucket};
+
+const GMAIL_ROOT: &str = "https://gmail.googleapis.com";
+const PUBSUB_ROOT: &str = "https://pubsub.googleapis.com";
+const PEOPLE_ROOT: &str = "https://people.googleapis.com";
+// Path prefix of Gmail calls, also used inside a batch request
+const BATCH_API_PATH: &str = "/gmail/v1";
+const BATCH_BOUNDARY: &str = "batch_gmail_cli";
+// The People API caps searchContacts at this many results
+const MAX_CONTACT_RESULTS: u32 = 30;
+// Messages above this size go through the resumable upload endpoint
+const SIMPLE_UPLOAD_LIMIT: usize = 5 * 1024 * 1024;
+// Maximum number of IDs accepted by batchModify/batchDelete
+const BATCH_LIMIT: usize = 1000;
+// Whole one-click unsubscribe POST, which goes to the sender's server
+const ONE_CLICK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Calls per batch request; Google allows 100 but throttles Gmail batches above 50
+pub const BATCH_GET_LIMIT: usize = 50;
+// Largest page size messages.list accepts
+const MAX_PAGE_SIZE: u32 = 500;
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+// Pub/Sub pulls block until messages arrive, so they outlive the default timeout
+const PULL_TIMEOUT: Duration = Duration::from_secs(90);
+// Marks the stand-in response returned for a request skipped by a dry run
+const DRY_RUN_HEADER: &str = "x-gmail-cli-dry-run";
+// Bodies longer than this are summarized in dry-run output and logs
+const BODY_PRINT_LIMIT: usize = 64 * 1024;
+// JSON and form fields whose values never go into logs
+const SECRET_FIELDS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "client_secret",
+    "assertion",
+];
 
 pub struct Client {
     http: reqwest::Client,
-    access_token: String,
-    last_request: Mutex<Option<Instant>>,
+    tokens: Box<dyn TokenProvider>,
+    scopes: Vec<String>,
+    quota: TokenBucket,
+    urls: Urls,
+    dry_run: bool,
+    journal: Option<PathBuf>,
+    label_names: Option<PathBuf>,
+    cache: Option<Cache>,
+    /// Socket of a `gmail daemon` that sends requests on this client's behalf
+    daemon: Option<PathBuf>,
+}
+
+/// Where the client sends each kind of request.
+struct Urls {
+    api: String,
+    upload: String,
+    batch: String,
+    pubsub: String,
+    people: String,
+}
+
+impl Urls {
+    /// Gmail, Pub/Sub and People each under the given server root.
+    fn new(gmail: &str, pubsub: &str, people: &str) -> Self {
+        Self {
+            api: format!("{}{}", gmail, BATCH_API_PATH),
+            upload: format!("{}/upload{}", gmail, BATCH_API_PATH),
+            batch: format!("{}/batch{}", gmail, BATCH_API_PATH),
+            pubsub: format!("{}/v1", pubsub),
+            people: format!("{}/v1", people),
+        }
+    }
+
+    /// Whether `url` is under one of these, rather than some other server.
+    fn contains(&self, url: &str) -> bool {
+        [
+            &self.api,
+            &self.upload,
+            &self.batch,
+            &self.pubsub,
+            &self.people,
+        ]
+        .iter()
+        .any(|base| {
+            url.strip_prefix(base.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?']))
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MessageList {
     pub messages: Option<Vec<MessageRef>>,
+    #[serde(rename = "nextPageToken")]
+    pub next_page_token: Option<String>,
+    /// Gmail's estimate of the total number of matches, across all pages
+    #[serde(rename = "resultSizeEstimate")]
+    pub result_size_estimate: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,348 +118,2439 @@ pub struct Label {
     pub name: String,
     #[serde(rename = "type")]
     pub label_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<LabelColor>,
+    // Counts are only returned when fetching a single label
+    #[serde(rename = "messagesTotal", skip_serializing_if = "Option::is_none")]
+    pub messages_total: Option<u32>,
+    #[serde(rename = "messagesUnread", skip_serializing_if = "Option::is_none")]
+    pub messages_unread: Option<u32>,
+    #[serde(rename = "threadsTotal", skip_serializing_if = "Option::is_none")]
+    pub threads_total: Option<u32>,
+    #[serde(rename = "threadsUnread", skip_serializing_if = "Option::is_none")]
+    pub threads_unread: Option<u32>,
+}
+
+/// Label colors; Gmail only accepts values from its fixed palette.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelColor {
+    pub text_color: String,
+    pub background_color: String,
+}
+
+/// A node in the `Parent/Child` label hierarchy, with counts summed over its subtree.
+///
+/// Messages carrying both a parent and a child label are counted once per label.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelNode {
+    /// Last path segment, e.g. `Invoices` for `Clients/Acme/Invoices`
+    pub name: String,
+    /// None when the parent path is implied by a child but isn't a label itself
+    pub id: Option<String>,
+    pub messages_total: u32,
+    pub messages_unread: u32,
+    pub threads_unread: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<LabelNode>,
+}
+
+/// Arrange labels into a tree by splitting their names on `/`, sorted by name.
+pub fn label_tree<'a>(labels: impl IntoIterator<Item = &'a Label>) -> Vec<LabelNode> {
+    let mut sorted: Vec<&Label> = labels.into_iter().collect();
+    sorted.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut roots: Vec<LabelNode> = Vec::new();
+    for label in sorted {
+        let mut level = &mut roots;
+        let segments: Vec<&str> = label.name.split('/').collect();
+        for (depth, segment) in segments.iter().enumerate() {
+            let index = match level.iter().position(|n| n.name == *segment) {
+                Some(index) => index,
+                None => {
+                    level.push(LabelNode {
+                        name: segment.to_string(),
+                        ..Default::default()
+                    });
+                    level.len() - 1
+                }
+            };
+            if depth == segments.len() - 1 {
+                let node = &mut level[index];
+                node.id = Some(label.id.clone());
+                node.messages_total = label.messages_total.unwrap_or(0);
+                node.messages_unread = label.messages_unread.unwrap_or(0);
+                node.threads_unread = label.threads_unread.unwrap_or(0);
+            }
+            level = &mut level[index].children;
+        }
+    }
+
+    for root in &mut roots {
+        roll_up_counts(root);
+    }
+    roots
+}
+
+fn roll_up_counts(node: &mut LabelNode) {
+    for child in &mut node.children {
+        roll_up_counts(child);
+        node.messages_total += child.messages_total;
+        node.messages_unread += child.messages_unread;
+        node.threads_unread += child.threads_unread;
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MessageRef {
     pub id: String,
+    #[serde(rename = "threadId")]
+    pub thread_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThreadList {
+    pub threads: Option<Vec<Thread>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Thread {
+    pub id: String,
+    pub snippet: Option<String>,
+    pub messages: Option<Vec<Message>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DraftList {
+    pub drafts: Option<Vec<Draft>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Draft {
+    pub id: String,
+    pub message: Option<Message>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FilterList {
+    pub filter: Option<Vec<Filter>>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Filter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub criteria: FilterCriteria,
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterCriteria {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negated_query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub has_attachment: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterAction {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add_label_ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove_label_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VacationSettings {
+    pub enable_auto_reply: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_body_plain_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_body_html: Option<String>,
+    #[serde(default)]
+    pub restrict_to_contacts: bool,
+    #[serde(default)]
+    pub restrict_to_domain: bool,
+    /// Milliseconds since the epoch, encoded as a string by the API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendAsList {
+    #[serde(rename = "sendAs")]
+    pub send_as: Option<Vec<SendAs>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendAs {
+    pub send_as_email: String,
+    pub display_name: Option<String>,
+    pub reply_to_address: Option<String>,
+    pub signature: Option<String>,
+    #[serde(default)]
+    pub is_primary: bool,
+    #[serde(default)]
+    pub is_default: bool,
+    pub verification_status: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForwardingAddressList {
+    #[serde(rename = "forwardingAddresses")]
+    pub forwarding_addresses: Option<Vec<ForwardingAddress>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardingAddress {
+    pub forwarding_email: String,
+    pub verification_status: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoForwarding {
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_address: Option<String>,
+    /// What happens to the original: leaveInInbox, archive, trash or markRead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disposition: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImapSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_expunge: Option<bool>,
+    /// archive, trash or deleteForever
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expunge_behavior: Option<String>,
+    /// Messages per folder; 0 means no limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_folder_size: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PopSettings {
+    /// disabled, fromNowOn or allMail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_window: Option<String>,
+    /// leaveInInbox, archive, trash or markRead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disposition: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub email_address: String,
+    pub messages_total: u64,
+    pub threads_total: u64,
+    pub history_id: String,
+}
+
+/// Gmail web URL for a thread. `authuser` takes the account's address rather than
+/// its sign-in index, so the right account opens whatever order the browser has them in.
+pub fn web_url(email_address: &str, thread_id: &str) -> String {
+    format!(
+        "https://mail.google.com/mail/?authuser={}#all/{}",
+        urlencoding::encode(email_address),
+        urlencoding::encode(thread_id)
+    )
+}
+
+/// The ways a message offers to unsubscribe, from its `List-Unsubscribe` header (RFC 2369).
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnsubscribeLinks {
+    /// HTTPS URL accepting an RFC 8058 one-click POST
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub one_click: Option<String>,
+    /// `mailto:` URI to send the request to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mailto: Option<String>,
+    /// Web page to finish unsubscribing in a browser
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl UnsubscribeLinks {
+    /// Parse `List-Unsubscribe`, which holds `<uri>` entries separated by commas.
+    ///
+    /// An HTTPS entry is one-click only when `List-Unsubscribe-Post` says so (RFC 8058).
+    pub fn parse(list_unsubscribe: &str, list_unsubscribe_post: Option<&str>) -> Self {
+        let one_click = list_unsubscribe_post.is_some_and(|post| {
+            post.trim()
+                .eq_ignore_ascii_case("List-Unsubscribe=One-Click")
+        });
+        let mut links = Self::default();
+        for entry in list_unsubscribe.split('<').skip(1) {
+            let Some((uri, _)) = entry.split_once('>') else {
+                continue;
+            };
+            // Folding may leave whitespace inside the brackets; it isn't part of the URI
+            let uri: String = uri.split_whitespace().collect();
+            let scheme = uri
+                .split_once(':')
+                .map(|(scheme, _)| scheme.to_ascii_lowercase());
+            match scheme.as_deref() {
+                Some("mailto") if links.mailto.is_none() => links.mailto = Some(uri),
+                Some("https") if one_click && links.one_click.is_none() => {
+                    links.one_click = Some(uri)
+                }
+                Some("https" | "http") if links.url.is_none() => links.url = Some(uri),
+                _ => {}
+            }
+        }
+        links
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.preferred().is_none()
+    }
+
+    /// The link `Client::unsubscribe` tries first.
+    pub fn preferred(&self) -> Option<&str> {
+        self.one_click
+            .as_deref()
+            .or(self.mailto.as_deref())
+            .or(self.url.as_deref())
+    }
+}
+
+/// What `Client::unsubscribe` did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unsubscribed {
+    /// Posted to this one-click URL
+    OneClick(String),
+    /// Nothing was posted; this `mailto:` URI asks for an email, for the caller
+    /// to confirm and send
+    Mailto(String),
+    /// Nothing could be sent; this page has to be visited instead
+    Manual(String),
+}
+
+/// The email a `mailto:` unsubscribe URI asks for, to its single address and
+/// with its `subject`; a `body` and any extra recipients are ignored.
+pub fn mailto_email(uri: &str) -> Result<crate::mime::Email> {
+    let url = url::Url::parse(uri).with_context(|| format!("Invalid mailto URI: {}", uri))?;
+    if url.scheme() != "mailto" {
+        bail!("Not a mailto URI: {}", uri);
+    }
+    let to = urlencoding::decode(url.path())
+        .with_context(|| format!("Invalid mailto URI: {}", uri))?
+        .into_owned();
+    if to.is_empty() {
+        bail!("No address in mailto URI: {}", uri);
+    }
+    let to = crate::mime::split_address_list(&to);
+    if to.len() != 1 {
+        bail!("Expected a single address in mailto URI: {}", uri);
+    }
+    let mut email = crate::mime::Email {
+        to,
+        subject: "unsubscribe".to_string(),
+        ..Default::default()
+    };
+    for (key, value) in url.query_pairs() {
+        if key.eq_ignore_ascii_case("subject") {
+            email.subject = value.into_owned();
+        }
+    }
+    Ok(email)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryList {
+    #[serde(default)]
+    pub history: Vec<History>,
+    pub next_page_token: Option<String>,
+    /// The mailbox's current history ID; pass it as the next start ID
+    pub history_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct History {
+    pub id: String,
+    #[serde(default)]
+    pub messages_added: Vec<HistoryMessage>,
+    #[serde(default)]
+    pub messages_deleted: Vec<HistoryMessage>,
+    #[serde(default)]
+    pub labels_added: Vec<HistoryLabelChange>,
+    #[serde(default)]
+    pub labels_removed: Vec<HistoryLabelChange>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryMessage {
+    pub message: MessageRef,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryLabelChange {
+    pub message: MessageRef,
+    #[serde(default)]
+    pub label_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchResponse {
+    pub history_id: String,
+    /// Milliseconds since the epoch; watches must be renewed before this
+    pub expiration: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PullResponse {
+    #[serde(default)]
+    pub received_messages: Vec<ReceivedMessage>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedMessage {
+    pub ack_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Message {
     pub id: String,
+    #[serde(rename = "threadId")]
+    pub thread_id: Option<String>,
     pub snippet: Option<String>,
     pub payload: Option<Payload>,
     #[serde(rename = "labelIds")]
     pub label_ids: Option<Vec<String>>,
+    /// Receipt time in milliseconds since the epoch, as a decimal string
+    #[serde(rename = "internalDate")]
+    pub internal_date: Option<String>,
+    /// Approximate size in bytes, counted against the storage quota
+    #[serde(rename = "sizeEstimate", skip_serializing_if = "Option::is_none")]
+    pub size_estimate: Option<u64>,
 }
 
+/// A message fetched with `format=raw`: the full RFC 822 source plus its labels.
 #[derive(Debug, Deserialize)]
+pub struct RawMessage {
+    pub id: String,
+    #[serde(rename = "labelIds", default)]
+    pub label_ids: Vec<String>,
+    raw: String,
+}
+
+impl RawMessage {
+    /// The decoded RFC 822 message bytes.
+    pub fn bytes(&self) -> Result<Vec<u8>> {
+        decode_base64url(&self.raw)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Payload {
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
     pub headers: Option<Vec<Header>>,
     pub body: Option<Body>,
     pub parts: Option<Vec<Part>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Header {
     pub name: String,
     pub value: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Body {
     pub data: Option<String>,
+    #[serde(rename = "attachmentId")]
+    pub attachment_id: Option<String>,
+    pub size: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Part {
+    #[serde(rename = "partId")]
+    pub part_id: Option<String>,
     #[serde(rename = "mimeType")]
     pub mime_type: String,
+    pub filename: Option<String>,
+    pub headers: Option<Vec<Header>>,
     pub body: Option<Body>,
     pub parts: Option<Vec<Part>>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct AttachmentData {
+    pub data: Option<String>,
+}
+
+/// Time limits for the requests of an HTTP client.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Longest wait for the server to send more of a response; big uploads and
+    /// downloads may take longer than this overall
+    pub read: Duration,
+    pub connect: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            read: Duration::from_secs(30),
+            connect: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Build the HTTP client to share between a [`Client`] and its token refreshes.
+///
+/// Redirects are not followed, as OAuth token endpoints must not be redirected.
+pub fn http_client(timeouts: Timeouts) -> reqwest::Client {
+    reqwest::Client::builder()
+        .read_timeout(timeouts.read)
+        .connect_timeout(timeouts.connect)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
 impl Client {
     pub fn new(access_token: &str) -> Self {
+        Self::with_http_client(http_client(Timeouts::default()), access_token)
+    }
+
+    /// A client sending its requests through `http`.
+    pub fn with_http_client(http: reqwest::Client, access_token: &str) -> Self {
+        Self::with_token_provider(http, StaticToken(access_token.to_string()))
+    }
+
+    /// A client authenticating with whatever token `tokens` hands out, renewing it on a 401.
+    pub fn with_token_provider(
+        http: reqwest::Client,
+        tokens: impl TokenProvider + 'static,
+    ) -> Self {
         Self {
-            http: reqwest::Client::builder()
-                .timeout(Duration::from_secs(30))
-                .build()
-                .expect("Failed to build HTTP client"),
-            access_token: access_token.to_string(),
-            last_request: Mutex::new(None),
-        }
-    }
-
-    async fn rate_limit(&self) {
-        let wait_duration = {
-            let mut last = self.last_request.lock().unwrap();
-            let now = Instant::now();
-            let wait = last
-                .map(|t| MIN_REQUEST_INTERVAL.saturating_sub(now.duration_since(t)))
-                .unwrap_or(Duration::ZERO);
-            *last = Some(now + wait);
-            wait
+            http,
+            tokens: Box::new(tokens),
+            scopes: Vec::new(),
+            quota: TokenBucket::new(quota::PER_USER_LIMIT),
+            urls: Urls::new(GMAIL_ROOT, PUBSUB_ROOT, PEOPLE_ROOT),
+            dry_run: false,
+            journal: None,
+            label_names: None,
+            cache: None,
+            daemon: None,
+        }
+    }
+
+    /// A client that sends its requests through the `gmail daemon` listening on
+    /// `socket`, which adds its own login (see [`crate::daemon`]).
+    pub fn via_daemon(http: reqwest::Client, socket: &Path) -> Self {
+        Self {
+            daemon: Some(socket.to_path_buf()),
+            ..Self::with_http_client(http, "")
+        }
+    }
+
+    /// Send every request, Gmail, Pub/Sub and People alike, to the server at `root`
+    /// (e.g. `http://127.0.0.1:8080`) instead of Google, for tests against a mock server.
+    pub fn with_base_url(mut self, root: &str) -> Self {
+        let root = root.trim_end_matches('/');
+        self.urls = Urls::new(root, root, root);
+        self
+    }
+
+    /// Serve messages seen before from `cache` instead of fetching them again.
+    pub fn with_cache(mut self, cache: Cache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    /// Record label changes to the undo journal at `path`.
+    pub fn with_journal(mut self, path: PathBuf) -> Self {
+        self.journal = Some(path);
+        self
+    }
+
+    /// Save the names of listed labels to `path`, for shell completion.
+    pub fn with_label_names(mut self, path: PathBuf) -> Self {
+        self.label_names = Some(path);
+        self
+    }
+
+    /// Stop recording to the undo journal.
+    pub fn without_journal(mut self) -> Self {
+        self.journal = None;
+        self
+    }
+
+    /// Note a label change that went through: journal it for undo and drop
+    /// the affected messages from the cache.
+    fn record(&self, target: Target, ids: &[String], add: &[&str], remove: &[&str]) -> Result<()> {
+        let entry = journal::Entry::new(target, ids, add, remove);
+        self.record_entries(target, ids, std::slice::from_ref(&entry))
+    }
+
+    /// `record` for a change journaled as several entries, or none if nothing changed.
+    fn record_entries(
+        &self,
+        target: Target,
+        ids: &[String],
+        entries: &[journal::Entry],
+    ) -> Result<()> {
+        if self.dry_run || ids.is_empty() {
+            return Ok(());
+        }
+        if let Some(cache) = &self.cache {
+            match target {
+                Target::Messages => cache.forget(ids),
+                Target::Threads => cache.forget_threads(ids),
+            }
+            .context("Labels were changed, but updating the cache failed")?;
+        }
+        if let Some(path) = self.journal.as_ref().filter(|_| !entries.is_empty()) {
+            journal::append_all(path, entries)
+                .context("Labels were changed, but recording them for undo failed")?;
+        }
+        Ok(())
+    }
+
+    /// Spend at most `units` of Gmail quota per second (see [`quota::units`]).
+    pub fn with_max_qps(mut self, units: u32) -> Self {
+        self.quota = TokenBucket::new(units);
+        self
+    }
+
+    /// Print requests that would change anything instead of sending them.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Record the scopes the token was granted so requests outside them fail fast.
+    pub fn with_scopes(mut self, scopes: Vec<String>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    /// The current access token, e.g. to inspect with `auth::token_info`.
+    pub async fn access_token(&self) -> Result<String> {
+        self.tokens.token().await
+    }
+
+    /// Send a request made elsewhere, e.g. by a process talking to `gmail daemon`,
+    /// with this client's login. Only Google API URLs are accepted, so the token
+    /// can't be sent to any other server.
+    pub(crate) async fn send_request(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+        headers: reqwest::header::HeaderMap,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        if !self.urls.contains(url) {
+            bail!("Refusing to send a request outside the Gmail API: {}", url);
+        }
+        let mut request = self.http.request(method, url).headers(headers);
+        if !body.is_empty() {
+            request = request.body(body);
+        }
+        self.send(request).await
+    }
+
+    /// Send an authenticated request, refreshing the token and retrying once on 401.
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        if let Some(socket) = &self.daemon {
+            let request = request.build().context("Failed to build request")?;
+            if self.dry_run && !self.is_read(&request) {
+                return Ok(skip_request(&request));
+            }
+            return crate::daemon::forward(socket, request).await;
+        }
+        let token = self.tokens.token().await?;
+        let request = request
+            .bearer_auth(&token)
+            .build()
+            .context("Failed to build request")?;
+        self.check_scope(&request)?;
+        if self.dry_run && !self.is_read(&request) {
+            return Ok(skip_request(&request));
+        }
+        let units = quota::units(request.method(), request.url().path());
+        self.rate_limit(units).await;
+
+        let retry = request.try_clone();
+        let resp = self.execute(request).await?;
+
+        if resp.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(resp);
+        }
+        let Some(mut retry) = retry else {
+            return Ok(resp);
         };
-        if !wait_duration.is_zero() {
-            tokio::time::sleep(wait_duration).await;
+
+        tracing::debug!("access token expired, refreshing");
+        let Some(token) = self.tokens.refresh(&token).await? else {
+            return Ok(resp);
+        };
+        retry.headers_mut().insert(
+            reqwest::header::AUTHORIZATION,
+            format!("Bearer {}", token).parse()?,
+        );
+        // The retry spends quota like any other request
+        self.rate_limit(units).await;
+        self.execute(retry).await
+    }
+
+    /// Whether a request only reads; batch requests here only ever carry GETs.
+    fn is_read(&self, request: &reqwest::Request) -> bool {
+        request.method() == reqwest::Method::GET || request.url().as_str() == self.urls.batch
+    }
+
+    /// Send a request as is, logging it and how long the response took.
+    async fn execute(&self, request: reqwest::Request) -> Result<reqwest::Response> {
+        tracing::debug!(method = %request.method(), url = %request.url(), "request");
+        if let Some(body) = request.body().and_then(|b| b.as_bytes()) {
+            tracing::trace!(body = %redact(&printable_body(body)), "request body");
+        }
+        let started = Instant::now();
+        let resp = self
+            .http
+            .execute(request)
+            .await
+            .context("Failed to send request")?;
+        tracing::debug!(
+            status = resp.status().as_u16(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "response"
+        );
+        Ok(resp)
+    }
+
+    /// Fail before sending if the login's granted scopes can't cover this request.
+    fn check_scope(&self, request: &reqwest::Request) -> Result<()> {
+        if self.scopes.is_empty() {
+            return Ok(());
+        }
+        let url = request.url().as_str();
+        let accepted = if url.starts_with(&self.urls.people) {
+            &[crate::auth::SCOPE_CONTACTS]
+        } else if [&self.urls.api, &self.urls.upload, &self.urls.batch]
+            .iter()
+            .any(|base| url.starts_with(base.as_str()))
+        {
+            required_scopes(request.method(), request.url().path())
+        } else {
+            return Ok(());
+        };
+        if accepted.iter().any(|s| self.scopes.iter().any(|g| g == s)) {
+            return Ok(());
+        }
+        let hint = if accepted == [crate::auth::SCOPE_FULL] {
+            "gmail login --full-access"
+        } else if accepted == [crate::auth::SCOPE_CONTACTS] {
+            "gmail login --contacts"
+        } else {
+            "gmail login"
+        };
+        bail!(
+            "This command needs one of these scopes: {}\nThe current login only granted: {}\nRe-login with broader scope: {}",
+            accepted.join(", "),
+            self.scopes.join(", "),
+            hint
+        )
+    }
+
+    /// Wait until `units` of quota are available.
+    async fn rate_limit(&self, units: u32) {
+        let wait = self.quota.reserve(units, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn parse_json<R: serde::de::DeserializeOwned>(resp: reqwest::Response) -> Result<R> {
+        if resp.headers().contains_key(DRY_RUN_HEADER) {
+            return Err(GmailError::DryRun);
         }
+        let body = resp.bytes().await.context("Failed to read response")?;
+        tracing::trace!(body = %redact(&printable_body(&body)), "response body");
+        serde_json::from_slice(&body).context("Failed to parse JSON response")
     }
 
     async fn check_response(resp: reqwest::Response) -> Result<reqwest::Response> {
         if !resp.status().is_success() {
             let status = resp.status();
             let body = resp.text().await.unwrap_or_default();
-            anyhow::bail!("HTTP {} - {}", status, body);
+            tracing::trace!(body = %redact(&body), "error response body");
+            return Err(GmailError::from_response(status.as_u16(), &body));
         }
         Ok(resp)
     }
 
     async fn get<T: serde::de::DeserializeOwned>(&self, endpoint: &str) -> Result<T> {
-        self.rate_limit().await;
-        let url = format!("{}{}", BASE_URL, endpoint);
+        let url = format!("{}{}", self.urls.api, endpoint);
+
+        let resp = self.send(self.http.get(&url)).await?;
+
+        let resp = Self::check_response(resp).await?;
+        Self::parse_json(resp).await
+    }
+
+    async fn post(&self, endpoint: &str) -> Result<()> {
+        let url = format!("{}{}", self.urls.api, endpoint);
 
         let resp = self
-            .http
-            .get(&url)
-            .bearer_auth(&self.access_token)
-            .send()
-            .await
-            .context("Failed to send request")?;
+            .send(self.http.post(&url).header("Content-Length", "0"))
+            .await?;
+
+        Self::check_response(resp).await?;
+        Ok(())
+    }
+
+    async fn post_json<T: Serialize>(&self, endpoint: &str, body: &T) -> Result<()> {
+        let url = format!("{}{}", self.urls.api, endpoint);
+
+        let resp = self.send(self.http.post(&url).json(body)).await?;
+
+        Self::check_response(resp).await?;
+        Ok(())
+    }
+
+    async fn post_json_with_response<T: Serialize, R: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        body: &T,
+    ) -> Result<R> {
+        let url = format!("{}{}", self.urls.api, endpoint);
+
+        let resp = self.send(self.http.post(&url).json(body)).await?;
+
+        let resp = Self::check_response(resp).await?;
+        Self::parse_json(resp).await
+    }
+
+    async fn put_json_with_response<T: Serialize, R: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        body: &T,
+    ) -> Result<R> {
+        let url = format!("{}{}", self.urls.api, endpoint);
+
+        let resp = self.send(self.http.put(&url).json(body)).await?;
 
         let resp = Self::check_response(resp).await?;
-        resp.json().await.context("Failed to parse JSON response")
+        Self::parse_json(resp).await
+    }
+
+    async fn patch_json_with_response<T: Serialize, R: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        body: &T,
+    ) -> Result<R> {
+        let url = format!("{}{}", self.urls.api, endpoint);
+
+        let resp = self.send(self.http.patch(&url).json(body)).await?;
+
+        let resp = Self::check_response(resp).await?;
+        Self::parse_json(resp).await
+    }
+
+    async fn delete(&self, endpoint: &str) -> Result<()> {
+        let url = format!("{}{}", self.urls.api, endpoint);
+
+        let resp = self.send(self.http.delete(&url)).await?;
+
+        Self::check_response(resp).await?;
+        Ok(())
+    }
+
+    /// Upload a raw RFC 822 message through a resumable upload session.
+    async fn upload_resumable<R: serde::de::DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+        metadata: &serde_json::Value,
+        raw: &[u8],
+    ) -> Result<R> {
+        let separator = if endpoint.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{}{}uploadType=resumable",
+            self.urls.upload, endpoint, separator
+        );
+
+        let resp = self
+            .send(
+                self.http
+                    .request(method, &url)
+                    .header("X-Upload-Content-Type", "message/rfc822")
+                    .header("X-Upload-Content-Length", raw.len())
+                    .json(metadata),
+            )
+            .await
+            .context("Failed to start upload session")?;
+
+        let resp = Self::check_response(resp).await?;
+        if resp.headers().contains_key(DRY_RUN_HEADER) {
+            return Err(GmailError::DryRun);
+        }
+        let session_url = resp
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| format_err!("Upload session response has no Location header"))?
+            .to_string();
+
+        let resp = self
+            .send(
+                self.http
+                    .put(&session_url)
+                    .header(reqwest::header::CONTENT_TYPE, "message/rfc822")
+                    .timeout(UPLOAD_TIMEOUT)
+                    .body(raw.to_vec()),
+            )
+            .await
+            .context("Failed to upload message")?;
+
+        let resp = Self::check_response(resp).await?;
+        Self::parse_json(resp).await
+    }
+
+    pub async fn get_profile(&self) -> Result<Profile> {
+        self.get("/users/me/profile").await
+    }
+
+    /// Fetch every change since `start_history_id`, following all result pages.
+    ///
+    /// Gmail only keeps about a week of history; older start IDs fail with 404
+    /// and the caller needs to fall back to a full listing.
+    pub async fn list_history(
+        &self,
+        start_history_id: &str,
+        label: Option<&str>,
+    ) -> Result<HistoryList> {
+        let mut all = HistoryList::default();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut endpoint = format!(
+                "/users/me/history?startHistoryId={}",
+                urlencoding::encode(start_history_id)
+            );
+            if let Some(label) = label {
+                endpoint.push_str(&format!("&labelId={}", urlencoding::encode(label)));
+            }
+            if let Some(token) = &page_token {
+                endpoint.push_str(&format!("&pageToken={}", urlencoding::encode(token)));
+            }
+            let page: HistoryList = self.get(&endpoint).await?;
+            all.history.extend(page.history);
+            all.history_id = page.history_id.or(all.history_id);
+            match page.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(all),
+            }
+        }
+    }
+
+    /// Start push notifications to a Cloud Pub/Sub topic (`projects/x/topics/y`).
+    pub async fn watch(&self, topic: &str, label_ids: &[String]) -> Result<WatchResponse> {
+        let mut body = serde_json::json!({ "topicName": topic });
+        if !label_ids.is_empty() {
+            body["labelIds"] = serde_json::json!(label_ids);
+            body["labelFilterBehavior"] = serde_json::json!("include");
+        }
+        self.post_json_with_response("/users/me/watch", &body).await
+    }
+
+    pub async fn stop_watch(&self) -> Result<()> {
+        self.post("/users/me/stop").await
+    }
+
+    /// Pull pending messages from a Pub/Sub subscription (`projects/x/subscriptions/y`).
+    ///
+    /// Blocks until messages arrive; returns an empty response if none do in time.
+    pub async fn pubsub_pull(&self, subscription: &str, max_messages: u32) -> Result<PullResponse> {
+        let url = format!("{}/{}:pull", self.urls.pubsub, subscription);
+
+        let result = self
+            .send(
+                self.http
+                    .post(&url)
+                    .timeout(PULL_TIMEOUT)
+                    .json(&serde_json::json!({ "maxMessages": max_messages })),
+            )
+            .await;
+        let resp = match result {
+            Ok(resp) => resp,
+            Err(e) if matches!(e.root(), GmailError::Network(e) if e.is_timeout()) => {
+                return Ok(PullResponse::default());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let resp = Self::check_response(resp).await?;
+        Self::parse_json(resp).await
+    }
+
+    pub async fn pubsub_ack(&self, subscription: &str, ack_ids: &[String]) -> Result<()> {
+        let url = format!("{}/{}:acknowledge", self.urls.pubsub, subscription);
+
+        let resp = self
+            .send(
+                self.http
+                    .post(&url)
+                    .json(&serde_json::json!({ "ackIds": ack_ids })),
+            )
+            .await?;
+
+        Self::check_response(resp).await?;
+        Ok(())
+    }
+
+    /// Search the user's contacts by name or email prefix through the People API.
+    pub async fn search_contacts(&self, query: &str, limit: u32) -> Result<Vec<Contact>> {
+        let url = format!("{}/people:searchContacts", self.urls.people);
+        let mut found = Vec::new();
+        // Google asks for an empty "warmup" search first so the results are current
+        for query in ["", query] {
+            let resp = self
+                .send(self.http.get(&url).query(&[
+                    ("query", query),
+                    ("readMask", "names,emailAddresses"),
+                    ("pageSize", &limit.min(MAX_CONTACT_RESULTS).to_string()),
+                ]))
+                .await?;
+            let resp = Self::check_response(resp).await?;
+            found = Self::parse_json::<SearchResponse>(resp)
+                .await?
+                .into_contacts();
+        }
+        Ok(found)
+    }
+
+    pub async fn list_labels(&self) -> Result<LabelList> {
+        let list: LabelList = self.get("/users/me/labels").await?;
+        if let Some(path) = &self.label_names {
+            let names: Vec<&str> = list
+                .labels
+                .iter()
+                .flatten()
+                .map(|l| l.name.as_str())
+                .collect();
+            // Only completions read it, so failing to save isn't worth failing the command
+            if let Err(e) = std::fs::write(path, serde_json::json!(names).to_string()) {
+                tracing::debug!("failed to save label names: {}", e);
+            }
+        }
+        Ok(list)
+    }
+
+    pub async fn create_label(&self, name: &str) -> Result<Label> {
+        // Capitalize first letter for consistency
+        let capitalized = capitalize_first(name);
+        let body = serde_json::json!({
+            "name": capitalized,
+            "labelListVisibility": "labelShow",
+            "messageListVisibility": "show"
+        });
+        self.post_json_with_response("/users/me/labels", &body)
+            .await
+    }
+
+    /// Create a label, first creating any missing parents of a nested `Parent/Child` name.
+    pub async fn create_label_path(&self, name: &str, color: Option<&LabelColor>) -> Result<Label> {
+        let existing = self.list_labels().await?.labels.unwrap_or_default();
+        let exists = |path: &str| existing.iter().any(|l| l.name.eq_ignore_ascii_case(path));
+        if exists(name) {
+            bail!("Label already exists: {}", name);
+        }
+
+        // Parents are created as typed; one that exists in other case keeps
+        // its name, so the new label nests under it rather than beside it
+        let mut segments: Vec<&str> = name.split('/').collect();
+        let leaf = segments.pop().expect("split yields at least one segment");
+        let mut path = String::new();
+        for segment in segments {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(segment);
+            match existing.iter().find(|l| l.name.eq_ignore_ascii_case(&path)) {
+                Some(label) => path = label.name.clone(),
+                None => {
+                    self.post_label(&path, None).await?;
+                }
+            }
+        }
+        if !path.is_empty() {
+            path.push('/');
+        }
+        path.push_str(leaf);
+        self.post_label(&path, color).await
+    }
+
+    async fn post_label(&self, name: &str, color: Option<&LabelColor>) -> Result<Label> {
+        let mut body = serde_json::json!({
+            "name": name,
+            "labelListVisibility": "labelShow",
+            "messageListVisibility": "show"
+        });
+        if let Some(color) = color {
+            body["color"] = serde_json::to_value(color)?;
+        }
+        self.post_json_with_response("/users/me/labels", &body)
+            .await
+    }
+
+    /// Fetch a single label, including its message and thread counts.
+    pub async fn get_label(&self, id: &str) -> Result<Label> {
+        self.get(&format!("/users/me/labels/{}", urlencoding::encode(id)))
+            .await
+    }
+
+    pub async fn update_label(&self, id: &str, changes: &serde_json::Value) -> Result<Label> {
+        self.patch_json_with_response(
+            &format!("/users/me/labels/{}", urlencoding::encode(id)),
+            changes,
+        )
+        .await
+    }
+
+    pub async fn delete_label(&self, id: &str) -> Result<()> {
+        self.delete(&format!("/users/me/labels/{}", urlencoding::encode(id)))
+            .await
+    }
+
+    /// Rename a label along with its nested children (`Old/x` becomes `New/x`).
+    ///
+    /// Returns the number of labels renamed.
+    pub async fn rename_label(&self, old_name: &str, new_name: &str) -> Result<usize> {
+        let labels = self.list_labels().await?.labels.unwrap_or_default();
+        let target = labels
+            .iter()
+            .find(|l| l.name.eq_ignore_ascii_case(old_name))
+            .ok_or_else(|| format_err!("Label not found: {}", old_name))?;
+        let child_prefix = format!("{}/", target.name);
+
+        let mut renamed = 0;
+        for label in &labels {
+            let name = if label.id == target.id {
+                new_name.to_string()
+            } else if let Some(rest) = label.name.strip_prefix(&child_prefix) {
+                format!("{}/{}", new_name, rest)
+            } else {
+                continue;
+            };
+            self.update_label(&label.id, &serde_json::json!({ "name": name }))
+                .await?;
+            renamed += 1;
+        }
+        Ok(renamed)
+    }
+
+    pub async fn get_or_create_label(&self, name: &str) -> Result<String> {
+        // Check if label already exists (case-insensitive, Gmail is case-insensitive)
+        let labels = self.list_labels().await?;
+        if let Some(existing) = labels.labels {
+            for label in existing {
+                if label.name.eq_ignore_ascii_case(name) {
+                    return Ok(label.id);
+                }
+            }
+        }
+        // Create new label
+        let label = self.create_label(name).await?;
+        Ok(label.id)
+    }
+
+    /// List up to `max_results` messages, following result pages as needed.
+    pub async fn list_messages(
+        &self,
+        query: Option<&str>,
+        label: &str,
+        max_results: u32,
+    ) -> Result<MessageList> {
+        let mut messages = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let remaining = max_results.saturating_sub(messages.len() as u32);
+            let page = self
+                .list_messages_page(
+                    query,
+                    label,
+                    remaining.min(MAX_PAGE_SIZE),
+                    page_token.as_deref(),
+                )
+                .await?;
+            messages.extend(page.messages.unwrap_or_default());
+            page_token = page.next_page_token;
+            if page_token.is_none() || messages.len() as u32 >= max_results {
+                break;
+            }
+        }
+        Ok(MessageList {
+            messages: (!messages.is_empty()).then_some(messages),
+            next_page_token: page_token,
+            result_size_estimate: None,
+        })
+    }
+
+    /// Fetch a single page of results; pass the previous page's `next_page_token` to continue.
+    pub async fn list_messages_page(
+        &self,
+        query: Option<&str>,
+        label: &str,
+        max_results: u32,
+        page_token: Option<&str>,
+    ) -> Result<MessageList> {
+        let mut endpoint = format!("/users/me/messages?maxResults={}", max_results);
+        if !label.is_empty() {
+            endpoint.push_str(&format!("&labelIds={}", urlencoding::encode(label)));
+        }
+        if let Some(q) = query {
+            endpoint.push_str(&format!("&q={}", urlencoding::encode(q)));
+        }
+        if let Some(token) = page_token {
+            endpoint.push_str(&format!("&pageToken={}", urlencoding::encode(token)));
+        }
+        self.get(&endpoint).await
+    }
+
+    /// Collect the IDs of every message matching a query, following all result pages.
+    pub async fn list_all_message_ids(
+        &self,
+        query: Option<&str>,
+        label: &str,
+    ) -> Result<Vec<String>> {
+        self.messages_stream(query, label)
+            .map_ok(|m| m.id)
+            .try_collect()
+            .await
+    }
+
+    /// Every message matching a query, fetching the next page only once the
+    /// stream has used up the previous one.
+    ///
+    /// An empty `label` searches all mail.
+    pub fn messages_stream<'a>(
+        &'a self,
+        query: Option<&'a str>,
+        label: &'a str,
+    ) -> impl Stream<Item = Result<MessageRef>> + 'a {
+        // None once the last page is in; Some(None) before the first
+        let first: Option<Option<String>> = Some(None);
+        stream::try_unfold(first, move |page_token| async move {
+            let Some(page_token) = page_token else {
+                return Ok::<_, GmailError>(None);
+            };
+            let page = self
+                .list_messages_page(query, label, MAX_PAGE_SIZE, page_token.as_deref())
+                .await?;
+            let refs = page.messages.unwrap_or_default().into_iter().map(Ok);
+            Ok(Some((stream::iter(refs), page.next_page_token.map(Some))))
+        })
+        .try_flatten()
+    }
+
+    /// IDs of messages whose Message-ID header is `message_id`, including spam and trash.
+    pub async fn find_by_rfc822_id(&self, message_id: &str) -> Result<Vec<String>> {
+        self.list_all_message_ids(Some(&rfc822_query(message_id)), "")
+            .await
+    }
+
+    pub async fn list_threads(
+        &self,
+        query: Option<&str>,
+        label: &str,
+        max_results: u32,
+    ) -> Result<ThreadList> {
+        let mut endpoint = format!("/users/me/threads?maxResults={}", max_results);
+        if !label.is_empty() {
+            endpoint.push_str(&format!("&labelIds={}", urlencoding::encode(label)));
+        }
+        if let Some(q) = query {
+            endpoint.push_str(&format!("&q={}", urlencoding::encode(q)));
+        }
+        self.get(&endpoint).await
+    }
+
+    pub async fn get_thread(&self, id: &str) -> Result<Thread> {
+        self.get(&format!("/users/me/threads/{}", urlencoding::encode(id)))
+            .await
+    }
+
+    /// Fetch a thread with only the headers needed to summarize it.
+    pub async fn get_thread_summary(&self, id: &str) -> Result<Thread> {
+        self.get(&format!(
+            "/users/me/threads/{}{}",
+            urlencoding::encode(id),
+            metadata_query(&["From", "Subject", "Date"])
+        ))
+        .await
+    }
+
+    pub async fn get_message(&self, id: &str) -> Result<Message> {
+        let endpoint = format!("/users/me/messages/{}", urlencoding::encode(id));
+        self.get_cached(id, Format::Full, &endpoint).await
+    }
+
+    pub async fn get_raw_message(&self, id: &str) -> Result<RawMessage> {
+        self.get(&format!(
+            "/users/me/messages/{}?format=raw",
+            urlencoding::encode(id)
+        ))
+        .await
+    }
+
+    /// Fetch only labels, snippet and the named headers, skipping the message body.
+    pub async fn get_message_metadata(&self, id: &str, headers: &[&str]) -> Result<Message> {
+        let endpoint = format!(
+            "/users/me/messages/{}{}",
+            urlencoding::encode(id),
+            metadata_query(headers)
+        );
+        self.get_cached(id, Format::Metadata(headers), &endpoint)
+            .await
+    }
+
+    /// Fetch the metadata of many messages with one batch request per [`BATCH_GET_LIMIT`] IDs.
+    ///
+    /// Results are in the order of `ids`, and each can fail on its own,
+    /// e.g. with a 404 for a message deleted since it was listed.
+    pub async fn get_messages_metadata(
+        &self,
+        ids: &[String],
+        headers: &[&str],
+    ) -> Result<Vec<Result<Message>>> {
+        let format = Format::Metadata(headers);
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            let cached = match &self.cache {
+                Some(cache) => cache.get(id, format)?,
+                None => None,
+            };
+            results.push(cached.map(Ok));
+        }
+
+        let missing: Vec<usize> = (0..ids.len()).filter(|&i| results[i].is_none()).collect();
+        let query = metadata_query(headers);
+        for chunk in missing.chunks(BATCH_GET_LIMIT) {
+            let endpoints: Vec<String> = chunk
+                .iter()
+                .map(|&i| {
+                    format!(
+                        "/users/me/messages/{}{}",
+                        urlencoding::encode(&ids[i]),
+                        query
+                    )
+                })
+                .collect();
+            for (&i, result) in chunk.iter().zip(self.batch_get_messages(&endpoints).await?) {
+                if let (Some(Ok(msg)), Some(cache)) = (&result, &self.cache) {
+                    cache.put(msg, format)?;
+                }
+                results[i] = result;
+            }
+        }
+
+        Ok(results
+            .into_iter()
+            .zip(ids)
+            .map(|(result, id)| {
+                result
+                    .unwrap_or_else(|| Err(format_err!("No response for message {} in batch", id)))
+            })
+            .collect())
+    }
+
+    /// GET up to [`BATCH_GET_LIMIT`] message endpoints in one batch request.
+    /// Results are in the order of `endpoints`; None where the batch had no answer.
+    async fn batch_get_messages(
+        &self,
+        endpoints: &[String],
+    ) -> Result<Vec<Option<Result<Message>>>> {
+        // The batch itself is nearly free; each call in it costs what it would alone
+        let units: u32 = endpoints
+            .iter()
+            .map(|e| quota::units(&reqwest::Method::GET, e))
+            .sum();
+        self.rate_limit(units).await;
+
+        let resp = self
+            .send(
+                self.http
+                    .post(&self.urls.batch)
+                    .header(
+                        reqwest::header::CONTENT_TYPE,
+                        format!("multipart/mixed; boundary={}", BATCH_BOUNDARY),
+                    )
+                    .body(batch_body(BATCH_BOUNDARY, endpoints)),
+            )
+            .await?;
+        let resp = Self::check_response(resp).await?;
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = resp.text().await?;
+        tracing::trace!(body = %printable_body(body.as_bytes()), "batch response body");
+        let mut results: Vec<Option<Result<Message>>> = std::iter::repeat_with(|| None)
+            .take(endpoints.len())
+            .collect();
+        for (n, status, body) in parse_batch_response(&content_type, &body)? {
+            let Some(slot) = results.get_mut(n) else {
+                continue;
+            };
+            *slot = Some(if status == 200 {
+                serde_json::from_str::<Message>(&body).map_err(GmailError::from)
+            } else {
+                Err(GmailError::from_response(status, &body))
+            });
+        }
+        Ok(results)
+    }
+
+    /// The labels each message has right now, bypassing the cache; None for a
+    /// message that no longer exists.
+    async fn current_label_ids(&self, ids: &[String]) -> Result<Vec<Option<Vec<String>>>> {
+        let mut labels = Vec::with_capacity(ids.len());
+        for chunk in ids.chunks(BATCH_GET_LIMIT) {
+            let endpoints: Vec<String> = chunk
+                .iter()
+                .map(|id| {
+                    format!(
+                        "/users/me/messages/{}?format=minimal",
+                        urlencoding::encode(id)
+                    )
+                })
+                .collect();
+            for (id, result) in chunk.iter().zip(self.batch_get_messages(&endpoints).await?) {
+                labels.push(match result {
+                    Some(Ok(msg)) => Some(msg.label_ids.unwrap_or_default()),
+                    Some(Err(e)) if e.is_not_found() => None,
+                    Some(Err(e)) => return Err(e),
+                    None => bail!("No response for message {} in batch", id),
+                });
+            }
+        }
+        Ok(labels)
+    }
+
+    /// Answer from the cache when it has the message in `format`, otherwise fetch and store it.
+    async fn get_cached(&self, id: &str, format: Format<'_>, endpoint: &str) -> Result<Message> {
+        let Some(cache) = &self.cache else {
+            return self.get(endpoint).await;
+        };
+        if let Some(msg) = cache.get(id, format)? {
+            return Ok(msg);
+        }
+        let msg: Message = self.get(endpoint).await?;
+        cache.put(&msg, format)?;
+        Ok(msg)
+    }
+
+    pub async fn modify_labels(&self, id: &str, add: &[&str], remove: &[&str]) -> Result<()> {
+        let endpoint = format!("/users/me/messages/{}/modify", urlencoding::encode(id));
+        let body = serde_json::json!({
+            "addLabelIds": add,
+            "removeLabelIds": remove
+        });
+        self.post_json(&endpoint, &body).await?;
+        self.record(Target::Messages, &[id.to_string()], add, remove)
+    }
+
+    /// Modify labels on many messages, one batchModify call per 1000 IDs.
+    ///
+    /// When journaling, each message's labels are read first so that undo only
+    /// reverses what actually changed.
+    pub async fn batch_modify(&self, ids: &[String], add: &[&str], remove: &[&str]) -> Result<()> {
+        let before = match &self.journal {
+            Some(_) if !self.dry_run && !ids.is_empty() => Some(self.current_label_ids(ids).await?),
+            _ => None,
+        };
+        let mut done = 0;
+        let mut result = Ok(());
+        for chunk in ids.chunks(BATCH_LIMIT) {
+            let body = serde_json::json!({
+                "ids": chunk,
+                "addLabelIds": add,
+                "removeLabelIds": remove
+            });
+            result = self
+                .post_json("/users/me/messages/batchModify", &body)
+                .await;
+            if result.is_err() {
+                break;
+            }
+            done += chunk.len();
+        }
+        // Chunks that went through can still be undone after a later one fails
+        let entries = match &before {
+            Some(before) => journal::actual_changes(&ids[..done], &before[..done], add, remove),
+            None => vec![journal::Entry::new(
+                Target::Messages,
+                &ids[..done],
+                add,
+                remove,
+            )],
+        };
+        self.record_entries(Target::Messages, &ids[..done], &entries)?;
+        result
+    }
+
+    pub async fn batch_add_label(&self, ids: &[String], label: &str) -> Result<()> {
+        let label_id = self.resolve_label_for_add(label).await?;
+        self.batch_modify(ids, &[&label_id], &[]).await
+    }
+
+    pub async fn batch_remove_label(&self, ids: &[String], label: &str) -> Result<()> {
+        let label_id = self.resolve_label_for_remove(label).await?;
+        self.batch_modify(ids, &[], &[&label_id]).await
+    }
+
+    /// Permanently delete messages, bypassing trash. Requires the full mail scope.
+    pub async fn batch_delete(&self, ids: &[String]) -> Result<()> {
+        for chunk in ids.chunks(BATCH_LIMIT) {
+            let body = serde_json::json!({ "ids": chunk });
+            self.post_json("/users/me/messages/batchDelete", &body)
+                .await?;
+            if let Some(cache) = self.cache.as_ref().filter(|_| !self.dry_run) {
+                cache.forget(chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Trash messages; a single ID uses the trash endpoint, several go through batchModify.
+    pub async fn batch_trash(&self, ids: &[String]) -> Result<()> {
+        match ids {
+            [id] => self.trash(id).await,
+            _ => self.batch_modify(ids, &["TRASH"], &[]).await,
+        }
+    }
+
+    pub async fn archive(&self, id: &str) -> Result<()> {
+        self.modify_labels(id, &[], &["INBOX"]).await
+    }
+
+    pub async fn mark_spam(&self, id: &str) -> Result<()> {
+        self.modify_labels(id, &["SPAM"], &["INBOX"]).await
+    }
+
+    pub async fn unspam(&self, id: &str) -> Result<()> {
+        self.modify_labels(id, &["INBOX"], &["SPAM"]).await
+    }
+
+    pub async fn mark_read(&self, id: &str) -> Result<()> {
+        self.modify_labels(id, &[], &["UNREAD"]).await
+    }
+
+    pub async fn mark_unread(&self, id: &str) -> Result<()> {
+        self.modify_labels(id, &["UNREAD"], &[]).await
+    }
+
+    pub async fn clear_labels(&self, id: &str) -> Result<Vec<String>> {
+        let msg = self.get_message(id).await?;
+        let labels = msg.label_ids.unwrap_or_default();
+        let user_labels: Vec<&str> = labels
+            .iter()
+            .filter(|l| !is_system_label(l))
+            .map(|s| s.as_str())
+            .collect();
+        if !user_labels.is_empty() {
+            self.modify_labels(id, &[], &user_labels).await?;
+        }
+        Ok(user_labels.into_iter().map(|s| s.to_string()).collect())
+    }
+
+    pub async fn add_label(&self, id: &str, label: &str) -> Result<()> {
+        let label_id = self.resolve_label_for_add(label).await?;
+        self.modify_labels(id, &[&label_id], &[]).await
+    }
+
+    pub async fn remove_label(&self, id: &str, label: &str) -> Result<()> {
+        let label_id = self.resolve_label_for_remove(label).await?;
+        self.modify_labels(id, &[], &[&label_id]).await
+    }
+
+    /// Resolve a label name to its ID, creating user labels that don't exist yet.
+    pub async fn resolve_label_for_add(&self, label: &str) -> Result<String> {
+        // For custom labels, we need to get/create the label ID first
+        if is_system_label(label) {
+            Ok(label.to_string())
+        } else {
+            self.get_or_create_label(label).await
+        }
+    }
+
+    /// Resolve a label name to the ID of an existing label.
+    pub async fn resolve_label_for_remove(&self, label: &str) -> Result<String> {
+        // For custom labels, we need to find the label ID first
+        if is_system_label(label) {
+            Ok(label.to_string())
+        } else {
+            self.find_label(label)
+                .await?
+                .ok_or_else(|| format_err!("Label not found: {}", label))
+        }
+    }
+
+    pub async fn modify_thread_labels(
+        &self,
+        id: &str,
+        add: &[&str],
+        remove: &[&str],
+    ) -> Result<()> {
+        let endpoint = format!("/users/me/threads/{}/modify", urlencoding::encode(id));
+        let body = serde_json::json!({
+            "addLabelIds": add,
+            "removeLabelIds": remove
+        });
+        self.post_json(&endpoint, &body).await?;
+        self.record(Target::Threads, &[id.to_string()], add, remove)
+    }
+
+    pub async fn archive_thread(&self, id: &str) -> Result<()> {
+        self.modify_thread_labels(id, &[], &["INBOX"]).await
+    }
+
+    pub async fn mark_thread_read(&self, id: &str) -> Result<()> {
+        self.modify_thread_labels(id, &[], &["UNREAD"]).await
+    }
+
+    pub async fn mark_thread_unread(&self, id: &str) -> Result<()> {
+        self.modify_thread_labels(id, &["UNREAD"], &[]).await
+    }
+
+    pub async fn add_thread_label(&self, id: &str, label: &str) -> Result<()> {
+        let label_id = self.resolve_label_for_add(label).await?;
+        self.modify_thread_labels(id, &[&label_id], &[]).await
+    }
+
+    pub async fn remove_thread_label(&self, id: &str, label: &str) -> Result<()> {
+        let label_id = self.resolve_label_for_remove(label).await?;
+        self.modify_thread_labels(id, &[], &[&label_id]).await
+    }
+
+    pub async fn trash_thread(&self, id: &str) -> Result<()> {
+        self.post(&format!(
+            "/users/me/threads/{}/trash",
+            urlencoding::encode(id)
+        ))
+        .await?;
+        self.record(Target::Threads, &[id.to_string()], &["TRASH"], &[])
+    }
+
+    pub async fn untrash_thread(&self, id: &str) -> Result<()> {
+        self.post(&format!(
+            "/users/me/threads/{}/untrash",
+            urlencoding::encode(id)
+        ))
+        .await?;
+        self.record(Target::Threads, &[id.to_string()], &[], &["TRASH"])
+    }
+
+    pub async fn find_label(&self, name: &str) -> Result<Option<String>> {
+        let labels = self.list_labels().await?;
+        if let Some(label_list) = labels.labels {
+            for label in label_list {
+                if label.name.eq_ignore_ascii_case(name) {
+                    return Ok(Some(label.id));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn trash(&self, id: &str) -> Result<()> {
+        self.post(&format!(
+            "/users/me/messages/{}/trash",
+            urlencoding::encode(id)
+        ))
+        .await?;
+        self.record(Target::Messages, &[id.to_string()], &["TRASH"], &[])
+    }
+
+    pub async fn untrash(&self, id: &str) -> Result<()> {
+        self.post(&format!(
+            "/users/me/messages/{}/untrash",
+            urlencoding::encode(id)
+        ))
+        .await?;
+        self.record(Target::Messages, &[id.to_string()], &[], &["TRASH"])
+    }
+
+    pub async fn send_message(&self, raw: &[u8], thread_id: Option<&str>) -> Result<MessageRef> {
+        let endpoint = "/users/me/messages/send";
+        if raw.len() > SIMPLE_UPLOAD_LIMIT {
+            let metadata = serde_json::json!({ "threadId": thread_id });
+            return self
+                .upload_resumable(reqwest::Method::POST, endpoint, &metadata, raw)
+                .await;
+        }
+        let body = raw_message(raw, thread_id);
+        self.post_json_with_response(endpoint, &body).await
+    }
+
+    /// Add a message to the mailbox as if it had been delivered, without sending it.
+    ///
+    /// `date_source` is Gmail's `internalDateSource`: `dateHeader` or `receivedTime`.
+    pub async fn import_message(
+        &self,
+        raw: &[u8],
+        label_ids: &[String],
+        date_source: &str,
+        never_mark_spam: bool,
+    ) -> Result<MessageRef> {
+        let endpoint = format!(
+            "/users/me/messages/import?internalDateSource={}&neverMarkSpam={}",
+            urlencoding::encode(date_source),
+            never_mark_spam
+        );
+        if raw.len() > SIMPLE_UPLOAD_LIMIT {
+            let metadata = serde_json::json!({ "labelIds": label_ids });
+            return self
+                .upload_resumable(reqwest::Method::POST, &endpoint, &metadata, raw)
+                .await;
+        }
+        let mut body = raw_message(raw, None);
+        body["labelIds"] = serde_json::json!(label_ids);
+        self.post_json_with_response(&endpoint, &body).await
+    }
+
+    pub async fn get_attachment(&self, message_id: &str, attachment_id: &str) -> Result<Vec<u8>> {
+        let attachment: AttachmentData = self
+            .get(&format!(
+                "/users/me/messages/{}/attachments/{}",
+                urlencoding::encode(message_id),
+                urlencoding::encode(attachment_id)
+            ))
+            .await?;
+        decode_base64url(attachment.data.as_deref().unwrap_or_default())
+    }
+
+    /// Fetch the content of a part, downloading it if Gmail only returned an attachment ID.
+    pub async fn get_part_data(&self, message_id: &str, part: &Part) -> Result<Vec<u8>> {
+        let body = part
+            .body
+            .as_ref()
+            .ok_or_else(|| format_err!("Part has no body"))?;
+        if let Some(data) = &body.data {
+            return decode_base64url(data);
+        }
+        match &body.attachment_id {
+            Some(attachment_id) => self.get_attachment(message_id, attachment_id).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub async fn list_drafts(&self, max_results: u32) -> Result<DraftList> {
+        self.get(&format!("/users/me/drafts?maxResults={}", max_results))
+            .await
+    }
+
+    pub async fn get_draft(&self, id: &str) -> Result<Draft> {
+        self.get(&format!("/users/me/drafts/{}", urlencoding::encode(id)))
+            .await
+    }
+
+    pub async fn create_draft(&self, raw: &[u8], thread_id: Option<&str>) -> Result<Draft> {
+        let endpoint = "/users/me/drafts";
+        if raw.len() > SIMPLE_UPLOAD_LIMIT {
+            let metadata = serde_json::json!({ "message": { "threadId": thread_id } });
+            return self
+                .upload_resumable(reqwest::Method::POST, endpoint, &metadata, raw)
+                .await;
+        }
+        let body = serde_json::json!({ "message": raw_message(raw, thread_id) });
+        self.post_json_with_response(endpoint, &body).await
+    }
+
+    pub async fn update_draft(
+        &self,
+        id: &str,
+        raw: &[u8],
+        thread_id: Option<&str>,
+    ) -> Result<Draft> {
+        let endpoint = format!("/users/me/drafts/{}", urlencoding::encode(id));
+        if raw.len() > SIMPLE_UPLOAD_LIMIT {
+            let metadata = serde_json::json!({ "id": id, "message": { "threadId": thread_id } });
+            return self
+                .upload_resumable(reqwest::Method::PUT, &endpoint, &metadata, raw)
+                .await;
+        }
+        let body = serde_json::json!({ "id": id, "message": raw_message(raw, thread_id) });
+        self.put_json_with_response(&endpoint, &body).await
+    }
+
+    pub async fn send_draft(&self, id: &str) -> Result<MessageRef> {
+        let body = serde_json::json!({ "id": id });
+        self.post_json_with_response("/users/me/drafts/send", &body)
+            .await
+    }
+
+    pub async fn delete_draft(&self, id: &str) -> Result<()> {
+        self.delete(&format!("/users/me/drafts/{}", urlencoding::encode(id)))
+            .await
+    }
+
+    pub async fn list_filters(&self) -> Result<FilterList> {
+        self.get("/users/me/settings/filters").await
+    }
+
+    pub async fn create_filter(&self, filter: &Filter) -> Result<Filter> {
+        self.post_json_with_response("/users/me/settings/filters", filter)
+            .await
+    }
+
+    pub async fn delete_filter(&self, id: &str) -> Result<()> {
+        self.delete(&format!(
+            "/users/me/settings/filters/{}",
+            urlencoding::encode(id)
+        ))
+        .await
+    }
+
+    pub async fn get_vacation(&self) -> Result<VacationSettings> {
+        self.get("/users/me/settings/vacation").await
+    }
+
+    pub async fn update_vacation(&self, settings: &VacationSettings) -> Result<VacationSettings> {
+        self.put_json_with_response("/users/me/settings/vacation", settings)
+            .await
+    }
+
+    pub async fn list_send_as(&self) -> Result<SendAsList> {
+        self.get("/users/me/settings/sendAs").await
+    }
+
+    pub async fn get_send_as(&self, email: &str) -> Result<SendAs> {
+        self.get(&format!(
+            "/users/me/settings/sendAs/{}",
+            urlencoding::encode(email)
+        ))
+        .await
+    }
+
+    pub async fn set_default_send_as(&self, email: &str) -> Result<SendAs> {
+        let body = serde_json::json!({ "isDefault": true });
+        self.patch_json_with_response(
+            &format!("/users/me/settings/sendAs/{}", urlencoding::encode(email)),
+            &body,
+        )
+        .await
+    }
+
+    pub async fn update_signature(&self, email: &str, signature: &str) -> Result<SendAs> {
+        let body = serde_json::json!({ "signature": signature });
+        self.patch_json_with_response(
+            &format!("/users/me/settings/sendAs/{}", urlencoding::encode(email)),
+            &body,
+        )
+        .await
+    }
+
+    pub async fn list_forwarding_addresses(&self) -> Result<ForwardingAddressList> {
+        self.get("/users/me/settings/forwardingAddresses").await
+    }
+
+    /// Register a forwarding address; Gmail emails it a verification link.
+    pub async fn add_forwarding_address(&self, email: &str) -> Result<ForwardingAddress> {
+        let body = serde_json::json!({ "forwardingEmail": email });
+        self.post_json_with_response("/users/me/settings/forwardingAddresses", &body)
+            .await
+    }
+
+    pub async fn get_auto_forwarding(&self) -> Result<AutoForwarding> {
+        self.get("/users/me/settings/autoForwarding").await
+    }
+
+    pub async fn update_auto_forwarding(
+        &self,
+        settings: &AutoForwarding,
+    ) -> Result<AutoForwarding> {
+        self.put_json_with_response("/users/me/settings/autoForwarding", settings)
+            .await
+    }
+
+    pub async fn get_imap(&self) -> Result<ImapSettings> {
+        self.get("/users/me/settings/imap").await
+    }
+
+    pub async fn update_imap(&self, settings: &ImapSettings) -> Result<ImapSettings> {
+        self.put_json_with_response("/users/me/settings/imap", settings)
+            .await
+    }
+
+    pub async fn get_pop(&self) -> Result<PopSettings> {
+        self.get("/users/me/settings/pop").await
+    }
+
+    pub async fn update_pop(&self, settings: &PopSettings) -> Result<PopSettings> {
+        self.put_json_with_response("/users/me/settings/pop", settings)
+            .await
+    }
+
+    /// The unsubscribe mechanisms a message's List-Unsubscribe headers offer.
+    pub async fn unsubscribe_links(&self, id: &str) -> Result<UnsubscribeLinks> {
+        let msg = self
+            .get_message_metadata(id, &["List-Unsubscribe", "List-Unsubscribe-Post"])
+            .await?;
+        let header = msg
+            .get_header("List-Unsubscribe")
+            .ok_or_else(|| format_err!("Message {} has no List-Unsubscribe header", id))?;
+        let links = UnsubscribeLinks::parse(header, msg.get_header("List-Unsubscribe-Post"));
+        if links.is_empty() {
+            bail!("Message {} has no usable unsubscribe link: {}", id, header);
+        }
+        Ok(links)
+    }
+
+    /// Unsubscribe from the list a message came from.
+    ///
+    /// Only ever sends the one-click POST: a mailto request is returned as
+    /// `Unsubscribed::Mailto` and a plain web link as `Unsubscribed::Manual`,
+    /// for the caller to act on.
+    pub async fn unsubscribe(&self, id: &str) -> Result<Unsubscribed> {
+        let links = self.unsubscribe_links(id).await?;
+        if let Some(url) = &links.one_click {
+            match self.one_click_unsubscribe(url).await {
+                Ok(()) => return Ok(Unsubscribed::OneClick(url.clone())),
+                Err(e) if links.mailto.is_none() => return Err(e),
+                Err(_) => {}
+            }
+        }
+        if let Some(uri) = links.mailto {
+            return Ok(Unsubscribed::Mailto(uri));
+        }
+        match links.url {
+            Some(url) => Ok(Unsubscribed::Manual(url)),
+            None => bail!("Message {} has no usable unsubscribe link", id),
+        }
+    }
+
+    /// RFC 8058 one-click POST, sent without our credentials since it goes to the sender.
+    ///
+    /// It goes through a client of its own: the sender's server gets no
+    /// connection or setting shared with Google's, and a redirect is an answer
+    /// rather than somewhere to POST to again.
+    async fn one_click_unsubscribe(&self, url: &str) -> Result<()> {
+        let http = reqwest::Client::builder()
+            .timeout(ONE_CLICK_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .context("Failed to build HTTP client")?;
+        let request = http
+            .post(url)
+            .form(&[("List-Unsubscribe", "One-Click")])
+            .build()
+            .context("Failed to build request")?;
+        if self.dry_run {
+            skip_request(&request);
+            return Ok(());
+        }
+        let resp = http
+            .execute(request)
+            .await
+            .with_context(|| format!("Failed to reach {}", url))?;
+        // RFC 8058 asks for a 2xx; a redirect (e.g. to a login page) proves nothing
+        if !resp.status().is_success() {
+            bail!(
+                "One-click unsubscribe failed: HTTP {} from {}",
+                resp.status(),
+                url
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Message {
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        find_header(self.payload.as_ref()?.headers.as_deref()?, name)
+    }
+
+    /// Every top-level header, in the order they appear in the message.
+    pub fn headers(&self) -> &[Header] {
+        self.payload
+            .as_ref()
+            .and_then(|p| p.headers.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// When the message was sent, from its Date header or else Gmail's receipt time.
+    pub fn date(&self) -> Option<chrono::DateTime<chrono::Local>> {
+        self.get_header("Date")
+            .and_then(crate::date::parse_header)
+            .or_else(|| crate::date::from_millis(self.internal_date.as_deref()?.parse().ok()?))
+    }
+
+    pub fn get_body_text(&self) -> Option<String> {
+        self.find_body("text/plain")
+    }
+
+    pub fn get_body_html(&self) -> Option<String> {
+        self.find_body("text/html")
+    }
+
+    /// The inline `text/calendar` part of an invitation.
+    pub fn get_body_calendar(&self) -> Option<String> {
+        self.find_body("text/calendar")
+    }
+
+    /// The part holding a calendar invite: a `text/calendar` part, else an attached `.ics` file.
+    pub fn calendar_part(&self) -> Option<&Part> {
+        let parts = self.payload.as_ref()?.parts.as_deref()?;
+        find_part_where(parts, &|p| p.mime_type == "text/calendar").or_else(|| {
+            find_part_where(parts, &|p| {
+                p.mime_type == "application/ics"
+                    || p.filename
+                        .as_deref()
+                        .is_some_and(|f| f.to_ascii_lowercase().ends_with(".ics"))
+            })
+        })
+    }
+
+    /// The plain-text body, or the HTML body rendered as text for HTML-only mail.
+    pub fn get_readable_body(&self, width: usize) -> Option<String> {
+        self.get_body_text().or_else(|| {
+            let html = self.get_body_html()?;
+            html2text::from_read(html.as_bytes(), width).ok()
+        })
+    }
+
+    fn find_body(&self, mime_type: &str) -> Option<String> {
+        let payload = self.payload.as_ref()?;
+
+        // Single-part messages carry the body on the payload itself
+        if payload.mime_type.as_deref().unwrap_or("text/plain") == mime_type
+            && let Some(text) = decode_text_body(payload.body.as_ref(), payload.headers.as_deref())
+        {
+            return Some(text);
+        }
+
+        // Try parts
+        if let Some(parts) = &payload.parts {
+            return find_part(parts, mime_type);
+        }
+
+        None
+    }
+
+    /// All parts carrying a filename, in document order.
+    pub fn attachments(&self) -> Vec<&Part> {
+        let mut found = Vec::new();
+        if let Some(parts) = self.payload.as_ref().and_then(|p| p.parts.as_ref()) {
+            collect_attachments(parts, &mut found);
+        }
+        found
+    }
+}
+
+fn find_part_where<'a>(parts: &'a [Part], matches: &impl Fn(&Part) -> bool) -> Option<&'a Part> {
+    parts.iter().find_map(|part| {
+        if matches(part) {
+            return Some(part);
+        }
+        find_part_where(part.parts.as_deref()?, matches)
+    })
+}
+
+fn collect_attachments<'a>(parts: &'a [Part], found: &mut Vec<&'a Part>) {
+    for part in parts {
+        if part.filename.as_deref().is_some_and(|f| !f.is_empty()) {
+            found.push(part);
+        }
+        if let Some(nested) = &part.parts {
+            collect_attachments(nested, found);
+        }
     }
+}
 
-    async fn post(&self, endpoint: &str) -> Result<()> {
-        self.rate_limit().await;
-        let url = format!("{}{}", BASE_URL, endpoint);
+/// Describe a request on stderr and stand in an empty success response for it.
+fn skip_request(request: &reqwest::Request) -> reqwest::Response {
+    eprintln!("Would {} {}", request.method(), request.url());
+    if let Some(body) = request.body().and_then(|b| b.as_bytes()) {
+        eprintln!("  {}", printable_body(body));
+    }
+    let resp = http::Response::builder()
+        .header(DRY_RUN_HEADER, "1")
+        .body("{}")
+        .expect("static response is valid");
+    reqwest::Response::from(resp)
+}
 
-        let resp = self
-            .http
-            .post(&url)
-            .bearer_auth(&self.access_token)
-            .header("Content-Length", "0")
-            .send()
-            .await
-            .context("Failed to send request")?;
+/// A body as text, or just its size when it's too long to print.
+fn printable_body(body: &[u8]) -> String {
+    if body.len() > BODY_PRINT_LIMIT {
+        format!("({} bytes)", body.len())
+    } else {
+        String::from_utf8_lossy(body).into_owned()
+    }
+}
 
-        Self::check_response(resp).await?;
-        Ok(())
+/// Mask tokens and secrets in a JSON or form-encoded body, and bearer tokens anywhere.
+pub(crate) fn redact(text: &str) -> String {
+    let mut text = redact_values(text, "Bearer ", |rest| {
+        Some((
+            0,
+            rest.find(|c: char| c.is_whitespace() || c == '"')
+                .unwrap_or(rest.len()),
+        ))
+    });
+    for field in SECRET_FIELDS {
+        text = redact_values(&text, &format!("\"{}\"", field), |rest| {
+            let start = rest.find('"')? + 1;
+            if !rest[..start - 1]
+                .trim()
+                .trim_start_matches(':')
+                .trim()
+                .is_empty()
+            {
+                return None;
+            }
+            Some((start, start + rest[start..].find('"')?))
+        });
+        text = redact_values(&text, &format!("{}=", field), |rest| {
+            Some((0, rest.find('&').unwrap_or(rest.len())))
+        });
     }
+    text
+}
 
-    async fn post_json<T: Serialize>(&self, endpoint: &str, body: &T) -> Result<()> {
-        self.rate_limit().await;
-        let url = format!("{}{}", BASE_URL, endpoint);
+/// Replace the value following each `key`, as located by `span` in the text after it.
+fn redact_values(text: &str, key: &str, span: impl Fn(&str) -> Option<(usize, usize)>) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find(key) {
+        let (before, after) = rest.split_at(pos + key.len());
+        redacted.push_str(before);
+        rest = after;
+        if let Some((start, end)) = span(rest) {
+            redacted.push_str(&rest[..start]);
+            redacted.push_str("[REDACTED]");
+            rest = &rest[end..];
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
 
-        let resp = self
-            .http
-            .post(&url)
-            .bearer_auth(&self.access_token)
-            .json(body)
-            .send()
-            .await
-            .context("Failed to send request")?;
+/// A Gmail search query built from typed terms instead of hand-written operators.
+///
+/// ```
+/// use gmail::api::Query;
+///
+/// let query = Query::new().from("news@example.com").is_unread().newer_than_days(7);
+/// assert_eq!(query.to_string(), "from:news@example.com is:unread newer_than:7d");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    terms: Vec<String>,
+}
 
-        Self::check_response(resp).await?;
-        Ok(())
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    async fn post_json_with_response<T: Serialize, R: serde::de::DeserializeOwned>(
-        &self,
-        endpoint: &str,
-        body: &T,
-    ) -> Result<R> {
-        self.rate_limit().await;
-        let url = format!("{}{}", BASE_URL, endpoint);
+    /// Add raw Gmail search syntax as is.
+    pub fn raw(mut self, terms: impl Into<String>) -> Self {
+        let terms = terms.into();
+        if !terms.trim().is_empty() {
+            self.terms.push(terms);
+        }
+        self
+    }
 
-        let resp = self
-            .http
-            .post(&url)
-            .bearer_auth(&self.access_token)
-            .json(body)
-            .send()
-            .await
-            .context("Failed to send request")?;
+    fn operator(self, name: &str, value: &str) -> Self {
+        self.raw(format!("{}:{}", name, group(value)))
+    }
 
-        let resp = Self::check_response(resp).await?;
-        resp.json().await.context("Failed to parse JSON response")
+    pub fn from(self, sender: &str) -> Self {
+        self.operator("from", sender)
     }
 
-    pub async fn list_labels(&self) -> Result<LabelList> {
-        self.get("/users/me/labels").await
+    pub fn to(self, recipient: &str) -> Self {
+        self.operator("to", recipient)
     }
 
-    pub async fn create_label(&self, name: &str) -> Result<Label> {
-        // Capitalize first letter for consistency
-        let capitalized = capitalize_first(name);
-        let body = serde_json::json!({
-            "name": capitalized,
-            "labelListVisibility": "labelShow",
-            "messageListVisibility": "show"
-        });
-        self.post_json_with_response("/users/me/labels", &body)
-            .await
+    /// Messages with all of these words in the subject.
+    pub fn subject(self, words: &str) -> Self {
+        self.operator("subject", words)
     }
 
-    pub async fn get_or_create_label(&self, name: &str) -> Result<String> {
-        // Check if label already exists (case-insensitive, Gmail is case-insensitive)
-        let labels = self.list_labels().await?;
-        if let Some(existing) = labels.labels {
-            for label in existing {
-                if label.name.eq_ignore_ascii_case(name) {
-                    return Ok(label.id);
-                }
-            }
-        }
-        // Create new label
-        let label = self.create_label(name).await?;
-        Ok(label.id)
+    /// Label name as shown in Gmail, e.g. "Clients/Acme".
+    pub fn label(self, name: &str) -> Self {
+        self.operator("label", name)
     }
 
-    pub async fn list_messages(
-        &self,
-        query: Option<&str>,
-        label: &str,
-        max_results: u32,
-    ) -> Result<MessageList> {
-        let mut endpoint = format!("/users/me/messages?maxResults={}", max_results);
-        if !label.is_empty() {
-            endpoint.push_str(&format!("&labelIds={}", urlencoding::encode(label)));
-        }
-        if let Some(q) = query {
-            endpoint.push_str(&format!("&q={}", urlencoding::encode(q)));
-        }
-        self.get(&endpoint).await
+    /// Attachment name or type, e.g. "pdf".
+    pub fn filename(self, name: &str) -> Self {
+        self.operator("filename", name)
     }
 
-    pub async fn get_message(&self, id: &str) -> Result<Message> {
-        self.get(&format!("/users/me/messages/{}", urlencoding::encode(id)))
-            .await
+    pub fn is_unread(self) -> Self {
+        self.raw("is:unread")
     }
 
-    pub async fn modify_labels(&self, id: &str, add: &[&str], remove: &[&str]) -> Result<()> {
-        let endpoint = format!("/users/me/messages/{}/modify", urlencoding::encode(id));
-        let body = serde_json::json!({
-            "addLabelIds": add,
-            "removeLabelIds": remove
-        });
-        self.post_json(&endpoint, &body).await
+    pub fn is_read(self) -> Self {
+        self.raw("is:read")
     }
 
-    pub async fn archive(&self, id: &str) -> Result<()> {
-        self.modify_labels(id, &[], &["INBOX"]).await
+    pub fn is_starred(self) -> Self {
+        self.raw("is:starred")
     }
 
-    pub async fn mark_spam(&self, id: &str) -> Result<()> {
-        self.modify_labels(id, &["SPAM"], &["INBOX"]).await
+    pub fn has_attachment(self) -> Self {
+        self.raw("has:attachment")
     }
 
-    pub async fn unspam(&self, id: &str) -> Result<()> {
-        self.modify_labels(id, &["INBOX"], &["SPAM"]).await
+    pub fn newer_than_days(self, days: u32) -> Self {
+        self.raw(format!("newer_than:{}d", days))
     }
 
-    pub async fn mark_read(&self, id: &str) -> Result<()> {
-        self.modify_labels(id, &[], &["UNREAD"]).await
+    pub fn older_than_days(self, days: u32) -> Self {
+        self.raw(format!("older_than:{}d", days))
     }
 
-    pub async fn mark_unread(&self, id: &str) -> Result<()> {
-        self.modify_labels(id, &["UNREAD"], &[]).await
+    /// Messages after a point in time, to the second.
+    pub fn after<Tz: chrono::TimeZone>(self, time: &chrono::DateTime<Tz>) -> Self {
+        // Seconds since the epoch, since Gmail reads YYYY/MM/DD dates in Pacific time
+        self.raw(format!("after:{}", time.timestamp()))
     }
 
-    pub async fn clear_labels(&self, id: &str) -> Result<Vec<String>> {
-        let msg = self.get_message(id).await?;
-        let labels = msg.label_ids.unwrap_or_default();
-        let user_labels: Vec<&str> = labels
-            .iter()
-            .filter(|l| !is_system_label(l))
-            .map(|s| s.as_str())
-            .collect();
-        if !user_labels.is_empty() {
-            self.modify_labels(id, &[], &user_labels).await?;
-        }
-        Ok(user_labels.into_iter().map(|s| s.to_string()).collect())
+    /// Messages before a point in time, to the second.
+    pub fn before<Tz: chrono::TimeZone>(self, time: &chrono::DateTime<Tz>) -> Self {
+        self.raw(format!("before:{}", time.timestamp()))
     }
 
-    pub async fn add_label(&self, id: &str, label: &str) -> Result<()> {
-        // For custom labels, we need to get/create the label ID first
-        let label_id = if is_system_label(label) {
-            label.to_string()
-        } else {
-            self.get_or_create_label(label).await?
-        };
-        self.modify_labels(id, &[&label_id], &[]).await
+    pub fn larger_than(self, bytes: u64) -> Self {
+        self.raw(format!("larger:{}", bytes))
     }
 
-    pub async fn remove_label(&self, id: &str, label: &str) -> Result<()> {
-        // For custom labels, we need to find the label ID first
-        let label_id = if is_system_label(label) {
-            label.to_string()
-        } else {
-            self.find_label(label)
-                .await?
-                .ok_or_else(|| anyhow::anyhow!("Label not found: {}", label))?
-        };
-        self.modify_labels(id, &[], &[&label_id]).await
+    pub fn smaller_than(self, bytes: u64) -> Self {
+        self.raw(format!("smaller:{}", bytes))
     }
 
-    async fn find_label(&self, name: &str) -> Result<Option<String>> {
-        let labels = self.list_labels().await?;
-        if let Some(label_list) = labels.labels {
-            for label in label_list {
-                if label.name.eq_ignore_ascii_case(name) {
-                    return Ok(Some(label.id));
-                }
-            }
+    /// Leave out messages matching `other`.
+    pub fn exclude(self, other: Query) -> Self {
+        match other.terms.as_slice() {
+            [] => self,
+            [term] => self.raw(format!("-{}", term)),
+            _ => self.raw(format!("-({})", other)),
         }
-        Ok(None)
     }
 
-    pub async fn trash(&self, id: &str) -> Result<()> {
-        self.post(&format!(
-            "/users/me/messages/{}/trash",
-            urlencoding::encode(id)
-        ))
-        .await
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
     }
+}
 
-    pub async fn unsubscribe(&self, id: &str) -> Result<()> {
-        self.post(&format!(
-            "/users/me/messages/{}/unsubscribe",
-            urlencoding::encode(id)
-        ))
-        .await
+impl std::fmt::Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.terms.join(" "))
     }
 }
 
-impl Message {
-    pub fn get_header(&self, name: &str) -> Option<&str> {
-        self.payload
-            .as_ref()?
-            .headers
-            .as_ref()?
-            .iter()
-            .find(|h| h.name.eq_ignore_ascii_case(name))
-            .map(|h| h.value.as_str())
+// Values with spaces or brackets are grouped so the operator covers all of them
+fn group(value: &str) -> String {
+    if value.contains(|c: char| c.is_whitespace() || "(){}\"".contains(c)) {
+        format!("({})", value)
+    } else {
+        value.to_string()
     }
+}
 
-    pub fn get_body_text(&self) -> Option<String> {
-        let payload = self.payload.as_ref()?;
+/// Search query matching an RFC 822 Message-ID, with or without angle brackets.
+pub fn rfc822_query(message_id: &str) -> String {
+    let bare = message_id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    format!("rfc822msgid:{} in:anywhere", bare)
+}
 
-        // Try direct body first
-        if let Some(body) = &payload.body {
-            if let Some(data) = &body.data {
-                if let Ok(decoded) = BASE64_URL_SAFE_NO_PAD.decode(data) {
-                    return String::from_utf8(decoded).ok();
-                }
+fn metadata_query(headers: &[&str]) -> String {
+    let mut query = "?format=metadata".to_string();
+    for header in headers {
+        query.push_str(&format!("&metadataHeaders={}", urlencoding::encode(header)));
+    }
+    query
+}
+
+/// A multipart/mixed batch body of GET calls to `endpoints`, each tagged with its index.
+fn batch_body(boundary: &str, endpoints: &[String]) -> String {
+    let mut body = String::new();
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        body.push_str(&format!(
+            "--{}\r\nContent-Type: application/http\r\nContent-ID: <item{}>\r\n\r\nGET {}{} HTTP/1.1\r\n\r\n",
+            boundary, i, BATCH_API_PATH, endpoint
+        ));
+    }
+    body.push_str(&format!("--{}--\r\n", boundary));
+    body
+}
+
+/// Split a batch response into `(index, status, body)` for each call it answers.
+fn parse_batch_response(content_type: &str, body: &str) -> Result<Vec<(usize, u16, String)>> {
+    let boundary = content_type
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))
+        .map(|b| b.trim_matches('"'))
+        .ok_or_else(|| format_err!("Batch response has no boundary: {}", content_type))?;
+    let body = body.replace("\r\n", "\n");
+    let mut responses = Vec::new();
+    for part in body.split(&format!("--{}", boundary)).skip(1) {
+        if part.starts_with("--") {
+            break;
+        }
+        let Some((headers, response)) = part.trim_start().split_once("\n\n") else {
+            continue;
+        };
+        let index = headers.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if !name.trim().eq_ignore_ascii_case("Content-ID") {
+                return None;
             }
+            value
+                .trim()
+                .trim_matches(['<', '>'])
+                .strip_prefix("response-item")?
+                .parse()
+                .ok()
+        });
+        let (head, content) = response.split_once("\n\n").unwrap_or((response, ""));
+        let status = head
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse().ok());
+        if let (Some(index), Some(status)) = (index, status) {
+            responses.push((index, status, content.trim_end().to_string()));
         }
+    }
+    Ok(responses)
+}
 
-        // Try parts
-        if let Some(parts) = &payload.parts {
-            return find_text_part(parts);
+/// Scopes that allow a Gmail API request; any one of them is enough.
+fn required_scopes(method: &reqwest::Method, path: &str) -> &'static [&'static str] {
+    use crate::auth::{
+        SCOPE_FULL, SCOPE_MODIFY, SCOPE_READONLY, SCOPE_SEND, SCOPE_SETTINGS_BASIC,
+        SCOPE_SETTINGS_SHARING,
+    };
+
+    let settings = path.contains("/settings/");
+    if method == reqwest::Method::GET || path.starts_with("/batch/") {
+        if settings {
+            return &[
+                SCOPE_READONLY,
+                SCOPE_MODIFY,
+                SCOPE_FULL,
+                SCOPE_SETTINGS_BASIC,
+                SCOPE_SETTINGS_SHARING,
+            ];
         }
+        return &[SCOPE_READONLY, SCOPE_MODIFY, SCOPE_FULL];
+    }
+    if path.ends_with("/messages/send") || path.ends_with("/drafts/send") {
+        return &[SCOPE_SEND, SCOPE_MODIFY, SCOPE_FULL];
+    }
+    if settings {
+        return &[SCOPE_SETTINGS_BASIC, SCOPE_SETTINGS_SHARING, SCOPE_FULL];
+    }
+    if path.ends_with("/watch") || path.ends_with("/stop") {
+        return &[SCOPE_READONLY, SCOPE_MODIFY, SCOPE_FULL];
+    }
+    // Permanent deletion is only allowed with full mailbox access
+    let permanent_delete = path.ends_with("/messages/batchDelete")
+        || (method == reqwest::Method::DELETE && path.contains("/messages/"));
+    if permanent_delete {
+        return &[SCOPE_FULL];
+    }
+    &[SCOPE_MODIFY, SCOPE_FULL]
+}
 
-        None
+fn raw_message(raw: &[u8], thread_id: Option<&str>) -> serde_json::Value {
+    let mut message = serde_json::json!({ "raw": BASE64_URL_SAFE_NO_PAD.encode(raw) });
+    if let Some(thread_id) = thread_id {
+        message["threadId"] = serde_json::json!(thread_id);
     }
+    message
+}
+
+/// Decode Gmail's base64url payloads, which may or may not carry padding.
+fn decode_base64url(data: &str) -> Result<Vec<u8>> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(data.trim_end_matches('='))
+        .context("Failed to decode base64 data")
+}
+
+fn find_header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+// Gmail has already undone the Content-Transfer-Encoding (base64 or
+// quoted-printable) in body data, but leaves the bytes in the part's charset
+fn decode_text_body(body: Option<&Body>, headers: Option<&[Header]>) -> Option<String> {
+    let bytes = decode_base64url(body?.data.as_deref()?).ok()?;
+    let content_type = headers.and_then(|h| find_header(h, "Content-Type"));
+    Some(crate::mime::decode_text(&bytes, content_type))
 }
 
-fn find_text_part(parts: &[Part]) -> Option<String> {
+fn find_part(parts: &[Part], mime_type: &str) -> Option<String> {
     for part in parts {
-        if part.mime_type == "text/plain" {
-            if let Some(body) = &part.body {
-                if let Some(data) = &body.data {
-                    if let Ok(decoded) = BASE64_URL_SAFE_NO_PAD.decode(data) {
-                        return String::from_utf8(decoded).ok();
-                    }
-                }
-            }
+        if part.mime_type == mime_type
+            && let Some(text) = decode_text_body(part.body.as_ref(), part.headers.as_deref())
+        {
+            return Some(text);
         }
-        if let Some(nested) = &part.parts {
-            if let Some(text) = find_text_part(nested) {
-                return Some(text);
-            }
+        if let Some(nested) = &part.parts
+            && let Some(text) = find_part(nested, mime_type)
+        {
+            return Some(text);
         }
     }
     None
@@ -407,24 +2587,139 @@ fn is_system_label(label: &str) -> bool {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact(r#"{"access_token": "ya29.x", "expires_in": 3599, "scope": "a"}"#),
+            r#"{"access_token": "[REDACTED]", "expires_in": 3599, "scope": "a"}"#
+        );
+        assert_eq!(
+            redact("grant_type=refresh_token&refresh_token=1//abc&client_secret=s"),
+            "grant_type=refresh_token&refresh_token=[REDACTED]&client_secret=[REDACTED]"
+        );
+        assert_eq!(
+            redact("Authorization: Bearer ya29.x\n"),
+            "Authorization: Bearer [REDACTED]\n"
+        );
+        assert_eq!(redact(r#"{"id": "abc"}"#), r#"{"id": "abc"}"#);
+    }
+
+    #[test]
+    fn test_batch_round_trip() {
+        let body = batch_body("b", &["/users/me/messages/a?format=metadata".to_string()]);
+        assert_eq!(
+            body,
+            "--b\r\nContent-Type: application/http\r\nContent-ID: <item0>\r\n\r\n\
+             GET /gmail/v1/users/me/messages/a?format=metadata HTTP/1.1\r\n\r\n--b--\r\n"
+        );
+
+        let response = "--batch_x\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: <response-item1>\r\n\r\n\
+            HTTP/1.1 404 Not Found\r\n\
+            Content-Type: application/json\r\n\r\n\
+            {\"error\": {\"code\": 404}}\r\n\
+            --batch_x\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: <response-item0>\r\n\r\n\
+            HTTP/1.1 200 OK\r\n\r\n\
+            {\"id\": \"a\"}\r\n\
+            --batch_x--\r\n";
+        let parts = parse_batch_response("multipart/mixed; boundary=batch_x", response).unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                (1, 404, r#"{"error": {"code": 404}}"#.to_string()),
+                (0, 200, r#"{"id": "a"}"#.to_string()),
+            ]
+        );
+        assert!(parse_batch_response("text/plain", response).is_err());
+    }
+
+    #[test]
+    fn test_query_builder() {
+        let query = Query::new()
+            .from("Jane Doe")
+            .subject("weekly report")
+            .label("Clients/Acme")
+            .has_attachment()
+            .larger_than(1_000_000)
+            .exclude(Query::new().is_starred())
+            .exclude(Query::new().from("a").to("b"))
+            .raw("");
+        assert_eq!(
+            query.to_string(),
+            "from:(Jane Doe) subject:(weekly report) label:Clients/Acme has:attachment \
+             larger:1000000 -is:starred -(from:a to:b)"
+        );
+        let after = chrono::DateTime::from_timestamp(1751362200, 0).unwrap();
+        assert_eq!(Query::new().after(&after).to_string(), "after:1751362200");
+        assert!(Query::new().exclude(Query::new()).is_empty());
+    }
+
+    #[test]
+    fn test_unsubscribe_links() {
+        let header =
+            "<mailto:leave@list.example.com?subject=bye>, <https://list.example.com/u?id=1>";
+        let links = UnsubscribeLinks::parse(header, Some("List-Unsubscribe=One-Click"));
+        assert_eq!(
+            links.one_click.as_deref(),
+            Some("https://list.example.com/u?id=1")
+        );
+        assert_eq!(
+            links.mailto.as_deref(),
+            Some("mailto:leave@list.example.com?subject=bye")
+        );
+        assert_eq!(links.url, None);
+
+        let links = UnsubscribeLinks::parse("<https://list.example.com/\r\n u>", None);
+        assert_eq!(links.one_click, None);
+        assert_eq!(links.url.as_deref(), Some("https://list.example.com/u"));
+        assert!(UnsubscribeLinks::parse("none here", None).is_empty());
+    }
+
+    #[test]
+    fn test_mailto_email() {
+        let email = mailto_email("mailto:leave%2B1@list.example.com?subject=Stop%20it").unwrap();
+        assert_eq!(email.to, vec!["leave+1@list.example.com"]);
+        assert_eq!(email.subject, "Stop it");
+        assert_eq!(
+            mailto_email("mailto:a@example.com").unwrap().subject,
+            "unsubscribe"
+        );
+        assert!(mailto_email("https://example.com").is_err());
+        assert!(mailto_email("mailto:a@example.com,b@example.com").is_err());
+        assert!(
+            mailto_email("mailto:a@example.com?body=hi&cc=b@example.com")
+                .unwrap()
+                .body
+                .is_empty()
+        );
+    }
+
     fn make_message(payload: Option<Payload>) -> Message {
         Message {
             id: "test123".to_string(),
+            thread_id: None,
             snippet: Some("snippet".to_string()),
             payload,
             label_ids: None,
+            internal_date: None,
+            size_estimate: None,
         }
     }
 
     fn make_body(text: &str) -> Body {
         Body {
             data: Some(BASE64_URL_SAFE_NO_PAD.encode(text)),
+            ..Default::default()
         }
     }
 
     #[test]
     fn test_get_header() {
         let msg = make_message(Some(Payload {
+            mime_type: None,
             headers: Some(vec![
                 Header {
                     name: "From".to_string(),
@@ -454,6 +2749,7 @@ mod tests {
     #[test]
     fn test_get_body_text_direct() {
         let msg = make_message(Some(Payload {
+            mime_type: None,
             headers: None,
             body: Some(make_body("Hello world")),
             parts: None,
@@ -465,18 +2761,19 @@ mod tests {
     #[test]
     fn test_get_body_text_from_parts() {
         let msg = make_message(Some(Payload {
+            mime_type: None,
             headers: None,
             body: None,
             parts: Some(vec![
                 Part {
                     mime_type: "text/html".to_string(),
                     body: Some(make_body("<b>HTML</b>")),
-                    parts: None,
+                    ..Default::default()
                 },
                 Part {
                     mime_type: "text/plain".to_string(),
                     body: Some(make_body("Plain text")),
-                    parts: None,
+                    ..Default::default()
                 },
             ]),
         }));
@@ -487,6 +2784,7 @@ mod tests {
     #[test]
     fn test_get_body_text_nested_parts() {
         let msg = make_message(Some(Payload {
+            mime_type: None,
             headers: None,
             body: None,
             parts: Some(vec![Part {
@@ -495,17 +2793,92 @@ mod tests {
                 parts: Some(vec![Part {
                     mime_type: "text/plain".to_string(),
                     body: Some(make_body("Nested text")),
-                    parts: None,
+                    ..Default::default()
                 }]),
+                ..Default::default()
             }]),
         }));
 
         assert_eq!(msg.get_body_text(), Some("Nested text".to_string()));
     }
 
+    #[test]
+    fn test_skip_request() {
+        let request = reqwest::Client::new()
+            .post(format!(
+                "{}/gmail/v1/users/me/messages/batchModify",
+                GMAIL_ROOT
+            ))
+            .json(&serde_json::json!({ "ids": ["18c1"] }))
+            .build()
+            .unwrap();
+        let resp = skip_request(&request);
+        assert!(resp.status().is_success());
+        assert!(resp.headers().contains_key(DRY_RUN_HEADER));
+    }
+
+    #[test]
+    fn test_rfc822_query() {
+        assert_eq!(
+            rfc822_query(" <CA+xyz@mail.example.com> "),
+            "rfc822msgid:CA+xyz@mail.example.com in:anywhere"
+        );
+    }
+
+    #[test]
+    fn test_web_url() {
+        assert_eq!(
+            web_url("me+work@example.com", "18c0ffee"),
+            "https://mail.google.com/mail/?authuser=me%2Bwork%40example.com#all/18c0ffee"
+        );
+    }
+
+    #[test]
+    fn test_get_body_html_only() {
+        let msg = make_message(Some(Payload {
+            mime_type: Some("text/html".to_string()),
+            headers: None,
+            body: Some(make_body("<p>Hello <b>world</b></p>")),
+            parts: None,
+        }));
+
+        assert_eq!(msg.get_body_text(), None);
+        assert_eq!(
+            msg.get_body_html(),
+            Some("<p>Hello <b>world</b></p>".to_string())
+        );
+        let rendered = msg.get_readable_body(80).unwrap();
+        assert!(rendered.contains("Hello"));
+        assert!(!rendered.contains("<p>"));
+    }
+
+    #[test]
+    fn test_get_body_text_charset() {
+        let msg = make_message(Some(Payload {
+            mime_type: None,
+            headers: None,
+            body: None,
+            parts: Some(vec![Part {
+                mime_type: "text/plain".to_string(),
+                headers: Some(vec![Header {
+                    name: "Content-Type".to_string(),
+                    value: "text/plain; charset=\"ISO-8859-1\"".to_string(),
+                }]),
+                body: Some(Body {
+                    data: Some(BASE64_URL_SAFE.encode(b"Caf\xe9 cr\xe8me")),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }]),
+        }));
+
+        assert_eq!(msg.get_body_text(), Some("Café crème".to_string()));
+    }
+
     #[test]
     fn test_get_body_text_no_body() {
         let msg = make_message(Some(Payload {
+            mime_type: None,
             headers: None,
             body: None,
             parts: None,
@@ -513,4 +2886,132 @@ mod tests {
 
         assert_eq!(msg.get_body_text(), None);
     }
+
+    #[test]
+    fn test_attachments_nested() {
+        let msg = make_message(Some(Payload {
+            mime_type: None,
+            headers: None,
+            body: None,
+            parts: Some(vec![
+                Part {
+                    mime_type: "text/plain".to_string(),
+                    filename: Some(String::new()),
+                    body: Some(make_body("Body")),
+                    ..Default::default()
+                },
+                Part {
+                    mime_type: "multipart/mixed".to_string(),
+                    parts: Some(vec![Part {
+                        mime_type: "application/pdf".to_string(),
+                        filename: Some("invoice.pdf".to_string()),
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                },
+            ]),
+        }));
+
+        let attachments = msg.attachments();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].filename.as_deref(), Some("invoice.pdf"));
+    }
+
+    #[test]
+    fn test_filter_serialization_skips_unset_fields() {
+        let filter = Filter {
+            criteria: FilterCriteria {
+                from: Some("news@example.com".to_string()),
+                ..Default::default()
+            },
+            action: FilterAction {
+                remove_label_ids: vec!["INBOX".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&filter).unwrap(),
+            serde_json::json!({
+                "criteria": { "from": "news@example.com" },
+                "action": { "removeLabelIds": ["INBOX"] }
+            })
+        );
+    }
+
+    #[test]
+    fn test_metadata_query() {
+        assert_eq!(
+            metadata_query(&["From", "X-Custom Header"]),
+            "?format=metadata&metadataHeaders=From&metadataHeaders=X-Custom%20Header"
+        );
+    }
+
+    #[test]
+    fn test_required_scopes() {
+        use crate::auth::{SCOPE_FULL, SCOPE_READONLY, SCOPE_SEND, SCOPE_SETTINGS_BASIC};
+        use reqwest::Method;
+
+        let base = "/gmail/v1/users/me";
+        assert!(
+            required_scopes(&Method::GET, &format!("{base}/messages")).contains(&SCOPE_READONLY)
+        );
+        assert!(
+            !required_scopes(&Method::POST, &format!("{base}/messages/x/modify"))
+                .contains(&SCOPE_READONLY)
+        );
+        assert!(
+            required_scopes(&Method::POST, &format!("{base}/messages/send")).contains(&SCOPE_SEND)
+        );
+        assert!(
+            required_scopes(&Method::PUT, &format!("{base}/settings/vacation"))
+                .contains(&SCOPE_SETTINGS_BASIC)
+        );
+        assert_eq!(
+            required_scopes(&Method::POST, &format!("{base}/messages/batchDelete")),
+            [SCOPE_FULL]
+        );
+    }
+
+    #[test]
+    fn test_label_tree() {
+        let label = |id: &str, name: &str, total: u32| Label {
+            id: id.to_string(),
+            name: name.to_string(),
+            label_type: Some("user".to_string()),
+            color: None,
+            messages_total: Some(total),
+            messages_unread: Some(1),
+            threads_total: None,
+            threads_unread: Some(1),
+        };
+        let labels = vec![
+            label("3", "Clients/Acme/Invoices", 80),
+            label("1", "Clients", 0),
+            label("2", "Clients/Acme", 20),
+            label("4", "Projects/Beta", 5),
+            label("5", "Clients/Zeta", 10),
+        ];
+
+        let tree = label_tree(&labels);
+        assert_eq!(tree.len(), 2);
+        let clients = &tree[0];
+        assert_eq!(clients.name, "Clients");
+        assert_eq!(clients.messages_total, 110);
+        assert_eq!(clients.messages_unread, 4);
+        assert_eq!(clients.children[0].name, "Acme");
+        assert_eq!(clients.children[0].messages_total, 100);
+        assert_eq!(clients.children[0].children[0].name, "Invoices");
+        assert_eq!(clients.children[1].name, "Zeta");
+
+        // "Projects" only exists implicitly through its child
+        assert_eq!(tree[1].id, None);
+        assert_eq!(tree[1].messages_total, 5);
+    }
+
+    #[test]
+    fn test_decode_base64url_with_padding() {
+        assert_eq!(decode_base64url("aGk=").unwrap(), b"hi");
+        assert_eq!(decode_base64url("aGk").unwrap(), b"hi");
+    }
 }
@@ -1,57 +1,258 @@
-use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
 use oauth2::basic::BasicClient;
 use oauth2::{
-    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
-    PkceCodeVerifier, RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
+    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, DeviceAuthorizationUrl,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, Scope,
+    StandardDeviceAuthorizationResponse, TokenResponse, TokenUrl,
 };
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
 use std::time::Duration;
 use url::Url;
 
-use crate::config::{self, Tokens};
+use crate::config::{self, ServiceAccount, Tokens};
+use crate::error::{Context, GmailError, Result, bail, format_err};
 
 const AUTH_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
 const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const DEVICE_AUTH_URL: &str = "https://oauth2.googleapis.com/device/code";
+const REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+const TOKENINFO_URL: &str = "https://oauth2.googleapis.com/tokeninfo";
+const JWT_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+// Google caps service account assertions at one hour
+const JWT_LIFETIME_SECS: i64 = 3600;
 const MAX_RETRIES: u32 = 3;
+// How long `login` waits for the browser to come back with a code
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub const SCOPE_MODIFY: &str = "https://www.googleapis.com/auth/gmail.modify";
+pub const SCOPE_SEND: &str = "https://www.googleapis.com/auth/gmail.send";
+pub const SCOPE_SETTINGS_BASIC: &str = "https://www.googleapis.com/auth/gmail.settings.basic";
+pub const SCOPE_SETTINGS_SHARING: &str = "https://www.googleapis.com/auth/gmail.settings.sharing";
+/// Full mailbox access, required for permanent deletion
+pub const SCOPE_FULL: &str = "https://mail.google.com/";
+/// Cloud Pub/Sub access, required to pull push-notification subscriptions
+pub const SCOPE_PUBSUB: &str = "https://www.googleapis.com/auth/pubsub";
+/// Read-only access to contacts, required by `gmail contacts` and name lookup in `send`
+pub const SCOPE_CONTACTS: &str = "https://www.googleapis.com/auth/contacts.readonly";
+/// Read-only access to messages, labels and settings
+pub const SCOPE_READONLY: &str = "https://www.googleapis.com/auth/gmail.readonly";
+pub const DEFAULT_SCOPES: &[&str] = &[
+    SCOPE_MODIFY,
+    SCOPE_SEND,
+    SCOPE_SETTINGS_BASIC,
+    SCOPE_SETTINGS_SHARING,
+];
 
 fn create_http_client() -> reqwest::Client {
-    reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .connect_timeout(Duration::from_secs(10))
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .expect("Client should build")
+    crate::api::http_client(crate::api::Timeouts::default())
 }
 
-pub async fn login(client_id: &str, client_secret: &str) -> Result<Tokens> {
-    // Bind to port 0 to get an OS-assigned available port (prevents port squatting)
-    let listener = TcpListener::bind("127.0.0.1:0").context("Failed to bind to local port")?;
+/// Which browser `login` opens the consent page in.
+#[derive(Debug, Clone, Default)]
+pub enum Browser {
+    /// The system's default browser
+    #[default]
+    System,
+    /// A browser command, e.g. `firefox`, or one with `%s` where the URL goes,
+    /// e.g. `firefox --new-window %s`
+    Command(String),
+    /// Only print the URL for the user to open
+    None,
+}
+
+/// How `login` sends the user to the consent page and waits for them.
+#[derive(Debug, Clone)]
+pub struct LoginOptions {
+    pub browser: Browser,
+    /// How long to wait for the browser to come back to the local callback
+    pub timeout: Duration,
+    /// Address the callback server listens on
+    pub bind: IpAddr,
+    /// Port of the callback server; 0 picks a free one
+    pub port: u16,
+    /// Ask for consent again, so Google issues a new refresh token
+    pub force: bool,
+}
+
+impl Default for LoginOptions {
+    fn default() -> Self {
+        Self {
+            browser: Browser::default(),
+            timeout: CALLBACK_TIMEOUT,
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 0,
+            force: false,
+        }
+    }
+}
+
+impl LoginOptions {
+    /// The redirect URI to register with Google, always on `localhost`: Google
+    /// only sends desktop clients back to a loopback address, so a wildcard
+    /// bind is for a container whose port is forwarded to the host's localhost.
+    fn redirect_uri(&self, port: u16) -> Result<String> {
+        if !self.bind.is_loopback() && !self.bind.is_unspecified() {
+            bail!(
+                "Google only redirects to localhost, so the callback can't be served on {}; use a loopback address, or 0.0.0.0 with the port forwarded to localhost",
+                self.bind
+            );
+        }
+        Ok(format!("http://localhost:{}", port))
+    }
+}
+
+pub async fn login(
+    client_id: &str,
+    client_secret: &str,
+    scopes: &[&str],
+    options: &LoginOptions,
+) -> Result<Tokens> {
+    // Port 0 gets an OS-assigned available port (prevents port squatting)
+    let listener = tokio::net::TcpListener::bind((options.bind, options.port))
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to listen on {}:{} (is the port in use?)",
+                options.bind, options.port
+            )
+        })?;
     let port = listener.local_addr()?.port();
+    let redirect = options.redirect_uri(port)?;
+
+    authorize(
+        client_id,
+        client_secret,
+        scopes,
+        &redirect,
+        options.force,
+        async move |auth_url, csrf_token| {
+            open_browser(&options.browser, auth_url);
+            wait_for_callback(listener, csrf_token, options.timeout).await
+        },
+    )
+    .await
+}
+
+/// Show the consent page, printing its URL when no browser can be started.
+fn open_browser(browser: &Browser, url: &Url) {
+    let opened = match browser {
+        Browser::System => open::that_detached(url.as_str()),
+        Browser::Command(command) if command.contains("%s") => {
+            spawn_browser(&browser_args(command, url.as_str()))
+        }
+        Browser::Command(command) => open::with_detached(url.as_str(), command),
+        Browser::None => {
+            println!("Open this URL in a browser on this machine:\n\n{}\n", url);
+            return;
+        }
+    };
+    match opened {
+        Ok(()) => println!("Opening browser for authentication..."),
+        Err(e) => println!(
+            "Couldn't start a browser ({}). Open this URL instead:\n\n{}\n",
+            e, url
+        ),
+    }
+}
 
+/// A `$BROWSER`-style command split into arguments, with the URL in place of `%s`.
+fn browser_args(command: &str, url: &str) -> Vec<String> {
+    command
+        .split_whitespace()
+        .map(|arg| arg.replace("%s", url))
+        .collect()
+}
+
+fn spawn_browser(args: &[String]) -> std::io::Result<()> {
+    let (program, args) = args.split_first().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Empty browser command")
+    })?;
+    std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .map(|_| ())
+}
+
+/// Log in without a local callback server: print the authorization URL and read
+/// the redirect URL (or bare code) pasted back from the browser.
+pub async fn login_manual(
+    client_id: &str,
+    client_secret: &str,
+    scopes: &[&str],
+    force: bool,
+) -> Result<Tokens> {
+    // Nothing listens here; the browser shows a connection error whose URL holds the code
+    let redirect = "http://localhost:1";
+
+    authorize(
+        client_id,
+        client_secret,
+        scopes,
+        redirect,
+        force,
+        async |auth_url, csrf_token| {
+            println!(
+                "Open this URL in a browser on any machine:\n\n{}\n",
+                auth_url
+            );
+            println!(
+                "After approving, the browser is sent to a localhost page that fails to load."
+            );
+            print!("Paste that page's full URL (or just the code) here: ");
+            std::io::stdout().flush()?;
+
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            parse_pasted_code(input.trim(), &csrf_token)
+        },
+    )
+    .await
+}
+
+/// Run the authorization code flow with PKCE; `get_code` sends the user to the
+/// consent page and returns the code from the redirect. With `force`, the consent
+/// screen is shown even if access was granted before.
+async fn authorize(
+    client_id: &str,
+    client_secret: &str,
+    scopes: &[&str],
+    redirect: &str,
+    force: bool,
+    get_code: impl AsyncFnOnce(&Url, CsrfToken) -> Result<AuthorizationCode>,
+) -> Result<Tokens> {
     let client = BasicClient::new(ClientId::new(client_id.to_string()))
         .set_client_secret(ClientSecret::new(client_secret.to_string()))
         .set_auth_uri(AuthUrl::new(AUTH_URL.to_string())?)
         .set_token_uri(TokenUrl::new(TOKEN_URL.to_string())?)
-        .set_redirect_uri(RedirectUrl::new(format!("http://localhost:{}", port))?);
+        .set_redirect_uri(RedirectUrl::new(redirect.to_string())?);
 
     let http_client = create_http_client();
 
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
     let pkce_secret = pkce_verifier.secret().to_string();
 
-    let (auth_url, csrf_token) = client
+    let mut request = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new(
-            "https://www.googleapis.com/auth/gmail.modify".to_string(),
-        ))
-        .set_pkce_challenge(pkce_challenge)
-        .url();
-
-    println!("Opening browser for authentication...");
-    open::that(auth_url.as_str())?;
+        .add_scopes(scopes.iter().map(|s| Scope::new(s.to_string())))
+        .set_pkce_challenge(pkce_challenge);
+    if force {
+        // Google only sends a refresh token with the first consent unless asked again
+        request = request
+            .add_extra_param("prompt", "consent")
+            .add_extra_param("access_type", "offline");
+    }
+    let (auth_url, csrf_token) = request.url();
 
-    let code = wait_for_callback(listener, csrf_token)?;
+    let code = get_code(&auth_url, csrf_token).await?;
 
     let mut last_error = None;
     let mut token_result = None;
@@ -99,59 +300,409 @@ pub async fn login(client_id: &str, client_secret: &str) -> Result<Tokens> {
         refresh_token: token_result
             .refresh_token()
             .map(|t| t.secret().to_string())
-            .ok_or_else(|| anyhow::anyhow!("No refresh token received"))?,
+            .ok_or_else(|| {
+                format_err!(
+                    "Google didn't send a refresh token; run 'gmail login --force' to grant access again"
+                )
+            })?,
+        scopes: granted_scopes(&token_result, scopes),
     };
 
     config::save_tokens(&tokens)?;
     Ok(tokens)
 }
 
-fn wait_for_callback(listener: TcpListener, expected_csrf: CsrfToken) -> Result<AuthorizationCode> {
-    let port = listener.local_addr()?.port();
-    println!("Waiting for OAuth callback on port {}...", port);
+/// Scopes from a token response, or the requested ones if the server didn't list them.
+fn granted_scopes(result: &impl TokenResponse, requested: &[&str]) -> Vec<String> {
+    match result.scopes() {
+        Some(granted) => granted.iter().map(|s| s.to_string()).collect(),
+        None => requested.iter().map(|s| s.to_string()).collect(),
+    }
+}
 
-    let (mut stream, _) = listener.accept()?;
-    let mut reader = BufReader::new(&stream);
-    let mut request_line = String::new();
-    reader.read_line(&mut request_line)?;
+/// Log in with the device authorization flow: print a code and URL, then poll until
+/// the user approves it on another device.
+///
+/// Needs a "TVs and Limited Input devices" OAuth client (see `gmail config`).
+pub async fn login_device(client_id: &str, client_secret: &str, scopes: &[&str]) -> Result<Tokens> {
+    let client = BasicClient::new(ClientId::new(client_id.to_string()))
+        .set_client_secret(ClientSecret::new(client_secret.to_string()))
+        .set_auth_uri(AuthUrl::new(AUTH_URL.to_string())?)
+        .set_token_uri(TokenUrl::new(TOKEN_URL.to_string())?)
+        .set_device_authorization_url(DeviceAuthorizationUrl::new(DEVICE_AUTH_URL.to_string())?);
 
-    let redirect_url = request_line
-        .split_whitespace()
-        .nth(1)
-        .ok_or_else(|| anyhow::anyhow!("Invalid request"))?;
+    let http_client = create_http_client();
 
-    let url = Url::parse(&format!("http://localhost{}", redirect_url))?;
+    let details: StandardDeviceAuthorizationResponse = client
+        .exchange_device_code()
+        .add_scopes(scopes.iter().map(|s| Scope::new(s.to_string())))
+        .request_async(&http_client)
+        .await
+        .context(
+            "Failed to start device authorization (is the client ID a TV/limited-input client?)",
+        )?;
+
+    println!(
+        "Open {} on any device and enter the code: {}",
+        details.verification_uri().as_str(),
+        details.user_code().secret()
+    );
+    println!(
+        "Waiting for approval (code expires in {} minutes)...",
+        details.expires_in().as_secs() / 60
+    );
+
+    let token_result = client
+        .exchange_device_access_token(&details)
+        .request_async(&http_client, tokio::time::sleep, None)
+        .await
+        .context("Device authorization failed")?;
 
-    let code = url
-        .query_pairs()
-        .find(|(key, _)| key == "code")
-        .map(|(_, value)| AuthorizationCode::new(value.into_owned()))
-        .ok_or_else(|| anyhow::anyhow!("No code in callback"))?;
+    let tokens = Tokens {
+        access_token: token_result.access_token().secret().to_string(),
+        refresh_token: token_result
+            .refresh_token()
+            .map(|t| t.secret().to_string())
+            .ok_or_else(|| format_err!("No refresh token received"))?,
+        scopes: granted_scopes(&token_result, scopes),
+    };
+
+    config::save_tokens(&tokens)?;
+    Ok(tokens)
+}
+
+/// The fields of a Google service account JSON key that are needed to sign assertions.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    TOKEN_URL.to_string()
+}
+
+#[derive(Serialize)]
+struct JwtClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    scope: String,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct JwtTokenResponse {
+    access_token: String,
+}
+
+/// Mint an access token for `account.subject` by signing a JWT with the service
+/// account key (domain-wide delegation must be granted for the scopes).
+pub async fn service_account_token(
+    http: &reqwest::Client,
+    account: &ServiceAccount,
+) -> Result<Tokens> {
+    let content = std::fs::read_to_string(&account.key_file).with_context(|| {
+        format!(
+            "Failed to read service account key {}",
+            account.key_file.display()
+        )
+    })?;
+    let key: ServiceAccountKey =
+        serde_json::from_str(&content).context("Invalid service account key file")?;
+
+    let now = chrono::Utc::now().timestamp();
+    let claims = JwtClaims {
+        iss: &key.client_email,
+        sub: &account.subject,
+        scope: account.scopes.join(" "),
+        aud: &key.token_uri,
+        iat: now,
+        exp: now + JWT_LIFETIME_SECS,
+    };
+    let signing_key = jsonwebtoken::EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+        .context("Invalid private key in service account key file")?;
+    let assertion = jsonwebtoken::encode(
+        &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256),
+        &claims,
+        &signing_key,
+    )?;
+
+    let resp = http
+        .post(&key.token_uri)
+        .form(&[("grant_type", JWT_BEARER_GRANT), ("assertion", &assertion)])
+        .send()
+        .await
+        .context("Failed to request service account token")?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        bail!(
+            "Service account token request failed: HTTP {} - {} (check domain-wide delegation for these scopes)",
+            status,
+            body
+        );
+    }
+    let token: JwtTokenResponse = resp
+        .json()
+        .await
+        .context("Failed to parse token response")?;
+
+    // Service accounts have no refresh token; a new assertion is signed instead
+    let tokens = Tokens {
+        access_token: token.access_token,
+        refresh_token: String::new(),
+        scopes: account.scopes.clone(),
+    };
+    Ok(tokens)
+}
+
+/// Revoke a token with Google. Tokens that are already invalid count as revoked.
+pub async fn revoke_token(token: &str) -> Result<()> {
+    let resp = create_http_client()
+        .post(REVOKE_URL)
+        .form(&[("token", token)])
+        .send()
+        .await
+        .context("Failed to reach revocation endpoint")?;
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = resp.text().await.unwrap_or_default();
+    if body.contains("invalid_token") {
+        return Ok(());
+    }
+    bail!("Token revocation failed: HTTP {} - {}", status, body)
+}
+
+/// What Google knows about an access token.
+#[derive(Debug, Deserialize)]
+pub struct TokenInfo {
+    /// Granted scopes, space-separated
+    #[serde(default)]
+    pub scope: String,
+    /// Seconds until the token expires (Google sends a string)
+    pub expires_in: Option<String>,
+    pub email: Option<String>,
+}
+
+impl TokenInfo {
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scope.split_whitespace().collect()
+    }
+}
+
+/// Ask Google about an access token; fails if the token is expired or revoked.
+pub async fn token_info(http: &reqwest::Client, token: &str) -> Result<TokenInfo> {
+    let resp = http
+        // In the body rather than the URL, which proxies and logs may keep
+        .post(TOKENINFO_URL)
+        .form(&[("access_token", token)])
+        .send()
+        .await
+        .context("Failed to reach tokeninfo endpoint")?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        return Err(GmailError::Auth(format!(
+            "Access token rejected: HTTP {} - {}",
+            status,
+            body.trim()
+        )));
+    }
+    resp.json()
+        .await
+        .context("Failed to parse tokeninfo response")
+}
+
+/// Serve the OAuth redirect until a request brings back a code or an error.
+///
+/// Every connection gets its own task, so a browser that opens a spare
+/// connection or asks for a favicon first doesn't hold up the real redirect.
+async fn wait_for_callback(
+    listener: tokio::net::TcpListener,
+    expected_csrf: CsrfToken,
+    timeout: Duration,
+) -> Result<AuthorizationCode> {
+    let port = listener.local_addr()?.port();
+    println!("Waiting for OAuth callback on port {}...", port);
 
-    let state = url
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let expected_csrf = std::sync::Arc::new(expected_csrf);
+    let wait = async {
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted?;
+                    let (tx, expected_csrf) = (tx.clone(), expected_csrf.clone());
+                    let service = hyper::service::service_fn(move |request| {
+                        let response = callback_response(&request, &expected_csrf, &tx);
+                        async move { Ok::<_, std::convert::Infallible>(response) }
+                    });
+                    tokio::spawn(async move {
+                        // Malformed requests and dropped connections only affect that connection
+                        let served = hyper::server::conn::http1::Builder::new()
+                            .keep_alive(false)
+                            .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                            .await;
+                        if let Err(e) = served {
+                            tracing::debug!("OAuth callback connection failed: {}", e);
+                        }
+                    });
+                }
+                Some(result) = rx.recv() => return result,
+            }
+        }
+    };
+    tokio::time::timeout(timeout, wait).await.map_err(|_| {
+        format_err!(
+            "Timed out after {}s waiting for the browser; run 'gmail login' again, or 'gmail login --manual' if the browser can't reach this machine",
+            timeout.as_secs()
+        )
+    })?
+}
+
+/// Answer one request to the callback server, passing on the outcome of a
+/// redirect that carries our state.
+fn callback_response<B>(
+    request: &Request<B>,
+    expected_csrf: &CsrfToken,
+    outcome: &tokio::sync::mpsc::Sender<Result<AuthorizationCode>>,
+) -> Response<Full<Bytes>> {
+    let url = Url::parse(&format!("http://localhost{}", request.uri()))
+        .ok()
+        .filter(|url| {
+            url.query_pairs()
+                .any(|(key, _)| key == "code" || key == "error")
+        });
+    // Favicons, prefetches and anything else that isn't Google's redirect
+    let Some(url) = url else {
+        return callback_page(
+            StatusCode::NOT_FOUND,
+            "Not found",
+            "This is gmail-cli's sign-in helper. Finish signing in from the page Google showed you.",
+        );
+    };
+    // Another page can send the browser here too; only our own redirect ends the wait
+    let ours = url
         .query_pairs()
-        .find(|(key, _)| key == "state")
-        .map(|(_, value)| CsrfToken::new(value.into_owned()))
-        .ok_or_else(|| anyhow::anyhow!("No state in callback"))?;
+        .any(|(key, value)| key == "state" && value == *expected_csrf.secret());
+    if !ours {
+        return callback_page(
+            StatusCode::BAD_REQUEST,
+            "Unexpected request",
+            "This doesn't belong to the sign-in in progress. Finish signing in from the page Google showed you.",
+        );
+    }
+    let denied = url
+        .query_pairs()
+        .any(|(key, value)| key == "error" && value == "access_denied");
+    let result = code_from_redirect(&url, expected_csrf);
+    let response = match &result {
+        Ok(_) => callback_page(
+            StatusCode::OK,
+            "You're signed in",
+            "gmail-cli can now reach your mailbox. You can close this tab and return to the terminal.",
+        ),
+        Err(_) if denied => callback_page(
+            StatusCode::FORBIDDEN,
+            "Access denied",
+            "gmail-cli wasn't given access. Run <code>gmail login</code> again and choose Allow to retry.",
+        ),
+        Err(_) => callback_page(
+            StatusCode::BAD_REQUEST,
+            "Sign-in failed",
+            "See the terminal for details. You can close this tab.",
+        ),
+    };
+    // Only the first redirect counts; later ones find the channel full or closed
+    let _ = outcome.try_send(result);
+    response
+}
+
+fn callback_page(status: StatusCode, title: &str, message: &str) -> Response<Full<Bytes>> {
+    let html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title} - gmail-cli</title>\
+         <style>body{{font-family:system-ui,sans-serif;max-width:32em;margin:4em auto;padding:0 1em;color:#202124}}\
+         h1{{font-weight:500}}p{{color:#5f6368;line-height:1.5}}</style></head>\
+         <body><h1>{title}</h1><p>{message}</p></body></html>"
+    );
+    let mut response = Response::new(Full::new(Bytes::from(html)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    response
+}
+
+fn code_from_redirect(url: &Url, expected_csrf: &CsrfToken) -> Result<AuthorizationCode> {
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    // Checked first: anything without our state didn't come from Google's redirect
+    let state = param("state").ok_or_else(|| format_err!("No state in callback"))?;
+    if state != *expected_csrf.secret() {
+        bail!("CSRF token mismatch");
+    }
 
-    if state.secret() != expected_csrf.secret() {
-        anyhow::bail!("CSRF token mismatch");
+    // Google sends `error` instead of a code when consent isn't given
+    if let Some(error) = param("error") {
+        if error == "access_denied" {
+            return Err(GmailError::Auth(
+                "Access was denied on the consent screen; run 'gmail login' again and choose Allow"
+                    .to_string(),
+            ));
+        }
+        return Err(GmailError::Auth(format!(
+            "Authorization failed: {}",
+            error.escape_debug()
+        )));
     }
+    param("code")
+        .map(AuthorizationCode::new)
+        .ok_or_else(|| format_err!("No code in callback"))
+}
 
-    let response = "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<html><body><h1>Authentication successful!</h1><p>You can close this window.</p></body></html>";
-    stream.write_all(response.as_bytes())?;
+/// Accept either the full redirect URL (state is checked) or a bare authorization code.
+fn parse_pasted_code(input: &str, expected_csrf: &CsrfToken) -> Result<AuthorizationCode> {
+    if input.is_empty() {
+        bail!("No code entered");
+    }
+    match Url::parse(input) {
+        Ok(url) => code_from_redirect(&url, expected_csrf),
+        Err(_) => Ok(AuthorizationCode::new(input.to_string())),
+    }
+}
 
-    Ok(code)
+/// Google's answer to a refresh token that was revoked, expired or issued to another client.
+fn is_invalid_grant<RE: std::error::Error>(
+    err: &oauth2::RequestTokenError<RE, oauth2::basic::BasicErrorResponse>,
+) -> bool {
+    matches!(
+        err,
+        oauth2::RequestTokenError::ServerResponse(response)
+            if *response.error() == oauth2::basic::BasicErrorResponseType::InvalidGrant
+    )
 }
 
-pub async fn refresh_token(client_id: &str, client_secret: &str, refresh: &str) -> Result<Tokens> {
+pub async fn refresh_token(
+    http_client: &reqwest::Client,
+    client_id: &str,
+    client_secret: &str,
+    refresh: &str,
+) -> Result<Tokens> {
     let client = BasicClient::new(ClientId::new(client_id.to_string()))
         .set_client_secret(ClientSecret::new(client_secret.to_string()))
         .set_auth_uri(AuthUrl::new(AUTH_URL.to_string())?)
         .set_token_uri(TokenUrl::new(TOKEN_URL.to_string())?);
 
-    let http_client = create_http_client();
-
     let mut last_error = None;
     let mut token_result = None;
 
@@ -164,7 +715,7 @@ pub async fn refresh_token(client_id: &str, client_secret: &str, refresh: &str)
 
         match client
             .exchange_refresh_token(&RefreshToken::new(refresh.to_string()))
-            .request_async(&http_client)
+            .request_async(http_client)
             .await
         {
             Ok(result) => {
@@ -173,6 +724,12 @@ pub async fn refresh_token(client_id: &str, client_secret: &str, refresh: &str)
             }
             Err(e) => {
                 let err_str = format!("{:?}", e);
+                if is_invalid_grant(&e) {
+                    return Err(GmailError::Auth(
+                        "The saved login was revoked or has expired; run 'gmail login --force' to sign in again"
+                            .to_string(),
+                    ));
+                }
                 if err_str.contains("timed out") || err_str.contains("Timeout") {
                     eprintln!(
                         "Token refresh timed out (attempt {}/{})",
@@ -197,8 +754,226 @@ pub async fn refresh_token(client_id: &str, client_secret: &str, refresh: &str)
             .refresh_token()
             .map(|t| t.secret().to_string())
             .unwrap_or_else(|| refresh.to_string()),
+        // Google reports the scopes on refresh too; keep the old list if it doesn't
+        scopes: match token_result.scopes() {
+            Some(granted) => granted.iter().map(|s| s.to_string()).collect(),
+            None => config::load_tokens().map(|t| t.scopes).unwrap_or_default(),
+        },
     };
-
-    config::save_tokens(&tokens)?;
     Ok(tokens)
 }
+
+/// Supplies the access tokens a [`Client`](crate::Client) sends with its requests.
+///
+/// Implement it to plug in another OAuth flow, or a stub in tests.
+pub trait TokenProvider: Send + Sync {
+    /// The token to send with the next request.
+    fn token(&self) -> BoxFuture<'_, Result<String>>;
+
+    /// A new token after the API rejected `stale` with a 401, or None if it can't be renewed.
+    ///
+    /// Requests failing together each ask; once `stale` has been replaced,
+    /// return the current token instead of renewing again.
+    fn refresh<'a>(&'a self, stale: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+}
+
+/// A fixed access token, used until it expires.
+pub struct StaticToken(pub String);
+
+impl TokenProvider for StaticToken {
+    fn token(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(async move { Ok(self.0.clone()) })
+    }
+
+    fn refresh<'a>(&'a self, _stale: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+/// An access token renewed on a 401, from a refresh token or by signing a new
+/// service account assertion. Renewed tokens are saved for the current account
+/// unless built [`without_saving`](Self::without_saving).
+pub struct RefreshingToken {
+    http: reqwest::Client,
+    access_token: Mutex<String>,
+    renew: Renew,
+    save: bool,
+    // Serializes refreshes so concurrent requests that fail together renew once
+    lock: tokio::sync::Mutex<()>,
+}
+
+enum Renew {
+    OAuth {
+        client_id: String,
+        client_secret: String,
+        refresh_token: Mutex<String>,
+    },
+    ServiceAccount(ServiceAccount),
+}
+
+impl RefreshingToken {
+    pub fn oauth(
+        http: reqwest::Client,
+        access_token: &str,
+        client_id: &str,
+        client_secret: &str,
+        refresh_token: &str,
+    ) -> Self {
+        Self::new(
+            http,
+            access_token,
+            Renew::OAuth {
+                client_id: client_id.to_string(),
+                client_secret: client_secret.to_string(),
+                refresh_token: Mutex::new(refresh_token.to_string()),
+            },
+        )
+    }
+
+    pub fn service_account(
+        http: reqwest::Client,
+        access_token: &str,
+        account: ServiceAccount,
+    ) -> Self {
+        Self::new(http, access_token, Renew::ServiceAccount(account))
+    }
+
+    fn new(http: reqwest::Client, access_token: &str, renew: Renew) -> Self {
+        Self {
+            http,
+            access_token: Mutex::new(access_token.to_string()),
+            renew,
+            save: true,
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Keep renewed tokens in memory only, e.g. when they came from the environment.
+    pub fn without_saving(mut self) -> Self {
+        self.save = false;
+        self
+    }
+
+    fn current(&self) -> String {
+        self.access_token.lock().unwrap().clone()
+    }
+}
+
+impl TokenProvider for RefreshingToken {
+    fn token(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(async move { Ok(self.current()) })
+    }
+
+    fn refresh<'a>(&'a self, stale: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(async move {
+            let _guard = self.lock.lock().await;
+            let current = self.current();
+            if current != stale {
+                return Ok(Some(current));
+            }
+
+            let tokens = match &self.renew {
+                Renew::OAuth {
+                    client_id,
+                    client_secret,
+                    refresh_token,
+                } => {
+                    let refresh = refresh_token.lock().unwrap().clone();
+                    let tokens =
+                        self::refresh_token(&self.http, client_id, client_secret, &refresh).await?;
+                    *refresh_token.lock().unwrap() = tokens.refresh_token.clone();
+                    tokens
+                }
+                Renew::ServiceAccount(account) => {
+                    service_account_token(&self.http, account).await?
+                }
+            };
+            if self.save {
+                config::save_tokens(&tokens)?;
+            }
+            *self.access_token.lock().unwrap() = tokens.access_token.clone();
+            Ok(Some(tokens.access_token))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pasted_code() {
+        let csrf = CsrfToken::new("abc".to_string());
+
+        let code =
+            parse_pasted_code("http://localhost:1/?state=abc&code=4/xyz&scope=s", &csrf).unwrap();
+        assert_eq!(code.secret(), "4/xyz");
+
+        let code = parse_pasted_code("4/xyz", &csrf).unwrap();
+        assert_eq!(code.secret(), "4/xyz");
+
+        assert!(parse_pasted_code("http://localhost:1/?state=bad&code=x", &csrf).is_err());
+        assert!(parse_pasted_code("", &csrf).is_err());
+
+        let denied = parse_pasted_code("http://localhost:1/?error=access_denied&state=abc", &csrf)
+            .unwrap_err();
+        assert!(denied.to_string().contains("denied"));
+        // An error without our state is not trusted
+        let forged =
+            parse_pasted_code("http://localhost:1/?error=access_denied", &csrf).unwrap_err();
+        assert!(!forged.to_string().contains("denied"));
+        let escaped =
+            parse_pasted_code("http://localhost:1/?error=x%1B%5B2J&state=abc", &csrf).unwrap_err();
+        assert!(!escaped.to_string().contains('\x1b'));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_callback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let base = format!("http://{}", addr);
+        let csrf = CsrfToken::new("abc".to_string());
+        let wait = tokio::spawn(wait_for_callback(listener, csrf, Duration::from_secs(5)));
+
+        // A spare connection left open doesn't block the redirect
+        let _idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let http = reqwest::Client::new();
+        let favicon = http.get(format!("{}/favicon.ico", base)).send().await;
+        assert_eq!(favicon.unwrap().status(), 404);
+        let forged = http
+            .get(format!("{}/?state=other&error=access_denied", base))
+            .send()
+            .await;
+        assert_eq!(forged.unwrap().status(), 400);
+        let redirect = http
+            .get(format!("{}/?state=abc&code=4/xyz", base))
+            .send()
+            .await;
+        assert_eq!(redirect.unwrap().status(), 200);
+        assert_eq!(wait.await.unwrap().unwrap().secret(), "4/xyz");
+    }
+
+    #[test]
+    fn test_redirect_uri() {
+        let options = LoginOptions::default();
+        assert_eq!(options.redirect_uri(8085).unwrap(), "http://localhost:8085");
+        let options = LoginOptions {
+            bind: "0.0.0.0".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(options.redirect_uri(8085).unwrap(), "http://localhost:8085");
+        let options = LoginOptions {
+            bind: "192.168.1.5".parse().unwrap(),
+            ..Default::default()
+        };
+        assert!(options.redirect_uri(8085).is_err());
+    }
+
+    #[test]
+    fn test_browser_args() {
+        assert_eq!(
+            browser_args("firefox --new-window %s", "https://x.test/?a=b"),
+            vec!["firefox", "--new-window", "https://x.test/?a=b"]
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use serde::Serialize;
+
+/// One method's verdict from an `Authentication-Results` header (RFC 8601),
+/// e.g. `dkim=pass header.d=example.com`.
+#[derive(Debug, PartialEq)]
+pub struct MethodResult {
+    pub method: String,
+    pub result: String,
+    /// `ptype.property` and value pairs, e.g. `("header.d", "example.com")`
+    pub properties: Vec<(String, String)>,
+}
+
+impl MethodResult {
+    fn property(&self, name: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The authserv-id Gmail puts on the `Authentication-Results` it adds on receipt.
+pub const GMAIL_AUTHSERV_ID: &str = "mx.google.com";
+
+/// The server that wrote an `Authentication-Results` header, e.g. `mx.google.com`.
+pub fn authserv_id(header: &str) -> String {
+    let header = strip_comments(header);
+    let id = header.split(';').next().unwrap_or_default();
+    // An optional version may follow the id
+    id.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// Gmail's own `Authentication-Results` among a message's, in message order.
+///
+/// Others were written by the sender or servers along the way, so they prove nothing.
+pub fn gmail_results<'a>(headers: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    headers
+        .into_iter()
+        .find(|header| authserv_id(header) == GMAIL_AUTHSERV_ID)
+}
+
+/// The method results of an `Authentication-Results` header, without the authserv-id.
+pub fn parse(header: &str) -> Vec<MethodResult> {
+    let header = strip_comments(header);
+    header
+        .split(';')
+        .skip(1)
+        .filter_map(|item| {
+            let mut words = item.split_whitespace();
+            let (method, result) = words.next()?.split_once('=')?;
+            // "dkim/1=pass" carries a method version
+            let method = method.split('/').next().unwrap_or(method);
+            let properties = words
+                .filter_map(|word| word.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.trim_matches('"').to_string()))
+                .collect();
+            Some(MethodResult {
+                method: method.to_ascii_lowercase(),
+                result: result.to_ascii_lowercase(),
+                properties,
+            })
+        })
+        .collect()
+}
+
+/// Drop RFC 5322 comments, which hold explanations like `(google.com: domain of ...)`.
+fn strip_comments(header: &str) -> String {
+    let mut out = String::with_capacity(header.len());
+    let mut depth = 0usize;
+    let mut escaped = false;
+    for c in header.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if depth > 0 => escaped = true,
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            _ if depth == 0 => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// SPF, DKIM and DMARC verdicts for a message, and whether they vouch for its From domain.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Summary {
+    pub spf: Option<String>,
+    pub dkim: Option<String>,
+    pub dmarc: Option<String>,
+    /// Whether a passing check covers the From domain; false suggests spoofing
+    pub aligned: Option<bool>,
+}
+
+impl Summary {
+    /// Summarize the receiving server's verdicts.
+    ///
+    /// Pass only the `Authentication-Results` header added by Gmail (see
+    /// [`gmail_results`]): any other came from elsewhere and proves nothing.
+    /// `Received-SPF` fills in SPF when the results lack it.
+    pub fn new(
+        authentication_results: Option<&str>,
+        received_spf: Option<&str>,
+        from: Option<&str>,
+    ) -> Option<Self> {
+        let results = authentication_results.map(parse).unwrap_or_default();
+        let verdict = |method: &str| -> Vec<&MethodResult> {
+            results.iter().filter(|r| r.method == method).collect()
+        };
+        let spf = verdict("spf");
+        let dkim = verdict("dkim");
+        let dmarc = verdict("dmarc");
+
+        let mut summary = Summary {
+            spf: spf.first().map(|r| r.result.clone()).or_else(|| {
+                let first = received_spf?.split_whitespace().next()?;
+                Some(first.to_ascii_lowercase())
+            }),
+            // With several signatures, one good one is enough
+            dkim: dkim
+                .iter()
+                .find(|r| r.result == "pass")
+                .or(dkim.first())
+                .map(|r| r.result.clone()),
+            dmarc: dmarc.first().map(|r| r.result.clone()),
+            aligned: None,
+        };
+        if summary == Summary::default() {
+            return None;
+        }
+
+        let from_domain = from
+            .map(crate::mime::address_of)
+            .and_then(|addr| addr.rsplit_once('@'))
+            .map(|(_, domain)| domain.to_ascii_lowercase());
+        summary.aligned = match (&summary.dmarc, from_domain) {
+            // Gmail already checked alignment when it evaluated DMARC
+            (Some(dmarc), _) if dmarc != "none" => Some(dmarc == "pass"),
+            (_, Some(from)) => {
+                let dkim_domains = dkim.iter().filter(|r| r.result == "pass").filter_map(|r| {
+                    r.property("header.d")
+                        .or_else(|| r.property("header.i")?.rsplit_once('@').map(|(_, d)| d))
+                });
+                let spf_domains = spf.iter().filter(|r| r.result == "pass").filter_map(|r| {
+                    let sender = r.property("smtp.mailfrom")?;
+                    Some(sender.rsplit_once('@').map_or(sender, |(_, d)| d))
+                });
+                Some(
+                    dkim_domains
+                        .chain(spf_domains)
+                        .any(|domain| is_aligned(&from, domain)),
+                )
+            }
+            (_, None) => None,
+        };
+        Some(summary)
+    }
+
+    /// One line such as `SPF pass, DKIM pass, DMARC pass`, with missing checks left out.
+    pub fn describe(&self) -> String {
+        let parts: Vec<String> = [
+            ("SPF", &self.spf),
+            ("DKIM", &self.dkim),
+            ("DMARC", &self.dmarc),
+        ]
+        .into_iter()
+        .filter_map(|(name, result)| Some(format!("{} {}", name, result.as_ref()?)))
+        .collect();
+        let mut line = parts.join(", ");
+        if self.aligned == Some(false) {
+            line.push_str(" - From domain not verified, possibly spoofed");
+        }
+        line
+    }
+}
+
+/// Relaxed alignment: the same domain or one a subdomain of the other.
+///
+/// Real DMARC compares organizational domains from the public suffix list, so
+/// `a.example.com` and `b.example.com` align there but not here.
+fn is_aligned(from: &str, domain: &str) -> bool {
+    let domain = domain.to_ascii_lowercase();
+    from == domain
+        || from.ends_with(&format!(".{}", domain))
+        || domain.ends_with(&format!(".{}", from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GMAIL: &str = "mx.google.com;\r\n       dkim=pass header.i=@example.com header.s=s1 header.b=AbC;\r\n       spf=pass (google.com: domain of bounce@mail.example.com designates 1.2.3.4 as permitted sender) smtp.mailfrom=bounce@mail.example.com;\r\n       dmarc=pass (p=REJECT sp=REJECT dis=NONE) header.from=example.com";
+
+    #[test]
+    fn test_parse() {
+        let results = parse(GMAIL);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].method, "dkim");
+        assert_eq!(results[0].result, "pass");
+        assert_eq!(results[0].property("header.i"), Some("@example.com"));
+        assert_eq!(
+            results[1].property("smtp.mailfrom"),
+            Some("bounce@mail.example.com")
+        );
+        assert_eq!(
+            results[2].properties,
+            vec![("header.from".to_string(), "example.com".to_string())]
+        );
+        assert!(parse("mx.google.com; none").is_empty());
+    }
+
+    #[test]
+    fn test_gmail_results() {
+        assert_eq!(authserv_id(GMAIL), "mx.google.com");
+        assert_eq!(authserv_id("MX.Google.com 1; none"), "mx.google.com");
+        let forged = "evil.test; dkim=pass header.d=example.com";
+        let prefixed = "mx.google.com.evil.test; dkim=pass header.d=example.com";
+        assert_eq!(gmail_results([forged, prefixed, GMAIL]), Some(GMAIL));
+        assert_eq!(gmail_results([forged]), None);
+    }
+
+    #[test]
+    fn test_summary() {
+        let summary = Summary::new(Some(GMAIL), None, Some("Shop <news@example.com>")).unwrap();
+        assert_eq!(summary.describe(), "SPF pass, DKIM pass, DMARC pass");
+        assert_eq!(summary.aligned, Some(true));
+
+        // No DMARC verdict: passing checks for another domain don't vouch for From
+        let spoofed =
+            "mx.google.com; dkim=pass header.d=evil.test; spf=softfail smtp.mailfrom=evil.test";
+        let summary = Summary::new(Some(spoofed), None, Some("bank@example.com")).unwrap();
+        assert_eq!(summary.aligned, Some(false));
+        assert_eq!(
+            summary.describe(),
+            "SPF softfail, DKIM pass - From domain not verified, possibly spoofed"
+        );
+
+        let summary = Summary::new(
+            None,
+            Some("Pass (sender SPF authorized) client-ip=1.2.3.4"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(summary.spf.as_deref(), Some("pass"));
+        assert_eq!(summary.aligned, None);
+
+        assert_eq!(Summary::new(None, None, Some("a@example.com")), None);
+    }
+}
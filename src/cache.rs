@@ -0,0 +1,530 @@
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::api::Message;
+use crate::config;
+use crate::error::{Context, Result};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS messages (
+    id TEXT PRIMARY KEY,
+    thread_id TEXT,
+    -- Space-separated with a leading and trailing space, so ' INBOX ' matches a whole label
+    labels TEXT NOT NULL,
+    internal_date INTEGER,
+    -- 1 when the whole message is stored, 0 for format=metadata
+    full INTEGER NOT NULL,
+    -- Lowercase header names fetched with format=metadata, or '*' for all of them
+    headers TEXT NOT NULL,
+    -- The message as returned by the API
+    message TEXT NOT NULL,
+    fetched_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_thread ON messages(thread_id);
+CREATE INDEX IF NOT EXISTS messages_date ON messages(internal_date);
+CREATE TABLE IF NOT EXISTS state (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+-- Shares rowids with messages, which stay put because the cache is never vacuumed
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(subject, sender, recipients, body);
+";
+
+// Bumped when existing caches need work done on open; stored as PRAGMA user_version
+const SCHEMA_VERSION: i64 = 1;
+
+// Rendering width for HTML-only bodies; only the words matter for search
+const INDEX_WIDTH: usize = 1000;
+
+/// Which parts of a message a request asked for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format<'a> {
+    Full,
+    /// format=metadata with these headers; empty means every header
+    Metadata(&'a [&'a str]),
+}
+
+/// Messages seen before, kept in SQLite so they need not be fetched again.
+///
+/// A message's content never changes once it exists, only its labels. Labels
+/// are kept current for changes made through this client; changes made
+/// elsewhere show up once the message is fetched again.
+pub struct Cache {
+    conn: Mutex<Connection>,
+}
+
+impl Cache {
+    /// Open the cache at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        // Message bodies are as private as the tokens next to them
+        if let Some(dir) = path.parent() {
+            config::ensure_dir(dir)?;
+        }
+        config::append_secure(path)
+            .with_context(|| format!("Failed to open cache {}", path.display()))?;
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open cache {}", path.display()))?;
+        Self::with_connection(conn)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)
+            .context("Failed to set up the message cache")?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < 1 {
+            // Caches from before full-text search have messages but no index
+            reindex(&conn).context("Failed to build the search index")?;
+        }
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The stored message, if it holds at least what `format` asks for.
+    pub fn get(&self, id: &str, format: Format) -> Result<Option<Message>> {
+        let row = self
+            .conn()
+            .query_row(
+                "SELECT full, headers, labels, message FROM messages WHERE id = ?1",
+                params![id],
+                |row| {
+                    Ok((
+                        row.get::<_, bool>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let Some((full, headers, labels, message)) = row else {
+            return Ok(None);
+        };
+        if !full && !covers(&headers, format) {
+            return Ok(None);
+        }
+        Ok(Some(decode(&labels, &message)?))
+    }
+
+    /// Store a message fetched in `format`. A fully stored message is never
+    /// replaced by metadata, but its labels are updated.
+    pub fn put(&self, msg: &Message, format: Format) -> Result<()> {
+        let (full, headers) = match format {
+            Format::Full => (true, String::new()),
+            Format::Metadata(headers) => (false, header_key(headers)),
+        };
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let stored_full: Option<bool> = tx
+            .query_row(
+                "SELECT full FROM messages WHERE id = ?1",
+                params![msg.id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        tx.execute(
+            "INSERT INTO messages (id, thread_id, labels, internal_date, full, headers, message, fetched_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, strftime('%s', 'now'))
+             ON CONFLICT(id) DO UPDATE SET
+                 labels = excluded.labels,
+                 fetched_at = excluded.fetched_at,
+                 thread_id = CASE WHEN excluded.full >= full THEN excluded.thread_id ELSE thread_id END,
+                 internal_date = CASE WHEN excluded.full >= full THEN excluded.internal_date ELSE internal_date END,
+                 headers = CASE WHEN excluded.full >= full THEN excluded.headers ELSE headers END,
+                 message = CASE WHEN excluded.full >= full THEN excluded.message ELSE message END,
+                 full = max(full, excluded.full)",
+            params![
+                msg.id,
+                msg.thread_id,
+                encode_labels(msg.label_ids.as_deref().unwrap_or_default()),
+                msg.internal_date.as_deref().and_then(|d| d.parse::<i64>().ok()),
+                full,
+                headers,
+                serde_json::to_string(msg)?,
+            ],
+        )?;
+        if full || stored_full != Some(true) {
+            index(&tx, msg)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Drop messages whose labels changed, by message ID. Returns how many
+    /// were cached.
+    pub fn forget(&self, ids: &[String]) -> Result<usize> {
+        self.forget_where("id", ids)
+    }
+
+    /// Drop every message in the given threads.
+    pub fn forget_threads(&self, thread_ids: &[String]) -> Result<usize> {
+        self.forget_where("thread_id", thread_ids)
+    }
+
+    fn forget_where(&self, column: &str, values: &[String]) -> Result<usize> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let mut removed = 0;
+        {
+            let mut unindex = tx.prepare(&format!(
+                "DELETE FROM messages_fts WHERE rowid IN (SELECT rowid FROM messages WHERE {} = ?1)",
+                column
+            ))?;
+            let mut delete = tx.prepare(&format!("DELETE FROM messages WHERE {} = ?1", column))?;
+            for value in values {
+                unindex.execute(params![value])?;
+                removed += delete.execute(params![value])?;
+            }
+        }
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    /// Apply a label change to a stored message; false when it isn't stored.
+    pub fn change_labels(&self, id: &str, add: &[String], remove: &[String]) -> Result<bool> {
+        let conn = self.conn();
+        let labels: Option<String> = conn
+            .query_row(
+                "SELECT labels FROM messages WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(labels) = labels else {
+            return Ok(false);
+        };
+        let mut labels: Vec<String> = labels
+            .split_whitespace()
+            .filter(|l| !remove.iter().any(|r| r == l))
+            .map(str::to_string)
+            .collect();
+        for label in add {
+            if !labels.contains(label) {
+                labels.push(label.clone());
+            }
+        }
+        conn.execute(
+            "UPDATE messages SET labels = ?2 WHERE id = ?1",
+            params![id, encode_labels(&labels)],
+        )?;
+        Ok(true)
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn()
+            .query_row("SELECT count(*) FROM messages", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// The history ID the cache is current as of, set by `gmail sync`.
+    pub fn history_id(&self) -> Result<Option<String>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT value FROM state WHERE key = 'history_id'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn set_history_id(&self, history_id: &str) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO state (key, value) VALUES ('history_id', ?1)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![history_id],
+        )?;
+        Ok(())
+    }
+
+    /// Stored messages matching words in their subject, sender, recipients or
+    /// body, best matches first. Bodies are only searched for messages stored
+    /// in full; others match on their snippet.
+    pub fn search(&self, query: &str, limit: u32) -> Result<Vec<Message>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT m.labels, m.message FROM messages_fts
+             JOIN messages m ON m.rowid = messages_fts.rowid
+             WHERE messages_fts MATCH ?1
+             ORDER BY rank LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![fts_query(query), limit], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows.map(|row| {
+            let (labels, message) = row?;
+            decode(&labels, &message)
+        })
+        .collect()
+    }
+
+    /// The newest stored messages with `label`, for browsing without a connection.
+    pub fn recent(&self, label: &str, limit: u32) -> Result<Vec<Message>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT labels, message FROM messages
+             WHERE labels LIKE ?1
+             ORDER BY internal_date DESC LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![format!("% {} %", label), limit], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows.map(|row| {
+            let (labels, message) = row?;
+            decode(&labels, &message)
+        })
+        .collect()
+    }
+}
+
+// Replace a message's search index entry
+fn index(conn: &Connection, msg: &Message) -> Result<()> {
+    let rowid: i64 = conn.query_row(
+        "SELECT rowid FROM messages WHERE id = ?1",
+        params![msg.id],
+        |row| row.get(0),
+    )?;
+    let header = |name: &str| msg.get_header(name).unwrap_or_default();
+    let recipients = [header("To"), header("Cc")].join(" ");
+    let body = msg
+        .get_readable_body(INDEX_WIDTH)
+        .or_else(|| msg.snippet.clone())
+        .unwrap_or_default();
+    conn.execute("DELETE FROM messages_fts WHERE rowid = ?1", params![rowid])?;
+    conn.execute(
+        "INSERT INTO messages_fts (rowid, subject, sender, recipients, body)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![rowid, header("Subject"), header("From"), recipients, body],
+    )?;
+    Ok(())
+}
+
+fn reindex(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT labels, message FROM messages")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for row in rows {
+        let (labels, message) = row?;
+        index(conn, &decode(&labels, &message)?)?;
+    }
+    Ok(())
+}
+
+/// Turn plain words into an FTS5 query: every word must match, `word*`
+/// matches a prefix and `OR` between words matches either. Anything else is
+/// taken literally, so punctuation like "acme.com" needs no escaping.
+fn fts_query(input: &str) -> String {
+    input
+        .split_whitespace()
+        .map(|word| {
+            if word == "OR" {
+                return word.to_string();
+            }
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(stem) if !stem.is_empty() => (stem, "*"),
+                _ => (word, ""),
+            };
+            format!("\"{}\"{}", word.replace('"', "\"\""), prefix)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn encode_labels(labels: &[String]) -> String {
+    format!(" {} ", labels.join(" "))
+}
+
+fn header_key(headers: &[&str]) -> String {
+    if headers.is_empty() {
+        return "*".to_string();
+    }
+    let mut names: Vec<String> = headers.iter().map(|h| h.to_ascii_lowercase()).collect();
+    names.sort();
+    names.dedup();
+    names.join(",")
+}
+
+// Whether metadata stored with `stored` headers answers a request for `format`
+fn covers(stored: &str, format: Format) -> bool {
+    match format {
+        Format::Full => false,
+        Format::Metadata(_) if stored == "*" => true,
+        Format::Metadata([]) => false,
+        Format::Metadata(wanted) => wanted
+            .iter()
+            .all(|h| stored.split(',').any(|s| s.eq_ignore_ascii_case(h))),
+    }
+}
+
+// The labels column is authoritative; the stored JSON may predate a label change
+fn decode(labels: &str, message: &str) -> Result<Message> {
+    let mut msg: Message = serde_json::from_str(message).context("Corrupt cached message")?;
+    msg.label_ids = Some(labels.split_whitespace().map(str::to_string).collect());
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> Cache {
+        Cache::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_open_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("gmail-cache-test-{}", std::process::id()));
+        let path = dir.join("cache.sqlite");
+        Cache::open(&path).unwrap();
+        let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(fts_query("invoice acme.com"), r#""invoice" "acme.com""#);
+        assert_eq!(fts_query("inv* OR bill"), r#""inv"* OR "bill""#);
+        assert_eq!(fts_query(r#"say "hi""#), r#""say" """hi""""#);
+    }
+
+    #[test]
+    fn test_search() {
+        let cache = cache();
+        let mut msg = message("a", &["INBOX"], "1000");
+        msg.snippet = Some("Your invoice from Acme is attached".to_string());
+        cache.put(&msg, Format::Metadata(&[])).unwrap();
+        cache
+            .put(&message("b", &["INBOX"], "2000"), Format::Full)
+            .unwrap();
+
+        let found = |q| {
+            cache
+                .search(q, 10)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(found("invoice acme"), vec!["a"]);
+        assert_eq!(found("invo*"), vec!["a"]);
+        assert_eq!(found("hi").len(), 2);
+        assert!(found("invoice missing").is_empty());
+
+        assert_eq!(cache.forget(&["a".to_string()]).unwrap(), 1);
+        assert_eq!(cache.forget(&["a".to_string()]).unwrap(), 0);
+        assert!(found("invoice").is_empty());
+    }
+
+    fn message(id: &str, labels: &[&str], date: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "threadId": "t1",
+            "labelIds": labels,
+            "internalDate": date,
+            "payload": { "headers": [{ "name": "Subject", "value": "Hi" }] }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_metadata_covers_requested_headers() {
+        let cache = cache();
+        let msg = message("a", &["INBOX"], "1000");
+        cache
+            .put(&msg, Format::Metadata(&["Subject", "From"]))
+            .unwrap();
+
+        assert!(
+            cache
+                .get("a", Format::Metadata(&["subject"]))
+                .unwrap()
+                .is_some()
+        );
+        assert!(cache.get("a", Format::Metadata(&["To"])).unwrap().is_none());
+        assert!(cache.get("a", Format::Full).unwrap().is_none());
+        assert!(cache.get("b", Format::Metadata(&[])).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_full_kept_over_metadata() {
+        let cache = cache();
+        cache
+            .put(&message("a", &["INBOX", "UNREAD"], "1000"), Format::Full)
+            .unwrap();
+        cache
+            .put(
+                &message("a", &["INBOX"], "1000"),
+                Format::Metadata(&["Subject"]),
+            )
+            .unwrap();
+
+        let msg = cache.get("a", Format::Full).unwrap().unwrap();
+        assert_eq!(msg.get_header("Subject"), Some("Hi"));
+        assert_eq!(msg.label_ids.unwrap(), vec!["INBOX"]);
+    }
+
+    #[test]
+    fn test_recent_and_forget() {
+        let cache = cache();
+        cache
+            .put(&message("a", &["INBOX"], "1000"), Format::Full)
+            .unwrap();
+        cache
+            .put(&message("b", &["INBOX"], "2000"), Format::Full)
+            .unwrap();
+        cache
+            .put(&message("c", &["SENT"], "3000"), Format::Full)
+            .unwrap();
+
+        let ids: Vec<String> = cache
+            .recent("INBOX", 10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, vec!["b", "a"]);
+
+        cache.forget(&["b".to_string()]).unwrap();
+        assert_eq!(cache.recent("INBOX", 10).unwrap().len(), 1);
+        cache.forget_threads(&["t1".to_string()]).unwrap();
+        assert!(cache.recent("SENT", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_change_labels_and_history_id() {
+        let cache = cache();
+        cache
+            .put(&message("a", &["INBOX", "UNREAD"], "1000"), Format::Full)
+            .unwrap();
+        let labels = |ids: &[&str]| ids.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert!(
+            cache
+                .change_labels("a", &labels(&["STARRED"]), &labels(&["UNREAD"]))
+                .unwrap()
+        );
+        assert!(
+            !cache
+                .change_labels("b", &labels(&["STARRED"]), &[])
+                .unwrap()
+        );
+        let msg = cache.get("a", Format::Full).unwrap().unwrap();
+        assert_eq!(msg.label_ids.unwrap(), vec!["INBOX", "STARRED"]);
+
+        assert_eq!(cache.history_id().unwrap(), None);
+        cache.set_history_id("100").unwrap();
+        cache.set_history_id("200").unwrap();
+        assert_eq!(cache.history_id().unwrap().as_deref(), Some("200"));
+    }
+}
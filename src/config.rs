@@ -1,9 +1,12 @@
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, Permissions};
 use std::io::Write;
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use crate::error::{Context, Result, bail, format_err};
 
 // Default OAuth credentials for gmail-cli
 // These are safe to embed in version control (public OAuth client)
@@ -15,8 +18,179 @@ pub const DEFAULT_CLIENT_SECRET: &str = "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";
 pub struct Config {
     pub client_id: Option<String>,
     pub client_secret: Option<String>,
+    /// Log in with only `gmail.readonly` unless told otherwise
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub readonly: bool,
+    /// Keep fetched messages in a local SQLite cache
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cache: bool,
+    /// Encrypt the token file with a passphrase
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt_tokens: bool,
+    /// Browser command `login` opens, instead of the system default
+    pub browser: Option<String>,
+    /// Default for `list --max`
+    pub max_results: Option<u32>,
+    /// Default label for `list`
+    pub label: Option<String>,
+    /// Default output format: "text" or "json"
+    pub format: Option<String>,
+    /// When to color output: "auto", "always" or "never"
+    pub color: Option<String>,
+    /// Styles for colored output
+    #[serde(default, skip_serializing_if = "Theme::is_empty")]
+    pub theme: Theme,
+    /// Saved searches used with `list --saved <name>`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, String>,
+    /// Message templates used with `send --template <name>`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub template: BTreeMap<String, Template>,
+    /// Set when logged in with a service account instead of OAuth consent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_account: Option<ServiceAccount>,
+}
+
+/// A service account key used with domain-wide delegation to act as `subject`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceAccount {
+    pub key_file: PathBuf,
+    pub subject: String,
+    pub scopes: Vec<String>,
+}
+
+/// Styles for colored output (`[theme]` in config), e.g. `unread = "bold yellow"`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Theme {
+    /// Rows of unread messages (default: bold)
+    pub unread: Option<String>,
+    /// Label names (default: cyan)
+    pub label: Option<String>,
+    /// Table headers and header names in `read` (default: dim)
+    pub header: Option<String>,
+}
+
+impl Theme {
+    fn is_empty(&self) -> bool {
+        self.unread.is_none() && self.label.is_none() && self.header.is_none()
+    }
+
+    fn role_mut(&mut self, role: &str) -> Result<&mut Option<String>> {
+        Ok(match role {
+            "unread" => &mut self.unread,
+            "label" => &mut self.label,
+            "header" => &mut self.header,
+            _ => bail!("Unknown theme role '{}' (unread, label, header)", role),
+        })
+    }
+}
+
+/// A message template (`[template.<name>]` in config). `{name}` placeholders
+/// are filled from `--var name=value`; `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Template {
+    #[serde(default)]
+    pub subject: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+impl Template {
+    /// The subject and body with every placeholder replaced, failing if any lack a value.
+    pub fn fill(&self, vars: &BTreeMap<String, String>) -> Result<(String, String)> {
+        let mut missing = Vec::new();
+        let subject = fill_placeholders(&self.subject, vars, &mut missing);
+        let body = fill_placeholders(&self.body, vars, &mut missing);
+        if !missing.is_empty() {
+            bail!("Missing --var for: {}", missing.join(", "));
+        }
+        Ok((subject, body))
+    }
+
+    /// Placeholder names in the subject and body, in order of first use.
+    pub fn placeholders(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        fill_placeholders(
+            &format!("{}\n{}", self.subject, self.body),
+            &BTreeMap::new(),
+            &mut names,
+        );
+        names
+    }
+}
+
+fn fill_placeholders(
+    text: &str,
+    vars: &BTreeMap<String, String>,
+    missing: &mut Vec<String>,
+) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(pos) = rest.find(['{', '}']) {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            out.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        let name = rest[1..]
+            .split_once('}')
+            .map(|(name, _)| name)
+            .filter(|name| {
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+            });
+        match name {
+            Some(name) => {
+                match vars.get(name) {
+                    Some(value) => out.push_str(value),
+                    None if !missing.iter().any(|m| m == name) => missing.push(name.to_string()),
+                    None => {}
+                }
+                rest = &rest[name.len() + 2..];
+            }
+            // Not a placeholder, e.g. a brace in code or JSON
+            None => {
+                out.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
 }
 
+/// Settings exposed through `gmail config get/set`, with descriptions
+pub const SETTINGS: &[(&str, &str)] = &[
+    ("client_id", "OAuth client ID (default: built-in)"),
+    ("client_secret", "OAuth client secret"),
+    ("readonly", "Log in with read-only access (true/false)"),
+    (
+        "cache",
+        "Keep fetched messages in a local cache (true/false)",
+    ),
+    (
+        "encrypt_tokens",
+        "Encrypt the token file with a passphrase (true/false)",
+    ),
+    (
+        "browser",
+        "Browser for `login` (default: $BROWSER or the system's)",
+    ),
+    ("max_results", "Default number of messages for `list`"),
+    ("label", "Default label for `list`"),
+    ("format", "Default output format (text, json)"),
+    ("color", "When to color output (auto, always, never)"),
+    ("theme.unread", "Style of unread messages (default: bold)"),
+    ("theme.label", "Style of label names (default: cyan)"),
+    ("theme.header", "Style of headers (default: dim)"),
+    ("account", "Default account (see `gmail accounts`)"),
+    ("query.<name>", "Saved search for `list --saved <name>`"),
+];
+
 impl Config {
     pub fn client_id(&self) -> &str {
         self.client_id.as_deref().unwrap_or(DEFAULT_CLIENT_ID)
@@ -26,69 +200,831 @@ impl Config {
             .as_deref()
             .unwrap_or(DEFAULT_CLIENT_SECRET)
     }
+
+    /// Look up a saved search by name.
+    pub fn saved_query(&self, name: &str) -> Result<&str> {
+        self.query.get(name).map(String::as_str).ok_or_else(|| {
+            format_err!(
+                "No saved search '{}'. Add one with: gmail config set query.{} \"<query>\"",
+                name,
+                name
+            )
+        })
+    }
+
+    pub fn json_output(&self) -> bool {
+        self.format.as_deref() == Some("json")
+    }
+
+    /// Read a setting stored in this file (`account` lives elsewhere, see `get_setting`).
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(name) = key.strip_prefix("query.") {
+            return Ok(self.query.get(name).cloned());
+        }
+        if let Some(role) = key.strip_prefix("theme.") {
+            return Ok(self.theme.clone().role_mut(role)?.take());
+        }
+        Ok(match key {
+            "client_id" => self.client_id.clone(),
+            "client_secret" => self.client_secret.clone(),
+            "readonly" => Some(self.readonly.to_string()),
+            "cache" => Some(self.cache.to_string()),
+            "encrypt_tokens" => Some(self.encrypt_tokens.to_string()),
+            "browser" => self.browser.clone(),
+            "max_results" => self.max_results.map(|n| n.to_string()),
+            "label" => self.label.clone(),
+            "format" => self.format.clone(),
+            "color" => self.color.clone(),
+            _ => bail!("Unknown setting '{}' (see 'gmail config list')", key),
+        })
+    }
+
+    /// Change a setting; an empty value removes a saved search.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        if let Some(name) = key.strip_prefix("query.") {
+            if name.is_empty() {
+                bail!("Saved search needs a name: query.<name>");
+            }
+            if value.is_empty() {
+                self.query.remove(name);
+            } else {
+                self.query.insert(name.to_string(), value.to_string());
+            }
+            return Ok(());
+        }
+        if let Some(role) = key.strip_prefix("theme.") {
+            crate::style::Style::parse(value)?;
+            let slot = self.theme.role_mut(role)?;
+            *slot = (!value.is_empty()).then(|| value.to_string());
+            return Ok(());
+        }
+        match key {
+            "client_id" => self.client_id = Some(value.to_string()),
+            "client_secret" => self.client_secret = Some(value.to_string()),
+            "readonly" => {
+                self.readonly = value
+                    .parse()
+                    .with_context(|| format!("readonly must be true or false, not '{}'", value))?
+            }
+            "cache" => {
+                self.cache = value
+                    .parse()
+                    .with_context(|| format!("cache must be true or false, not '{}'", value))?
+            }
+            "encrypt_tokens" => {
+                self.encrypt_tokens = value.parse().with_context(|| {
+                    format!("encrypt_tokens must be true or false, not '{}'", value)
+                })?
+            }
+            "browser" => self.browser = (!value.is_empty()).then(|| value.to_string()),
+            "max_results" => {
+                let max: u32 = value
+                    .parse()
+                    .with_context(|| format!("max_results must be a number, not '{}'", value))?;
+                // The API reads maxResults=0 as its default page size
+                if max == 0 {
+                    bail!("max_results must be at least 1");
+                }
+                self.max_results = Some(max);
+            }
+            "label" => self.label = Some(value.to_string()),
+            "format" => {
+                if !matches!(value, "text" | "json") {
+                    bail!("format must be 'text' or 'json', not '{}'", value);
+                }
+                self.format = Some(value.to_string());
+            }
+            "color" => {
+                if !matches!(value, "auto" | "always" | "never") {
+                    bail!("color must be 'auto', 'always' or 'never', not '{}'", value);
+                }
+                self.color = Some(value.to_string());
+            }
+            _ => bail!("Unknown setting '{}' (see 'gmail config list')", key),
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Tokens {
     pub access_token: String,
     pub refresh_token: String,
+    /// Scopes Google granted; empty for tokens saved before scopes were tracked
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
 }
 
+/// Account selected with `--account`, set once at startup
+static ACCOUNT: OnceLock<String> = OnceLock::new();
+
+/// Config directory given with `--config`, set once at startup
+static CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct AccountsFile {
+    default: Option<String>,
+}
+
+/// Use `dir` in place of the default config directory for the rest of this process.
+pub fn set_config_dir(dir: &Path) -> Result<()> {
+    let current = CONFIG_DIR.get_or_init(|| dir.to_path_buf());
+    if current != dir {
+        bail!("Config directory '{}' is already set", current.display());
+    }
+    Ok(())
+}
+
+/// Directory holding config, tokens and accounts: `--config`, else `$GMAIL_CONFIG_DIR`,
+/// else `gmail-cli` under `$XDG_CONFIG_HOME` or the platform's config directory.
+/// An existing platform directory wins over a missing XDG one.
 pub fn config_dir() -> PathBuf {
-    dirs::config_dir()
+    if let Some(dir) = CONFIG_DIR.get() {
+        return dir.clone();
+    }
+    resolve_config_dir(
+        |name| std::env::var_os(name),
+        dirs::config_dir(),
+        |dir| dir.exists(),
+    )
+}
+
+fn resolve_config_dir(
+    env: impl Fn(&str) -> Option<std::ffi::OsString>,
+    platform: Option<PathBuf>,
+    exists: impl Fn(&Path) -> bool,
+) -> PathBuf {
+    if let Some(dir) = env("GMAIL_CONFIG_DIR").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir);
+    }
+    let platform = platform
         .unwrap_or_else(|| PathBuf::from("."))
-        .join("gmail-cli")
+        .join("gmail-cli");
+    // The XDG spec says relative paths are invalid and must be ignored
+    let Some(xdg) = env("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+    else {
+        return platform;
+    };
+    let xdg = xdg.join("gmail-cli");
+    // On macOS the platform directory is ~/Library/Application Support, where
+    // existing logins were saved before XDG_CONFIG_HOME was honored
+    if !exists(&xdg) && exists(&platform) {
+        return platform;
+    }
+    xdg
+}
+
+fn accounts_dir() -> PathBuf {
+    config_dir().join("accounts")
+}
+
+fn accounts_file_path() -> PathBuf {
+    config_dir().join("accounts.json")
+}
+
+pub fn account_dir(name: &str) -> PathBuf {
+    accounts_dir().join(name)
+}
+
+/// Select the account used by every later load/save in this process.
+pub fn set_account(name: &str) -> Result<()> {
+    validate_account_name(name)?;
+    let current = ACCOUNT.get_or_init(|| name.to_string());
+    if current != name {
+        bail!("Account '{}' is already selected", current);
+    }
+    Ok(())
 }
 
-fn config_path() -> PathBuf {
-    config_dir().join("config.json")
+/// The account in use: `--account`, else the default account, else none (legacy single-account layout).
+pub fn active_account() -> Option<String> {
+    if let Some(name) = ACCOUNT.get() {
+        return Some(name.clone());
+    }
+    default_account()
+}
+
+/// Directory holding config and tokens for the active account.
+pub fn profile_dir() -> PathBuf {
+    match active_account() {
+        Some(name) => account_dir(&name),
+        None => config_dir(),
+    }
+}
+
+const CONFIG_FILE: &str = "config.toml";
+// Written by versions before the TOML config; still read if no TOML file exists
+const LEGACY_CONFIG_FILE: &str = "config.json";
+
+/// Directory whose config `gmail config set` edits: the account's with `--account`,
+/// otherwise the shared top-level one.
+fn settings_dir() -> PathBuf {
+    match ACCOUNT.get() {
+        Some(name) => account_dir(name),
+        None => config_dir(),
+    }
+}
+
+/// Make sure the file `gmail config edit` opens exists, migrating a legacy JSON config.
+pub fn init_settings_file() -> Result<PathBuf> {
+    let dir = settings_dir();
+    let path = dir.join(CONFIG_FILE);
+    if !path.exists() {
+        let config: Config = toml::Value::Table(read_config_table(&dir)?).try_into()?;
+        write_config(&dir, &config)?;
+    }
+    Ok(path)
 }
 
 pub fn tokens_path() -> PathBuf {
-    config_dir().join("tokens.json")
+    profile_dir().join("tokens.json")
 }
 
-fn write_secure(path: &PathBuf, content: &str) -> Result<()> {
+/// Local message cache, used when `cache = true`.
+pub fn cache_path() -> PathBuf {
+    profile_dir().join("cache.sqlite3")
+}
+
+/// Messages queued by `gmail send --at`.
+pub fn outbox_dir() -> PathBuf {
+    profile_dir().join("outbox")
+}
+
+/// Rules for `gmail rules apply`.
+pub fn rules_path() -> PathBuf {
+    profile_dir().join("rules.toml")
+}
+
+/// Label changes recorded for `gmail undo`.
+pub fn journal_path() -> PathBuf {
+    profile_dir().join("journal.jsonl")
+}
+
+/// Socket `gmail daemon` listens on.
+pub fn daemon_socket_path() -> PathBuf {
+    profile_dir().join("daemon.sock")
+}
+
+/// Label names from the last label list, for shell completion.
+pub fn label_names_path() -> PathBuf {
+    profile_dir().join("labels.json")
+}
+
+/// Label names saved at `label_names_path`; empty if none were saved yet.
+pub fn cached_label_names() -> Vec<String> {
+    fs::read_to_string(label_names_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn validate_account_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'));
+    if !valid {
+        bail!(
+            "Invalid account name '{}' (use letters, digits, '-', '_', '.', '@')",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Replace `path` with `content`, readable and writable by the owner only.
+pub(crate) fn write_secure(path: &Path, content: impl AsRef<[u8]>) -> Result<()> {
     let mut file = fs::OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
         .mode(0o600)
         .open(path)?;
-    file.write_all(content.as_bytes())?;
+    // The mode above only applies to new files
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(content.as_ref())?;
     Ok(())
 }
 
-pub fn load_config() -> Result<Config> {
-    let path = config_path();
+/// Open `path` for appending, creating it if needed, readable and writable by
+/// the owner only.
+pub(crate) fn append_secure(path: &Path) -> Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+/// Whether other users can read or write `path`.
+pub(crate) fn is_shared(path: &Path) -> Result<bool> {
+    Ok(fs::metadata(path)?.permissions().mode() & 0o077 != 0)
+}
+
+/// Warn that `path` is open to other users, once per file however often it's read.
+fn warn_shared(path: &Path) {
+    static WARNED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+    let mut warned = WARNED.lock().expect("warned lock poisoned");
+    if warned.insert(path.to_path_buf()) {
+        tracing::warn!(
+            "{} is accessible by other users; fix with: chmod 600 {}",
+            path.display(),
+            path.display()
+        );
+    }
+}
+
+/// Config directories and files, tokens included, that other users can read or write.
+pub fn shared_files() -> Vec<PathBuf> {
+    let profile = profile_dir();
+    let mut paths = vec![
+        config_dir(),
+        config_dir().join(CONFIG_FILE),
+        accounts_file_path(),
+    ];
+    if profile != config_dir() {
+        paths.extend([profile.clone(), profile.join(CONFIG_FILE)]);
+    }
+    paths.push(tokens_path());
+    paths
+        .into_iter()
+        .filter(|path| path.exists() && is_shared(path).unwrap_or(false))
+        .collect()
+}
+
+fn read_config_table(dir: &Path) -> Result<toml::Table> {
+    let path = dir.join(CONFIG_FILE);
     if path.exists() {
+        if is_shared(&path)? {
+            warn_shared(&path);
+        }
         let content = fs::read_to_string(&path)?;
-        return Ok(serde_json::from_str(&content)?);
+        return toml::from_str(&content).with_context(|| format!("Invalid {}", path.display()));
     }
-    Ok(Config::default())
+    let legacy = dir.join(LEGACY_CONFIG_FILE);
+    if legacy.exists() {
+        let content = fs::read_to_string(&legacy)?;
+        let config: Config = serde_json::from_str(&content)?;
+        return Ok(toml::Table::try_from(config)?);
+    }
+    Ok(toml::Table::new())
 }
 
-fn ensure_config_dir() -> Result<PathBuf> {
-    let dir = config_dir();
+/// Overlay `over` onto `base`, merging nested tables key by key.
+fn merge_tables(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(over_table)) => {
+                merge_tables(base_table, over_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Load the shared config with the active account's config layered on top.
+pub fn load_config() -> Result<Config> {
+    let mut table = read_config_table(&config_dir())?;
+    let profile = profile_dir();
+    if profile != config_dir() {
+        merge_tables(&mut table, read_config_table(&profile)?);
+    }
+    Ok(toml::Value::Table(table).try_into()?)
+}
+
+/// Load only the active account's own config, without shared defaults.
+pub fn load_profile_config() -> Result<Config> {
+    Ok(toml::Value::Table(read_config_table(&profile_dir())?).try_into()?)
+}
+
+fn write_config(dir: &Path, config: &Config) -> Result<()> {
+    ensure_dir(&config_dir())?;
+    ensure_dir(dir)?;
+    write_secure(&dir.join(CONFIG_FILE), &toml::to_string_pretty(config)?)?;
+    let legacy = dir.join(LEGACY_CONFIG_FILE);
+    if legacy.exists() {
+        fs::remove_file(legacy)?;
+    }
+    Ok(())
+}
+
+/// Read a setting as `gmail config get` reports it.
+pub fn get_setting(key: &str) -> Result<Option<String>> {
+    if key == "account" {
+        return Ok(default_account());
+    }
+    load_config()?.get(key)
+}
+
+/// Change a setting in the shared config (or the `--account` one); returns the file written.
+pub fn set_setting(key: &str, value: &str) -> Result<PathBuf> {
+    if key == "account" {
+        if !account_dir(value).exists() {
+            bail!("No account named '{}'", value);
+        }
+        set_default_account(Some(value))?;
+        return Ok(accounts_file_path());
+    }
+    let path = update_settings(|config| config.set(key, value))?;
+    // Rewrite stored tokens so they're encrypted (or decrypted) right away
+    if key == "encrypt_tokens" && tokens_path().exists() {
+        save_tokens(&load_tokens()?)?;
+    }
+    Ok(path)
+}
+
+/// Change the shared config (or the `--account` one) in place; returns the file written.
+pub fn update_settings(change: impl FnOnce(&mut Config) -> Result<()>) -> Result<PathBuf> {
+    let dir = settings_dir();
+    let mut config: Config = toml::Value::Table(read_config_table(&dir)?).try_into()?;
+    change(&mut config)?;
+    write_config(&dir, &config)?;
+    Ok(dir.join(CONFIG_FILE))
+}
+
+/// Check that a config file parses, e.g. after editing it by hand.
+pub fn validate_config_file(path: &Path) -> Result<()> {
+    let content = fs::read_to_string(path)?;
+    toml::from_str::<Config>(&content).with_context(|| format!("Invalid {}", path.display()))?;
+    Ok(())
+}
+
+/// Create `dir` if missing, accessible by the owner only.
+pub(crate) fn ensure_dir(dir: &Path) -> Result<()> {
     if !dir.exists() {
-        fs::create_dir_all(&dir)?;
-        fs::set_permissions(&dir, Permissions::from_mode(0o700))?;
+        fs::create_dir_all(dir)?;
+        fs::set_permissions(dir, Permissions::from_mode(0o700))?;
     }
+    Ok(())
+}
+
+fn ensure_config_dir() -> Result<PathBuf> {
+    ensure_dir(&config_dir())?;
+    let dir = profile_dir();
+    ensure_dir(&dir)?;
     Ok(dir)
 }
 
+/// Save the active account's own config (see `load_profile_config`).
 pub fn save_config(config: &Config) -> Result<()> {
-    ensure_config_dir()?;
-    write_secure(&config_path(), &serde_json::to_string_pretty(config)?)
+    write_config(&profile_dir(), config)
+}
+
+fn load_accounts_file() -> AccountsFile {
+    fs::read_to_string(accounts_file_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+pub fn default_account() -> Option<String> {
+    load_accounts_file().default
+}
+
+pub fn set_default_account(name: Option<&str>) -> Result<()> {
+    if let Some(name) = name {
+        validate_account_name(name)?;
+    }
+    ensure_dir(&config_dir())?;
+    let file = AccountsFile {
+        default: name.map(str::to_string),
+    };
+    write_secure(&accounts_file_path(), &serde_json::to_string_pretty(&file)?)
+}
+
+/// Names of all configured accounts, sorted.
+pub fn list_accounts() -> Result<Vec<String>> {
+    let dir = accounts_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub fn account_logged_in(name: &str) -> bool {
+    account_dir(name).join("tokens.json").exists()
+}
+
+/// Delete an account's stored config and tokens, clearing it as default if needed.
+pub fn remove_account(name: &str) -> Result<()> {
+    validate_account_name(name)?;
+    let dir = account_dir(name);
+    if !dir.exists() {
+        bail!("No account named '{}'", name);
+    }
+    fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+    if default_account().as_deref() == Some(name) {
+        set_default_account(None)?;
+    }
+    Ok(())
+}
+
+// What the single-account layout keeps next to the shared config: the login
+// and everything local to that mailbox
+const PROFILE_FILES: &[&str] = &[
+    "tokens.json",
+    "cache.sqlite3",
+    "outbox",
+    "rules.toml",
+    "journal.jsonl",
+    "labels.json",
+];
+
+/// Move the login of the single-account layout into `name` when it's the first
+/// account added, so making it the default doesn't log the user out.
+/// Returns whether there was a login to move.
+pub fn adopt_legacy_profile(name: &str) -> Result<bool> {
+    validate_account_name(name)?;
+    if !list_accounts()?.is_empty() {
+        return Ok(false);
+    }
+    move_profile(&config_dir(), &account_dir(name))
+}
+
+fn move_profile(from: &Path, to: &Path) -> Result<bool> {
+    if !from.join("tokens.json").exists() {
+        return Ok(false);
+    }
+    ensure_dir(to)?;
+    for file in PROFILE_FILES {
+        let path = from.join(file);
+        if path.exists() {
+            fs::rename(&path, to.join(file))
+                .with_context(|| format!("Failed to move {}", path.display()))?;
+        }
+    }
+    Ok(true)
 }
 
+/// Load the stored tokens, refusing a token file other users can read.
 pub fn load_tokens() -> Result<Tokens> {
     let path = tokens_path();
-    let content = fs::read_to_string(&path)?;
+    if is_shared(&path)? {
+        bail!(
+            "{} is accessible by other users; fix with: chmod 600 {}",
+            path.display(),
+            path.display()
+        );
+    }
+    let mut content = fs::read_to_string(&path)?;
+    if content.starts_with(ENCRYPTED_HEADER) {
+        content = decrypt(&content, token_passphrase()?)
+            .with_context(|| format!("Failed to decrypt {}", path.display()))?;
+    }
     Ok(serde_json::from_str(&content)?)
 }
 
+/// Tokens from `GMAIL_ACCESS_TOKEN` (and optionally `GMAIL_REFRESH_TOKEN`), which take
+/// the place of the token file for workflows that mint their own.
+pub fn env_tokens() -> Option<Tokens> {
+    let access_token = std::env::var("GMAIL_ACCESS_TOKEN")
+        .ok()
+        .filter(|t| !t.is_empty())?;
+    Some(Tokens {
+        access_token,
+        refresh_token: std::env::var("GMAIL_REFRESH_TOKEN").unwrap_or_default(),
+        scopes: Vec::new(),
+    })
+}
+
+/// Delete stored tokens; returns false if there were none.
+pub fn delete_tokens() -> Result<bool> {
+    let path = tokens_path();
+    if !path.exists() {
+        return Ok(false);
+    }
+    fs::remove_file(&path)?;
+    Ok(true)
+}
+
 pub fn save_tokens(tokens: &Tokens) -> Result<()> {
     ensure_config_dir()?;
-    write_secure(&tokens_path(), &serde_json::to_string_pretty(tokens)?)
+    let mut content = serde_json::to_string_pretty(tokens)?;
+    if load_config()?.encrypt_tokens {
+        content = encrypt(&content, token_passphrase()?, SCRYPT_WORK_FACTOR)?;
+    }
+    write_secure(&tokens_path(), &content)
+}
+
+// Start of an ASCII-armored age file, which is how an encrypted token file is told apart
+const ENCRYPTED_HEADER: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+// log2 of the scrypt cost; about a second to derive the key on a laptop
+const SCRYPT_WORK_FACTOR: u8 = 18;
+
+/// Passphrase for the token file, asked for at most once per process
+static PASSPHRASE: OnceLock<String> = OnceLock::new();
+
+/// `GMAIL_TOKEN_PASSPHRASE`, else a prompt on the terminal.
+fn token_passphrase() -> Result<&'static str> {
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase);
+    }
+    let passphrase = match std::env::var("GMAIL_TOKEN_PASSPHRASE") {
+        Ok(passphrase) if !passphrase.is_empty() => passphrase,
+        _ => rpassword::prompt_password("Token file passphrase: ")
+            .context("Failed to read the passphrase (or set GMAIL_TOKEN_PASSPHRASE)")?,
+    };
+    if passphrase.is_empty() {
+        bail!("The token file passphrase can't be empty");
+    }
+    Ok(PASSPHRASE.get_or_init(|| passphrase))
+}
+
+fn encrypt(plaintext: &str, passphrase: &str, work_factor: u8) -> Result<String> {
+    let mut recipient = age::scrypt::Recipient::new(passphrase.to_string().into());
+    recipient.set_work_factor(work_factor);
+    let encryptor = age::Encryptor::with_recipients(std::iter::once(&recipient as _))?;
+    let mut out = Vec::new();
+    let armor = age::armor::ArmoredWriter::wrap_output(&mut out, age::armor::Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(armor)?;
+    writer.write_all(plaintext.as_bytes())?;
+    writer.finish()?.finish()?;
+    Ok(String::from_utf8(out)?)
+}
+
+fn decrypt(armored: &str, passphrase: &str) -> Result<String> {
+    let decryptor = age::Decryptor::new(age::armor::ArmoredReader::new(armored.as_bytes()))?;
+    let identity = age::scrypt::Identity::new(passphrase.to_string().into());
+    let mut reader = decryptor.decrypt(std::iter::once(&identity as _))?;
+    let mut plaintext = String::new();
+    std::io::Read::read_to_string(&mut reader, &mut plaintext)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_set_get() {
+        let mut config = Config::default();
+        config.set("max_results", "50").unwrap();
+        config.set("format", "json").unwrap();
+        assert_eq!(config.get("max_results").unwrap().as_deref(), Some("50"));
+        assert!(config.json_output());
+        assert!(config.set("format", "xml").is_err());
+        assert!(config.set("max_results", "many").is_err());
+        assert!(config.set("max_results", "0").is_err());
+        assert!(config.get("nope").is_err());
+    }
+
+    #[test]
+    fn test_theme_settings() {
+        let mut config = Config::default();
+        config.set("theme.unread", "bold yellow").unwrap();
+        assert_eq!(
+            config.get("theme.unread").unwrap().as_deref(),
+            Some("bold yellow")
+        );
+        assert!(config.set("theme.unread", "sparkly").is_err());
+        assert!(config.set("theme.nope", "bold").is_err());
+        config.set("theme.unread", "").unwrap();
+        assert_eq!(config.get("theme.unread").unwrap(), None);
+    }
+
+    #[test]
+    fn test_template_fill() {
+        let template = Template {
+            subject: "Report for week {week}".to_string(),
+            body: "Hi {name},\n{{not a var}} {week} {json: 1}".to_string(),
+        };
+        assert_eq!(template.placeholders(), vec!["week", "name"]);
+
+        let vars = BTreeMap::from([
+            ("week".to_string(), "27".to_string()),
+            ("name".to_string(), "Ana".to_string()),
+        ]);
+        let (subject, body) = template.fill(&vars).unwrap();
+        assert_eq!(subject, "Report for week 27");
+        assert_eq!(body, "Hi Ana,\n{not a var} 27 {json: 1}");
+
+        let err = template.fill(&BTreeMap::new()).unwrap_err();
+        assert_eq!(err.to_string(), "Missing --var for: week, name");
+    }
+
+    #[test]
+    fn test_saved_queries() {
+        let mut config = Config::default();
+        config
+            .set("query.receipts", "from:noreply subject:receipt")
+            .unwrap();
+        assert_eq!(
+            config.saved_query("receipts").unwrap(),
+            "from:noreply subject:receipt"
+        );
+        assert!(config.saved_query("missing").is_err());
+
+        let table = toml::Table::try_from(&config).unwrap();
+        assert_eq!(
+            table["query"]["receipts"].as_str(),
+            Some("from:noreply subject:receipt")
+        );
+
+        config.set("query.receipts", "").unwrap();
+        assert!(config.query.is_empty());
+        assert!(config.set("query.", "x").is_err());
+    }
+
+    #[test]
+    fn test_merge_tables() {
+        let mut base: toml::Table = toml::from_str("label = 'inbox'\nmax_results = 20").unwrap();
+        let over: toml::Table = toml::from_str("max_results = 50").unwrap();
+        merge_tables(&mut base, over);
+        let config: Config = toml::Value::Table(base).try_into().unwrap();
+        assert_eq!(config.label.as_deref(), Some("inbox"));
+        assert_eq!(config.max_results, Some(50));
+    }
+
+    #[test]
+    fn test_resolve_config_dir() {
+        let platform = Some(PathBuf::from("/Library/Application Support"));
+        let nothing_exists = |_: &Path| false;
+        let dir = resolve_config_dir(
+            |name| match name {
+                "GMAIL_CONFIG_DIR" => Some("/srv/gmail".into()),
+                _ => Some("/xdg".into()),
+            },
+            platform.clone(),
+            nothing_exists,
+        );
+        assert_eq!(dir, Path::new("/srv/gmail"));
+
+        let xdg = |name: &str| match name {
+            "XDG_CONFIG_HOME" => Some("/xdg".into()),
+            _ => Some("".into()),
+        };
+        let dir = resolve_config_dir(xdg, platform.clone(), nothing_exists);
+        assert_eq!(dir, Path::new("/xdg/gmail-cli"));
+
+        // A login saved in the platform directory is still found
+        let old_exists = |dir: &Path| dir.starts_with("/Library");
+        let dir = resolve_config_dir(xdg, platform.clone(), old_exists);
+        assert_eq!(dir, Path::new("/Library/Application Support/gmail-cli"));
+        let dir = resolve_config_dir(xdg, platform.clone(), |_| true);
+        assert_eq!(dir, Path::new("/xdg/gmail-cli"));
+
+        let dir = resolve_config_dir(
+            |name| (name == "XDG_CONFIG_HOME").then(|| "rel".into()),
+            platform,
+            nothing_exists,
+        );
+        assert_eq!(dir, Path::new("/Library/Application Support/gmail-cli"));
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let armored = encrypt("{\"access_token\": \"a\"}", "hunter2", 10).unwrap();
+        assert!(armored.starts_with(ENCRYPTED_HEADER));
+        assert!(!armored.contains("access_token"));
+        assert_eq!(
+            decrypt(&armored, "hunter2").unwrap(),
+            "{\"access_token\": \"a\"}"
+        );
+        assert!(decrypt(&armored, "wrong").is_err());
+    }
+
+    #[test]
+    fn test_move_profile() {
+        let dir = std::env::temp_dir().join(format!("gmail-profile-test-{}", std::process::id()));
+        let account = dir.join("accounts").join("personal");
+        fs::create_dir_all(dir.join("outbox")).unwrap();
+        fs::write(dir.join(CONFIG_FILE), "").unwrap();
+        assert!(!move_profile(&dir, &account).unwrap());
+
+        fs::write(dir.join("tokens.json"), "{}").unwrap();
+        fs::write(dir.join("rules.toml"), "").unwrap();
+        assert!(move_profile(&dir, &account).unwrap());
+        assert!(account.join("tokens.json").exists());
+        assert!(account.join("rules.toml").exists());
+        assert!(account.join("outbox").is_dir());
+        assert!(!dir.join("tokens.json").exists());
+        // The shared config stays where every account reads it
+        assert!(dir.join(CONFIG_FILE).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_account_name() {
+        assert!(validate_account_name("work").is_ok());
+        assert!(validate_account_name("me@example.com").is_ok());
+        assert!(validate_account_name("").is_err());
+        assert!(validate_account_name("..").is_err());
+        assert!(validate_account_name("a/b").is_err());
+    }
 }
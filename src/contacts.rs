@@ -0,0 +1,168 @@
+use serde::Deserialize;
+
+use crate::error::{Result, bail};
+
+/// A contact from the People API, reduced to what recipient lookup needs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contact {
+    pub name: Option<String>,
+    pub emails: Vec<String>,
+}
+
+/// Response of `people:searchContacts`.
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchResponse {
+    #[serde(default)]
+    results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResult {
+    person: Person,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Person {
+    #[serde(default)]
+    names: Vec<Name>,
+    #[serde(default)]
+    email_addresses: Vec<EmailAddress>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Name {
+    display_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmailAddress {
+    value: Option<String>,
+}
+
+impl SearchResponse {
+    /// Contacts that have at least one email address.
+    pub fn into_contacts(self) -> Vec<Contact> {
+        self.results
+            .into_iter()
+            .map(|result| Contact {
+                name: result
+                    .person
+                    .names
+                    .into_iter()
+                    .find_map(|name| name.display_name),
+                emails: result
+                    .person
+                    .email_addresses
+                    .into_iter()
+                    .filter_map(|email| email.value)
+                    .collect(),
+            })
+            .filter(|contact| !contact.emails.is_empty())
+            .collect()
+    }
+}
+
+impl Contact {
+    /// `Name <address>` for each of the contact's addresses.
+    pub fn mailboxes(&self) -> Vec<String> {
+        self.emails
+            .iter()
+            .map(|email| mailbox(self.name.as_deref(), email))
+            .collect()
+    }
+}
+
+/// Whether a recipient given on the command line is a name to look up rather than an address.
+pub fn needs_lookup(recipient: &str) -> bool {
+    !recipient.contains('@')
+}
+
+/// Pick the one address `query` refers to among the contacts a search returned.
+///
+/// Several matches are narrowed to the contacts whose name is exactly `query`;
+/// anything still ambiguous is an error listing the candidates.
+pub fn resolve(query: &str, contacts: &[Contact]) -> Result<String> {
+    let exact: Vec<&Contact> = contacts
+        .iter()
+        .filter(|c| {
+            c.name
+                .as_deref()
+                .is_some_and(|name| name.eq_ignore_ascii_case(query.trim()))
+        })
+        .collect();
+    let candidates: Vec<String> = if exact.is_empty() {
+        contacts.iter().flat_map(Contact::mailboxes).collect()
+    } else {
+        exact.into_iter().flat_map(Contact::mailboxes).collect()
+    };
+    match candidates.as_slice() {
+        [] => bail!("No contact matches '{}'", query),
+        [only] => Ok(only.clone()),
+        _ => bail!(
+            "'{}' matches several contacts; give the address instead:\n  {}",
+            query,
+            candidates.join("\n  ")
+        ),
+    }
+}
+
+fn mailbox(name: Option<&str>, email: &str) -> String {
+    match name {
+        Some(name) if name.contains([',', ';', '"', '<', '>', '(', ')', '@', ':']) => {
+            format!("\"{}\" <{}>", name.replace('"', "'"), email)
+        }
+        Some(name) => format!("{} <{}>", name, email),
+        None => email.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(name: &str, emails: &[&str]) -> Contact {
+        Contact {
+            name: Some(name.to_string()),
+            emails: emails.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_search_response() {
+        let response: SearchResponse = serde_json::from_str(
+            r#"{"results": [
+                {"person": {"names": [{"displayName": "Alice Doe"}],
+                            "emailAddresses": [{"value": "alice@example.com"}]}},
+                {"person": {"names": [{"displayName": "No Email"}]}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.into_contacts(),
+            vec![contact("Alice Doe", &["alice@example.com"])]
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let alice = contact("Alice Doe", &["alice@example.com"]);
+        let alicia = contact("Alicia", &["a@example.com", "b@example.com"]);
+        assert_eq!(
+            resolve("ali", std::slice::from_ref(&alice)).unwrap(),
+            "Alice Doe <alice@example.com>"
+        );
+        assert_eq!(
+            resolve("alice doe", &[alice.clone(), alicia.clone()]).unwrap(),
+            "Alice Doe <alice@example.com>"
+        );
+        assert!(resolve("ali", &[alice, alicia.clone()]).is_err());
+        assert!(resolve("alicia", &[alicia]).is_err());
+        assert!(resolve("bob", &[]).is_err());
+        assert_eq!(
+            contact("Doe, J", &["j@example.com"]).mailboxes(),
+            vec!["\"Doe, J\" <j@example.com>"]
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::api::Client;
+use crate::config;
+use crate::error::{Context, GmailError, Result, bail};
+
+// How long the daemon answers label lists from memory
+const LABELS_TTL: Duration = Duration::from_secs(60);
+// Headers that describe one connection rather than the message
+const HOP_BY_HOP: &[&str] = &["connection", "content-length", "transfer-encoding"];
+// A request with this method asks the daemon to exit
+const STOP: &str = "STOP";
+
+/// A request sent to the daemon, one JSON object per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    /// Base64 of the body
+    pub body: String,
+}
+
+/// The daemon's answer: Gmail's response, or why it couldn't get one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// Base64 of the body
+    pub body: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The error is about the login, e.g. a revoked refresh token
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unauthorized: bool,
+}
+
+/// Whether a daemon is listening on `socket`.
+pub fn is_running(socket: &Path) -> bool {
+    std::os::unix::net::UnixStream::connect(socket).is_ok()
+}
+
+/// Ask the daemon on `socket` to exit, e.g. because a login replaced the tokens
+/// it started with. Returns whether one was running.
+pub async fn stop(socket: &Path) -> Result<bool> {
+    if !is_running(socket) {
+        return Ok(false);
+    }
+    let line = serde_json::to_string(&Request {
+        method: STOP.to_string(),
+        url: String::new(),
+        headers: Vec::new(),
+        body: String::new(),
+    })?;
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Failed to reach gmail daemon at {}", socket.display()))?;
+    let (read, mut write) = stream.into_split();
+    write.write_all(format!("{}\n", line).as_bytes()).await?;
+    // The daemon answers once it has stopped accepting connections
+    let mut answer = String::new();
+    BufReader::new(read).read_line(&mut answer).await?;
+    Ok(true)
+}
+
+/// Send a request through the daemon on `socket`, which adds its login and
+/// uses its open connections.
+pub async fn forward(socket: &Path, request: reqwest::Request) -> Result<reqwest::Response> {
+    let body = match request.body() {
+        Some(body) => body
+            .as_bytes()
+            .ok_or_else(|| GmailError::Message("Can't forward a streamed body".to_string()))?,
+        None => &[],
+    };
+    let line = serde_json::to_string(&Request {
+        method: request.method().to_string(),
+        url: request.url().to_string(),
+        headers: request
+            .headers()
+            .iter()
+            .filter(|(name, _)| *name != reqwest::header::AUTHORIZATION)
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body: STANDARD.encode(body),
+    })?;
+
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Failed to reach gmail daemon at {}", socket.display()))?;
+    let (read, mut write) = stream.into_split();
+    write.write_all(format!("{}\n", line).as_bytes()).await?;
+    let mut answer = String::new();
+    BufReader::new(read).read_line(&mut answer).await?;
+    let reply: Reply = serde_json::from_str(&answer).context("Invalid reply from gmail daemon")?;
+
+    if let Some(error) = reply.error {
+        if reply.unauthorized {
+            return Err(GmailError::Auth(error));
+        }
+        bail!("gmail daemon: {}", error);
+    }
+    let mut response = http::Response::builder().status(reply.status);
+    for (name, value) in &reply.headers {
+        response = response.header(name, value);
+    }
+    let response = response
+        .body(STANDARD.decode(&reply.body)?)
+        .map_err(|e| GmailError::Message(format!("Invalid reply from gmail daemon: {}", e)))?;
+    Ok(reqwest::Response::from(response))
+}
+
+/// The daemon's state, shared by its connections.
+struct Daemon {
+    client: Client,
+    labels: Mutex<Option<(String, Instant, Reply)>>,
+    stop: tokio::sync::Notify,
+}
+
+impl Daemon {
+    async fn handle(&self, stream: UnixStream) -> Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        while let Some(line) = lines.next_line().await? {
+            let reply = match serde_json::from_str::<Request>(&line) {
+                Ok(request) if request.method == STOP => {
+                    self.stop.notify_one();
+                    Reply {
+                        status: 200,
+                        ..Default::default()
+                    }
+                }
+                Ok(request) => self.reply(request).await,
+                Err(e) => Reply {
+                    error: Some(format!("Invalid request: {}", e)),
+                    ..Default::default()
+                },
+            };
+            let answer = serde_json::to_string(&reply)?;
+            write.write_all(format!("{}\n", answer).as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn reply(&self, request: Request) -> Reply {
+        // Label lists are asked for by most commands and rarely change
+        let is_label_list = request.method == "GET" && request.url.ends_with("/users/me/labels");
+        if is_label_list {
+            let cached = self.labels.lock().expect("labels lock poisoned");
+            if let Some((url, fetched, reply)) = cached.as_ref()
+                && *url == request.url
+                && fetched.elapsed() < LABELS_TTL
+            {
+                return reply.clone();
+            }
+        } else if request.method != "GET" && request.url.contains("/labels") {
+            *self.labels.lock().expect("labels lock poisoned") = None;
+        }
+
+        let url = request.url.clone();
+        let reply = match self.send(request).await {
+            Ok(reply) => reply,
+            Err(e) => Reply {
+                error: Some(format!("{:#}", e)),
+                unauthorized: e.is_unauthorized(),
+                ..Default::default()
+            },
+        };
+        if is_label_list && reply.status == 200 {
+            *self.labels.lock().expect("labels lock poisoned") =
+                Some((url, Instant::now(), reply.clone()));
+        }
+        reply
+    }
+
+    async fn send(&self, request: Request) -> Result<Reply> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in &request.headers {
+            headers.insert(
+                reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| GmailError::Message(e.to_string()))?,
+                value.parse()?,
+            );
+        }
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())
+            .map_err(|e| GmailError::Message(e.to_string()))?;
+        let resp = self
+            .client
+            .send_request(
+                method,
+                &request.url,
+                headers,
+                STANDARD.decode(&request.body)?,
+            )
+            .await?;
+        let status = resp.status().as_u16();
+        let headers = resp
+            .headers()
+            .iter()
+            .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str()))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let body = resp.bytes().await?;
+        Ok(Reply {
+            status,
+            headers,
+            body: STANDARD.encode(&body),
+            ..Default::default()
+        })
+    }
+}
+
+/// Answer requests on `socket` with `client` until interrupted.
+///
+/// Other processes of the same user then skip loading and refreshing tokens,
+/// reuse the open HTTPS connections and share one request quota.
+pub async fn serve(client: Client, socket: &Path) -> Result<()> {
+    if socket.exists() {
+        if is_running(socket) {
+            bail!(
+                "A gmail daemon is already listening on {}",
+                socket.display()
+            );
+        }
+        // Left behind by a daemon that didn't shut down cleanly
+        std::fs::remove_file(socket)?;
+    }
+    // Other users can't reach a socket in a directory only its owner can
+    // enter, even before the socket itself is narrowed below
+    if let Some(dir) = socket.parent() {
+        config::ensure_dir(dir)?;
+        if config::is_shared(dir)? {
+            bail!(
+                "{} is open to other users; run 'chmod 700 {}' before starting the daemon",
+                dir.display(),
+                dir.display()
+            );
+        }
+    }
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+
+    let daemon = Arc::new(Daemon {
+        client,
+        labels: Mutex::new(None),
+        stop: tokio::sync::Notify::new(),
+    });
+    let result = loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = match accepted {
+                    Ok(connection) => connection,
+                    Err(e) => break Err(e.into()),
+                };
+                let daemon = daemon.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = daemon.handle(stream).await {
+                        tracing::debug!("daemon connection failed: {:#}", e);
+                    }
+                });
+            }
+            _ = daemon.stop.notified() => break Ok(()),
+            _ = tokio::signal::ctrl_c() => break Ok(()),
+        }
+    };
+    std::fs::remove_file(socket)?;
+    result
+}
@@ -0,0 +1,355 @@
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+
+use crate::error::{Result, bail, format_err};
+
+/// Parse a user-supplied date or date-time in the local timezone.
+///
+/// Accepts `YYYY-MM-DD`, `YYYY-MM-DD HH:MM` and RFC 3339 timestamps.
+pub fn parse_local(input: &str) -> Result<DateTime<Local>> {
+    let input = input.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(input) {
+        return Ok(dt.with_timezone(&Local));
+    }
+    let naive = if let Ok(dt) = NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M") {
+        dt
+    } else if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        date.and_hms_opt(0, 0, 0).expect("midnight is valid")
+    } else {
+        bail!(
+            "Invalid date '{}' (expected YYYY-MM-DD, YYYY-MM-DD HH:MM or RFC 3339)",
+            input
+        );
+    };
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format_err!("'{}' does not exist in the local timezone", input))
+}
+
+/// Parse the end of a period like `parse_local`, except that a bare date means
+/// the whole of that day: midnight at the start of the next one.
+pub fn parse_local_end(input: &str) -> Result<DateTime<Local>> {
+    let Ok(date) = NaiveDate::parse_from_str(input.trim(), "%Y-%m-%d") else {
+        return parse_local(input);
+    };
+    let next = date
+        .succ_opt()
+        .ok_or_else(|| format_err!("'{}' is out of range", input))?;
+    parse_local(&next.format("%Y-%m-%d").to_string())
+}
+
+// Time of day used when only a day is given, e.g. "monday"
+const DEFAULT_HOUR: u32 = 8;
+
+/// Parse a time in the future, relative to `now`: "monday 9am", "tomorrow",
+/// "5pm", "in 3 days", "in 2h", or anything `parse_local` accepts.
+///
+/// A day without a time means 8am; a weekday is its next occurrence after today.
+pub fn parse_future(input: &str, now: DateTime<Local>) -> Result<DateTime<Local>> {
+    let dt = match parse_local(input) {
+        Ok(dt) => dt,
+        Err(_) => parse_relative(input, now).ok_or_else(|| {
+            format_err!(
+                "Invalid time '{}' (try \"monday 9am\", \"tomorrow\", \"in 3 days\" or YYYY-MM-DD HH:MM)",
+                input.trim()
+            )
+        })?,
+    };
+    if dt <= now {
+        bail!("'{}' is in the past", input.trim());
+    }
+    Ok(dt)
+}
+
+/// Parse a point in the past, relative to `now`: "yesterday", "3 days ago", "2w ago",
+/// "monday" (the most recent one), or anything `parse_local` accepts.
+///
+/// Days without a time mean midnight at their start.
+pub fn parse_past(input: &str, now: DateTime<Local>) -> Result<DateTime<Local>> {
+    if let Ok(dt) = parse_local(input) {
+        return Ok(dt);
+    }
+    parse_ago(input, now).ok_or_else(|| {
+        format_err!(
+            "Invalid date '{}' (try \"yesterday\", \"3 days ago\", \"monday\" or YYYY-MM-DD)",
+            input.trim()
+        )
+    })
+}
+
+fn parse_ago(input: &str, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    let input = input.trim().to_lowercase();
+    let words: Vec<&str> = input.split_whitespace().collect();
+    if let [amount @ .., "ago"] = words.as_slice() {
+        return now.checked_sub_signed(parse_duration(&amount.concat())?);
+    }
+    let today = now.date_naive();
+    let day = match words.as_slice() {
+        ["today"] => today,
+        ["yesterday"] => today.pred_opt()?,
+        // The weekday before today, so "monday" on a Monday means a week ago
+        ["last", word] | [word] if *word != "tomorrow" => {
+            parse_day(word, today.pred_opt()?)?.checked_sub_signed(chrono::Duration::weeks(1))?
+        }
+        _ => return None,
+    };
+    Local
+        .from_local_datetime(&day.and_hms_opt(0, 0, 0)?)
+        .earliest()
+}
+
+fn parse_relative(input: &str, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    let input = input.trim().to_lowercase();
+    let words: Vec<&str> = input.split_whitespace().collect();
+    if let ["in", amount @ ..] = words.as_slice() {
+        return now.checked_add_signed(parse_duration(&amount.concat())?);
+    }
+
+    let today = now.date_naive();
+    let mut day = None;
+    let mut time = None;
+    for word in words {
+        if matches!(word, "next" | "at" | "on") {
+            continue;
+        }
+        // Unknown words, or a second day or time, make the input invalid
+        let invalid = if let Some(d) = parse_day(word, today) {
+            day.replace(d).is_some()
+        } else if let Some(t) = parse_time(word) {
+            time.replace(t).is_some()
+        } else {
+            true
+        };
+        if invalid {
+            return None;
+        }
+    }
+    let (date, time) = match (day, time) {
+        (None, None) => return None,
+        (Some(date), time) => (
+            date,
+            time.unwrap_or(NaiveTime::from_hms_opt(DEFAULT_HOUR, 0, 0)?),
+        ),
+        // A bare time is today if it's still ahead, otherwise tomorrow
+        (None, Some(time)) if today.and_time(time) > now.naive_local() => (today, time),
+        (None, Some(time)) => (today.succ_opt()?, time),
+    };
+    Local.from_local_datetime(&date.and_time(time)).earliest()
+}
+
+// "3d", "2hours", "90min", "1week"
+fn parse_duration(amount: &str) -> Option<chrono::Duration> {
+    let split = amount.find(|c: char| !c.is_ascii_digit())?;
+    let (n, unit) = amount.split_at(split);
+    let n: i64 = n.parse().ok()?;
+    Some(match unit {
+        "m" | "min" | "mins" | "minute" | "minutes" => chrono::Duration::minutes(n),
+        "h" | "hr" | "hrs" | "hour" | "hours" => chrono::Duration::hours(n),
+        "d" | "day" | "days" => chrono::Duration::days(n),
+        "w" | "week" | "weeks" => chrono::Duration::weeks(n),
+        _ => return None,
+    })
+}
+
+fn parse_day(word: &str, today: NaiveDate) -> Option<NaiveDate> {
+    const WEEKDAYS: [&str; 7] = [
+        "monday",
+        "tuesday",
+        "wednesday",
+        "thursday",
+        "friday",
+        "saturday",
+        "sunday",
+    ];
+    match word {
+        "today" => return Some(today),
+        "tomorrow" => return today.succ_opt(),
+        _ => {}
+    }
+    // "mon", "tues" and "monday" all work
+    let target = WEEKDAYS
+        .iter()
+        .position(|day| word.len() >= 3 && day.starts_with(word))? as i64;
+    let current = today.weekday().num_days_from_monday() as i64;
+    let ahead = match (target - current).rem_euclid(7) {
+        0 => 7,
+        n => n,
+    };
+    today.checked_add_signed(chrono::Duration::days(ahead))
+}
+
+// "9am", "9:30pm", "17:00", "noon"
+fn parse_time(word: &str) -> Option<NaiveTime> {
+    if word == "noon" {
+        return NaiveTime::from_hms_opt(12, 0, 0);
+    }
+    let (clock, meridiem) = match (word.strip_suffix("am"), word.strip_suffix("pm")) {
+        (Some(clock), _) => (clock, Some(0)),
+        (_, Some(clock)) => (clock, Some(12)),
+        _ => (word, None),
+    };
+    let (hour, minute) = match clock.split_once(':') {
+        Some((h, m)) => (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?),
+        // A bare number is only a time with am/pm
+        None if meridiem.is_some() => (clock.parse::<u32>().ok()?, 0),
+        None => return None,
+    };
+    let hour = match meridiem {
+        Some(offset) if (1..=12).contains(&hour) => hour % 12 + offset,
+        Some(_) => return None,
+        None => hour,
+    };
+    NaiveTime::from_hms_opt(hour, minute, 0)
+}
+
+/// Convert milliseconds since the epoch to local time.
+pub fn from_millis(millis: i64) -> Option<DateTime<Local>> {
+    DateTime::from_timestamp_millis(millis).map(|dt| dt.with_timezone(&Local))
+}
+
+/// Parse an RFC 2822 `Date` header into local time.
+pub fn parse_header(value: &str) -> Option<DateTime<Local>> {
+    // Many mailers append the zone name as a comment, e.g. "+0000 (UTC)"
+    let value = match value.find('(') {
+        Some(comment) => &value[..comment],
+        None => value,
+    };
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|dt| dt.with_timezone(&Local))
+}
+
+/// Short form of a `Date` header for listings, or the raw value if it doesn't parse.
+pub fn format_header(value: &str) -> String {
+    match parse_header(value) {
+        Some(dt) => format_short(dt),
+        None => value.to_string(),
+    }
+}
+
+pub fn format_short(dt: DateTime<Local>) -> String {
+    dt.format("%Y-%m-%d %H:%M").to_string()
+}
+
+/// Describe `dt` relative to `now`: "just now", "5m ago", "3h ago", "yesterday",
+/// "4 days ago", falling back to the date for anything older or in the future.
+pub fn format_relative(dt: DateTime<Local>, now: DateTime<Local>) -> String {
+    let elapsed = now.signed_duration_since(dt);
+    if elapsed < chrono::Duration::zero() {
+        return format_short(dt);
+    }
+    let days = (now.date_naive() - dt.date_naive()).num_days();
+    if elapsed.num_minutes() < 1 {
+        "just now".to_string()
+    } else if elapsed.num_hours() < 1 {
+        format!("{}m ago", elapsed.num_minutes())
+    } else if days == 0 {
+        format!("{}h ago", elapsed.num_hours())
+    } else if days == 1 {
+        "yesterday".to_string()
+    } else if days < 7 {
+        format!("{} days ago", days)
+    } else {
+        dt.format("%Y-%m-%d").to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, Timelike};
+
+    #[test]
+    fn test_parse_date_only() {
+        let dt = parse_local("2025-07-01").unwrap();
+        assert_eq!((dt.year(), dt.month(), dt.day()), (2025, 7, 1));
+        assert_eq!((dt.hour(), dt.minute()), (0, 0));
+    }
+
+    #[test]
+    fn test_parse_date_time() {
+        let dt = parse_local("2025-07-01 09:30").unwrap();
+        assert_eq!((dt.hour(), dt.minute()), (9, 30));
+    }
+
+    #[test]
+    fn test_parse_local_end() {
+        let dt = parse_local_end("2025-07-31").unwrap();
+        assert_eq!((dt.month(), dt.day(), dt.hour()), (8, 1, 0));
+        let dt = parse_local_end("2025-07-31 17:00").unwrap();
+        assert_eq!((dt.day(), dt.hour()), (31, 17));
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        let dt = parse_local("2025-07-01T09:30:00Z").unwrap();
+        assert_eq!(dt.timestamp(), 1751362200);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse_local("next tuesday").is_err());
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let dt = parse_header("Tue, 1 Jul 2025 09:30:00 +0000").unwrap();
+        assert_eq!(dt.timestamp(), 1751362200);
+        assert_eq!(format_header("not a date"), "not a date");
+    }
+
+    #[test]
+    fn test_parse_header_with_comment() {
+        let dt = parse_header("Tue, 1 Jul 2025 09:30:00 +0000 (UTC)").unwrap();
+        assert_eq!(dt.timestamp(), 1751362200);
+    }
+
+    #[test]
+    fn test_parse_future() {
+        // A Thursday afternoon
+        let now = parse_local("2025-07-10 15:00").unwrap();
+        let at = |input: &str| format_short(parse_future(input, now).unwrap());
+        assert_eq!(at("monday 9am"), "2025-07-14 09:00");
+        assert_eq!(at("Next Thu"), "2025-07-17 08:00");
+        assert_eq!(at("tomorrow"), "2025-07-11 08:00");
+        assert_eq!(at("tomorrow at 12:30pm"), "2025-07-11 12:30");
+        assert_eq!(at("5pm"), "2025-07-10 17:00");
+        assert_eq!(at("9am"), "2025-07-11 09:00");
+        assert_eq!(at("in 2 hours"), "2025-07-10 17:00");
+        assert_eq!(at("in 3d"), "2025-07-13 15:00");
+        assert_eq!(at("2025-08-01 10:00"), "2025-08-01 10:00");
+
+        assert!(parse_future("2025-07-01", now).is_err());
+        assert!(parse_future("someday", now).is_err());
+        assert!(parse_future("13pm", now).is_err());
+        assert!(parse_future("monday tuesday", now).is_err());
+    }
+
+    #[test]
+    fn test_parse_past() {
+        // A Thursday afternoon
+        let now = parse_local("2025-07-10 15:00").unwrap();
+        let at = |input: &str| format_short(parse_past(input, now).unwrap());
+        assert_eq!(at("yesterday"), "2025-07-09 00:00");
+        assert_eq!(at("3 days ago"), "2025-07-07 15:00");
+        assert_eq!(at("2w ago"), "2025-06-26 15:00");
+        assert_eq!(at("monday"), "2025-07-07 00:00");
+        assert_eq!(at("last thursday"), "2025-07-03 00:00");
+        assert_eq!(at("2025-01-01"), "2025-01-01 00:00");
+        assert!(parse_past("tomorrow", now).is_err());
+        assert!(parse_past("ages ago", now).is_err());
+    }
+
+    #[test]
+    fn test_format_relative() {
+        let now = parse_local("2025-07-10 15:00").unwrap();
+        let ago = |input: &str| format_relative(parse_local(input).unwrap(), now);
+        assert_eq!(ago("2025-07-10 15:00"), "just now");
+        assert_eq!(ago("2025-07-10 14:35"), "25m ago");
+        assert_eq!(ago("2025-07-10 09:00"), "6h ago");
+        assert_eq!(ago("2025-07-09 23:00"), "yesterday");
+        assert_eq!(ago("2025-07-06 12:00"), "4 days ago");
+        assert_eq!(ago("2025-06-01 12:00"), "2025-06-01");
+        assert_eq!(ago("2025-07-11 08:00"), "2025-07-11 08:00");
+    }
+}
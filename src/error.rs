@@ -0,0 +1,300 @@
+use serde::Deserialize;
+
+/// Result type of the library, failing with a [`GmailError`].
+pub type Result<T, E = GmailError> = std::result::Result<T, E>;
+
+/// Everything the library can fail with.
+///
+/// API failures keep Google's status, reason and message so callers can tell a
+/// missing message from an expired login or a rate limit without matching on text.
+#[derive(Debug, thiserror::Error)]
+pub enum GmailError {
+    /// The API answered with an error status.
+    #[error("HTTP {status} - {message}")]
+    Api {
+        status: u16,
+        /// Google's machine-readable reason, e.g. `notFound` or `rateLimitExceeded`
+        reason: Option<String>,
+        message: String,
+    },
+    /// The request never got a response, e.g. when offline.
+    #[error(transparent)]
+    Network(#[from] reqwest::Error),
+    /// Logging in or renewing the access token failed.
+    #[error("{0}")]
+    Auth(String),
+    /// A dry run reached a request whose response the command needs to go on.
+    #[error("Dry run stopped: the next step needs the response of a skipped request")]
+    DryRun,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Cache(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error(transparent)]
+    TomlWrite(#[from] toml::ser::Error),
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+    #[error(transparent)]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    #[error(transparent)]
+    Encrypt(#[from] age::EncryptError),
+    #[error(transparent)]
+    Decrypt(#[from] age::DecryptError),
+    #[error(transparent)]
+    Header(#[from] reqwest::header::InvalidHeaderValue),
+    #[error(transparent)]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error(transparent)]
+    ParseInt(#[from] std::num::ParseIntError),
+    #[error(transparent)]
+    ParseBool(#[from] std::str::ParseBoolError),
+    /// Invalid input or state, described for the user.
+    #[error("{0}")]
+    Message(String),
+    /// Another error with a description of what was being done.
+    #[error(transparent)]
+    Context(Box<ContextError>),
+}
+
+/// A [`GmailError`] wrapped with what was being done when it happened.
+///
+/// Like `anyhow`, `{}` shows only the context and `{:#}` the whole chain.
+#[derive(Debug)]
+pub struct ContextError {
+    context: String,
+    cause: GmailError,
+}
+
+impl std::fmt::Display for ContextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            write!(f, "{}: {:#}", self.context, self.cause)
+        } else {
+            write!(f, "{}", self.context)
+        }
+    }
+}
+
+impl std::error::Error for ContextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+impl GmailError {
+    /// Parse an error response, falling back to the raw body when it isn't Google's JSON.
+    pub fn from_response(status: u16, body: &str) -> Self {
+        #[derive(Deserialize)]
+        struct Payload {
+            error: Detail,
+        }
+        #[derive(Deserialize)]
+        struct Detail {
+            message: Option<String>,
+            status: Option<String>,
+            #[serde(default)]
+            errors: Vec<Item>,
+        }
+        #[derive(Deserialize)]
+        struct Item {
+            reason: Option<String>,
+        }
+
+        match serde_json::from_str::<Payload>(body) {
+            Ok(Payload { error }) => GmailError::Api {
+                status,
+                reason: error
+                    .errors
+                    .into_iter()
+                    .find_map(|item| item.reason)
+                    .or(error.status),
+                message: error.message.unwrap_or_else(|| body.trim().to_string()),
+            },
+            Err(_) => GmailError::Api {
+                status,
+                reason: None,
+                message: body.trim().to_string(),
+            },
+        }
+    }
+
+    /// The underlying error, past any context added on the way up.
+    pub fn root(&self) -> &GmailError {
+        match self {
+            GmailError::Context(inner) => inner.cause.root(),
+            other => other,
+        }
+    }
+
+    /// HTTP status of an API error.
+    pub fn status(&self) -> Option<u16> {
+        match self.root() {
+            GmailError::Api { status, .. } => Some(*status),
+            GmailError::Network(e) => e.status().map(|s| s.as_u16()),
+            _ => None,
+        }
+    }
+
+    /// Google's reason for an API error, e.g. `rateLimitExceeded`.
+    pub fn reason(&self) -> Option<&str> {
+        match self.root() {
+            GmailError::Api { reason, .. } => reason.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(404)
+    }
+
+    /// The login is missing, expired or revoked.
+    pub fn is_unauthorized(&self) -> bool {
+        self.status() == Some(401) || matches!(self.root(), GmailError::Auth(_))
+    }
+
+    /// Too many requests, either by status or by Google's quota reasons on a 403.
+    pub fn is_rate_limited(&self) -> bool {
+        self.status() == Some(429)
+            || matches!(
+                self.reason(),
+                Some("rateLimitExceeded" | "userRateLimitExceeded" | "RESOURCE_EXHAUSTED")
+            )
+    }
+
+    /// The login lacks a scope the request needs, e.g. full access for permanent deletion.
+    pub fn is_insufficient_scope(&self) -> bool {
+        self.status() == Some(403)
+            && matches!(
+                self.reason(),
+                Some("insufficientPermissions" | "ACCESS_TOKEN_SCOPE_INSUFFICIENT")
+            )
+    }
+
+    /// Worth trying again after a pause: rate limited, or a server error.
+    pub fn is_transient(&self) -> bool {
+        self.is_rate_limited() || self.status().is_some_and(|status| status >= 500)
+    }
+
+    /// The server couldn't be reached at all, e.g. when offline.
+    pub fn is_network(&self) -> bool {
+        matches!(self.root(), GmailError::Network(e) if e.is_connect() || e.is_timeout())
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        matches!(self.root(), GmailError::DryRun)
+    }
+
+    fn with_context(self, context: String) -> Self {
+        GmailError::Context(Box::new(ContextError {
+            context,
+            cause: self,
+        }))
+    }
+}
+
+impl<RE, T> From<oauth2::RequestTokenError<RE, T>> for GmailError
+where
+    RE: std::error::Error + 'static,
+    T: oauth2::ErrorResponse + 'static,
+{
+    fn from(err: oauth2::RequestTokenError<RE, T>) -> Self {
+        let mut message = err.to_string();
+        let mut source = std::error::Error::source(&err);
+        while let Some(e) = source {
+            message = format!("{}: {}", message, e);
+            source = e.source();
+        }
+        GmailError::Auth(message)
+    }
+}
+
+/// Add a description of what was being done to an error, like `anyhow::Context`.
+pub trait Context<T> {
+    fn context(self, context: impl std::fmt::Display) -> Result<T>;
+
+    fn with_context<C: std::fmt::Display>(self, f: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T, E: Into<GmailError>> Context<T> for std::result::Result<T, E> {
+    fn context(self, context: impl std::fmt::Display) -> Result<T> {
+        self.map_err(|e| e.into().with_context(context.to_string()))
+    }
+
+    fn with_context<C: std::fmt::Display>(self, f: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|e| e.into().with_context(f().to_string()))
+    }
+}
+
+/// Build a [`GmailError::Message`] from format arguments.
+macro_rules! format_err {
+    ($($arg:tt)*) => {
+        $crate::error::GmailError::Message(format!($($arg)*))
+    };
+}
+
+/// Return early with a [`GmailError::Message`].
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err($crate::error::format_err!($($arg)*).into())
+    };
+}
+
+pub(crate) use {bail, format_err};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_response() {
+        let err = GmailError::from_response(
+            404,
+            r#"{"error": {"code": 404, "message": "Requested entity was not found.",
+                "errors": [{"reason": "notFound"}], "status": "NOT_FOUND"}}"#,
+        );
+        assert!(err.is_not_found());
+        assert_eq!(err.reason(), Some("notFound"));
+        assert_eq!(
+            err.to_string(),
+            "HTTP 404 - Requested entity was not found."
+        );
+
+        let err = GmailError::from_response(429, r#"{"error": {"status": "RESOURCE_EXHAUSTED"}}"#);
+        assert!(err.is_rate_limited());
+        assert!(err.is_transient());
+        assert_eq!(err.reason(), Some("RESOURCE_EXHAUSTED"));
+
+        let err = GmailError::from_response(
+            403,
+            r#"{"error": {"code": 403, "message": "Request had insufficient authentication scopes.",
+                "errors": [{"reason": "insufficientPermissions"}], "status": "PERMISSION_DENIED"}}"#,
+        );
+        assert!(err.is_insufficient_scope());
+        assert!(!GmailError::from_response(403, "Forbidden").is_insufficient_scope());
+
+        let err = GmailError::from_response(502, "Bad Gateway\n");
+        assert!(err.is_transient());
+        assert_eq!(err.status(), Some(502));
+        assert_eq!(err.reason(), None);
+        assert_eq!(err.to_string(), "HTTP 502 - Bad Gateway");
+    }
+
+    #[test]
+    fn test_context() {
+        let err: Result<()> = Err(GmailError::from_response(401, "expired"));
+        let err = err.context("Failed to list messages").unwrap_err();
+        assert!(err.is_unauthorized());
+        assert!(!err.is_not_found());
+        assert_eq!(err.to_string(), "Failed to list messages");
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to list messages: HTTP 401 - expired"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::Serialize;
+
+/// The first event of an iCalendar invite (RFC 5545), as much as `read` shows.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Event {
+    /// What the invite asks for: REQUEST, CANCEL, REPLY...
+    pub method: Option<String>,
+    pub summary: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub location: Option<String>,
+    pub organizer: Option<Person>,
+    pub attendees: Vec<Person>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Person {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    /// Attendee's answer: ACCEPTED, DECLINED, TENTATIVE or NEEDS-ACTION
+    pub status: Option<String>,
+}
+
+impl Person {
+    fn from_property(params: &[(String, String)], value: &str) -> Self {
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+        };
+        let email = value
+            .strip_prefix("mailto:")
+            .or_else(|| value.strip_prefix("MAILTO:"))
+            .unwrap_or(value);
+        Self {
+            name: param("CN"),
+            email: (!email.is_empty()).then(|| email.to_string()),
+            status: param("PARTSTAT"),
+        }
+    }
+
+    /// `Name <email>`, or whichever of the two is known.
+    pub fn display(&self) -> String {
+        match (&self.name, &self.email) {
+            (Some(name), Some(email)) if name != email => format!("{} <{}>", name, email),
+            (_, Some(email)) => email.clone(),
+            (Some(name), None) => name.clone(),
+            (None, None) => "(unknown)".to_string(),
+        }
+    }
+}
+
+/// Parse the first VEVENT of a calendar; None if there is none.
+pub fn parse(ics: &str) -> Option<Event> {
+    let mut event = Event::default();
+    let mut in_event = false;
+    let mut found = false;
+    for line in unfold(ics) {
+        let Some((name, params, value)) = split_property(&line) else {
+            continue;
+        };
+        match (name.to_ascii_uppercase().as_str(), in_event) {
+            ("BEGIN", false) if value.eq_ignore_ascii_case("VEVENT") && !found => {
+                in_event = true;
+                found = true;
+            }
+            ("END", true) if value.eq_ignore_ascii_case("VEVENT") => in_event = false,
+            ("METHOD", false) => event.method = Some(value.to_ascii_uppercase()),
+            ("SUMMARY", true) => event.summary = Some(unescape(value)),
+            ("LOCATION", true) => event.location = Some(unescape(value)).filter(|l| !l.is_empty()),
+            ("DTSTART", true) => event.start = Some(format_time(&params, value)),
+            ("DTEND", true) => event.end = Some(format_time(&params, value)),
+            ("ORGANIZER", true) => event.organizer = Some(Person::from_property(&params, value)),
+            ("ATTENDEE", true) => event.attendees.push(Person::from_property(&params, value)),
+            _ => {}
+        }
+    }
+    found.then_some(event)
+}
+
+/// Join folded lines: a line starting with a space or tab continues the previous one.
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in ics.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+// Property parameters such as `CN` and `PARTSTAT`, in order
+type Params = Vec<(String, String)>;
+
+/// `NAME;PARAM=x;PARAM="y":value` into its parts.
+fn split_property(line: &str) -> Option<(&str, Params, &str)> {
+    // The value starts at the first colon outside a quoted parameter value
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+    let mut parts = head.split(';');
+    let name = parts.next()?;
+    let params = parts
+        .filter_map(|p| p.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.trim_matches('"').to_string()))
+        .collect();
+    Some((name, params, value))
+}
+
+/// Undo TEXT escaping: `\n`, `\,`, `\;` and `\\`.
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+/// A DTSTART/DTEND value for display: UTC times in local time, floating and
+/// TZID times as written (with the zone), and dates as all-day.
+fn format_time(params: &[(String, String)], value: &str) -> String {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return format!("{} (all day)", date.format("%Y-%m-%d"));
+    }
+    if let Some(utc) = value.strip_suffix('Z')
+        && let Ok(time) = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")
+    {
+        let local = Utc.from_utc_datetime(&time).with_timezone(&chrono::Local);
+        return local.format("%Y-%m-%d %H:%M").to_string();
+    }
+    let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") else {
+        return value.to_string();
+    };
+    let formatted = time.format("%Y-%m-%d %H:%M").to_string();
+    match params
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("TZID"))
+    {
+        Some((_, zone)) => format!("{} ({})", formatted, zone),
+        None => formatted,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVITE: &str = "BEGIN:VCALENDAR\r\nMETHOD:REQUEST\r\nBEGIN:VTIMEZONE\r\nTZID:Europe/Paris\r\nEND:VTIMEZONE\r\nBEGIN:VEVENT\r\nDTSTART;TZID=Europe/Paris:20250710T093000\r\nDTEND;TZID=Europe/Paris:20250710T100000\r\nSUMMARY:Weekly sync\\, team A\r\nLOCATION:Room 4\r\nORGANIZER;CN=Ana Lima:mailto:ana@example.com\r\nATTENDEE;CUTYPE=INDIVIDUAL;ROLE=REQ-PARTICIPANT;PARTSTAT=ACCEPTED;CN=\"Lee, Bo\r\n \";X-NUM-GUESTS=0:mailto:bo@example.com\r\nATTENDEE;PARTSTAT=NEEDS-ACTION:mailto:me@example.com\r\nDESCRIPTION:Line one\\nLine two\r\nEND:VEVENT\r\nBEGIN:VEVENT\r\nSUMMARY:Second\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+
+    #[test]
+    fn test_parse() {
+        let event = parse(INVITE).unwrap();
+        assert_eq!(event.method.as_deref(), Some("REQUEST"));
+        assert_eq!(event.summary.as_deref(), Some("Weekly sync, team A"));
+        assert_eq!(
+            event.start.as_deref(),
+            Some("2025-07-10 09:30 (Europe/Paris)")
+        );
+        assert_eq!(event.location.as_deref(), Some("Room 4"));
+        assert_eq!(
+            event.organizer.unwrap().display(),
+            "Ana Lima <ana@example.com>"
+        );
+        assert_eq!(event.attendees.len(), 2);
+        assert_eq!(event.attendees[0].name.as_deref(), Some("Lee, Bo"));
+        assert_eq!(event.attendees[0].status.as_deref(), Some("ACCEPTED"));
+        assert_eq!(event.attendees[1].display(), "me@example.com");
+
+        assert_eq!(parse("BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n"), None);
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(&[], "20250710"), "2025-07-10 (all day)");
+        assert_eq!(format_time(&[], "20250710T093000"), "2025-07-10 09:30");
+        assert_eq!(format_time(&[], "garbage"), "garbage");
+    }
+}
@@ -0,0 +1,36 @@
+/// Message IDs piped in on stdin, one per line.
+///
+/// Takes the first column of each line, so `list --format tsv` and plain
+/// `list` output work as-is, and the `id` field of NDJSON lines from
+/// `list --ndjson`. Blank lines and `ID` header rows are skipped.
+pub fn parse_id_list(input: &str) -> Vec<String> {
+    input
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.starts_with('{') {
+                let value: serde_json::Value = serde_json::from_str(line).ok()?;
+                return value["id"].as_str().map(str::to_string);
+            }
+            let id = line.split([' ', '\t', ',']).next()?;
+            (!id.is_empty() && !id.eq_ignore_ascii_case("id")).then(|| id.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_id_list() {
+        let input = "ID  DATE  SUBJECT\n18c1  2025-07-01 09:30  Hi\n\n18c2\t2025-07-02\n18c3,x\n";
+        assert_eq!(parse_id_list(input), vec!["18c1", "18c2", "18c3"]);
+    }
+
+    #[test]
+    fn test_parse_id_list_ndjson() {
+        let input = "{\"id\":\"18c1\",\"subject\":\"a b\"}\n{\"subject\":\"no id\"}\n";
+        assert_eq!(parse_id_list(input), vec!["18c1"]);
+    }
+}
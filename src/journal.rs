@@ -0,0 +1,259 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::config;
+use crate::error::{Context, Result};
+
+// Oldest changes are dropped whole once the journal has more entries than this
+const JOURNAL_LIMIT: usize = 200;
+
+/// One recorded label change, enough to reverse it with `gmail undo`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// RFC 3339 time of the change. Entries written together for one change
+    /// share it and are undone together
+    pub time: String,
+    #[serde(default)]
+    pub target: Target,
+    pub ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    #[default]
+    Messages,
+    Threads,
+}
+
+impl Entry {
+    pub fn new(target: Target, ids: &[String], add: &[&str], remove: &[&str]) -> Self {
+        Self {
+            time: chrono::Local::now().to_rfc3339(),
+            target,
+            ids: ids.to_vec(),
+            add: add.iter().map(|s| s.to_string()).collect(),
+            remove: remove.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// The change that puts the labels back.
+    ///
+    /// Labels are restored blindly, so an entry should only hold what actually
+    /// changed (see [`actual_changes`]): otherwise removing UNREAD from an
+    /// already-read message and undoing it leaves the message unread.
+    pub fn inverse(&self) -> Self {
+        Self {
+            time: self.time.clone(),
+            target: self.target,
+            ids: self.ids.clone(),
+            add: self.remove.clone(),
+            remove: self.add.clone(),
+        }
+    }
+}
+
+/// Entries recording exactly what `add`/`remove` changed on messages
+/// that had the labels in `before` (None for a message that's gone): one per
+/// distinct change, leaving out messages that already had the labels asked for.
+pub fn actual_changes(
+    ids: &[String],
+    before: &[Option<Vec<String>>],
+    add: &[&str],
+    remove: &[&str],
+) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    for (id, labels) in ids.iter().zip(before) {
+        let Some(labels) = labels else {
+            continue;
+        };
+        let has = |label: &str| labels.iter().any(|l| l == label);
+        let added: Vec<&str> = add.iter().copied().filter(|l| !has(l)).collect();
+        let removed: Vec<&str> = remove.iter().copied().filter(|l| has(l)).collect();
+        if added.is_empty() && removed.is_empty() {
+            continue;
+        }
+        match entries
+            .iter_mut()
+            .find(|e| e.add == added && e.remove == removed)
+        {
+            Some(entry) => entry.ids.push(id.clone()),
+            None => {
+                let mut entry =
+                    Entry::new(Target::Messages, std::slice::from_ref(id), &added, &removed);
+                if let Some(first) = entries.first() {
+                    entry.time = first.time.clone();
+                }
+                entries.push(entry);
+            }
+        }
+    }
+    entries
+}
+
+/// Split entries, oldest first, into the changes they record: runs sharing a time.
+pub fn changes(entries: &[Entry]) -> Vec<std::ops::Range<usize>> {
+    let mut changes: Vec<std::ops::Range<usize>> = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        match changes.last_mut() {
+            Some(last) if entries[last.start].time == entry.time => last.end = index + 1,
+            _ => changes.push(index..index + 1),
+        }
+    }
+    changes
+}
+
+/// Add an entry to the end of the journal, dropping the oldest changes past the limit.
+pub fn append(path: &Path, entry: &Entry) -> Result<()> {
+    append_all(path, std::slice::from_ref(entry))
+}
+
+/// Add the entries of one change to the end of the journal.
+pub fn append_all(path: &Path, entries: &[Entry]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        config::ensure_dir(dir)?;
+    }
+    let mut file = config::append_secure(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    for entry in entries {
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    }
+
+    let entries = read(path)?;
+    let keep = kept_from(&entries);
+    if keep > 0 {
+        write_all(path, &entries[keep..])?;
+    }
+    Ok(())
+}
+
+/// Index of the first entry to keep: the oldest change that still fits the
+/// limit, so a change is never cut in half. The newest change always stays.
+fn kept_from(entries: &[Entry]) -> usize {
+    let changes = changes(entries);
+    changes
+        .iter()
+        .map(|change| change.start)
+        .find(|&start| entries.len() - start <= JOURNAL_LIMIT)
+        .or_else(|| changes.last().map(|change| change.start))
+        .unwrap_or(0)
+}
+
+/// Every entry, oldest first. A missing journal is empty.
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).context("Corrupt undo journal entry"))
+        .collect()
+}
+
+/// Drop every entry after the first `len`.
+pub fn truncate(path: &Path, len: usize) -> Result<()> {
+    let entries = read(path)?;
+    write_all(path, &entries[..len.min(entries.len())])
+}
+
+fn write_all(path: &Path, entries: &[Entry]) -> Result<()> {
+    let mut content = String::new();
+    for entry in entries {
+        content.push_str(&serde_json::to_string(entry)?);
+        content.push('\n');
+    }
+    config::write_secure(path, content)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inverse() {
+        let entry = Entry::new(
+            Target::Messages,
+            &["18c1".to_string()],
+            &["SPAM"],
+            &["INBOX"],
+        );
+        let inverse = entry.inverse();
+        assert_eq!(inverse.add, vec!["INBOX"]);
+        assert_eq!(inverse.remove, vec!["SPAM"]);
+        assert_eq!(inverse.inverse(), entry);
+    }
+
+    #[test]
+    fn test_actual_changes() {
+        let ids = ["a", "b", "c", "d"].map(String::from);
+        let before = [
+            Some(vec!["INBOX".to_string(), "UNREAD".to_string()]),
+            Some(vec!["UNREAD".to_string()]),
+            Some(vec!["SPAM".to_string()]),
+            None,
+        ];
+        let entries = actual_changes(&ids, &before, &["SPAM"], &["INBOX"]);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].ids, ["a"]);
+        assert_eq!(
+            (entries[0].add.clone(), entries[0].remove.clone()),
+            (vec!["SPAM".to_string()], vec!["INBOX".to_string()])
+        );
+        assert_eq!(entries[1].ids, ["b"]);
+        assert!(entries[1].remove.is_empty());
+        assert_eq!(entries[0].time, entries[1].time);
+        assert_eq!(changes(&entries), vec![0..2]);
+    }
+
+    #[test]
+    fn test_kept_from_whole_changes() {
+        let at = |time: &str| Entry {
+            time: time.to_string(),
+            ..Entry::new(Target::Messages, &["a".to_string()], &[], &["INBOX"])
+        };
+        let mut entries = vec![at("1"); 2];
+        entries.extend(vec![at("2"); JOURNAL_LIMIT - 1]);
+        assert_eq!(kept_from(&entries[2..]), 0);
+        // One over the limit drops the whole first change, not just its first entry
+        assert_eq!(kept_from(&entries), 2);
+
+        let huge = vec![at("3"); JOURNAL_LIMIT + 1];
+        assert_eq!(kept_from(&huge), 0);
+        assert_eq!(kept_from(&[]), 0);
+    }
+
+    #[test]
+    fn test_append_read_truncate() {
+        let dir = std::env::temp_dir().join(format!("gmail-journal-test-{}", std::process::id()));
+        let path = dir.join("journal.jsonl");
+        let ids = ["18c1".to_string()];
+
+        assert!(read(&path).unwrap().is_empty());
+        append(&path, &Entry::new(Target::Messages, &ids, &[], &["INBOX"])).unwrap();
+        append(&path, &Entry::new(Target::Threads, &ids, &["TRASH"], &[])).unwrap();
+        let entries = read(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        let mode = |p: &Path| {
+            use std::os::unix::fs::PermissionsExt;
+            fs::metadata(p).unwrap().permissions().mode() & 0o777
+        };
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(entries[1].target, Target::Threads);
+
+        truncate(&path, 1).unwrap();
+        assert_eq!(read(&path).unwrap(), entries[..1]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,29 @@
 pub mod api;
 pub mod auth;
+pub mod authres;
+pub mod cache;
 pub mod config;
+pub mod contacts;
+pub mod daemon;
+pub mod date;
+pub mod error;
+pub mod ics;
+pub mod ids;
+pub mod journal;
+pub mod links;
+pub mod maildir;
+pub mod mbox;
+pub mod mime;
+pub mod otp;
+pub mod outbox;
+pub mod quota;
+pub mod rules;
+pub mod stats;
+pub mod style;
+pub mod table;
 
-pub use api::{Client, Label, LabelList, Message, MessageList, MessageRef};
+pub use api::{Client, Label, LabelList, Message, MessageList, MessageRef, Query};
+pub use auth::TokenProvider;
 pub use config::{Config, Tokens};
+pub use error::GmailError;
+pub use mime::Email;
@@ -0,0 +1,266 @@
+use serde::Serialize;
+
+/// A link found in a message body.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Link {
+    pub url: String,
+    /// Anchor text, for links from HTML
+    pub text: Option<String>,
+    /// Query parameters that only identify the recipient or campaign, e.g. `utm_source`
+    #[serde(rename = "trackingParams", skip_serializing_if = "Vec::is_empty")]
+    pub tracking_params: Vec<String>,
+    /// Where a redirector sends you, when the destination is in the URL itself
+    #[serde(rename = "redirectsTo")]
+    pub redirects_to: Option<String>,
+    /// The host is a known click tracker or link shortener
+    pub tracker: bool,
+}
+
+impl Link {
+    fn new(url: String, text: Option<String>) -> Self {
+        let (tracking_params, redirects_to) = inspect_query(&url);
+        let tracker = host(&url).is_some_and(|host| {
+            TRACKER_HOSTS
+                .iter()
+                .any(|t| host == *t || host.ends_with(&format!(".{}", t)))
+                || TRACKER_PREFIXES.iter().any(|p| host.starts_with(p))
+        });
+        Self {
+            url,
+            text,
+            tracking_params,
+            redirects_to,
+            tracker,
+        }
+    }
+}
+
+// Shorteners and email click-tracking domains
+const TRACKER_HOSTS: &[&str] = &[
+    "bit.ly",
+    "t.co",
+    "lnkd.in",
+    "tinyurl.com",
+    "ow.ly",
+    "list-manage.com",
+    "mailchi.mp",
+    "sendgrid.net",
+    "mandrillapp.com",
+    "hubspotlinks.com",
+    "mailgun.org",
+    "exacttarget.com",
+    "safelinks.protection.outlook.com",
+    "urldefense.com",
+];
+// Subdomains ESPs use for tracked links on the sender's own domain
+const TRACKER_PREFIXES: &[&str] = &["click.", "clicks.", "links.", "track.", "email.", "t."];
+// Query parameters that carry the real destination of a redirector
+const REDIRECT_PARAMS: &[&str] = &[
+    "url",
+    "u",
+    "q",
+    "redirect",
+    "redirect_url",
+    "target",
+    "dest",
+];
+
+/// Links from an HTML body, with their anchor text, followed by bare URLs in its text.
+pub fn from_html(html: &str) -> Vec<Link> {
+    let mut links = Vec::new();
+    let lower = html.to_ascii_lowercase();
+    let mut rest = 0;
+    while let Some(start) = lower[rest..].find("<a").map(|i| rest + i) {
+        let Some(tag_end) = lower[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        rest = tag_end + 1;
+        // "<abbr>" and friends aren't anchors
+        if !lower[start + 2..].starts_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let Some(href) = attribute(&html[start..tag_end], "href") else {
+            continue;
+        };
+        let close = lower[rest..].find("</a").map_or(html.len(), |i| rest + i);
+        let text = collapse_whitespace(&decode_entities(&strip_tags(&html[rest..close])));
+        if is_web_url(&href) {
+            links.push(Link::new(href, (!text.is_empty()).then_some(text)));
+        }
+        rest = close;
+    }
+    links.extend(from_text(&decode_entities(&strip_tags(html))));
+    dedup(links)
+}
+
+/// Bare `http(s)://` URLs in plain text.
+pub fn from_text(text: &str) -> Vec<Link> {
+    let mut links = Vec::new();
+    let mut rest = text;
+    while let Some(start) = find_scheme(rest) {
+        let candidate = &rest[start..];
+        let end = candidate
+            .find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\'' | '`'))
+            .unwrap_or(candidate.len());
+        // Sentence punctuation and closing brackets around a URL aren't part of it
+        let url = candidate[..end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']']);
+        if url.len() > "https://".len() {
+            links.push(Link::new(url.to_string(), None));
+        }
+        rest = &candidate[end..];
+    }
+    dedup(links)
+}
+
+/// Drop repeated URLs, keeping the first and any anchor text a later copy had.
+pub fn dedup(links: Vec<Link>) -> Vec<Link> {
+    let mut out: Vec<Link> = Vec::new();
+    for link in links {
+        match out.iter_mut().find(|l| l.url == link.url) {
+            Some(existing) => {
+                if existing.text.is_none() {
+                    existing.text = link.text;
+                }
+            }
+            None => out.push(link),
+        }
+    }
+    out
+}
+
+fn find_scheme(text: &str) -> Option<usize> {
+    let lower = text.to_ascii_lowercase();
+    [lower.find("https://"), lower.find("http://")]
+        .into_iter()
+        .flatten()
+        .min()
+}
+
+fn is_web_url(url: &str) -> bool {
+    let lower = url.to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+fn host(url: &str) -> Option<String> {
+    Some(url::Url::parse(url).ok()?.host_str()?.to_ascii_lowercase())
+}
+
+/// Tracking parameters, and the destination if a parameter holds one.
+fn inspect_query(url: &str) -> (Vec<String>, Option<String>) {
+    let Ok(parsed) = url::Url::parse(url) else {
+        return (Vec::new(), None);
+    };
+    let mut tracking = Vec::new();
+    let mut redirect = None;
+    for (key, value) in parsed.query_pairs() {
+        let lower = key.to_ascii_lowercase();
+        if lower.starts_with("utm_")
+            || matches!(lower.as_str(), "fbclid" | "gclid" | "mc_eid" | "mc_cid")
+        {
+            tracking.push(key.to_string());
+        } else if redirect.is_none()
+            && REDIRECT_PARAMS.contains(&lower.as_str())
+            && is_web_url(&value)
+        {
+            redirect = Some(value.to_string());
+        }
+    }
+    (tracking, redirect)
+}
+
+/// The value of `name` in an HTML start tag such as `<a class="x" href="...">`.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find(name).map(|i| from + i) {
+        from = i + name.len();
+        // Must be a whole attribute name, e.g. not "data-href"
+        let before = lower[..i].chars().next_back();
+        if !before.is_some_and(|c| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let after = lower[from..].trim_start();
+        let Some(value) = after.strip_prefix('=') else {
+            continue;
+        };
+        let value_start = tag.len() - value.trim_start().len();
+        let value = &tag[value_start..];
+        let raw = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or(""),
+            _ => value
+                .split(|c: char| c.is_ascii_whitespace() || c == '>')
+                .next()
+                .unwrap_or(""),
+        };
+        return Some(decode_entities(raw.trim()));
+    }
+    None
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                out.push(' ');
+            }
+            _ if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Decode the entities that show up in links and their text.
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x2F;", "/")
+        .replace("&amp;", "&")
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_text() {
+        let links = from_text(
+            "See https://example.com/a, or (https://example.com/b). Again: https://example.com/a",
+        );
+        let urls: Vec<_> = links.iter().map(|l| l.url.as_str()).collect();
+        assert_eq!(urls, ["https://example.com/a", "https://example.com/b"]);
+    }
+
+    #[test]
+    fn test_from_html() {
+        let html = r#"<p>Hi <abbr>x</abbr><a class="btn" href="https://example.com/?a=1&amp;utm_source=news">Read <b>more</b></a>
+            <A HREF='mailto:me@example.com'>mail</A>
+            <a data-href="https://nope.test" href=https://click.shop.test/x?url=https%3A%2F%2Fshop.test%2Fsale>Sale</a>
+            Plain: https://example.org</p>"#;
+        let links = from_html(html);
+        assert_eq!(links.len(), 3);
+        assert_eq!(links[0].url, "https://example.com/?a=1&utm_source=news");
+        assert_eq!(links[0].text.as_deref(), Some("Read more"));
+        assert_eq!(links[0].tracking_params, ["utm_source"]);
+        assert!(!links[0].tracker);
+        assert_eq!(links[1].text.as_deref(), Some("Sale"));
+        assert!(links[1].tracker);
+        assert_eq!(
+            links[1].redirects_to.as_deref(),
+            Some("https://shop.test/sale")
+        );
+        assert_eq!(links[2].url, "https://example.org");
+        assert_eq!(links[2].text, None);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{Context, Result};
+
+// Disambiguates deliveries made within the same second by this process
+static DELIVERIES: AtomicU64 = AtomicU64::new(0);
+
+/// A Maildir folder (new/cur/tmp) that Gmail messages are exported into.
+///
+/// File names embed the Gmail message ID so re-running an export only
+/// delivers messages that are not already present.
+#[derive(Debug)]
+pub struct Maildir {
+    root: PathBuf,
+    hostname: String,
+}
+
+impl Maildir {
+    /// Open the Maildir at `root`, creating it and its subdirectories if needed.
+    pub fn create(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        for sub in ["tmp", "new", "cur"] {
+            let dir = root.join(sub);
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        Ok(Self {
+            root,
+            hostname: hostname(),
+        })
+    }
+
+    /// Gmail message IDs already delivered to new/ or cur/.
+    pub fn existing_ids(&self) -> Result<HashSet<String>> {
+        let mut ids = HashSet::new();
+        for sub in ["new", "cur"] {
+            let dir = self.root.join(sub);
+            for entry in
+                fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?
+            {
+                if let Some(id) = entry?.file_name().to_str().and_then(gmail_id) {
+                    ids.insert(id.to_string());
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Deliver a message: write it to tmp/, then rename it into new/ or cur/.
+    ///
+    /// Unread messages without other flags go to new/; everything else goes to
+    /// cur/ with its flags in the info suffix, as mutt and notmuch expect.
+    pub fn deliver(&self, id: &str, raw: &[u8], label_ids: &[String]) -> Result<PathBuf> {
+        let name = unique_name(id, &self.hostname);
+        let tmp = self.root.join("tmp").join(&name);
+        fs::write(&tmp, raw).with_context(|| format!("Failed to write {}", tmp.display()))?;
+
+        let flags = flags(label_ids);
+        let dest = if flags.is_empty() {
+            self.root.join("new").join(&name)
+        } else {
+            self.root.join("cur").join(format!("{}:2,{}", name, flags))
+        };
+        fs::rename(&tmp, &dest).with_context(|| format!("Failed to move {}", dest.display()))?;
+        Ok(dest)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+/// Maildir info flags for a message's Gmail labels, in the required ASCII order.
+pub fn flags(label_ids: &[String]) -> String {
+    let has = |label: &str| label_ids.iter().any(|l| l == label);
+    let mut flags = String::new();
+    if has("DRAFT") {
+        flags.push('D');
+    }
+    if has("STARRED") {
+        flags.push('F');
+    }
+    if !has("UNREAD") {
+        flags.push('S');
+    }
+    if has("TRASH") {
+        flags.push('T');
+    }
+    flags
+}
+
+// <seconds>.M<micros>P<pid>Q<n>_<gmail id>.<host>
+fn unique_name(id: &str, hostname: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.M{}P{}Q{}_{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        DELIVERIES.fetch_add(1, Ordering::Relaxed),
+        id,
+        hostname
+    )
+}
+
+/// Extract the Gmail message ID from a file name written by `Maildir::deliver`.
+fn gmail_id(file_name: &str) -> Option<&str> {
+    let (_, rest) = file_name.split_once('_')?;
+    let (id, _) = rest.split_once('.')?;
+    Some(id).filter(|id| !id.is_empty())
+}
+
+// The spec reserves '/' and ':' in file names; dots would confuse `gmail_id`
+fn hostname() -> String {
+    let name = fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_default();
+    let name = name.trim();
+    if name.is_empty() {
+        return "localhost".to_string();
+    }
+    name.replace('/', "\\057")
+        .replace(':', "\\072")
+        .replace('.', "\\056")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(flags(&labels(&["INBOX", "UNREAD"])), "");
+        assert_eq!(flags(&labels(&["INBOX"])), "S");
+        assert_eq!(flags(&labels(&["STARRED", "UNREAD"])), "F");
+        assert_eq!(flags(&labels(&["TRASH", "STARRED", "DRAFT"])), "DFST");
+    }
+
+    #[test]
+    fn test_deliver_and_existing_ids() {
+        let root = std::env::temp_dir().join(format!("gmail-maildir-test-{}", std::process::id()));
+        let maildir = Maildir::create(&root).unwrap();
+
+        let unread = maildir
+            .deliver("18c1", b"Subject: a\r\n\r\n", &labels(&["UNREAD"]))
+            .unwrap();
+        let read = maildir
+            .deliver("18c2", b"Subject: b\r\n\r\n", &labels(&["STARRED"]))
+            .unwrap();
+
+        assert_eq!(unread.parent().unwrap(), root.join("new"));
+        assert_eq!(read.parent().unwrap(), root.join("cur"));
+        assert!(read.to_str().unwrap().ends_with(":2,FS"));
+        assert_eq!(
+            maildir.existing_ids().unwrap(),
+            HashSet::from(["18c1".to_string(), "18c2".to_string()])
+        );
+        assert!(fs::read_dir(root.join("tmp")).unwrap().next().is_none());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
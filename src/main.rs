@@ -1,9 +1,13 @@
-mod api;
-mod auth;
-mod config;
-
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::ArgValueCandidates;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use gmail::table::{self, Table};
+use gmail::{api, auth, authres, config, contacts, date, ics, links, mime, otp, stats, style};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "gmail")]
@@ -13,207 +17,5953 @@ struct Cli {
     #[arg(long, global = true)]
     json: bool,
 
+    /// Directory for config and tokens [default: $GMAIL_CONFIG_DIR or ~/.config/gmail-cli]
+    #[arg(long = "config", global = true, value_name = "DIR")]
+    config_dir: Option<PathBuf>,
+
+    /// Account to use (see `gmail accounts`); defaults to the default account
+    #[arg(long, global = true)]
+    account: Option<String>,
+
+    /// Show the changes that would be made without making them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Don't ask for confirmation before deleting or bulk changes (needed without a terminal)
+    #[arg(short, long, global = true)]
+    yes: bool,
+
+    /// Gmail quota units to spend per second at most; messages.get costs 5, send 100
+    #[arg(long, global = true, value_name = "UNITS", default_value = "250", value_parser = clap::value_parser!(u32).range(1..))]
+    max_qps: u32,
+
+    /// Talk to Gmail directly even when `gmail daemon` is running
+    #[arg(long, global = true)]
+    no_daemon: bool,
+
+    /// Seconds to wait for Gmail to respond before giving up
+    #[arg(long, global = true, value_name = "SECS", default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: u64,
+
+    /// Log API requests to stderr: -v for endpoints and timing, -vv for bodies too
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// When to color output [default: auto, or color from config]
+    #[arg(long, global = true, value_enum)]
+    color: Option<ColorChoice>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Set custom OAuth client ID (optional - has built-in default)
+    /// Show or change settings (client ID, defaults, account)
     Config {
-        /// Client ID (from Google Cloud Console)
-        client_id: String,
+        #[command(subcommand)]
+        command: ConfigCommands,
     },
     /// Authenticate with Gmail (opens browser)
-    Login,
-    /// List available labels
-    Labels,
+    Login {
+        /// Also request full mailbox access (needed for permanent deletion)
+        #[arg(long)]
+        full_access: bool,
+        /// Only request read-only access (also the default with `readonly` set in config)
+        #[arg(long, conflicts_with = "full_access")]
+        readonly: bool,
+        /// Also request Cloud Pub/Sub access (needed for `watch listen`)
+        #[arg(long)]
+        pubsub: bool,
+        /// Also request read-only contacts access (needed for `contacts` and `send --to NAME`)
+        #[arg(long)]
+        contacts: bool,
+        /// Use the device code flow (for machines without a local browser)
+        #[arg(long, conflicts_with = "manual")]
+        device: bool,
+        /// Print the authorization URL and paste the result back (for SSH sessions)
+        #[arg(long)]
+        manual: bool,
+        /// Browser to open, with `%s` for the URL if it goes somewhere other than
+        /// last (default: the `browser` setting, $BROWSER, or the system's)
+        #[arg(long, value_name = "COMMAND", conflicts_with_all = ["device", "manual"])]
+        browser: Option<String>,
+        /// Print the authorization URL instead of opening a browser
+        #[arg(long, conflicts_with_all = ["browser", "device", "manual"])]
+        no_browser: bool,
+        /// Port for the OAuth callback, for clients registered with a fixed redirect URI
+        #[arg(long, conflicts_with_all = ["device", "manual"])]
+        port: Option<u16>,
+        /// Show the consent screen again so Google issues a new refresh token
+        /// (after a revoked login or to add scopes)
+        #[arg(long, conflicts_with_all = ["device", "service_account"])]
+        force: bool,
+        /// Address the OAuth callback server listens on: a loopback address, or
+        /// 0.0.0.0 in a container whose port is forwarded to localhost
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1", conflicts_with_all = ["device", "manual"])]
+        bind: std::net::IpAddr,
+        /// Authenticate with a service account JSON key (Workspace domain-wide delegation)
+        #[arg(long, value_name = "KEY_FILE", requires = "impersonate", conflicts_with_all = ["device", "manual"])]
+        service_account: Option<PathBuf>,
+        /// User whose mailbox the service account acts on
+        #[arg(long, value_name = "EMAIL", requires = "service_account")]
+        impersonate: Option<String>,
+    },
+    /// Revoke tokens with Google and delete them locally (for the selected account)
+    Logout,
+    /// Print a shell completion script, e.g. `source <(gmail completions bash)`
+    ///
+    /// Label names complete from the labels seen by the last `gmail labels` or label lookup.
+    Completions {
+        #[arg(value_parser = ["bash", "zsh", "fish", "powershell", "elvish"])]
+        shell: String,
+    },
+    /// Print the man page, e.g. `gmail man | man -l -`
+    Man,
+    /// Print just the number of matching messages, e.g. for a status bar
+    Count {
+        /// Label to count (inbox, all, or any label name)
+        #[arg(short, long, default_value = "inbox", add = ArgValueCandidates::new(label_candidates))]
+        label: String,
+        /// Count only unread messages
+        #[arg(short, long)]
+        unread: bool,
+        /// Only count messages matching this search query (an estimate from Gmail)
+        #[arg(short, long)]
+        query: Option<String>,
+    },
+    /// Show the authenticated account and mailbox totals
+    Profile,
+    /// Check config, login, permissions, connectivity and clock, and suggest fixes
+    Doctor,
+    /// Keep the login and connections warm for other gmail commands, which use it automatically
+    ///
+    /// Listens on a Unix socket in the account's config directory until interrupted.
+    Daemon,
+    /// List available labels, or create/delete/rename/color them
+    #[command(args_conflicts_with_subcommands = true)]
+    Labels {
+        #[command(subcommand)]
+        command: Option<LabelsCommands>,
+        /// Also show total and unread counts for each label
+        #[arg(long)]
+        counts: bool,
+        /// Show nested labels (Parent/Child) as a tree with counts rolled up per branch
+        #[arg(long)]
+        tree: bool,
+        /// Output format (--json is the same as --format json)
+        #[arg(long)]
+        format: Option<OutputFormat>,
+    },
     /// List messages
     List {
-        /// Maximum number of messages to show
-        #[arg(short = 'n', long, default_value = "100")]
-        max: u32,
+        /// Maximum number of messages to show [default: 100, or max_results from config]
+        #[arg(short = 'n', long, value_parser = clap::value_parser!(u32).range(1..))]
+        max: Option<u32>,
         /// Search query (Gmail search syntax)
         #[arg(short, long)]
         query: Option<String>,
-        /// Label to filter by (inbox, sent, trash, spam, starred, all, drafts)
-        #[arg(short, long, default_value = "inbox")]
-        label: String,
+        /// Use a saved search from config (`gmail config set query.<name> "<query>"`)
+        #[arg(long, value_name = "NAME")]
+        saved: Option<String>,
+        /// Label to filter by (inbox, sent, trash, spam, starred, all, drafts) [default: inbox, or label from config]
+        #[arg(short, long, add = ArgValueCandidates::new(label_candidates))]
+        label: Option<String>,
         /// Show only unread messages
         #[arg(short, long)]
         unread: bool,
+        /// Group results into conversations
+        #[arg(long)]
+        threads: bool,
+        /// Fetch every matching message, ignoring --max
+        #[arg(long, conflicts_with = "threads")]
+        all: bool,
+        /// Batch requests (of 50 messages each) to send in parallel
+        #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
+        /// Columns to show, comma-separated
+        #[arg(long, value_delimiter = ',', default_value = "id,date,from,subject")]
+        columns: Vec<ListColumn>,
+        /// Show dates relative to now ("2h ago", "yesterday")
+        #[arg(long)]
+        relative: bool,
+        /// Output format (--json is the same as --format json)
+        #[arg(long)]
+        format: Option<OutputFormat>,
+        /// Stream one JSON object per line as each message is fetched
+        #[arg(long, conflicts_with_all = ["format", "threads"])]
+        ndjson: bool,
+        #[command(flatten)]
+        search: SearchArgs,
+    },
+    /// Show mailbox changes since a history ID (see `gmail profile`)
+    History {
+        /// History ID to start from
+        #[arg(long)]
+        since: String,
+        /// Only report changes to messages with this label
+        #[arg(short, long, add = ArgValueCandidates::new(label_candidates))]
+        label: Option<String>,
     },
     /// Read a specific message
     Read {
         /// Message ID
-        id: String,
+        #[arg(required_unless_present = "rfc822_id")]
+        id: Option<String>,
+        /// Find the message by its Message-ID header instead
+        #[arg(long, conflicts_with = "id")]
+        rfc822_id: Option<String>,
+        /// Print the exact RFC 822 source instead of the decoded message
+        #[arg(long, conflicts_with = "html")]
+        raw: bool,
+        /// Print the HTML body as-is instead of the rendered text
+        #[arg(long)]
+        html: bool,
+        /// Show this header instead of From/To/Subject/Date (repeatable, e.g. --header List-Id)
+        #[arg(long = "header", visible_alias = "headers", value_name = "NAME", conflicts_with_all = ["raw", "html"])]
+        header: Vec<String>,
+        /// Show every header, e.g. Received and Return-Path when debugging delivery
+        #[arg(long, conflicts_with_all = ["header", "raw", "html"])]
+        full_headers: bool,
+        /// Don't pipe the message through $PAGER
+        #[arg(long)]
+        no_pager: bool,
     },
-    /// Archive a message (remove from inbox)
-    Archive {
+    /// List the links in a message, flagging trackers and redirects (nothing is opened)
+    Urls {
         /// Message ID
         id: String,
     },
-    /// Mark a message as spam
-    Spam {
+    /// Print the verification code from the newest message that has one
+    Otp {
+        /// Only look at mail from this sender (address or domain)
+        #[arg(long)]
+        from: Option<String>,
+        /// How many recent messages (from the last day) to search
+        #[arg(short = 'n', long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+        max: u32,
+        /// Also copy the code to the clipboard
+        #[arg(short, long)]
+        copy: bool,
+    },
+    /// Save the calendar invite in a message as an .ics file
+    Ics {
         /// Message ID
         id: String,
+        /// File to write [default: stdout]
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Print the Gmail ID of the message with an RFC 822 Message-ID
+    FindId {
+        /// Message-ID header value, e.g. '<CA+xyz@mail.example.com>'
+        message_id: String,
+    },
+    /// Archive messages (remove from inbox)
+    Archive {
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Mark messages as spam
+    Spam {
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
     },
     /// Remove from spam and move to inbox
     Unspam {
-        /// Message ID
-        id: String,
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
     },
-    /// Add a label to a message
+    /// Add a label to messages
     Label {
-        /// Message ID
-        id: String,
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
         /// Label to add
+        #[arg(add = ArgValueCandidates::new(label_candidates))]
         label: String,
     },
-    /// Remove a label from a message
+    /// Remove a label from messages
     Unlabel {
-        /// Message ID
-        id: String,
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
         /// Label to remove
+        #[arg(add = ArgValueCandidates::new(label_candidates))]
         label: String,
     },
-    /// Move a message to trash
+    /// Move messages to trash
     Delete {
-        /// Message ID
-        id: String,
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
+        /// Delete forever instead of trashing (needs `gmail login --full-access`;
+        /// asks first, or pass --yes)
+        #[arg(long)]
+        permanent: bool,
     },
-    /// Mark a message as read
+    /// Mark messages as read
     #[command(name = "mark-read")]
     MarkRead {
-        /// Message ID
-        id: String,
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
     },
-    /// Mark a message as unread
+    /// Mark messages as unread
     #[command(name = "mark-unread")]
     MarkUnread {
-        /// Message ID
-        id: String,
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Star messages
+    Star {
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Remove the star from messages
+    Unstar {
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
     },
-    /// Remove all user labels from a message
+    /// Mark messages as important
+    Important {
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Mark messages as not important
+    Unimportant {
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Remove all user labels from messages
     #[command(name = "clear-labels")]
     ClearLabels {
+        /// Message IDs ("-" reads them from stdin)
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Open a message's conversation in Gmail in the browser
+    Open {
         /// Message ID
         id: String,
+        /// Print the URL instead of opening it
+        #[arg(long)]
+        print: bool,
     },
-    /// Unsubscribe from a mailing list (opens unsubscribe link)
+    /// Unsubscribe from a mailing list (one-click or email; opens the web link as a last resort)
+    ///
+    /// With --dry-run, only lists the unsubscribe links the message offers.
+    /// With --from or --query, unsubscribes once per matching sender, using their latest message.
     Unsubscribe {
+        /// Message ID
+        #[arg(required_unless_present_any = ["from", "query"], conflicts_with_all = ["from", "query"])]
+        id: Option<String>,
+        /// Unsubscribe from every list this sender (address or domain) mails you from
+        #[arg(long, conflicts_with = "query")]
+        from: Option<String>,
+        /// Unsubscribe from the sender of each message matching this Gmail search
+        #[arg(short, long)]
+        query: Option<String>,
+        /// Most recent matching messages to look through for senders
+        #[arg(long, default_value = "500", value_parser = clap::value_parser!(u32).range(1..))]
+        max: u32,
+    },
+    /// Compose and send a message
+    Send {
+        /// Send-as alias to send from (see `gmail alias list`)
+        #[arg(long)]
+        from: Option<String>,
+        /// Recipient address, or a contact's name to look up (repeatable)
+        #[arg(long, required_unless_present = "edit")]
+        to: Vec<String>,
+        /// Cc address (repeatable)
+        #[arg(long)]
+        cc: Vec<String>,
+        /// Bcc address (repeatable)
+        #[arg(long)]
+        bcc: Vec<String>,
+        /// Subject line
+        #[arg(short, long, default_value = "")]
+        subject: String,
+        /// Message body (reads stdin if neither --body nor --file is given)
+        #[arg(short, long, conflicts_with = "file")]
+        body: Option<String>,
+        /// Read message body from a file
+        #[arg(short, long)]
+        file: Option<std::path::PathBuf>,
+        /// Attach a file (repeatable)
+        #[arg(short, long)]
+        attach: Vec<std::path::PathBuf>,
+        /// Fill subject and body from a saved template (see `gmail template`)
+        #[arg(short, long)]
+        template: Option<String>,
+        /// Value for a template placeholder, as NAME=VALUE (repeatable)
+        #[arg(long, value_parser = parse_var, requires = "template")]
+        var: Vec<(String, String)>,
+        /// Queue the message in the local outbox and send it at this time
+        /// ("tomorrow 9am", "2025-07-01 09:00"; needs `gmail outbox flush` to run)
+        #[arg(long)]
+        at: Option<String>,
+        /// Write the message in $EDITOR first; sending is aborted if it is left empty
+        #[arg(short, long)]
+        edit: bool,
+    },
+    /// Reply to a message, keeping it in the same thread
+    Reply {
+        /// Message ID to reply to
+        #[arg(required_unless_present = "rfc822_id")]
+        id: Option<String>,
+        /// Find the message to reply to by its Message-ID header instead
+        #[arg(long, conflicts_with = "id")]
+        rfc822_id: Option<String>,
+        /// Send-as alias to reply from (see `gmail alias list`)
+        #[arg(long)]
+        from: Option<String>,
+        /// Reply body (reads stdin if neither --body nor --file is given)
+        #[arg(short, long, conflicts_with = "file")]
+        body: Option<String>,
+        /// Read reply body from a file
+        #[arg(short, long)]
+        file: Option<std::path::PathBuf>,
+        /// Attach a file (repeatable)
+        #[arg(short, long)]
+        attach: Vec<std::path::PathBuf>,
+        /// Write the reply in $EDITOR, below the quoted original; aborted if left empty
+        #[arg(short, long)]
+        edit: bool,
+    },
+    /// Forward a message, including its attachments
+    Forward {
+        /// Message ID to forward
+        id: String,
+        /// Recipient address (repeatable)
+        #[arg(long, required = true)]
+        to: Vec<String>,
+        /// Cc address (repeatable)
+        #[arg(long)]
+        cc: Vec<String>,
+        /// Note to put above the forwarded message
+        #[arg(short, long, conflicts_with = "file")]
+        body: Option<String>,
+        /// Read the note from a file
+        #[arg(short, long)]
+        file: Option<std::path::PathBuf>,
+    },
+    /// Download attachments from a message
+    Attachment {
         /// Message ID
         id: String,
+        /// Only download the attachment with this part ID (see `gmail read`)
+        #[arg(short, long)]
+        part: Option<String>,
+        /// Directory to save attachments into
+        #[arg(short, long, default_value = ".")]
+        out: std::path::PathBuf,
+    },
+    /// Download attachments from many messages at once
+    Attachments {
+        #[command(subcommand)]
+        command: AttachmentsCommands,
+    },
+    /// Apply an action to every message matching a query
+    Sweep {
+        /// Search query selecting the messages (Gmail search syntax)
+        #[arg(short, long)]
+        query: Option<String>,
+        #[command(flatten)]
+        search: SearchArgs,
+        /// Action to apply
+        #[arg(short, long, value_enum)]
+        action: SweepAction,
+        /// Label to add or remove (for the label and unlabel actions)
+        #[arg(short, long, required_if_eq_any([("action", "label"), ("action", "unlabel")]), add = ArgValueCandidates::new(label_candidates))]
+        label: Option<String>,
+    },
+    /// Send all future mail from a sender straight to trash with a filter
+    Block {
+        /// Sender address or domain (e.g. spam@example.com, example.com)
+        sender: String,
+        /// Also move the sender's existing mail to trash
+        #[arg(long)]
+        trash_existing: bool,
+    },
+    /// Permanently delete everything in the trash (needs `gmail login --full-access`)
+    #[command(name = "empty-trash")]
+    EmptyTrash,
+    /// Permanently delete everything in spam (needs `gmail login --full-access`)
+    #[command(name = "empty-spam")]
+    EmptySpam,
+    /// Permanently delete every message matching a query (bypasses trash)
+    Purge {
+        /// Search query selecting the messages (Gmail search syntax)
+        #[arg(short, long)]
+        query: String,
+        /// Confirm that matching messages should be deleted forever
+        #[arg(long)]
+        yes_permanently_delete: bool,
+    },
+    /// Work with whole conversations
+    Thread {
+        #[command(subcommand)]
+        command: ThreadCommands,
+    },
+    /// Manage Gmail filters
+    Filter {
+        #[command(subcommand)]
+        command: FilterCommands,
+    },
+    /// Show or change the vacation auto-responder
+    Vacation {
+        #[command(subcommand)]
+        command: VacationCommands,
+    },
+    /// Manage send-as aliases and signatures
+    Alias {
+        #[command(subcommand)]
+        command: AliasCommands,
+    },
+    /// Manage forwarding addresses and automatic forwarding
+    Forwarding {
+        #[command(subcommand)]
+        command: ForwardingCommands,
+    },
+    /// Show or change account settings
+    Settings {
+        #[command(subcommand)]
+        command: SettingsCommands,
+    },
+    /// Poll for new messages and run a command for each, or manage Pub/Sub push notifications
+    #[command(args_conflicts_with_subcommands = true)]
+    Watch {
+        #[command(subcommand)]
+        command: Option<WatchCommands>,
+        /// Seconds between polls
+        #[arg(long, default_value = "60")]
+        interval: u64,
+        /// Only trigger for messages matching this Gmail search query
+        #[arg(short, long)]
+        query: Option<String>,
+        /// Only trigger for messages with this label
+        #[arg(short, long, default_value = "INBOX", add = ArgValueCandidates::new(label_candidates))]
+        label: String,
+        /// Command to run per new message ({id} and {thread} are substituted)
+        #[arg(long)]
+        exec: Option<String>,
+    },
+    /// Manage multiple accounts
+    Accounts {
+        #[command(subcommand)]
+        command: AccountsCommands,
+    },
+    /// Import .eml files or mbox archives into the mailbox without sending them
+    ///
+    /// Messages whose Message-ID is already in the mailbox are skipped, so an
+    /// interrupted import can simply be run again.
+    Import {
+        /// Files to import (one message per file unless --mbox is given)
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Treat the files as mbox archives holding many messages
+        #[arg(long)]
+        mbox: bool,
+        /// Label to apply to imported messages (repeatable; created if missing)
+        #[arg(short, long)]
+        label: Vec<String>,
+        /// Where Gmail takes the message date from
+        #[arg(long, value_enum, default_value = "header")]
+        date_source: DateSource,
+        /// Never classify imported messages as spam
+        #[arg(long)]
+        never_mark_spam: bool,
+    },
+    /// Go through messages one at a time, acting on each with a single key
+    Triage {
+        /// Messages to go through (Gmail search syntax)
+        #[arg(short, long, default_value = "in:inbox is:unread")]
+        query: String,
+        /// Maximum number of messages
+        #[arg(short, long, default_value = "100", value_parser = clap::value_parser!(u32).range(1..))]
+        max: u32,
+    },
+    /// Export messages for use with local mail tools
+    Export {
+        #[command(subcommand)]
+        command: ExportCommands,
+    },
+    /// Bring the local message cache up to date (full pull first, then only changes)
+    Sync {
+        /// Fetch the whole mailbox again instead of only what changed
+        #[arg(long)]
+        full: bool,
+        /// Fetch whole messages so `search-local` can search their bodies
+        #[arg(long)]
+        bodies: bool,
+        /// Parallel requests while fetching messages
+        #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
+    },
+    /// Saved subjects and bodies with {placeholders}, for `send --template`
+    Template {
+        #[command(subcommand)]
+        command: TemplateCommands,
+    },
+    /// Reports on who fills the mailbox
+    Stats {
+        #[command(subcommand)]
+        command: StatsCommands,
+    },
+    /// Look up people in your Google contacts (needs `gmail login --contacts`)
+    Contacts {
+        #[command(subcommand)]
+        command: ContactsCommands,
+    },
+    /// Messages queued with `send --at`
+    Outbox {
+        #[command(subcommand)]
+        command: OutboxCommands,
+    },
+    /// Archive messages until a given time, then bring them back with `snooze wake`
+    #[command(args_conflicts_with_subcommands = true)]
+    Snooze {
+        #[command(subcommand)]
+        command: Option<SnoozeCommands>,
+        /// Message IDs ("-" reads them from stdin)
+        ids: Vec<String>,
+        /// When to bring them back: "monday 9am", "tomorrow", "in 3 days", "2025-07-01 09:00"
+        #[arg(short, long)]
+        until: Option<String>,
+    },
+    /// Re-runnable filters: apply rules from a TOML file to existing mail
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommands,
+    },
+    /// Search messages in the local cache without going online (see `gmail sync`)
+    SearchLocal {
+        /// Words to find in subjects, senders, recipients and bodies (`word*` for a prefix)
+        #[arg(required = true)]
+        query: Vec<String>,
+        /// Maximum number of results
+        #[arg(short, long, default_value = "50")]
+        max: u32,
+        /// Columns to show, comma-separated
+        #[arg(long, value_delimiter = ',', default_value = "id,date,from,subject")]
+        columns: Vec<ListColumn>,
+    },
+    /// Reverse the most recent label changes, trashes and archives
+    Undo {
+        /// Number of changes to reverse
+        #[arg(default_value = "1")]
+        count: usize,
+        /// Show recorded changes instead of undoing them
+        #[arg(long)]
+        list: bool,
+    },
+    /// Manage drafts
+    Draft {
+        #[command(subcommand)]
+        command: DraftCommands,
     },
 }
 
-fn normalize_label(label: &str) -> String {
-    match label.to_lowercase().as_str() {
-        "inbox" => "INBOX".to_string(),
-        "sent" => "SENT".to_string(),
-        "trash" => "TRASH".to_string(),
-        "spam" => "SPAM".to_string(),
-        "starred" => "STARRED".to_string(),
-        "unread" => "UNREAD".to_string(),
-        "important" => "IMPORTANT".to_string(),
-        "drafts" | "draft" => "DRAFT".to_string(),
-        "all" => "".to_string(),
-        "primary" => "CATEGORY_PERSONAL".to_string(),
-        "social" => "CATEGORY_SOCIAL".to_string(),
-        "promotions" => "CATEGORY_PROMOTIONS".to_string(),
-        "updates" => "CATEGORY_UPDATES".to_string(),
-        "forums" => "CATEGORY_FORUMS".to_string(),
-        other => other.to_string(), // Keep custom labels as-is
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// Aligned table
+    Text,
+    Json,
+    /// Comma-separated values with a header row
+    Csv,
+    /// Tab-separated values with a header row
+    Tsv,
+}
+
+impl OutputFormat {
+    fn resolve(format: Option<OutputFormat>, json: bool) -> OutputFormat {
+        match format {
+            Some(format) => format,
+            None if json => OutputFormat::Json,
+            None => OutputFormat::Text,
+        }
+    }
+
+    fn print_table(self, table: &Table) {
+        match self {
+            OutputFormat::Csv => print!("{}", table.render_delimited(',')),
+            OutputFormat::Tsv => print!("{}", table.render_delimited('\t')),
+            OutputFormat::Text | OutputFormat::Json => {
+                print!("{}", table.render(table::terminal_width()))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ColorChoice {
+    /// Color when writing to a terminal and NO_COLOR is unset
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        use std::io::IsTerminal;
+        match self {
+            ColorChoice::Auto => {
+                std::io::stdout().is_terminal()
+                    && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ListColumn {
+    Id,
+    Thread,
+    Date,
+    From,
+    To,
+    Subject,
+    Snippet,
+    Labels,
+}
+
+impl ListColumn {
+    fn header(self) -> &'static str {
+        match self {
+            ListColumn::Id => "ID",
+            ListColumn::Thread => "THREAD",
+            ListColumn::Date => "DATE",
+            ListColumn::From => "FROM",
+            ListColumn::To => "TO",
+            ListColumn::Subject => "SUBJECT",
+            ListColumn::Snippet => "SNIPPET",
+            ListColumn::Labels => "LABELS",
+        }
+    }
+
+    fn value(self, msg: &api::Message, relative: bool) -> String {
+        match self {
+            ListColumn::Id => msg.id.clone(),
+            ListColumn::Thread => msg.thread_id.clone().unwrap_or_default(),
+            ListColumn::Date => match msg.date() {
+                Some(dt) if relative => date::format_relative(dt, chrono::Local::now()),
+                Some(dt) => date::format_short(dt),
+                None => msg.get_header("Date").unwrap_or_default().to_string(),
+            },
+            ListColumn::From => msg.get_header("From").unwrap_or("Unknown").to_string(),
+            ListColumn::To => msg.get_header("To").unwrap_or_default().to_string(),
+            ListColumn::Subject => msg
+                .get_header("Subject")
+                .unwrap_or("(no subject)")
+                .to_string(),
+            ListColumn::Snippet => msg.snippet.clone().unwrap_or_default(),
+            ListColumn::Labels => msg.label_ids.as_deref().unwrap_or_default().join(","),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DateSource {
+    /// The message's Date header
+    Header,
+    /// The time of import
+    Received,
+}
+
+impl DateSource {
+    fn api_value(self) -> &'static str {
+        match self {
+            DateSource::Header => "dateHeader",
+            DateSource::Received => "receivedTime",
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SweepAction {
+    Archive,
+    Delete,
+    Spam,
+    MarkRead,
+    MarkUnread,
+    Label,
+    Unlabel,
+}
+
+#[derive(Subcommand)]
+enum AttachmentsCommands {
+    /// Save every attachment of the messages matching a query
+    ///
+    /// Files are named `DATE_SENDER_MESSAGEID_FILENAME`; ones already in the directory are skipped.
+    Download {
+        /// Search query selecting the messages, e.g. "from:billing filename:pdf"
+        #[arg(short, long)]
+        query: String,
+        /// Directory to save attachments into
+        #[arg(short, long, default_value = ".")]
+        out: std::path::PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ThreadCommands {
+    /// Read every message in a thread, oldest first
+    Read {
+        /// Thread ID
+        id: String,
+    },
+    /// Archive a whole thread
+    Archive {
+        /// Thread ID
+        id: String,
+    },
+    /// Move a whole thread to trash
+    Delete {
+        /// Thread ID
+        id: String,
+    },
+    /// Add a label to every message in a thread
+    Label {
+        /// Thread ID
+        id: String,
+        /// Label to add
+        #[arg(add = ArgValueCandidates::new(label_candidates))]
+        label: String,
+    },
+    /// Remove a label from every message in a thread
+    Unlabel {
+        /// Thread ID
+        id: String,
+        /// Label to remove
+        #[arg(add = ArgValueCandidates::new(label_candidates))]
+        label: String,
+    },
+    /// Mark a whole thread as read
+    #[command(name = "mark-read")]
+    MarkRead {
+        /// Thread ID
+        id: String,
+    },
+    /// Mark a whole thread as unread
+    #[command(name = "mark-unread")]
+    MarkUnread {
+        /// Thread ID
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum FilterCommands {
+    /// List filters
+    List,
+    /// Create a filter
+    Create {
+        /// Match sender
+        #[arg(long)]
+        from: Option<String>,
+        /// Match recipient
+        #[arg(long)]
+        to: Option<String>,
+        /// Match subject
+        #[arg(long)]
+        subject: Option<String>,
+        /// Match a Gmail search query
+        #[arg(short, long)]
+        query: Option<String>,
+        /// Only match messages with attachments
+        #[arg(long)]
+        has_attachment: bool,
+        /// Apply a label (created if missing)
+        #[arg(long)]
+        add_label: Option<String>,
+        /// Skip the inbox
+        #[arg(long)]
+        archive: bool,
+        /// Mark as read
+        #[arg(long)]
+        mark_read: bool,
+        /// Star the message
+        #[arg(long)]
+        star: bool,
+        /// Move to trash
+        #[arg(long)]
+        delete: bool,
+        /// Never send to spam
+        #[arg(long)]
+        never_spam: bool,
+    },
+    /// Delete a filter
+    Delete {
+        /// Filter ID
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum VacationCommands {
+    /// Show the current auto-reply settings
+    Show,
+    /// Enable the auto-reply with a new message
+    Set {
+        /// Auto-reply subject
+        #[arg(short, long)]
+        subject: String,
+        /// Auto-reply text (reads stdin if neither --message nor --file is given)
+        #[arg(short, long, conflicts_with = "file")]
+        message: Option<String>,
+        /// Read auto-reply text from a file
+        #[arg(short, long)]
+        file: Option<std::path::PathBuf>,
+        /// First day to send replies (YYYY-MM-DD or YYYY-MM-DD HH:MM, local time)
+        #[arg(long)]
+        start: Option<String>,
+        /// Last day to send replies, inclusive (YYYY-MM-DD), or the moment to stop
+        /// (YYYY-MM-DD HH:MM), in local time
+        #[arg(long)]
+        end: Option<String>,
+        /// Only reply to people in your contacts
+        #[arg(long)]
+        contacts_only: bool,
+        /// Only reply to people in your domain (Workspace accounts)
+        #[arg(long)]
+        domain_only: bool,
+    },
+    /// Turn the auto-reply off, keeping its message
+    Disable,
+}
+
+#[derive(Subcommand)]
+enum AliasCommands {
+    /// List send-as aliases
+    List,
+    /// Show an alias, including its signature
+    Show {
+        /// Alias email address
+        email: String,
+    },
+    /// Make an alias the default From address
+    #[command(name = "set-default")]
+    SetDefault {
+        /// Alias email address
+        email: String,
+    },
+    /// Replace an alias's signature (HTML allowed)
+    #[command(name = "update-signature")]
+    UpdateSignature {
+        /// Alias email address
+        email: String,
+        /// Signature (reads stdin if neither --signature nor --file is given)
+        #[arg(short, long, conflicts_with = "file")]
+        signature: Option<String>,
+        /// Read the signature from a file
+        #[arg(short, long)]
+        file: Option<std::path::PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ForwardingCommands {
+    /// List forwarding addresses and the auto-forwarding state
+    List,
+    /// Register a forwarding address (Gmail sends it a verification email)
+    Add {
+        /// Address to forward to
+        email: String,
+    },
+    /// Forward all incoming mail to a verified address
+    Enable {
+        /// Verified forwarding address
+        email: String,
+        /// What to do with the original message
+        #[arg(long, value_enum, default_value = "keep")]
+        disposition: ForwardDisposition,
+    },
+    /// Stop forwarding incoming mail
+    Disable,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ForwardDisposition {
+    Keep,
+    Archive,
+    Trash,
+    MarkRead,
+}
+
+impl ForwardDisposition {
+    fn api_value(self) -> &'static str {
+        match self {
+            Self::Keep => "leaveInInbox",
+            Self::Archive => "archive",
+            Self::Trash => "trash",
+            Self::MarkRead => "markRead",
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum SettingsCommands {
+    /// IMAP access settings
+    Imap {
+        #[command(subcommand)]
+        command: ImapCommands,
+    },
+    /// POP access settings
+    Pop {
+        #[command(subcommand)]
+        command: PopCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImapCommands {
+    /// Show IMAP settings
+    Show,
+    /// Enable IMAP, optionally changing its behavior
+    Enable {
+        /// What happens to messages deleted over IMAP
+        #[arg(long, value_enum)]
+        expunge: Option<ExpungeBehavior>,
+        /// Expunge immediately instead of waiting for the client
+        #[arg(long)]
+        auto_expunge: Option<bool>,
+        /// Maximum messages per folder (0, 1000, 2000, 5000 or 10000; 0 means no limit)
+        #[arg(long)]
+        max_folder_size: Option<u32>,
+    },
+    /// Disable IMAP
+    Disable,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExpungeBehavior {
+    Archive,
+    Trash,
+    DeleteForever,
+}
+
+impl ExpungeBehavior {
+    fn api_value(self) -> &'static str {
+        match self {
+            Self::Archive => "archive",
+            Self::Trash => "trash",
+            Self::DeleteForever => "deleteForever",
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum PopCommands {
+    /// Show POP settings
+    Show,
+    /// Enable POP
+    Enable {
+        /// Which messages POP clients can download
+        #[arg(long, value_enum, default_value = "from-now-on")]
+        window: PopWindow,
+        /// What happens to messages after a POP client downloads them
+        #[arg(long, value_enum, default_value = "keep")]
+        disposition: ForwardDisposition,
+    },
+    /// Disable POP
+    Disable,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PopWindow {
+    FromNowOn,
+    AllMail,
+}
+
+impl PopWindow {
+    fn api_value(self) -> &'static str {
+        match self {
+            Self::FromNowOn => "fromNowOn",
+            Self::AllMail => "allMail",
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum WatchCommands {
+    /// Start publishing mailbox changes to a Pub/Sub topic (renew at least weekly)
+    Start {
+        /// Topic name (projects/<project>/topics/<topic>)
+        #[arg(long)]
+        topic: String,
+        /// Only notify for changes to these labels (repeatable)
+        #[arg(short, long)]
+        label: Vec<String>,
+    },
+    /// Stop push notifications
+    Stop,
+    /// Pull notifications from a subscription and print new messages as they arrive
+    Listen {
+        /// Subscription name (projects/<project>/subscriptions/<subscription>)
+        #[arg(long)]
+        subscription: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Change a setting (applies to all accounts unless --account is given)
+    Set {
+        /// Setting name (see `gmail config list`)
+        key: String,
+        value: String,
+    },
+    /// Print a setting's value
+    Get {
+        /// Setting name
+        key: String,
+    },
+    /// List all settings and their values
+    List,
+    /// Open the config file in $EDITOR
+    Edit,
+}
+
+#[derive(Subcommand)]
+enum AccountsCommands {
+    /// List configured accounts (* marks the default)
+    List,
+    /// Add an account and log in to it
+    Add {
+        /// Account name (e.g. personal, work)
+        name: String,
+        /// Also request full mailbox access (needed for permanent deletion)
+        #[arg(long)]
+        full_access: bool,
+    },
+    /// Remove an account's stored tokens and config
+    Remove {
+        /// Account name
+        name: String,
+    },
+    /// Set the account used when --account is not given
+    Default {
+        /// Account name
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum LabelsCommands {
+    /// Create a label (nested names like Parent/Child create missing parents)
+    Create {
+        /// Label name
+        name: String,
+        #[command(flatten)]
+        color: LabelColorArgs,
+    },
+    /// Delete a label (messages keep their other labels)
+    Delete {
+        /// Label name
+        name: String,
+    },
+    /// Rename a label and its nested children
+    Rename {
+        /// Current label name
+        name: String,
+        /// New label name
+        new_name: String,
+    },
+    /// Set a label's colors (Gmail only accepts colors from its palette)
+    Color {
+        /// Label name
+        name: String,
+        #[command(flatten)]
+        color: LabelColorArgs,
+    },
+}
+
+/// Search flags that spell out Gmail operators, combined with any -q query.
+#[derive(clap::Args)]
+struct SearchArgs {
+    /// Only messages from this sender (address, name or domain)
+    #[arg(long)]
+    from: Option<String>,
+    /// Only messages to this recipient
+    #[arg(long)]
+    to: Option<String>,
+    /// Only messages with these words in the subject
+    #[arg(long)]
+    subject: Option<String>,
+    /// Only messages after this date ("2025-01-31", "yesterday", "2 weeks ago", "monday")
+    #[arg(long)]
+    after: Option<String>,
+    /// Only messages before this date (same formats as --after)
+    #[arg(long)]
+    before: Option<String>,
+    /// Only messages with attachments
+    #[arg(long)]
+    has_attachment: bool,
+    /// Only messages larger than this size (bytes, or with K/M: "5M")
+    #[arg(long, value_parser = parse_size)]
+    larger: Option<String>,
+    /// Only messages smaller than this size
+    #[arg(long, value_parser = parse_size)]
+    smaller: Option<String>,
+    /// Only messages with an attachment of this name or type ("pdf", "report.xlsx")
+    #[arg(long)]
+    filename: Option<String>,
+}
+
+impl SearchArgs {
+    /// The flags as Gmail search terms, after the terms of `query`.
+    fn to_query(&self, query: Option<String>) -> Result<Option<String>> {
+        let now = chrono::Local::now();
+        let mut built = api::Query::new().raw(query.unwrap_or_default());
+        if let Some(from) = &self.from {
+            built = built.from(from);
+        }
+        if let Some(to) = &self.to {
+            built = built.to(to);
+        }
+        if let Some(subject) = &self.subject {
+            built = built.subject(subject);
+        }
+        if let Some(after) = &self.after {
+            built = built.after(&date::parse_past(after, now)?);
+        }
+        if let Some(before) = &self.before {
+            built = built.before(&date::parse_past(before, now)?);
+        }
+        if self.has_attachment {
+            built = built.has_attachment();
+        }
+        // Sizes keep their K/M suffix, which Query's byte counts can't express
+        if let Some(size) = &self.larger {
+            built = built.raw(format!("larger:{}", size));
+        }
+        if let Some(size) = &self.smaller {
+            built = built.raw(format!("smaller:{}", size));
+        }
+        if let Some(filename) = &self.filename {
+            built = built.filename(filename);
+        }
+        Ok((!built.is_empty()).then(|| built.to_string()))
+    }
+}
+
+// Gmail sizes: a byte count, optionally with a K or M suffix
+fn parse_size(s: &str) -> Result<String, String> {
+    let digits = s.trim_end_matches(['k', 'K', 'm', 'M']);
+    if digits.is_empty()
+        || !digits.chars().all(|c| c.is_ascii_digit())
+        || s.len() - digits.len() > 1
+    {
+        return Err(format!(
+            "expected a size like 500000, 100K or 5M, got '{}'",
+            s
+        ));
+    }
+    Ok(s.to_uppercase())
+}
+
+#[derive(clap::Args)]
+struct LabelColorArgs {
+    /// Background color as hex, e.g. "#fb4c2f"
+    #[arg(long, requires = "text")]
+    background: Option<String>,
+    /// Text color as hex, e.g. "#ffffff"
+    #[arg(long, requires = "background")]
+    text: Option<String>,
+}
+
+impl LabelColorArgs {
+    fn to_color(&self) -> Result<Option<api::LabelColor>> {
+        let (Some(background), Some(text)) = (&self.background, &self.text) else {
+            return Ok(None);
+        };
+        for value in [background, text] {
+            let valid = value.len() == 7
+                && value.starts_with('#')
+                && value[1..].chars().all(|c| c.is_ascii_hexdigit());
+            if !valid {
+                anyhow::bail!("Invalid color '{}': expected hex like #fb4c2f", value);
+            }
+        }
+        Ok(Some(api::LabelColor {
+            text_color: text.to_lowercase(),
+            background_color: background.to_lowercase(),
+        }))
+    }
+}
+
+#[derive(Subcommand)]
+enum ExportCommands {
+    /// Save a message's exact RFC 822 source as an .eml file
+    Eml {
+        /// Message ID
+        id: String,
+        /// File to write (defaults to stdout)
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Write messages to a Maildir (new/cur/tmp), skipping ones already exported
+    Maildir {
+        /// Maildir to write to (created if missing)
+        #[arg(short, long)]
+        out: PathBuf,
+        /// Label to export (use "all" for every message)
+        #[arg(short, long, default_value = "inbox", add = ArgValueCandidates::new(label_candidates))]
+        label: String,
+        /// Only export messages matching this search query
+        #[arg(short, long)]
+        query: Option<String>,
+        /// Number of messages to download in parallel
+        #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum TemplateCommands {
+    /// Save a template (replaces one with the same name)
+    Add {
+        name: String,
+        /// Subject line, may contain {placeholders}
+        #[arg(short, long, default_value = "")]
+        subject: String,
+        /// Body (reads stdin if neither --body nor --file is given)
+        #[arg(short, long, conflicts_with = "file")]
+        body: Option<String>,
+        /// Read the body from a file
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+    },
+    /// List templates and their placeholders
+    List,
+    /// Print a template filled in, without sending anything
+    Use {
+        name: String,
+        /// Value for a placeholder, as NAME=VALUE (repeatable)
+        #[arg(long, value_parser = parse_var)]
+        var: Vec<(String, String)>,
+    },
+    /// Delete a template
+    Remove { name: String },
+}
+
+/// Parse `NAME=VALUE` for `--var`.
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", s))
+}
+
+#[derive(Subcommand)]
+enum StatsCommands {
+    /// Count messages and their total size per sender
+    Senders {
+        /// Only count messages matching this Gmail search (default: all mail)
+        #[arg(short, long)]
+        query: Option<String>,
+        /// Number of senders to show
+        #[arg(long, default_value = "20")]
+        top: usize,
+        /// Group senders by domain instead of by address
+        #[arg(long)]
+        by_domain: bool,
+        /// Parallel requests while fetching messages
+        #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
+    },
+    /// Estimate storage per label and list the biggest messages
+    Usage {
+        /// Newest messages sampled per label to estimate its size
+        #[arg(long, default_value = "100", conflicts_with = "full")]
+        sample: u32,
+        /// Fetch the size of every message instead of sampling (slow on big mailboxes)
+        #[arg(long)]
+        full: bool,
+        /// Number of biggest messages to list
+        #[arg(long, default_value = "10")]
+        biggest: usize,
+        /// Parallel requests while fetching messages
+        #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum ContactsCommands {
+    /// Find contacts by name or email prefix
+    Search {
+        /// Name or address to search for
+        query: String,
+        /// Maximum number of contacts (at most 30)
+        #[arg(short = 'n', long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+        max: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum OutboxCommands {
+    /// Show queued messages and when they go out
+    List,
+    /// Send every message whose time has come (run from cron)
+    Flush,
+    /// Remove a queued message without sending it
+    Cancel {
+        /// Queue ID from `gmail outbox list`
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SnoozeCommands {
+    /// Return messages whose snooze is over to the inbox, unread (run from cron)
+    Wake,
+    /// Show snoozed messages by wake-up time
+    List,
+}
+
+#[derive(Subcommand)]
+enum RulesCommands {
+    /// Apply every rule to matching messages in the inbox
+    Apply {
+        /// Rules file (default: rules.toml in the config directory)
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+        /// Only apply the rule with this name
+        #[arg(short, long)]
+        rule: Option<String>,
+        /// Match messages anywhere, not just in the inbox
+        #[arg(long)]
+        all_mail: bool,
+    },
+    /// Show each rule with the search query it runs
+    List {
+        /// Rules file (default: rules.toml in the config directory)
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum DraftCommands {
+    /// Create a new draft
+    Create {
+        /// Recipient address (repeatable)
+        #[arg(long)]
+        to: Vec<String>,
+        /// Cc address (repeatable)
+        #[arg(long)]
+        cc: Vec<String>,
+        /// Bcc address (repeatable)
+        #[arg(long)]
+        bcc: Vec<String>,
+        /// Subject line
+        #[arg(short, long, default_value = "")]
+        subject: String,
+        /// Draft body (reads stdin if neither --body nor --file is given)
+        #[arg(short, long, conflicts_with = "file")]
+        body: Option<String>,
+        /// Read draft body from a file
+        #[arg(short, long)]
+        file: Option<std::path::PathBuf>,
+        /// Attach a file (repeatable)
+        #[arg(short, long)]
+        attach: Vec<std::path::PathBuf>,
+    },
+    /// List drafts
+    List {
+        /// Maximum number of drafts to show
+        #[arg(short = 'n', long, default_value = "100", value_parser = clap::value_parser!(u32).range(1..))]
+        max: u32,
+    },
+    /// Show a draft
+    Show {
+        /// Draft ID
+        id: String,
+    },
+    /// Replace fields of an existing plain-text draft
+    Edit {
+        /// Draft ID
+        id: String,
+        /// Replace recipients (repeatable)
+        #[arg(long)]
+        to: Vec<String>,
+        /// Replace Cc recipients (repeatable)
+        #[arg(long)]
+        cc: Vec<String>,
+        /// Replace subject line
+        #[arg(short, long)]
+        subject: Option<String>,
+        /// Replace body
+        #[arg(short, long, conflicts_with = "file")]
+        body: Option<String>,
+        /// Replace body with the contents of a file
+        #[arg(short, long)]
+        file: Option<std::path::PathBuf>,
+    },
+    /// Send a draft
+    Send {
+        /// Draft ID
+        id: String,
+    },
+    /// Delete a draft permanently
+    Delete {
+        /// Draft ID
+        id: String,
+    },
+}
+
+fn normalize_label(label: &str) -> String {
+    match label.to_lowercase().as_str() {
+        "inbox" => "INBOX".to_string(),
+        "sent" => "SENT".to_string(),
+        "trash" => "TRASH".to_string(),
+        "spam" => "SPAM".to_string(),
+        "starred" => "STARRED".to_string(),
+        "unread" => "UNREAD".to_string(),
+        "important" => "IMPORTANT".to_string(),
+        "drafts" | "draft" => "DRAFT".to_string(),
+        "all" => "".to_string(),
+        "primary" => "CATEGORY_PERSONAL".to_string(),
+        "social" => "CATEGORY_SOCIAL".to_string(),
+        "promotions" => "CATEGORY_PROMOTIONS".to_string(),
+        "updates" => "CATEGORY_UPDATES".to_string(),
+        "forums" => "CATEGORY_FORUMS".to_string(),
+        other => other.to_string(), // Keep custom labels as-is
+    }
+}
+
+/// Messages in a label, exact from the label's own counts (one cheap request)
+/// unless a query needs Gmail's search estimate instead.
+async fn count_messages(
+    client: &api::Client,
+    label_id: &str,
+    unread: bool,
+    query: Option<String>,
+) -> Result<u64> {
+    if let Some(query) = query {
+        let query = if unread {
+            format!("is:unread {}", query)
+        } else {
+            query
+        };
+        let page = client
+            .list_messages_page(Some(&query), label_id, 1, None)
+            .await?;
+        return Ok(page.result_size_estimate.unwrap_or(0).into());
+    }
+    let (label_id, unread) = match label_id {
+        "" if !unread => return Ok(client.get_profile().await?.messages_total),
+        // Across all mail, the unread messages are exactly those labeled UNREAD
+        "" => ("UNREAD", false),
+        id => (id, unread),
+    };
+    let id = client.resolve_label_for_remove(label_id).await?;
+    let label = client.get_label(&id).await?;
+    let count = if unread {
+        label.messages_unread
+    } else {
+        label.messages_total
+    };
+    Ok(count.unwrap_or(0).into())
+}
+
+/// The Gmail ID given directly, or else looked up from an RFC 822 Message-ID.
+async fn message_id_arg(
+    client: &api::Client,
+    id: Option<String>,
+    rfc822_id: Option<&str>,
+) -> Result<String> {
+    if let Some(id) = id {
+        return Ok(id);
+    }
+    let rfc822_id = rfc822_id.context("A message ID or --rfc822-id is required")?;
+    client
+        .find_by_rfc822_id(rfc822_id)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No message with Message-ID {}", rfc822_id))
+}
+
+/// Replace a "-" argument with the IDs piped in on stdin.
+fn read_ids(args: Vec<String>) -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for arg in args {
+        if arg == "-" {
+            let input = std::io::read_to_string(std::io::stdin())
+                .context("Failed to read message IDs from stdin")?;
+            ids.extend(gmail::ids::parse_id_list(&input));
+        } else {
+            ids.push(arg);
+        }
+    }
+    if ids.is_empty() {
+        anyhow::bail!("No message IDs given");
+    }
+    Ok(ids)
+}
+
+/// Name a set of messages in confirmation output: the ID itself, or a count.
+fn describe_ids(ids: &[String]) -> String {
+    match ids {
+        [id] => id.clone(),
+        _ => format!("{} messages", format_count(ids.len())),
+    }
+}
+
+/// `1234567` as `1,234,567`.
+fn format_count(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Ask before a destructive change, failing if the answer isn't yes.
+///
+/// `--yes` and `--dry-run` skip the question. It is asked on the terminal
+/// whatever stdin and stdout are redirected to, so piped IDs can't answer it;
+/// without a terminal only `--yes` goes ahead.
+fn confirm(question: &str) -> Result<()> {
+    use std::io::{BufRead, Write};
+
+    if ASSUME_YES.load(Ordering::Relaxed) || DRY_RUN.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let Ok(tty) = std::fs::File::open("/dev/tty") else {
+        anyhow::bail!("{} No terminal to ask on; pass --yes to go ahead", question);
+    };
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::BufReader::new(tty).read_line(&mut answer)?;
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        anyhow::bail!("Aborted");
+    }
+    Ok(())
+}
+
+fn read_body(body: Option<String>, file: Option<&std::path::Path>) -> Result<String> {
+    if let Some(body) = body {
+        return Ok(body);
+    }
+    if let Some(path) = file {
+        return std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()));
+    }
+    let mut buf = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut buf)
+        .context("Failed to read body from stdin")?;
+    Ok(buf)
+}
+
+/// Let the user edit `email` in $EDITOR; an empty file aborts.
+fn edit_email(email: &mut mime::Email) -> Result<()> {
+    use std::io::Write;
+
+    let (path, mut file) = create_temp_file("gmail-compose", "eml")?;
+    let written = file
+        .write_all(email.to_editable().as_bytes())
+        .with_context(|| format!("Failed to write {}", path.display()));
+    drop(file);
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    let edited = run_editor(&path).and_then(|()| {
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))
+    });
+    let _ = std::fs::remove_file(&path);
+    let edited = edited?;
+    if edited.trim().is_empty() {
+        anyhow::bail!("Aborted: the message was left empty");
+    }
+    Ok(email.apply_edited(&edited)?)
+}
+
+/// Create a new file in the temp directory that only this user can read.
+///
+/// The file must not exist yet (O_EXCL), so another user can't plant one under
+/// the name, or a symlink, and read what's written to it.
+fn create_temp_file(prefix: &str, extension: &str) -> Result<(PathBuf, std::fs::File)> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let dir = std::env::temp_dir();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    for attempt in 0..100 {
+        let path = dir.join(format!(
+            "{}-{}-{}-{}.{}",
+            prefix,
+            std::process::id(),
+            nanos,
+            attempt,
+            extension
+        ));
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {}", path.display()));
+            }
+        }
+    }
+    anyhow::bail!("Failed to create a temporary file in {}", dir.display())
+}
+
+fn run_editor(path: &std::path::Path) -> Result<()> {
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    // Run through the shell so EDITOR may carry arguments (e.g. "code --wait")
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run editor: {}", editor))?;
+    if !status.success() {
+        anyhow::bail!("Editor exited with {}", status);
+    }
+    Ok(())
+}
+
+fn load_attachments(paths: &[std::path::PathBuf]) -> Result<Vec<mime::Attachment>> {
+    paths
+        .iter()
+        .map(|path| {
+            mime::Attachment::from_path(path)
+                .with_context(|| format!("Failed to read attachment {}", path.display()))
+        })
+        .collect()
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Strip any directory components so a hostile filename can't escape the output dir.
+fn safe_filename(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    match base {
+        "" | "." | ".." => "attachment".to_string(),
+        _ => base.to_string(),
+    }
+}
+
+/// `2025-07-01_billing@example.com_18c1_invoice.pdf`: the message date, sender
+/// and ID in front of the original name, so downloads from many messages don't collide.
+fn dated_attachment_name(msg: &api::Message, filename: &str) -> String {
+    let date = msg
+        .date()
+        .map(|d| d.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| "undated".to_string());
+    let sender: String = msg
+        .get_header("From")
+        .map(mime::address_of)
+        .unwrap_or("unknown")
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '@' | '.' | '-' | '+') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!(
+        "{}_{}_{}_{}",
+        date,
+        sender,
+        safe_filename(&msg.id),
+        safe_filename(filename)
+    )
+}
+
+/// `name`, or `name-2.ext`, `name-3.ext`... if an earlier file already took it.
+fn unique_filename(name: String, taken: &mut HashSet<String>) -> String {
+    if taken.insert(name.clone()) {
+        return name;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name.as_str(), String::new()),
+    };
+    (2..)
+        .map(|n| format!("{}-{}{}", stem, n, extension))
+        .find(|candidate| taken.insert(candidate.clone()))
+        .expect("some numbered name is free")
+}
+
+/// Write `data` to a temporary file next to `path` and rename it into place,
+/// so an interrupted download never leaves a truncated file under the real name.
+fn write_file_atomically(path: &std::path::Path, data: &[u8]) -> Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = path.with_file_name(format!(".{}.part", name));
+    std::fs::write(&partial, data)
+        .with_context(|| format!("Failed to write {}", partial.display()))?;
+    std::fs::rename(&partial, path).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn message_json(msg: &api::Message) -> serde_json::Value {
+    let attachments: Vec<_> = msg
+        .attachments()
+        .iter()
+        .map(|part| {
+            serde_json::json!({
+                "partId": part.part_id,
+                "filename": part.filename,
+                "mimeType": part.mime_type,
+                "size": part.body.as_ref().and_then(|b| b.size),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "id": msg.id,
+        "threadId": msg.thread_id,
+        "from": msg.get_header("From"),
+        "to": msg.get_header("To"),
+        "subject": msg.get_header("Subject"),
+        "date": msg.get_header("Date"),
+        "body": msg.get_readable_body(HTML_RENDER_WIDTH),
+        "html": msg.get_body_html(),
+        "snippet": msg.snippet,
+        "attachments": attachments,
+        "authentication": auth_summary(msg),
+        "calendar": msg.get_body_calendar().as_deref().and_then(ics::parse),
+    })
+}
+
+/// Put `text` on the clipboard with whichever clipboard tool is installed.
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    const TOOLS: &[&[&str]] = &[
+        &["wl-copy"],
+        &["xclip", "-selection", "clipboard"],
+        &["xsel", "--clipboard", "--input"],
+        &["pbcopy"],
+        &["clip.exe"],
+    ];
+    for tool in TOOLS {
+        let Ok(mut child) = Command::new(tool[0])
+            .args(&tool[1..])
+            .stdin(Stdio::piped())
+            .spawn()
+        else {
+            continue;
+        };
+        child.stdin.take().unwrap().write_all(text.as_bytes())?;
+        if child.wait()?.success() {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("No clipboard tool found (install wl-copy, xclip or xsel)")
+}
+
+/// An invite's title, time, place and people, one per line.
+fn format_event(event: &ics::Event) -> String {
+    let header = &style::palette().header;
+    let title = event.summary.as_deref().unwrap_or("(untitled)");
+    let kind = match event.method.as_deref() {
+        Some("CANCEL") => "Cancelled:",
+        Some("REPLY") => "Invite reply:",
+        _ => "Invite:",
+    };
+    let mut out = format!("{} {}\n", header.paint(kind), title);
+    let mut field = |name: &str, value: String| {
+        out.push_str(&format!("  {} {}\n", header.paint(name), value));
+    };
+    match (&event.start, &event.end) {
+        (Some(start), Some(end)) => field("When:", format!("{} - {}", start, end)),
+        (Some(start), None) => field("When:", start.clone()),
+        _ => {}
+    }
+    if let Some(location) = &event.location {
+        field("Where:", location.clone());
+    }
+    if let Some(organizer) = &event.organizer {
+        field("Organizer:", organizer.display());
+    }
+    if !event.attendees.is_empty() {
+        let attendees: Vec<String> = event
+            .attendees
+            .iter()
+            .map(|a| match &a.status {
+                Some(status) => format!("{} ({})", a.display(), status.to_lowercase()),
+                None => a.display(),
+            })
+            .collect();
+        field("Attendees:", attendees.join(", "));
+    }
+    out
+}
+
+/// A link and its anchor text, then what's suspicious about it on indented lines.
+fn print_link(link: &links::Link) {
+    match &link.text {
+        Some(text) if *text != link.url => println!("{}  {}", link.url, text),
+        _ => println!("{}", link.url),
+    }
+    if link.tracker {
+        println!("  click tracker or shortener");
+    }
+    if let Some(target) = &link.redirects_to {
+        println!("  redirects to {}", target);
+    }
+    if !link.tracking_params.is_empty() {
+        println!("  tracking: {}", link.tracking_params.join(", "));
+    }
+}
+
+/// SPF/DKIM/DMARC verdicts from the headers Gmail added on receipt.
+fn auth_summary(msg: &api::Message) -> Option<authres::Summary> {
+    let results = msg
+        .headers()
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("Authentication-Results"))
+        .map(|h| h.value.as_str());
+    authres::Summary::new(
+        authres::gmail_results(results),
+        msg.get_header("Received-SPF"),
+        msg.get_header("From"),
+    )
+}
+
+// Line width for HTML bodies rendered as text when stdout is not a terminal
+const HTML_RENDER_WIDTH: usize = 80;
+
+fn print_message(msg: &api::Message, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(&message_json(msg))?);
+    } else {
+        print!("{}", format_message(msg, &ShownHeaders::Summary));
+    }
+    Ok(())
+}
+
+/// Headers `read` shows above the body.
+enum ShownHeaders {
+    /// From, To, Subject and Date, with placeholders when missing
+    Summary,
+    /// Only these, in message order (a header can appear more than once)
+    Named(Vec<String>),
+    All,
+}
+
+impl ShownHeaders {
+    fn new(names: Vec<String>, all: bool) -> Self {
+        match (names.is_empty(), all) {
+            (_, true) => ShownHeaders::All,
+            (true, false) => ShownHeaders::Summary,
+            (false, false) => ShownHeaders::Named(names),
+        }
+    }
+
+    /// The message's headers picked by `--header`/`--full-headers`; None for the summary.
+    fn select<'a>(&self, msg: &'a api::Message) -> Option<Vec<&'a api::Header>> {
+        let headers = msg.headers().iter();
+        match self {
+            ShownHeaders::Summary => None,
+            ShownHeaders::Named(names) => Some(
+                headers
+                    .filter(|h| names.iter().any(|n| n.eq_ignore_ascii_case(&h.name)))
+                    .collect(),
+            ),
+            ShownHeaders::All => Some(headers.collect()),
+        }
+    }
+}
+
+/// A message as shown by `read`: headers, body, then attachments.
+fn format_message(msg: &api::Message, shown: &ShownHeaders) -> String {
+    let header = &style::palette().header;
+    let mut out = String::new();
+    match shown.select(msg) {
+        Some(headers) => {
+            for h in headers {
+                out.push_str(&format!(
+                    "{} {}\n",
+                    header.paint(&format!("{}:", h.name)),
+                    h.value
+                ));
+            }
+        }
+        None => {
+            for (name, fallback) in [
+                ("From", "Unknown"),
+                ("To", "Unknown"),
+                ("Subject", "(no subject)"),
+                ("Date", "Unknown"),
+            ] {
+                out.push_str(&format!(
+                    "{} {}\n",
+                    header.paint(&format!("{}:", name)),
+                    msg.get_header(name).unwrap_or(fallback)
+                ));
+            }
+        }
+    }
+    if let Some(summary) = auth_summary(msg) {
+        out.push_str(&format!(
+            "{} {}\n",
+            header.paint("Auth:"),
+            summary.describe()
+        ));
+    }
+    if let Some(event) = msg.get_body_calendar().as_deref().and_then(ics::parse) {
+        out.push_str(&format_event(&event));
+    }
+    out.push_str("---\n");
+
+    let width = table::terminal_width().unwrap_or(HTML_RENDER_WIDTH);
+    if let Some(body) = msg.get_readable_body(width) {
+        out.push_str(&format!("{}\n", body));
+    } else if let Some(snippet) = &msg.snippet {
+        out.push_str(&format!("{}\n", snippet));
+    }
+
+    let attachments = msg.attachments();
+    if !attachments.is_empty() {
+        out.push_str("---\nAttachments:\n");
+        for part in attachments {
+            out.push_str(&format!(
+                "  [{}] {} ({}, {})\n",
+                part.part_id.as_deref().unwrap_or("?"),
+                part.filename.as_deref().unwrap_or(""),
+                part.mime_type,
+                format_size(part.body.as_ref().and_then(|b| b.size).unwrap_or(0))
+            ));
+        }
+    }
+    out
+}
+
+/// Show `text` through `$PAGER` (default `less`) when stdout is a terminal, like git.
+fn page(text: &str) -> Result<()> {
+    use std::io::{IsTerminal, Write};
+
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+    if !std::io::stdout().is_terminal() || pager.is_empty() || pager == "cat" {
+        print!("{}", text);
+        return Ok(());
+    }
+
+    let mut command = std::process::Command::new("sh");
+    // Run through the shell so PAGER may carry arguments (e.g. "less -S")
+    command
+        .arg("-c")
+        .arg(&pager)
+        .stdin(std::process::Stdio::piped());
+    if std::env::var_os("LESS").is_none() {
+        // Quit if it fits on one screen, keep colors, don't clear the screen
+        command.env("LESS", "FRX");
+    }
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Failed to run pager '{}'", pager))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The user may quit the pager before reading everything
+        match stdin.write_all(text.as_bytes()) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+            result => result?,
+        }
+    }
+    child.wait()?;
+    Ok(())
+}
+
+// Headers shown by `list`; fetched with format=metadata instead of whole messages
+const LIST_HEADERS: &[&str] = &["From", "To", "Subject", "Date"];
+
+// Tries for a message that keeps being rate limited or failing on Google's side
+const FETCH_ATTEMPTS: u32 = 4;
+
+/// Fetch message metadata with up to `concurrency` requests in flight, keeping
+/// the order of `ids` and skipping messages deleted meanwhile.
+async fn fetch_messages(
+    client: &api::Client,
+    ids: &[String],
+    concurrency: u32,
+) -> Result<Vec<api::Message>> {
+    // A single batch is over too quickly for a bar to help
+    let bar = if ids.len() > api::BATCH_GET_LIMIT {
+        progress_bar(ids.len())
+    } else {
+        indicatif::ProgressBar::hidden()
+    };
+    // One batch request per chunk; `buffered` keeps them in order
+    let batches: Vec<Vec<api::Message>> = stream::iter(ids.chunks(api::BATCH_GET_LIMIT))
+        .map(|chunk| async {
+            let batch = fetch_metadata_batch(client, chunk).await;
+            bar.inc(chunk.len() as u64);
+            batch
+        })
+        .buffered(concurrency as usize)
+        .try_collect()
+        .await?;
+    bar.finish_and_clear();
+    Ok(batches.into_iter().flatten().collect())
+}
+
+/// Fetch list metadata for one batch of `ids` in order, skipping messages
+/// deleted meanwhile and retrying rate-limited or failed ones with backoff.
+async fn fetch_metadata_batch(client: &api::Client, ids: &[String]) -> Result<Vec<api::Message>> {
+    let mut messages: Vec<Option<api::Message>> = ids.iter().map(|_| None).collect();
+    let mut pending: Vec<usize> = (0..ids.len()).collect();
+    let mut delay = Duration::from_secs(1);
+    for attempt in 1..=FETCH_ATTEMPTS {
+        let wanted: Vec<String> = pending.iter().map(|&i| ids[i].clone()).collect();
+        let last = attempt == FETCH_ATTEMPTS;
+        let mut retry = Vec::new();
+        match client.get_messages_metadata(&wanted, LIST_HEADERS).await {
+            Ok(batch) => {
+                for (i, result) in pending.into_iter().zip(batch) {
+                    match result {
+                        Ok(msg) => messages[i] = Some(msg),
+                        Err(e) if e.is_not_found() => {}
+                        Err(e) if e.is_transient() && !last => retry.push(i),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            Err(e) if e.is_transient() && !last => retry = pending,
+            Err(e) => return Err(e.into()),
+        }
+        if retry.is_empty() {
+            break;
+        }
+        tracing::debug!("retrying {} messages in {:?}", retry.len(), delay);
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        pending = retry;
+    }
+    Ok(messages.into_iter().flatten().collect())
+}
+
+/// A table of `messages` with one column per entry in `columns`, unread rows highlighted.
+fn message_table(messages: &[api::Message], columns: &[ListColumn], relative: bool) -> Table {
+    let palette = style::palette();
+    let mut table = Table::new(columns.iter().map(|c| c.header()));
+    table.style_header(palette.header.clone());
+    if let Some(i) = columns.iter().position(|c| matches!(c, ListColumn::Labels)) {
+        table.style_column(i, palette.label.clone());
+    }
+    for msg in messages {
+        let unread = msg
+            .label_ids
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .any(|l| l == "UNREAD");
+        let row_style = if unread {
+            palette.unread.clone()
+        } else {
+            style::Style::default()
+        };
+        table.add_styled_row(columns.iter().map(|c| c.value(msg, relative)), row_style);
+    }
+    table
+}
+
+fn list_item_json(msg: &api::Message) -> serde_json::Value {
+    serde_json::json!({
+        "id": msg.id,
+        "from": msg.get_header("From"),
+        "to": msg.get_header("To"),
+        "subject": msg.get_header("Subject"),
+        "date": msg.get_header("Date"),
+        "timestamp": msg.date().map(|dt| dt.to_rfc3339()),
+        "snippet": msg.snippet,
+    })
+}
+
+// Small pages so the first lines show up quickly
+const NDJSON_PAGE_SIZE: u32 = 100;
+
+/// Print each message as a JSON line as soon as it's fetched, one result page at a time.
+async fn stream_ndjson(
+    client: &api::Client,
+    query: Option<&str>,
+    label: &str,
+    limit: Option<u32>,
+    concurrency: u32,
+) -> Result<()> {
+    let mut emitted = 0u32;
+    let mut page_token: Option<String> = None;
+    loop {
+        let page_size = match limit {
+            Some(limit) => limit.saturating_sub(emitted).min(NDJSON_PAGE_SIZE),
+            None => NDJSON_PAGE_SIZE,
+        };
+        if page_size == 0 {
+            return Ok(());
+        }
+        let page = client
+            .list_messages_page(query, label, page_size, page_token.as_deref())
+            .await?;
+
+        let ids: Vec<String> = page
+            .messages
+            .unwrap_or_default()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        for msg in fetch_messages(client, &ids, concurrency).await? {
+            println!("{}", serde_json::to_string(&list_item_json(&msg))?);
+            emitted += 1;
+        }
+
+        page_token = page.next_page_token;
+        if page_token.is_none() {
+            return Ok(());
+        }
+    }
+}
+
+async fn list_labels(
+    client: &api::Client,
+    counts: bool,
+    tree: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let mut labels = client.list_labels().await?.labels.unwrap_or_default();
+    if counts || tree {
+        labels = stream::iter(labels.iter())
+            .map(|label| client.get_label(&label.id))
+            .buffer_unordered(10)
+            .try_collect()
+            .await?;
+    }
+
+    if tree {
+        return print_label_tree(&labels, format);
+    }
+
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&labels)?);
+        return Ok(());
+    }
+
+    let (mut system, mut user): (Vec<_>, Vec<_>) = labels
+        .iter()
+        .partition(|l| l.label_type.as_deref() == Some("system"));
+    system.sort_by(|a, b| a.name.cmp(&b.name));
+    user.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let label_table = |section: &[&api::Label]| {
+        let mut headers = vec!["NAME", "ID", "TYPE"];
+        if counts {
+            headers.extend(["TOTAL", "UNREAD", "UNREAD THREADS"]);
+        }
+        let mut table = Table::new(headers);
+        table.style_header(style::palette().header.clone());
+        table.style_column(0, style::palette().label.clone());
+        for label in section {
+            let mut row = vec![
+                label.name.clone(),
+                label.id.clone(),
+                label.label_type.clone().unwrap_or_default(),
+            ];
+            if counts {
+                row.extend([
+                    label.messages_total.unwrap_or(0).to_string(),
+                    label.messages_unread.unwrap_or(0).to_string(),
+                    label.threads_unread.unwrap_or(0).to_string(),
+                ]);
+            }
+            table.add_row(row);
+        }
+        table
+    };
+
+    if format != OutputFormat::Text {
+        let all: Vec<&api::Label> = system.iter().chain(&user).copied().collect();
+        format.print_table(&label_table(&all));
+        return Ok(());
+    }
+
+    let print_section = |title: &str, section: &[&api::Label]| {
+        println!("{}:", title);
+        if !counts {
+            for label in section {
+                println!("  {} ({})", label.name, label.id);
+            }
+            return;
+        }
+        let table = label_table(section);
+        for line in table.render(table::terminal_width()).lines() {
+            println!("  {}", line);
+        }
+    };
+
+    print_section("System labels", &system);
+    if !user.is_empty() {
+        println!();
+        print_section("User labels", &user);
+    }
+    Ok(())
+}
+
+fn print_label_tree(labels: &[api::Label], format: OutputFormat) -> Result<()> {
+    let (system, user): (Vec<&api::Label>, Vec<&api::Label>) = labels
+        .iter()
+        .partition(|l| l.label_type.as_deref() == Some("system"));
+    let mut roots = api::label_tree(system);
+    roots.extend(api::label_tree(user));
+
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&roots)?);
+        return Ok(());
+    }
+
+    struct Row<'a> {
+        drawn: String,
+        path: String,
+        node: &'a api::LabelNode,
+    }
+
+    fn collect<'a>(
+        nodes: &'a [api::LabelNode],
+        prefix: &str,
+        parent: &str,
+        rows: &mut Vec<Row<'a>>,
+    ) {
+        for (i, node) in nodes.iter().enumerate() {
+            let last = i == nodes.len() - 1;
+            let (branch, indent) = if last {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            let path = format!("{}/{}", parent, node.name);
+            rows.push(Row {
+                drawn: format!("{}{}{}", prefix, branch, node.name),
+                path: path.clone(),
+                node,
+            });
+            collect(
+                &node.children,
+                &format!("{}{}", prefix, indent),
+                &path,
+                rows,
+            );
+        }
+    }
+
+    let mut rows = Vec::new();
+    for root in &roots {
+        rows.push(Row {
+            drawn: root.name.clone(),
+            path: root.name.clone(),
+            node: root,
+        });
+        collect(&root.children, "", &root.name, &mut rows);
+    }
+
+    // Spreadsheets get the full path instead of the drawn branches
+    let mut table = Table::new(["LABEL", "TOTAL", "UNREAD", "UNREAD THREADS"]);
+    for row in rows {
+        let label = if format == OutputFormat::Text {
+            row.drawn
+        } else {
+            row.path
+        };
+        table.add_row([
+            label,
+            row.node.messages_total.to_string(),
+            row.node.messages_unread.to_string(),
+            row.node.threads_unread.to_string(),
+        ]);
+    }
+    format.print_table(&table);
+    Ok(())
+}
+
+fn print_history(history: &api::HistoryList, json: bool) -> Result<()> {
+    if json {
+        let ids = |messages: &[api::HistoryMessage]| -> Vec<String> {
+            messages.iter().map(|m| m.message.id.clone()).collect()
+        };
+        let changes = |changes: &[api::HistoryLabelChange]| -> Vec<serde_json::Value> {
+            changes
+                .iter()
+                .map(|c| serde_json::json!({ "id": c.message.id, "labelIds": c.label_ids }))
+                .collect()
+        };
+        let records: Vec<_> = history
+            .history
+            .iter()
+            .map(|h| {
+                serde_json::json!({
+                    "id": h.id,
+                    "messagesAdded": ids(&h.messages_added),
+                    "messagesDeleted": ids(&h.messages_deleted),
+                    "labelsAdded": changes(&h.labels_added),
+                    "labelsRemoved": changes(&h.labels_removed),
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::json!({ "historyId": history.history_id, "history": records })
+        );
+        return Ok(());
+    }
+
+    for record in &history.history {
+        for added in &record.messages_added {
+            println!("+ {} added", added.message.id);
+        }
+        for deleted in &record.messages_deleted {
+            println!("- {} deleted", deleted.message.id);
+        }
+        for change in &record.labels_added {
+            println!("  {} +{}", change.message.id, change.label_ids.join(" +"));
+        }
+        for change in &record.labels_removed {
+            println!("  {} -{}", change.message.id, change.label_ids.join(" -"));
+        }
+    }
+    if history.history.is_empty() {
+        println!("No changes.");
+    }
+    println!(
+        "History ID: {}",
+        history.history_id.as_deref().unwrap_or("unknown")
+    );
+    Ok(())
+}
+
+async fn list_threads(
+    client: &api::Client,
+    query: Option<&str>,
+    label_id: &str,
+    max: u32,
+    format: OutputFormat,
+    relative: bool,
+    concurrency: u32,
+) -> Result<()> {
+    let refs = client
+        .list_threads(query, label_id, max)
+        .await?
+        .threads
+        .unwrap_or_default();
+    // `buffered` keeps the threads in list order
+    let threads: Vec<api::Thread> = stream::iter(&refs)
+        .map(|thread_ref| client.get_thread_summary(&thread_ref.id))
+        .buffered(concurrency as usize)
+        .try_collect()
+        .await?;
+    let threads: Vec<(api::Thread, Vec<api::Message>)> = threads
+        .into_iter()
+        .filter_map(|mut thread| {
+            let messages = thread.messages.take().unwrap_or_default();
+            (!messages.is_empty()).then_some((thread, messages))
+        })
+        .collect();
+
+    if format == OutputFormat::Json {
+        let items: Vec<_> = threads
+            .iter()
+            .map(|(thread, messages)| {
+                let (first, last) = (&messages[0], &messages[messages.len() - 1]);
+                serde_json::json!({
+                    "id": thread.id,
+                    "messages": messages.len(),
+                    "from": first.get_header("From").unwrap_or("Unknown"),
+                    "subject": first.get_header("Subject").unwrap_or("(no subject)"),
+                    "date": last.get_header("Date"),
+                    "snippet": thread.snippet,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string(&items)?);
+    } else if threads.is_empty() && format == OutputFormat::Text {
+        println!("No threads found.");
+    } else {
+        format.print_table(&thread_table(&threads, relative));
+    }
+    Ok(())
+}
+
+/// Threads as list rows: the first message's sender and subject, the last one's date.
+fn thread_table(threads: &[(api::Thread, Vec<api::Message>)], relative: bool) -> Table {
+    let palette = style::palette();
+    let mut table = Table::new(["ID", "MESSAGES", "DATE", "FROM", "SUBJECT"]);
+    table.style_header(palette.header.clone());
+    for (thread, messages) in threads {
+        let (first, last) = (&messages[0], &messages[messages.len() - 1]);
+        let unread = messages.iter().any(|m| {
+            m.label_ids
+                .as_deref()
+                .unwrap_or_default()
+                .iter()
+                .any(|l| l == "UNREAD")
+        });
+        let row_style = if unread {
+            palette.unread.clone()
+        } else {
+            style::Style::default()
+        };
+        table.add_styled_row(
+            [
+                thread.id.clone(),
+                messages.len().to_string(),
+                ListColumn::Date.value(last, relative),
+                ListColumn::From.value(first, relative),
+                ListColumn::Subject.value(first, relative),
+            ],
+            row_style,
+        );
+    }
+    table
+}
+
+// Messages modified per batchModify call during a sweep; smaller than the API
+// limit so the progress bar moves on modest result sets
+const SWEEP_CHUNK: usize = 250;
+
+async fn run_sweep(
+    query: &str,
+    action: SweepAction,
+    label: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let client = get_client().await?;
+
+    let spinner = progress_spinner("Searching...");
+    let ids = client.list_all_message_ids(Some(query), "").await?;
+    spinner.finish_and_clear();
+
+    if !ids.is_empty() {
+        let action_name = action
+            .to_possible_value()
+            .expect("sweep actions are never hidden");
+        confirm(&format!(
+            "Really {} {} messages matching \"{}\"?",
+            action_name.get_name(),
+            format_count(ids.len()),
+            query
+        ))?;
+    }
+
+    let (add, remove, verb): (Vec<String>, Vec<String>, &str) = match action {
+        SweepAction::Archive => (vec![], vec!["INBOX".into()], "Archived"),
+        SweepAction::Delete => (vec!["TRASH".into()], vec![], "Moved to trash"),
+        SweepAction::Spam => (vec!["SPAM".into()], vec!["INBOX".into()], "Marked as spam"),
+        SweepAction::MarkRead => (vec![], vec!["UNREAD".into()], "Marked as read"),
+        SweepAction::MarkUnread => (vec!["UNREAD".into()], vec![], "Marked as unread"),
+        SweepAction::Label => {
+            let label = normalize_label(label.unwrap_or_default());
+            let id = client.resolve_label_for_add(&label).await?;
+            (vec![id], vec![], "Labeled")
+        }
+        SweepAction::Unlabel => {
+            let label = normalize_label(label.unwrap_or_default());
+            let id = client.resolve_label_for_remove(&label).await?;
+            (vec![], vec![id], "Unlabeled")
+        }
+    };
+    let add: Vec<&str> = add.iter().map(String::as_str).collect();
+    let remove: Vec<&str> = remove.iter().map(String::as_str).collect();
+
+    let bar = progress_bar(ids.len());
+    for chunk in ids.chunks(SWEEP_CHUNK) {
+        client.batch_modify(chunk, &add, &remove).await?;
+        bar.inc(chunk.len() as u64);
+    }
+    bar.finish_and_clear();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "query": query, "count": ids.len() })
+        );
+    } else {
+        println!("{} {} messages", verb, ids.len());
+    }
+    Ok(())
+}
+
+async fn run_block(sender: &str, trash_existing: bool, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    // Ask before changing anything, so declining leaves no filter behind
+    let existing = if trash_existing {
+        client
+            .list_all_message_ids(Some(&format!("from:({})", sender)), "")
+            .await?
+    } else {
+        Vec::new()
+    };
+    if !existing.is_empty() {
+        confirm(&format!(
+            "Really move {} messages from {} to trash?",
+            format_count(existing.len()),
+            sender
+        ))?;
+    }
+
+    let created = client
+        .create_filter(&api::Filter {
+            id: None,
+            criteria: api::FilterCriteria {
+                from: Some(sender.to_string()),
+                ..Default::default()
+            },
+            action: api::FilterAction {
+                add_label_ids: vec!["TRASH".to_string()],
+                remove_label_ids: vec!["INBOX".to_string()],
+                ..Default::default()
+            },
+        })
+        .await?;
+    let filter_id = created.id.unwrap_or_default();
+
+    for chunk in existing.chunks(SWEEP_CHUNK) {
+        client.batch_modify(chunk, &["TRASH"], &["INBOX"]).await?;
+    }
+    let trashed = existing.len();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "sender": sender, "filter": filter_id, "trashed": trashed })
+        );
+    } else {
+        println!("Blocked {} (filter {})", sender, filter_id);
+        if trash_existing {
+            println!("Moved {} existing messages to trash", trashed);
+        }
+    }
+    Ok(())
+}
+
+fn confirm_permanent(question: &str) -> Result<()> {
+    confirm(&format!("{} This cannot be undone", question))
+}
+
+/// Point a permanent deletion refused for lack of scope at `login --full-access`.
+fn full_access_hint(e: gmail::GmailError) -> anyhow::Error {
+    if e.is_insufficient_scope() {
+        return anyhow::Error::from(e)
+            .context("Permanent deletion requires full access: run 'gmail login --full-access'");
+    }
+    e.into()
+}
+
+/// Delete `ids` forever once `confirm` agrees; nothing is sent if it doesn't.
+async fn delete_permanently(
+    client: &api::Client,
+    ids: &[String],
+    json: bool,
+    confirm: impl FnOnce(&str) -> Result<()>,
+) -> Result<()> {
+    if ids.is_empty() {
+        if json {
+            println!("{}", serde_json::json!({ "deleted": ids }));
+        } else {
+            println!("No messages to delete");
+        }
+        return Ok(());
+    }
+    confirm(&format!("Permanently delete {}?", describe_ids(ids)))?;
+    client.batch_delete(ids).await.map_err(full_access_hint)?;
+    if json {
+        println!("{}", serde_json::json!({ "deleted": ids }));
+    } else {
+        println!("Permanently deleted {}", describe_ids(ids));
+    }
+    Ok(())
+}
+
+/// Permanently delete everything under a system label such as TRASH or SPAM.
+async fn empty_label(label: &str, name: &str, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    let ids = client.list_all_message_ids(None, label).await?;
+    if ids.is_empty() {
+        if json {
+            println!("{}", serde_json::json!({ "label": label, "count": 0 }));
+        } else {
+            println!("{} is already empty", name);
+        }
+        return Ok(());
+    }
+    confirm_permanent(&format!(
+        "Permanently delete all {} messages in {}?",
+        format_count(ids.len()),
+        name
+    ))?;
+
+    let bar = progress_bar(ids.len());
+    for chunk in ids.chunks(SWEEP_CHUNK) {
+        client.batch_delete(chunk).await.map_err(full_access_hint)?;
+        bar.inc(chunk.len() as u64);
+    }
+    bar.finish_and_clear();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "label": label, "count": ids.len() })
+        );
+    } else {
+        println!("Permanently deleted {} messages from {}", ids.len(), name);
+    }
+    Ok(())
+}
+
+/// The query `purge` deletes by; an empty one would select all mail.
+fn purge_query(query: &str) -> Result<String> {
+    let built = api::Query::new().raw(query);
+    if built.is_empty() {
+        anyhow::bail!("purge needs a non-empty --query; it won't delete all mail");
+    }
+    Ok(built.to_string())
+}
+
+async fn run_purge(query: &str, confirmed: bool, json: bool) -> Result<()> {
+    let query = purge_query(query)?;
+    let client = get_client().await?;
+    let ids = client.list_all_message_ids(Some(&query), "").await?;
+
+    if ids.is_empty() {
+        if json {
+            println!("{}", serde_json::json!({ "query": query, "count": 0 }));
+        } else {
+            println!("No messages match");
+        }
+        return Ok(());
+    }
+    if !confirmed {
+        anyhow::bail!(
+            "{} messages match; re-run with --yes-permanently-delete to delete them forever",
+            ids.len()
+        );
+    }
+
+    let bar = progress_bar(ids.len());
+    for chunk in ids.chunks(SWEEP_CHUNK) {
+        client.batch_delete(chunk).await.map_err(full_access_hint)?;
+        bar.inc(chunk.len() as u64);
+    }
+    bar.finish_and_clear();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "query": query, "count": ids.len() })
+        );
+    } else {
+        println!("Permanently deleted {} messages", ids.len());
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// One finding of `gmail doctor`.
+struct Check {
+    name: &'static str,
+    status: CheckStatus,
+    detail: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            status: CheckStatus::Warn,
+            fix: Some(fix.into()),
+            ..Self::ok(name, detail)
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            status: CheckStatus::Fail,
+            ..Self::warn(name, detail, fix)
+        }
+    }
+}
+
+// Clock differences that make Google reject signed assertions, or get close to it
+const CLOCK_SKEW_WARN_SECS: i64 = 30;
+const CLOCK_SKEW_FAIL_SECS: i64 = 300;
+
+/// How far the local clock is ahead of Google's, from the `Date` of a response.
+async fn clock_skew(http: &reqwest::Client) -> Result<chrono::TimeDelta> {
+    let resp = http
+        .head("https://gmail.googleapis.com/")
+        .send()
+        .await
+        .context("Failed to reach gmail.googleapis.com")?;
+    let date = resp
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| anyhow::anyhow!("No Date header in the response"))?;
+    let server = chrono::DateTime::parse_from_rfc2822(date)
+        .with_context(|| format!("Invalid Date header: {}", date))?;
+    Ok(chrono::Utc::now() - server.to_utc())
+}
+
+/// Work through setup one step at a time, stopping at the first step the rest depend on.
+async fn doctor_checks() -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let cfg = match config::load_config() {
+        Ok(cfg) => {
+            checks.push(Check::ok(
+                "Config",
+                config::config_dir().display().to_string(),
+            ));
+            cfg
+        }
+        Err(e) => {
+            checks.push(Check::fail(
+                "Config",
+                format!("{:#}", e),
+                "Fix the file with 'gmail config edit'",
+            ));
+            return checks;
+        }
+    };
+
+    let shared = config::shared_files();
+    if shared.is_empty() {
+        checks.push(Check::ok(
+            "Permissions",
+            "Only you can read the config and tokens",
+        ));
+    } else {
+        let paths: Vec<String> = shared.iter().map(|p| p.display().to_string()).collect();
+        checks.push(Check::fail(
+            "Permissions",
+            format!("Accessible by other users: {}", paths.join(", ")),
+            format!("chmod go-rwx {}", paths.join(" ")),
+        ));
+    }
+
+    let login = if config::env_tokens().is_some() {
+        Check::ok("Login", "Using GMAIL_ACCESS_TOKEN from the environment")
+    } else if let Some(account) = &cfg.service_account {
+        Check::ok(
+            "Login",
+            format!("Service account acting as {}", account.subject),
+        )
+    } else if !config::tokens_path().exists() {
+        Check::fail("Login", "Not logged in", "gmail login")
+    } else {
+        match config::load_tokens() {
+            Ok(_) => Check::ok("Login", config::tokens_path().display().to_string()),
+            Err(e) => Check::fail(
+                "Login",
+                format!("{:#}", e),
+                "Fix the problem above, or log in again with 'gmail login'",
+            ),
+        }
+    };
+    let logged_in = login.status == CheckStatus::Ok;
+    checks.push(login);
+
+    let http = http_client();
+    match clock_skew(&http).await {
+        Ok(skew) => {
+            checks.push(Check::ok("Network", "gmail.googleapis.com is reachable"));
+            let secs = skew.num_seconds();
+            let detail = match secs {
+                0 => "In sync with Google".to_string(),
+                s if s > 0 => format!("{}s ahead of Google", s),
+                s => format!("{}s behind Google", -s),
+            };
+            let fix = "Sync the system clock, e.g. with 'timedatectl set-ntp true'";
+            checks.push(match secs.abs() {
+                s if s >= CLOCK_SKEW_FAIL_SECS => Check::fail("Clock", detail, fix),
+                s if s >= CLOCK_SKEW_WARN_SECS => Check::warn("Clock", detail, fix),
+                _ => Check::ok("Clock", detail),
+            });
+        }
+        Err(e) => {
+            checks.push(Check::fail(
+                "Network",
+                format!("{:#}", e),
+                "Check your connection, firewall or HTTPS_PROXY setting",
+            ));
+            return checks;
+        }
+    }
+    if !logged_in {
+        return checks;
+    }
+
+    let client = match get_client().await {
+        Ok(client) => client,
+        Err(e) => {
+            checks.push(Check::fail("API", format!("{:#}", e), "gmail login"));
+            return checks;
+        }
+    };
+    match client.get_profile().await {
+        Ok(profile) => checks.push(Check::ok(
+            "API",
+            format!("Signed in as {}", profile.email_address),
+        )),
+        Err(e) => {
+            let fix = if e.is_unauthorized() {
+                "The login was revoked or expired; run 'gmail login --force'"
+            } else if e.status() == Some(403) {
+                "Enable the Gmail API for your OAuth client's Google Cloud project"
+            } else {
+                "Retry later, or run with -v for details"
+            };
+            checks.push(Check::fail("API", format!("{:#}", e), fix));
+            return checks;
+        }
+    }
+
+    let token = match client.access_token().await {
+        Ok(token) => token,
+        Err(e) => {
+            checks.push(Check::fail("Scopes", format!("{:#}", e), "gmail login"));
+            return checks;
+        }
+    };
+    match auth::token_info(&http, &token).await {
+        Ok(info) => {
+            let granted = info.scopes();
+            let wanted: &[&str] = if cfg.readonly {
+                &[auth::SCOPE_READONLY]
+            } else {
+                auth::DEFAULT_SCOPES
+            };
+            let missing: Vec<&str> = if granted.contains(&auth::SCOPE_FULL) {
+                Vec::new()
+            } else {
+                wanted
+                    .iter()
+                    .filter(|scope| !granted.contains(scope))
+                    .copied()
+                    .collect()
+            };
+            let expiry = info
+                .expires_in
+                .as_deref()
+                .and_then(|secs| secs.parse::<u64>().ok())
+                .map(|secs| format!(" (token valid for {} more minutes)", secs / 60))
+                .unwrap_or_default();
+            checks.push(if missing.is_empty() {
+                Check::ok("Scopes", format!("{}{}", granted.join(", "), expiry))
+            } else {
+                Check::warn(
+                    "Scopes",
+                    format!("Missing {}", missing.join(", ")),
+                    "Some commands will fail; grant them with 'gmail login'",
+                )
+            });
+        }
+        Err(e) => checks.push(Check::fail("Scopes", format!("{:#}", e), "gmail login")),
+    }
+    checks
+}
+
+async fn run_doctor(json: bool) -> Result<()> {
+    let checks = doctor_checks().await;
+    if json {
+        let items: Vec<_> = checks
+            .iter()
+            .map(|check| {
+                serde_json::json!({
+                    "name": check.name,
+                    "status": match check.status {
+                        CheckStatus::Ok => "ok",
+                        CheckStatus::Warn => "warn",
+                        CheckStatus::Fail => "fail",
+                    },
+                    "detail": check.detail,
+                    "fix": check.fix,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string(&items)?);
+    } else {
+        let header = &style::palette().header;
+        for check in &checks {
+            let status = match check.status {
+                CheckStatus::Ok => "ok",
+                CheckStatus::Warn => "warn",
+                CheckStatus::Fail => "FAIL",
+            };
+            println!("{:<5} {:<12} {}", status, check.name, check.detail);
+            if let Some(fix) = &check.fix {
+                println!("{}", header.paint(&format!("{:19}Fix: {}", "", fix)));
+            }
+        }
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Fail)
+        .count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+async fn run_attachments_command(command: AttachmentsCommands, json: bool) -> Result<()> {
+    let AttachmentsCommands::Download { query, out } = command;
+    let client = get_client().await?;
+
+    let spinner = progress_spinner("Searching...");
+    let ids = client.list_all_message_ids(Some(&query), "").await?;
+    spinner.finish_and_clear();
+
+    std::fs::create_dir_all(&out).with_context(|| format!("Failed to create {}", out.display()))?;
+    let mut saved = Vec::new();
+    let mut skipped = Vec::new();
+    let bar = progress_bar(ids.len());
+    for id in &ids {
+        let msg = client.get_message(id).await?;
+        // Two attachments of one message can share a name
+        let mut taken = HashSet::new();
+        for attachment in msg.attachments() {
+            let name = unique_filename(
+                dated_attachment_name(&msg, attachment.filename.as_deref().unwrap_or_default()),
+                &mut taken,
+            );
+            let path = out.join(name);
+            if path.exists() {
+                skipped.push(path);
+                continue;
+            }
+            let data = client.get_part_data(id, attachment).await?;
+            write_file_atomically(&path, &data)?;
+            if !json {
+                bar.suspend(|| {
+                    println!(
+                        "Saved {} ({})",
+                        path.display(),
+                        format_size(data.len() as u64)
+                    )
+                });
+            }
+            saved.push(path);
+        }
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "saved": saved, "skipped": skipped })
+        );
+    } else {
+        println!(
+            "Saved {} attachments from {} messages, skipped {} already present",
+            saved.len(),
+            ids.len(),
+            skipped.len()
+        );
+    }
+    Ok(())
+}
+
+async fn run_thread_command(command: ThreadCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        ThreadCommands::Read { id } => {
+            let thread = client.get_thread(&id).await?;
+            let messages = thread.messages.unwrap_or_default();
+            if json {
+                let items: Vec<_> = messages.iter().map(message_json).collect();
+                println!("{}", serde_json::to_string(&items)?);
+            } else {
+                for (i, msg) in messages.iter().enumerate() {
+                    if i > 0 {
+                        println!("\n========================================\n");
+                    }
+                    print_message(msg, false)?;
+                }
+            }
+        }
+        ThreadCommands::Archive { id } => {
+            client.archive_thread(&id).await?;
+            println!("Archived thread {}", id);
+        }
+        ThreadCommands::Delete { id } => {
+            client.trash_thread(&id).await?;
+            println!("Moved thread to trash {}", id);
+        }
+        ThreadCommands::Label { id, label } => {
+            client
+                .add_thread_label(&id, &normalize_label(&label))
+                .await?;
+            println!("Added label {} to thread {}", label, id);
+        }
+        ThreadCommands::Unlabel { id, label } => {
+            client
+                .remove_thread_label(&id, &normalize_label(&label))
+                .await?;
+            println!("Removed label {} from thread {}", label, id);
+        }
+        ThreadCommands::MarkRead { id } => {
+            client.mark_thread_read(&id).await?;
+            println!("Marked thread as read {}", id);
+        }
+        ThreadCommands::MarkUnread { id } => {
+            client.mark_thread_unread(&id).await?;
+            println!("Marked thread as unread {}", id);
+        }
+    }
+    Ok(())
+}
+
+fn describe_filter(filter: &api::Filter, label_names: &HashMap<String, String>) -> String {
+    let c = &filter.criteria;
+    let mut criteria = Vec::new();
+    if let Some(from) = &c.from {
+        criteria.push(format!("from:{}", from));
+    }
+    if let Some(to) = &c.to {
+        criteria.push(format!("to:{}", to));
+    }
+    if let Some(subject) = &c.subject {
+        criteria.push(format!("subject:{}", subject));
+    }
+    if let Some(query) = &c.query {
+        criteria.push(query.clone());
+    }
+    if let Some(negated) = &c.negated_query {
+        criteria.push(format!("-{{{}}}", negated));
+    }
+    if c.has_attachment == Some(true) {
+        criteria.push("has:attachment".to_string());
+    }
+
+    let a = &filter.action;
+    let label_name = |id: &String| label_names.get(id).cloned().unwrap_or_else(|| id.clone());
+    let mut actions = Vec::new();
+    for id in &a.add_label_ids {
+        actions.push(match id.as_str() {
+            "TRASH" => "delete".to_string(),
+            "STARRED" => "star".to_string(),
+            _ => format!("label:{}", label_name(id)),
+        });
+    }
+    for id in &a.remove_label_ids {
+        actions.push(match id.as_str() {
+            "INBOX" => "archive".to_string(),
+            "UNREAD" => "mark-read".to_string(),
+            "SPAM" => "never-spam".to_string(),
+            _ => format!("unlabel:{}", label_name(id)),
+        });
+    }
+    if let Some(forward) = &a.forward {
+        actions.push(format!("forward:{}", forward));
+    }
+
+    format!("{} -> {}", criteria.join(" "), actions.join(", "))
+}
+
+async fn run_filter_command(command: FilterCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        FilterCommands::List => {
+            let filters = client.list_filters().await?.filter.unwrap_or_default();
+            if json {
+                println!("{}", serde_json::to_string(&filters)?);
+            } else if filters.is_empty() {
+                println!("No filters found.");
+            } else {
+                let label_names: HashMap<String, String> = client
+                    .list_labels()
+                    .await?
+                    .labels
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|l| (l.id, l.name))
+                    .collect();
+                for filter in &filters {
+                    println!(
+                        "{} | {}",
+                        filter.id.as_deref().unwrap_or("?"),
+                        describe_filter(filter, &label_names)
+                    );
+                }
+            }
+        }
+        FilterCommands::Create {
+            from,
+            to,
+            subject,
+            query,
+            has_attachment,
+            add_label,
+            archive,
+            mark_read,
+            star,
+            delete,
+            never_spam,
+        } => {
+            let criteria = api::FilterCriteria {
+                from,
+                to,
+                subject,
+                query,
+                has_attachment: has_attachment.then_some(true),
+                ..Default::default()
+            };
+            let mut action = api::FilterAction::default();
+            if let Some(label) = add_label {
+                let label_id = client
+                    .resolve_label_for_add(&normalize_label(&label))
+                    .await?;
+                action.add_label_ids.push(label_id);
+            }
+            if star {
+                action.add_label_ids.push("STARRED".to_string());
+            }
+            if delete {
+                action.add_label_ids.push("TRASH".to_string());
+            }
+            if archive {
+                action.remove_label_ids.push("INBOX".to_string());
+            }
+            if mark_read {
+                action.remove_label_ids.push("UNREAD".to_string());
+            }
+            if never_spam {
+                action.remove_label_ids.push("SPAM".to_string());
+            }
+            if action.add_label_ids.is_empty() && action.remove_label_ids.is_empty() {
+                anyhow::bail!("A filter needs at least one action");
+            }
+
+            let created = client
+                .create_filter(&api::Filter {
+                    id: None,
+                    criteria,
+                    action,
+                })
+                .await?;
+            if json {
+                println!("{}", serde_json::to_string(&created)?);
+            } else {
+                println!(
+                    "Created filter {}",
+                    created.id.as_deref().unwrap_or_default()
+                );
+            }
+        }
+        FilterCommands::Delete { id } => {
+            client.delete_filter(&id).await?;
+            println!("Deleted filter {}", id);
+        }
+    }
+    Ok(())
+}
+
+fn format_vacation_time(millis: Option<&str>) -> String {
+    millis
+        .and_then(|m| m.parse().ok())
+        .and_then(date::from_millis)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| "(not set)".to_string())
+}
+
+async fn run_vacation_command(command: VacationCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        VacationCommands::Show => {
+            let settings = client.get_vacation().await?;
+            if json {
+                println!("{}", serde_json::to_string(&settings)?);
+                return Ok(());
+            }
+            let enabled = if settings.enable_auto_reply {
+                "enabled"
+            } else {
+                "disabled"
+            };
+            println!("Auto-reply: {}", enabled);
+            println!(
+                "Subject: {}",
+                settings.response_subject.as_deref().unwrap_or("")
+            );
+            println!(
+                "Start: {}",
+                format_vacation_time(settings.start_time.as_deref())
+            );
+            println!(
+                "End: {}",
+                format_vacation_time(settings.end_time.as_deref())
+            );
+            println!("Contacts only: {}", settings.restrict_to_contacts);
+            println!("Domain only: {}", settings.restrict_to_domain);
+            println!("---");
+            if let Some(body) = &settings.response_body_plain_text {
+                println!("{}", body);
+            } else if let Some(html) = &settings.response_body_html {
+                println!("{}", html);
+            }
+        }
+        VacationCommands::Set {
+            subject,
+            message,
+            file,
+            start,
+            end,
+            contacts_only,
+            domain_only,
+        } => {
+            let body = read_body(message, file.as_deref())?;
+            let to_millis = |dt: chrono::DateTime<chrono::Local>| dt.timestamp_millis().to_string();
+            let start = start.as_deref().map(date::parse_local).transpose()?;
+            // A day given alone is the last day replies go out, not the first without
+            let end = end.as_deref().map(date::parse_local_end).transpose()?;
+            let settings = api::VacationSettings {
+                enable_auto_reply: true,
+                response_subject: Some(subject),
+                response_body_plain_text: Some(body),
+                response_body_html: None,
+                restrict_to_contacts: contacts_only,
+                restrict_to_domain: domain_only,
+                start_time: start.map(to_millis),
+                end_time: end.map(to_millis),
+            };
+            let updated = client.update_vacation(&settings).await?;
+            if json {
+                println!("{}", serde_json::to_string(&updated)?);
+            } else {
+                println!("Vacation auto-reply enabled");
+            }
+        }
+        VacationCommands::Disable => {
+            let mut settings = client.get_vacation().await?;
+            settings.enable_auto_reply = false;
+            client.update_vacation(&settings).await?;
+            println!("Vacation auto-reply disabled");
+        }
+    }
+    Ok(())
+}
+
+async fn run_alias_command(command: AliasCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        AliasCommands::List => {
+            let aliases = client.list_send_as().await?.send_as.unwrap_or_default();
+            if json {
+                println!("{}", serde_json::to_string(&aliases)?);
+                return Ok(());
+            }
+            for alias in aliases {
+                let mut flags = Vec::new();
+                if alias.is_primary {
+                    flags.push("primary");
+                }
+                if alias.is_default {
+                    flags.push("default");
+                }
+                if let Some(status) = alias.verification_status.as_deref()
+                    && status != "accepted"
+                {
+                    flags.push(status);
+                }
+                println!(
+                    "{} | {} | {}",
+                    alias.send_as_email,
+                    alias.display_name.as_deref().unwrap_or(""),
+                    flags.join(", ")
+                );
+            }
+        }
+        AliasCommands::Show { email } => {
+            let alias = client.get_send_as(&email).await?;
+            if json {
+                println!("{}", serde_json::to_string(&alias)?);
+                return Ok(());
+            }
+            println!("Email: {}", alias.send_as_email);
+            println!("Name: {}", alias.display_name.as_deref().unwrap_or(""));
+            if let Some(reply_to) = &alias.reply_to_address {
+                println!("Reply-To: {}", reply_to);
+            }
+            println!("Primary: {}", alias.is_primary);
+            println!("Default: {}", alias.is_default);
+            println!("---");
+            println!("{}", alias.signature.as_deref().unwrap_or(""));
+        }
+        AliasCommands::SetDefault { email } => {
+            client.set_default_send_as(&email).await?;
+            println!("Default alias set to {}", email);
+        }
+        AliasCommands::UpdateSignature {
+            email,
+            signature,
+            file,
+        } => {
+            let signature = read_body(signature, file.as_deref())?;
+            client
+                .update_signature(&email, signature.trim_end())
+                .await?;
+            println!("Updated signature for {}", email);
+        }
+    }
+    Ok(())
+}
+
+async fn run_forwarding_command(command: ForwardingCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        ForwardingCommands::List => {
+            let addresses = client
+                .list_forwarding_addresses()
+                .await?
+                .forwarding_addresses
+                .unwrap_or_default();
+            let auto = client.get_auto_forwarding().await?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "addresses": addresses, "autoForwarding": auto })
+                );
+                return Ok(());
+            }
+            if addresses.is_empty() {
+                println!("No forwarding addresses.");
+            }
+            for address in &addresses {
+                println!(
+                    "{} ({})",
+                    address.forwarding_email,
+                    address.verification_status.as_deref().unwrap_or("unknown")
+                );
+            }
+            if auto.enabled {
+                println!(
+                    "\nAuto-forwarding: enabled to {} ({})",
+                    auto.email_address.as_deref().unwrap_or("?"),
+                    auto.disposition.as_deref().unwrap_or("leaveInInbox")
+                );
+            } else {
+                println!("\nAuto-forwarding: disabled");
+            }
+        }
+        ForwardingCommands::Add { email } => {
+            let address = client.add_forwarding_address(&email).await?;
+            println!(
+                "Added forwarding address {} ({})",
+                address.forwarding_email,
+                address.verification_status.as_deref().unwrap_or("unknown")
+            );
+        }
+        ForwardingCommands::Enable { email, disposition } => {
+            let settings = api::AutoForwarding {
+                enabled: true,
+                email_address: Some(email.clone()),
+                disposition: Some(disposition.api_value().to_string()),
+            };
+            client.update_auto_forwarding(&settings).await?;
+            println!("Forwarding incoming mail to {}", email);
+        }
+        ForwardingCommands::Disable => {
+            let settings = api::AutoForwarding {
+                enabled: false,
+                ..Default::default()
+            };
+            client.update_auto_forwarding(&settings).await?;
+            println!("Auto-forwarding disabled");
+        }
+    }
+    Ok(())
+}
+
+fn print_imap(settings: &api::ImapSettings, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(settings)?);
+        return Ok(());
+    }
+    println!(
+        "IMAP: {}",
+        if settings.enabled {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    println!("Auto-expunge: {}", settings.auto_expunge.unwrap_or(true));
+    println!(
+        "Expunge behavior: {}",
+        settings.expunge_behavior.as_deref().unwrap_or("archive")
+    );
+    match settings.max_folder_size {
+        Some(size) if size > 0 => println!("Max folder size: {}", size),
+        _ => println!("Max folder size: unlimited"),
+    }
+    Ok(())
+}
+
+fn print_pop(settings: &api::PopSettings, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(settings)?);
+        return Ok(());
+    }
+    println!(
+        "POP access: {}",
+        settings.access_window.as_deref().unwrap_or("disabled")
+    );
+    println!(
+        "Disposition: {}",
+        settings.disposition.as_deref().unwrap_or("leaveInInbox")
+    );
+    Ok(())
+}
+
+async fn run_settings_command(command: SettingsCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        SettingsCommands::Imap { command } => match command {
+            ImapCommands::Show => print_imap(&client.get_imap().await?, json)?,
+            ImapCommands::Enable {
+                expunge,
+                auto_expunge,
+                max_folder_size,
+            } => {
+                if let Some(size) = max_folder_size
+                    && ![0, 1000, 2000, 5000, 10000].contains(&size)
+                {
+                    anyhow::bail!("--max-folder-size must be 0, 1000, 2000, 5000 or 10000");
+                }
+                let mut settings = client.get_imap().await?;
+                settings.enabled = true;
+                if let Some(expunge) = expunge {
+                    settings.expunge_behavior = Some(expunge.api_value().to_string());
+                }
+                if auto_expunge.is_some() {
+                    settings.auto_expunge = auto_expunge;
+                }
+                if max_folder_size.is_some() {
+                    settings.max_folder_size = max_folder_size;
+                }
+                print_imap(&client.update_imap(&settings).await?, json)?;
+            }
+            ImapCommands::Disable => {
+                let mut settings = client.get_imap().await?;
+                settings.enabled = false;
+                print_imap(&client.update_imap(&settings).await?, json)?;
+            }
+        },
+        SettingsCommands::Pop { command } => match command {
+            PopCommands::Show => print_pop(&client.get_pop().await?, json)?,
+            PopCommands::Enable {
+                window,
+                disposition,
+            } => {
+                let settings = api::PopSettings {
+                    access_window: Some(window.api_value().to_string()),
+                    disposition: Some(disposition.api_value().to_string()),
+                };
+                print_pop(&client.update_pop(&settings).await?, json)?;
+            }
+            PopCommands::Disable => {
+                let mut settings = client.get_pop().await?;
+                settings.access_window = Some("disabled".to_string());
+                print_pop(&client.update_pop(&settings).await?, json)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+async fn run_watch_command(command: WatchCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        WatchCommands::Start { topic, label } => {
+            let mut label_ids = Vec::new();
+            for l in &label {
+                label_ids.push(client.resolve_label_for_remove(&normalize_label(l)).await?);
+            }
+            let watch = client.watch(&topic, &label_ids).await?;
+            if json {
+                println!("{}", serde_json::to_string(&watch)?);
+            } else {
+                let expires = watch
+                    .expiration
+                    .parse()
+                    .ok()
+                    .and_then(date::from_millis)
+                    .map(|dt| dt.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or(watch.expiration);
+                println!(
+                    "Watching (history ID {}, expires {})",
+                    watch.history_id, expires
+                );
+            }
+        }
+        WatchCommands::Stop => {
+            client.stop_watch().await?;
+            println!("Stopped push notifications");
+        }
+        WatchCommands::Listen { subscription } => {
+            let mut history_id = client.get_profile().await?.history_id;
+            if !json {
+                eprintln!("Listening on {} (Ctrl-C to stop)...", subscription);
+            }
+            loop {
+                let pulled = client.pubsub_pull(&subscription, 100).await?;
+                if pulled.received_messages.is_empty() {
+                    continue;
+                }
+
+                let history = client.list_history(&history_id, None).await?;
+                for record in &history.history {
+                    for added in &record.messages_added {
+                        print_new_message_event(&client, &added.message.id, json).await?;
+                    }
+                }
+                if let Some(latest) = history.history_id {
+                    history_id = latest;
+                }
+
+                let ack_ids: Vec<String> = pulled
+                    .received_messages
+                    .into_iter()
+                    .map(|m| m.ack_id)
+                    .collect();
+                client.pubsub_ack(&subscription, &ack_ids).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+const POLL_PAGE_SIZE: u32 = 100;
+// Wait before retrying a poll that failed on Google's side or the network's,
+// doubling up to the maximum while it keeps failing
+const POLL_RETRY_START: Duration = Duration::from_secs(5);
+const POLL_RETRY_MAX: Duration = Duration::from_secs(300);
+
+async fn run_poll_watch(
+    interval: u64,
+    query: Option<&str>,
+    label: &str,
+    exec: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let client = get_client().await?;
+
+    // Everything already matching is considered seen; only later arrivals trigger
+    let initial = client
+        .list_messages_page(query, label, POLL_PAGE_SIZE, None)
+        .await?;
+    let mut seen: HashSet<String> = initial
+        .messages
+        .unwrap_or_default()
+        .into_iter()
+        .map(|m| m.id)
+        .collect();
+
+    if !json {
+        eprintln!("Polling every {}s (Ctrl-C to stop)...", interval);
+    }
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    let mut retry_in = None;
+    loop {
+        match retry_in {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => {
+                ticker.tick().await;
+            }
+        }
+        match poll_new_messages(&client, query, label, exec, json, &mut seen).await {
+            Ok(()) => retry_in = None,
+            Err(e) if is_transient(&e) => {
+                let delay = retry_in.map_or(POLL_RETRY_START, |delay: Duration| {
+                    (delay * 2).min(POLL_RETRY_MAX)
+                });
+                tracing::warn!("Poll failed, retrying in {}s: {:#}", delay.as_secs(), e);
+                retry_in = Some(delay);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Handle messages on the newest page that aren't in `seen`, oldest first.
+///
+/// `seen` ends up holding only that page's IDs, which is all a later poll can
+/// see again, so it stays bounded however long `watch` runs.
+async fn poll_new_messages(
+    client: &api::Client,
+    query: Option<&str>,
+    label: &str,
+    exec: Option<&str>,
+    json: bool,
+    seen: &mut HashSet<String>,
+) -> Result<()> {
+    let page = client
+        .list_messages_page(query, label, POLL_PAGE_SIZE, None)
+        .await?;
+    let messages = page.messages.unwrap_or_default();
+
+    // The API lists newest first; handle arrivals in the order they came in
+    for msg in messages.iter().rev() {
+        if seen.contains(&msg.id) {
+            continue;
+        }
+        match exec {
+            Some(template) => run_trigger(template, msg).await?,
+            None => match print_new_message_event(client, &msg.id, json).await {
+                Ok(()) => {}
+                // Deleted again before we got to it
+                Err(e) if gmail_error(&e).is_some_and(|e| e.is_not_found()) => {}
+                Err(e) => return Err(e),
+            },
+        }
+        // Only once handled, so a retried poll picks up where this one failed
+        seen.insert(msg.id.clone());
+    }
+    seen.retain(|id| messages.iter().any(|m| &m.id == id));
+    Ok(())
+}
+
+/// The Gmail API error behind `e`, if that's what it is.
+fn gmail_error(e: &anyhow::Error) -> Option<&gmail::GmailError> {
+    e.chain()
+        .find_map(|c| c.downcast_ref::<gmail::GmailError>())
+}
+
+/// Whether `e` may go away by trying again: a rate limit, a server error or no network.
+fn is_transient(e: &anyhow::Error) -> bool {
+    gmail_error(e).is_some_and(|e| e.is_transient() || e.is_network())
+}
+
+async fn run_trigger(template: &str, msg: &api::MessageRef) -> Result<()> {
+    let thread_id = msg.thread_id.as_deref().unwrap_or_default();
+    let command = template
+        .replace("{id}", &msg.id)
+        .replace("{thread}", thread_id);
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .env("GMAIL_MESSAGE_ID", &msg.id)
+        .env("GMAIL_THREAD_ID", thread_id)
+        .status()
+        .await
+        .with_context(|| format!("Failed to run: {}", command))?;
+    if !status.success() {
+        eprintln!("Command exited with {}: {}", status, command);
+    }
+    Ok(())
+}
+
+async fn print_new_message_event(client: &api::Client, id: &str, json: bool) -> Result<()> {
+    let msg = client.get_message_metadata(id, LIST_HEADERS).await?;
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "event": "messageAdded",
+                "id": msg.id,
+                "threadId": msg.thread_id,
+                "from": msg.get_header("From"),
+                "subject": msg.get_header("Subject"),
+            })
+        );
+    } else {
+        println!(
+            "{} | {} | {}",
+            msg.id,
+            msg.get_header("From").unwrap_or("Unknown"),
+            msg.get_header("Subject").unwrap_or("(no subject)")
+        );
+    }
+    Ok(())
+}
+
+fn run_config_command(command: ConfigCommands, json: bool) -> Result<()> {
+    match command {
+        ConfigCommands::Set { key, value } => {
+            let path = config::set_setting(&key, &value)?;
+            println!("Set {} = {} in {}", key, value, path.display());
+        }
+        ConfigCommands::Get { key } => match config::get_setting(&key)? {
+            Some(value) => println!("{}", value),
+            None => anyhow::bail!("{} is not set", key),
+        },
+        ConfigCommands::List => {
+            let mut values = serde_json::Map::new();
+            for (key, description) in config::SETTINGS {
+                if key.contains('<') {
+                    continue;
+                }
+                let value = config::get_setting(key)?;
+                if json {
+                    values.insert(key.to_string(), serde_json::json!(value));
+                } else {
+                    let shown = value.as_deref().unwrap_or("(unset)");
+                    println!("{:<14} {:<30} {}", key, shown, description);
+                }
+            }
+            let cfg = config::load_config()?;
+            for (name, query) in &cfg.query {
+                let key = format!("query.{}", name);
+                if json {
+                    values.insert(key, serde_json::json!(query));
+                } else {
+                    println!("{:<14} {}", key, query);
+                }
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&values)?);
+            }
+        }
+        ConfigCommands::Edit => {
+            let path = config::init_settings_file()?;
+            run_editor(&path)?;
+            config::validate_config_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_labels_command(command: LabelsCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        LabelsCommands::Create { name, color } => {
+            let label = client
+                .create_label_path(&name, color.to_color()?.as_ref())
+                .await?;
+            if json {
+                println!("{}", serde_json::to_string(&label)?);
+            } else {
+                println!("Created label {} ({})", label.name, label.id);
+            }
+        }
+        LabelsCommands::Delete { name } => {
+            let id = client
+                .find_label(&name)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Label not found: {}", name))?;
+            client.delete_label(&id).await?;
+            println!("Deleted label {}", name);
+        }
+        LabelsCommands::Rename { name, new_name } => {
+            let count = client.rename_label(&name, &new_name).await?;
+            if count > 1 {
+                println!(
+                    "Renamed {} to {} ({} nested labels)",
+                    name,
+                    new_name,
+                    count - 1
+                );
+            } else {
+                println!("Renamed {} to {}", name, new_name);
+            }
+        }
+        LabelsCommands::Color { name, color } => {
+            let color = color
+                .to_color()?
+                .ok_or_else(|| anyhow::anyhow!("Pass --background and --text"))?;
+            let id = client
+                .find_label(&name)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Label not found: {}", name))?;
+            let label = client
+                .update_label(&id, &serde_json::json!({ "color": color }))
+                .await?;
+            if json {
+                println!("{}", serde_json::to_string(&label)?);
+            } else {
+                println!("Updated colors of {}", label.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_accounts_command(command: AccountsCommands, json: bool) -> Result<()> {
+    match command {
+        AccountsCommands::List => {
+            let default = config::default_account();
+            let names = config::list_accounts()?;
+            if json {
+                let accounts: Vec<_> = names
+                    .iter()
+                    .map(|name| {
+                        serde_json::json!({
+                            "name": name,
+                            "default": default.as_deref() == Some(name.as_str()),
+                            "loggedIn": config::account_logged_in(name),
+                        })
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string(&accounts)?);
+            } else if names.is_empty() {
+                println!("No accounts. Add one with 'gmail accounts add <name>'");
+            } else {
+                for name in &names {
+                    let marker = if default.as_deref() == Some(name.as_str()) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    let status = if config::account_logged_in(name) {
+                        ""
+                    } else {
+                        " (not logged in)"
+                    };
+                    println!("{} {}{}", marker, name, status);
+                }
+            }
+        }
+        AccountsCommands::Add { name, full_access } => {
+            config::set_account(&name)?;
+            let adopted = config::adopt_legacy_profile(&name)?;
+            if adopted {
+                println!("Moved the existing login into account {}", name);
+            }
+            // An adopted login only needs renewing to widen its access
+            if !adopted || full_access {
+                let cfg = config::load_config()?;
+                let mut scopes = auth::DEFAULT_SCOPES.to_vec();
+                if full_access {
+                    scopes.push(auth::SCOPE_FULL);
+                }
+                let options = auth::LoginOptions {
+                    browser: login_browser(None, false, &cfg),
+                    ..Default::default()
+                };
+                auth::login(cfg.client_id(), cfg.client_secret(), &scopes, &options).await?;
+            }
+            if config::default_account().is_none() {
+                config::set_default_account(Some(&name))?;
+                println!("Added account {} (default)", name);
+            } else {
+                println!("Added account {}", name);
+            }
+        }
+        AccountsCommands::Remove { name } => {
+            config::remove_account(&name)?;
+            println!("Removed account {}", name);
+        }
+        AccountsCommands::Default { name } => {
+            if !config::account_dir(&name).exists() {
+                anyhow::bail!(
+                    "No account named '{}'. Add it with 'gmail accounts add {}'",
+                    name,
+                    name
+                );
+            }
+            config::set_default_account(Some(&name))?;
+            println!("Default account set to {}", name);
+        }
+    }
+    Ok(())
+}
+
+const TRIAGE_PROMPT: &str =
+    "[a]rchive [d]elete [s]pam [l]abel [r]ead [A]lways archive sender [space] skip [q]uit";
+
+async fn run_triage(query: &str, max: u32) -> Result<()> {
+    use std::io::{IsTerminal, Write};
+
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("triage needs an interactive terminal");
+    }
+    let client = get_client().await?;
+    let refs = client
+        .list_messages(Some(query), "", max)
+        .await?
+        .messages
+        .unwrap_or_default();
+    if refs.is_empty() {
+        println!("Nothing to triage.");
+        return Ok(());
+    }
+
+    let palette = style::palette();
+    let mut handled = 0;
+    'messages: for (i, msg_ref) in refs.iter().enumerate() {
+        let msg = client
+            .get_message_metadata(&msg_ref.id, LIST_HEADERS)
+            .await?;
+        let ids = std::slice::from_ref(&msg.id);
+        let from = msg.get_header("From").unwrap_or("Unknown");
+        println!();
+        println!(
+            "{} {}",
+            palette.header.paint(&format!("[{}/{}]", i + 1, refs.len())),
+            from
+        );
+        println!(
+            "{}",
+            palette
+                .unread
+                .paint(msg.get_header("Subject").unwrap_or("(no subject)"))
+        );
+        if let Some(dt) = msg.date() {
+            println!("{}", date::format_relative(dt, chrono::Local::now()));
+        }
+        println!("{}", msg.snippet.as_deref().unwrap_or_default());
+
+        loop {
+            print!("{} > ", TRIAGE_PROMPT);
+            std::io::stdout().flush()?;
+            let key = read_key()?;
+            println!();
+            match key {
+                'a' => {
+                    client.batch_modify(ids, &[], &["INBOX"]).await?;
+                    println!("Archived");
+                }
+                'd' => {
+                    client.batch_trash(ids).await?;
+                    println!("Moved to trash");
+                }
+                's' => {
+                    client.batch_modify(ids, &["SPAM"], &["INBOX"]).await?;
+                    println!("Marked as spam");
+                }
+                'r' => {
+                    client.batch_modify(ids, &[], &["UNREAD"]).await?;
+                    println!("Marked as read");
+                }
+                'l' => {
+                    print!("Label: ");
+                    std::io::stdout().flush()?;
+                    let mut label = String::new();
+                    std::io::stdin().read_line(&mut label)?;
+                    let label = label.trim();
+                    if label.is_empty() {
+                        continue;
+                    }
+                    let label_id = client
+                        .resolve_label_for_add(&normalize_label(label))
+                        .await?;
+                    client.batch_modify(ids, &[&label_id], &[]).await?;
+                    println!("Labeled {}", label);
+                }
+                'A' => {
+                    let sender = mime::address_of(from).to_string();
+                    let filter = api::Filter {
+                        id: None,
+                        criteria: api::FilterCriteria {
+                            from: Some(sender.clone()),
+                            ..Default::default()
+                        },
+                        action: api::FilterAction {
+                            remove_label_ids: vec!["INBOX".to_string()],
+                            ..Default::default()
+                        },
+                    };
+                    client.create_filter(&filter).await?;
+                    client.batch_modify(ids, &[], &["INBOX"]).await?;
+                    println!("Archived; mail from {} will skip the inbox", sender);
+                }
+                ' ' | 'n' => {
+                    println!("Skipped");
+                    continue 'messages;
+                }
+                // Ctrl-C and Ctrl-D arrive as plain bytes while reading single keys
+                'q' | '\x03' | '\x04' => break 'messages,
+                _ => continue,
+            }
+            handled += 1;
+            break;
+        }
+    }
+    println!("\nHandled {} of {} messages", handled, refs.len());
+    Ok(())
+}
+
+/// Read one keypress from the terminal without waiting for Enter.
+fn read_key() -> Result<char> {
+    use std::io::Read;
+
+    let saved = stty(&["-g"])?;
+    stty(&["-icanon", "-echo", "-isig", "min", "1"])?;
+    let mut byte = [0u8; 1];
+    let read = std::io::stdin().read_exact(&mut byte);
+    stty(&[saved.trim()])?;
+    read?;
+    Ok(byte[0] as char)
+}
+
+// stty acts on the terminal it gets as stdin, so that must be inherited
+fn stty(args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("stty")
+        .args(args)
+        .stdin(std::process::Stdio::inherit())
+        .output()
+        .context("Failed to run stty")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "stty failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn run_import(
+    files: &[PathBuf],
+    mbox: bool,
+    labels: &[String],
+    date_source: DateSource,
+    never_mark_spam: bool,
+    json: bool,
+) -> Result<()> {
+    let client = get_client().await?;
+    let mut label_ids = Vec::new();
+    for label in labels {
+        label_ids.push(
+            client
+                .resolve_label_for_add(&normalize_label(label))
+                .await?,
+        );
+    }
+
+    // Messages are read and imported one at a time, so an archive of any size
+    // streams through, and a rerun after an interruption skips what got in
+    let bar = if mbox {
+        progress_spinner("Importing...")
+    } else {
+        progress_bar(files.len())
+    };
+    let mut ids = Vec::new();
+    let mut skipped = 0;
+    for path in files {
+        let mut empty = false;
+        let messages: Box<dyn Iterator<Item = std::io::Result<Vec<u8>>>> = if mbox {
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            empty = file.metadata()?.len() == 0;
+            Box::new(gmail::mbox::Reader::new(std::io::BufReader::new(file)))
+        } else {
+            Box::new(std::iter::once(std::fs::read(path)))
+        };
+        let mut found = false;
+        for raw in messages {
+            let raw = raw.with_context(|| format!("Failed to read {}", path.display()))?;
+            found = true;
+            match import_new_message(&client, &raw, &label_ids, date_source, never_mark_spam)
+                .await?
+            {
+                Some(id) => ids.push(id),
+                None => skipped += 1,
+            }
+            bar.inc(1);
+            bar.set_message(format!("Imported {}, skipped {}", ids.len(), skipped));
+        }
+        // An empty file is a valid mbox of no messages
+        if !found && !empty {
+            anyhow::bail!("{} is not an mbox archive", path.display());
+        }
+    }
+    bar.finish_and_clear();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "imported": ids, "skipped": skipped })
+        );
+    } else if skipped > 0 {
+        println!(
+            "Imported {} messages, skipped {} already in the mailbox",
+            ids.len(),
+            skipped
+        );
+    } else {
+        println!("Imported {} messages", ids.len());
+    }
+    Ok(())
+}
+
+/// Import `raw` unless a message with its Message-ID is already in the mailbox.
+/// Returns the new message's ID, or `None` if it was skipped.
+async fn import_new_message(
+    client: &api::Client,
+    raw: &[u8],
+    label_ids: &[String],
+    date_source: DateSource,
+    never_mark_spam: bool,
+) -> Result<Option<String>> {
+    let message_id = mime::raw_header(&String::from_utf8_lossy(raw), "Message-ID");
+    if let Some(message_id) = message_id.filter(|id| !id.is_empty())
+        && !client.find_by_rfc822_id(&message_id).await?.is_empty()
+    {
+        return Ok(None);
+    }
+    let imported = client
+        .import_message(raw, label_ids, date_source.api_value(), never_mark_spam)
+        .await?;
+    Ok(Some(imported.id))
+}
+
+/// Write a message's RFC 822 bytes, unmodified, to `out` or stdout.
+async fn write_raw_message(
+    client: &api::Client,
+    id: &str,
+    out: Option<&std::path::Path>,
+) -> Result<()> {
+    let raw = client.get_raw_message(id).await?.bytes()?;
+    match out {
+        Some(path) => std::fs::write(path, &raw)
+            .with_context(|| format!("Failed to write {}", path.display())),
+        None => {
+            use std::io::Write;
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&raw)?;
+            stdout.flush()?;
+            Ok(())
+        }
+    }
+}
+
+async fn run_export_command(command: ExportCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        ExportCommands::Eml { id, out } => {
+            write_raw_message(&client, &id, out.as_deref()).await?;
+            if let Some(out) = out {
+                println!("Saved {}", out.display());
+            }
+        }
+        ExportCommands::Maildir {
+            out,
+            label,
+            query,
+            concurrency,
+        } => {
+            let label = normalize_label(&label);
+            let label_id = if label.is_empty() {
+                label
+            } else {
+                client.resolve_label_for_remove(&label).await?
+            };
+            let maildir = gmail::maildir::Maildir::create(&out)?;
+            let existing = maildir.existing_ids()?;
+
+            let spinner = progress_spinner("Searching...");
+            let ids: Vec<String> = client
+                .list_all_message_ids(query.as_deref(), &label_id)
+                .await?
+                .into_iter()
+                .filter(|id| !existing.contains(id))
+                .collect();
+            spinner.finish_and_clear();
+
+            let bar = progress_bar(ids.len());
+            let mut messages = stream::iter(&ids)
+                .map(|id| client.get_raw_message(id))
+                .buffer_unordered(concurrency as usize);
+            while let Some(msg) = messages.next().await {
+                let msg = msg?;
+                maildir.deliver(&msg.id, &msg.bytes()?, &msg.label_ids)?;
+                bar.inc(1);
+            }
+            bar.finish_and_clear();
+
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "maildir": maildir.root(),
+                        "exported": ids.len(),
+                        "already_present": existing.len(),
+                    })
+                );
+            } else {
+                println!(
+                    "Exported {} messages to {} ({} already present)",
+                    ids.len(),
+                    maildir.root().display(),
+                    existing.len()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn find_template<'a>(cfg: &'a config::Config, name: &str) -> Result<&'a config::Template> {
+    cfg.template.get(name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "No template '{}'. Add one with: gmail template add {} --subject ... --body ...",
+            name,
+            name
+        )
+    })
+}
+
+fn run_template_command(command: TemplateCommands, cfg: &config::Config, json: bool) -> Result<()> {
+    match command {
+        TemplateCommands::Add {
+            name,
+            subject,
+            body,
+            file,
+        } => {
+            let template = config::Template {
+                subject,
+                body: read_body(body, file.as_deref())?,
+            };
+            let path = config::update_settings(|config| {
+                config.template.insert(name.clone(), template);
+                Ok(())
+            })?;
+            println!("Saved template {} in {}", name, path.display());
+        }
+        TemplateCommands::List => {
+            if json {
+                println!("{}", serde_json::to_string_pretty(&cfg.template)?);
+                return Ok(());
+            }
+            if cfg.template.is_empty() {
+                println!(
+                    "No templates. Add one with: gmail template add <name> --subject ... --body ..."
+                );
+                return Ok(());
+            }
+            let mut table = Table::new(["NAME", "SUBJECT", "PLACEHOLDERS"]);
+            for (name, template) in &cfg.template {
+                table.add_row([
+                    name.clone(),
+                    template.subject.clone(),
+                    template.placeholders().join(", "),
+                ]);
+            }
+            print!("{}", table.render(table::terminal_width()));
+        }
+        TemplateCommands::Use { name, var } => {
+            let (subject, body) = find_template(cfg, &name)?.fill(&var.into_iter().collect())?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "subject": subject, "body": body })
+                );
+            } else {
+                println!("Subject: {}\n\n{}", subject, body);
+            }
+        }
+        TemplateCommands::Remove { name } => {
+            let mut found = false;
+            config::update_settings(|config| {
+                found = config.template.remove(&name).is_some();
+                Ok(())
+            })?;
+            if !found {
+                anyhow::bail!("No template '{}' in this config file", name);
+            }
+            println!("Removed template {}", name);
+        }
+    }
+    Ok(())
+}
+
+fn queue_message(at: chrono::DateTime<chrono::Local>, raw: &[u8], json: bool) -> Result<()> {
+    let when = date::format_short(at);
+    if DRY_RUN.load(Ordering::Relaxed) {
+        eprintln!("Would queue a message to send at {}", when);
+        return Ok(());
+    }
+    let queued = gmail::outbox::Outbox::new(config::outbox_dir()).queue(at, raw)?;
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "queued": queued.id, "at": at.to_rfc3339() })
+        );
+    } else {
+        println!(
+            "Queued {} for {} (sent by 'gmail outbox flush')",
+            queued.id, when
+        );
+    }
+    Ok(())
+}
+
+/// Unsubscribe from each sender of the messages matching `query`, via their latest message.
+async fn run_bulk_unsubscribe(
+    client: &api::Client,
+    query: &str,
+    max: u32,
+    json: bool,
+) -> Result<()> {
+    let ids: Vec<String> = client
+        .list_messages(Some(query), "", max)
+        .await?
+        .messages
+        .unwrap_or_default()
+        .into_iter()
+        .map(|m| m.id)
+        .collect();
+    let messages = fetch_messages(client, &ids, 10).await?;
+
+    // Results come newest first, so the first message seen per sender is their latest
+    let mut latest: Vec<(String, String)> = Vec::new();
+    for msg in &messages {
+        let Some(from) = msg.get_header("From") else {
+            continue;
+        };
+        let sender = stats::sender_key(from, false);
+        if !latest.iter().any(|(s, _)| *s == sender) {
+            latest.push((sender, msg.id.clone()));
+        }
+    }
+    if latest.is_empty() {
+        println!("No messages match \"{}\"", query);
+        return Ok(());
+    }
+
+    confirm(&format!(
+        "Really unsubscribe from {} senders?",
+        format_count(latest.len())
+    ))?;
+
+    let mut results = Vec::new();
+    for (sender, id) in &latest {
+        let outcome = if DRY_RUN.load(Ordering::Relaxed) {
+            client.unsubscribe_links(id).await.map(|links| {
+                (
+                    "would use",
+                    links.preferred().unwrap_or_default().to_string(),
+                )
+            })
+        } else {
+            client.unsubscribe(id).await.map(|done| match done {
+                api::Unsubscribed::OneClick(url) => ("one-click", url),
+                api::Unsubscribed::Mailto(uri) => ("mailto", uri),
+                // Opening one browser tab per sender would be a mess; list them instead
+                api::Unsubscribed::Manual(url) => ("open", url),
+            })
+        };
+        results.push((sender, id, outcome));
+    }
+
+    // Lists without one-click want an email; show where each goes before sending any
+    let mut emails = Vec::new();
+    for (index, (_, _, outcome)) in results.iter_mut().enumerate() {
+        if let Ok(("mailto", uri)) = outcome {
+            match api::mailto_email(uri) {
+                Ok(email) => emails.push((index, email)),
+                Err(e) => *outcome = Err(e),
+            }
+        }
+    }
+    if !emails.is_empty() {
+        eprintln!("These senders want an unsubscribe email:");
+        for (_, email) in &emails {
+            eprintln!("  {}", describe_unsubscribe_email(email));
+        }
+        let send = confirm(&format!("Send {} emails?", format_count(emails.len()))).is_ok();
+        for (index, email) in emails {
+            let address = email.to[0].clone();
+            results[index].2 = if !send {
+                Ok(("skipped", address))
+            } else {
+                match client
+                    .send_message(email.to_rfc822().as_bytes(), None)
+                    .await
+                {
+                    Ok(_) => Ok(("mailto", address)),
+                    Err(e) => Err(e),
+                }
+            };
+        }
+    }
+
+    if json {
+        let items: Vec<_> = results
+            .iter()
+            .map(|(sender, id, outcome)| match outcome {
+                Ok((method, target)) => serde_json::json!({
+                    "sender": sender, "id": id, "ok": true, "method": method, "target": target,
+                }),
+                Err(e) => serde_json::json!({
+                    "sender": sender, "id": id, "ok": false, "error": format!("{:#}", e),
+                }),
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&items)?);
+        return Ok(());
+    }
+    let palette = style::palette();
+    let mut table = Table::new(["SENDER", "RESULT", "DETAILS"]);
+    table.style_header(palette.header.clone());
+    let mut failed = 0;
+    for (sender, _, outcome) in &results {
+        match outcome {
+            Ok((method, target)) => {
+                table.add_row([sender.to_string(), method.to_string(), target.clone()])
+            }
+            Err(e) => {
+                failed += 1;
+                table.add_row([sender.to_string(), "failed".to_string(), format!("{:#}", e)]);
+            }
+        }
+    }
+    print!("{}", table.render(table::terminal_width()));
+    if failed > 0 {
+        anyhow::bail!("{} of {} senders failed", failed, results.len());
+    }
+    Ok(())
+}
+
+fn describe_unsubscribe_email(email: &mime::Email) -> String {
+    format!("{} (subject \"{}\")", email.to[0], email.subject)
+}
+
+/// Show what `unsubscribe` would use for a message, without acting on it.
+async fn print_unsubscribe_links(client: &api::Client, id: &str, json: bool) -> Result<()> {
+    let links = client.unsubscribe_links(id).await?;
+    if json {
+        let mut value = serde_json::to_value(&links)?;
+        value["id"] = serde_json::json!(id);
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        return Ok(());
+    }
+    let mechanisms = [
+        ("One-click", &links.one_click),
+        ("Mailto", &links.mailto),
+        ("Web link", &links.url),
+    ];
+    for (name, link) in mechanisms {
+        let Some(link) = link else {
+            continue;
+        };
+        let note = if links.preferred() == Some(link) {
+            "  (would be used)"
+        } else {
+            ""
+        };
+        println!("{:<10} {}{}", format!("{}:", name), link, note);
+    }
+    Ok(())
+}
+
+async fn run_stats_command(command: StatsCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        StatsCommands::Senders {
+            query,
+            top,
+            by_domain,
+            concurrency,
+        } => {
+            let spinner = progress_spinner("Searching...");
+            let ids = client.list_all_message_ids(query.as_deref(), "").await?;
+            spinner.finish_and_clear();
+
+            let messages = fetch_metadata_with_progress(&client, &ids, concurrency).await?;
+            let mut tallies = stats::tally(messages.iter().filter_map(|msg| {
+                let from = msg.get_header("From")?;
+                Some((
+                    stats::sender_key(from, by_domain),
+                    msg.size_estimate.unwrap_or(0),
+                ))
+            }));
+            tallies.truncate(top);
+
+            if json {
+                let items: Vec<_> = tallies
+                    .iter()
+                    .map(|t| serde_json::json!({ "sender": t.key, "count": t.count, "bytes": t.bytes }))
+                    .collect();
+                println!("{}", serde_json::to_string(&items)?);
+                return Ok(());
+            }
+            if tallies.is_empty() {
+                println!("No messages found");
+                return Ok(());
+            }
+            let mut table = Table::new([
+                if by_domain { "DOMAIN" } else { "SENDER" },
+                "MESSAGES",
+                "SIZE",
+            ]);
+            for t in &tallies {
+                table.add_row([t.key.clone(), format_count(t.count), format_size(t.bytes)]);
+            }
+            print!("{}", table.render(table::terminal_width()));
+            println!("{} messages scanned", format_count(messages.len()));
+        }
+        StatsCommands::Usage {
+            sample,
+            full,
+            biggest,
+            concurrency,
+        } => run_usage_report(&client, sample, full, biggest, concurrency, json).await?,
+    }
+    Ok(())
+}
+
+async fn run_usage_report(
+    client: &api::Client,
+    sample: u32,
+    full: bool,
+    biggest: usize,
+    concurrency: u32,
+    json: bool,
+) -> Result<()> {
+    let spinner = progress_spinner("Counting...");
+    let mut labels = Vec::new();
+    for label in client.list_labels().await?.labels.unwrap_or_default() {
+        let label = client.get_label(&label.id).await?;
+        if label.messages_total.unwrap_or(0) == 0 {
+            continue;
+        }
+        let ids = if full {
+            client.list_all_message_ids(None, &label.id).await?
+        } else {
+            let page = client
+                .list_messages_page(None, &label.id, sample, None)
+                .await?;
+            page.messages
+                .unwrap_or_default()
+                .into_iter()
+                .map(|m| m.id)
+                .collect()
+        };
+        labels.push((label, ids));
+    }
+
+    // Only messages above some size are worth ranking; widen until enough turn up
+    let mut big_ids = Vec::new();
+    for threshold in stats::SIZE_THRESHOLDS {
+        big_ids = client
+            .list_all_message_ids(Some(&format!("larger:{}", threshold)), "")
+            .await?;
+        if big_ids.len() >= biggest {
+            break;
+        }
+    }
+    spinner.finish_and_clear();
+
+    let mut wanted: Vec<String> = labels.iter().flat_map(|(_, ids)| ids.clone()).collect();
+    wanted.extend(big_ids.iter().cloned());
+    wanted.sort();
+    wanted.dedup();
+    let messages = fetch_metadata_with_progress(client, &wanted, concurrency).await?;
+    let sizes: std::collections::HashMap<&str, u64> = messages
+        .iter()
+        .map(|msg| (msg.id.as_str(), msg.size_estimate.unwrap_or(0)))
+        .collect();
+
+    let mut usage: Vec<(String, u64, u64, bool)> = labels
+        .iter()
+        .map(|(label, ids)| {
+            let total = u64::from(label.messages_total.unwrap_or(0));
+            let sample: Vec<u64> = ids
+                .iter()
+                .filter_map(|id| sizes.get(id.as_str()).copied())
+                .collect();
+            let estimated = (sample.len() as u64) < total;
+            let bytes = stats::estimate_total(total, &sample);
+            (label.name.clone(), total, bytes, estimated)
+        })
+        .collect();
+    usage.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+
+    let big_ids: std::collections::HashSet<&String> = big_ids.iter().collect();
+    let mut largest: Vec<&api::Message> = messages
+        .iter()
+        .filter(|msg| big_ids.contains(&msg.id))
+        .collect();
+    largest.sort_by_key(|msg| std::cmp::Reverse(msg.size_estimate.unwrap_or(0)));
+    largest.truncate(biggest);
+
+    if json {
+        let labels: Vec<_> = usage
+            .iter()
+            .map(|(name, count, bytes, estimated)| {
+                serde_json::json!({
+                    "label": name,
+                    "messages": count,
+                    "bytes": bytes,
+                    "estimated": estimated,
+                })
+            })
+            .collect();
+        let largest: Vec<_> = largest
+            .iter()
+            .map(|msg| {
+                serde_json::json!({
+                    "id": msg.id,
+                    "bytes": msg.size_estimate.unwrap_or(0),
+                    "from": msg.get_header("From"),
+                    "subject": msg.get_header("Subject"),
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::json!({ "labels": labels, "biggest": largest })
+        );
+        return Ok(());
+    }
+
+    let mut table = Table::new(["LABEL", "MESSAGES", "SIZE"]);
+    for (name, count, bytes, estimated) in &usage {
+        let size = format_size(*bytes);
+        table.add_row([
+            name.clone(),
+            format_count(*count as usize),
+            if *estimated {
+                format!("~{}", size)
+            } else {
+                size
+            },
+        ]);
+    }
+    print!("{}", table.render(table::terminal_width()));
+    if usage.iter().any(|(_, _, _, estimated)| *estimated) {
+        println!(
+            "~ estimated from the newest {} messages per label, which may not be typical (--full to count all)",
+            sample
+        );
+    }
+    if !largest.is_empty() {
+        println!();
+        let mut table = Table::new(["ID", "SIZE", "FROM", "SUBJECT"]);
+        for msg in &largest {
+            table.add_row([
+                msg.id.clone(),
+                format_size(msg.size_estimate.unwrap_or(0)),
+                msg.get_header("From").unwrap_or_default().to_string(),
+                msg.get_header("Subject").unwrap_or_default().to_string(),
+            ]);
+        }
+        print!("{}", table.render(table::terminal_width()));
+    }
+    Ok(())
+}
+
+/// Fetch list metadata for `ids` under a progress bar, skipping messages deleted meanwhile.
+async fn fetch_metadata_with_progress(
+    client: &api::Client,
+    ids: &[String],
+    concurrency: u32,
+) -> Result<Vec<api::Message>> {
+    let bar = progress_bar(ids.len());
+    let mut messages = Vec::with_capacity(ids.len());
+    let mut batches = stream::iter(ids.chunks(api::BATCH_GET_LIMIT))
+        .map(|chunk| async move { (chunk.len(), fetch_metadata_batch(client, chunk).await) })
+        .buffer_unordered(concurrency as usize);
+    while let Some((len, batch)) = batches.next().await {
+        messages.extend(batch?);
+        bar.inc(len as u64);
+    }
+    bar.finish_and_clear();
+    Ok(messages)
+}
+
+async fn run_contacts_command(command: ContactsCommands, json: bool) -> Result<()> {
+    match command {
+        ContactsCommands::Search { query, max } => {
+            let client = get_client().await?;
+            let contacts = client.search_contacts(&query, max).await?;
+            if json {
+                let items: Vec<_> = contacts
+                    .iter()
+                    .map(|c| serde_json::json!({ "name": c.name, "emails": c.emails }))
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&items)?);
+                return Ok(());
+            }
+            if contacts.is_empty() {
+                println!("No contacts match '{}'", query);
+                return Ok(());
+            }
+            let mut table = Table::new(["NAME", "EMAIL"]);
+            for contact in &contacts {
+                for email in &contact.emails {
+                    table.add_row([contact.name.clone().unwrap_or_default(), email.clone()]);
+                }
+            }
+            print!("{}", table.render(table::terminal_width()));
+        }
+    }
+    Ok(())
+}
+
+/// Replace recipients given as names with the matching contact's address.
+async fn resolve_recipients(client: &api::Client, recipients: &mut [String]) -> Result<()> {
+    for recipient in recipients.iter_mut() {
+        if contacts::needs_lookup(recipient) {
+            let found = client.search_contacts(recipient, 10).await?;
+            *recipient = contacts::resolve(recipient, &found)?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_outbox_command(command: OutboxCommands, json: bool) -> Result<()> {
+    let outbox = gmail::outbox::Outbox::new(config::outbox_dir());
+    match command {
+        OutboxCommands::List => {
+            let queued = outbox.list()?;
+            if json {
+                let items: Vec<_> = queued
+                    .iter()
+                    .map(|item| serde_json::json!({ "id": item.id, "at": item.at.to_rfc3339() }))
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&items)?);
+                return Ok(());
+            }
+            if queued.is_empty() {
+                println!("The outbox is empty");
+                return Ok(());
+            }
+            let mut table = Table::new(["ID", "SEND AT", "TO", "SUBJECT"]);
+            for item in &queued {
+                let raw = String::from_utf8_lossy(&item.read()?).into_owned();
+                let header = |name| mime::raw_header(&raw, name).unwrap_or_default();
+                table.add_row([
+                    item.id.clone(),
+                    date::format_short(item.at),
+                    header("To"),
+                    header("Subject"),
+                ]);
+            }
+            print!("{}", table.render(table::terminal_width()));
+        }
+        OutboxCommands::Flush => {
+            let due = outbox.due(chrono::Local::now())?;
+            if due.is_empty() {
+                if json {
+                    println!("[]");
+                }
+                return Ok(());
+            }
+            let client = get_client().await?;
+            let mut sent = Vec::new();
+            for item in &due {
+                // Another flush running at the same time may have taken it
+                let Some(claimed) = item.claim()? else {
+                    continue;
+                };
+                let message = match client.send_message(&claimed.read()?, None).await {
+                    Ok(message) => message,
+                    Err(e) => {
+                        // Back in the queue, so the next flush retries it
+                        claimed.release()?;
+                        return Err(e.into());
+                    }
+                };
+                claimed.remove()?;
+                if !json {
+                    println!("Sent {} as {}", item.id, message.id);
+                }
+                sent.push(serde_json::json!({ "queued": item.id, "id": message.id }));
+            }
+            if json {
+                println!("{}", serde_json::to_string(&sent)?);
+            }
+        }
+        OutboxCommands::Cancel { id } => {
+            outbox.find(&id)?.remove()?;
+            println!("Cancelled {}", id);
+        }
+    }
+    Ok(())
+}
+
+// Snoozed messages carry "Snoozed/<wake-up time>" until `snooze wake` returns them
+const SNOOZE_LABEL_PREFIX: &str = "Snoozed/";
+const SNOOZE_TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+async fn snooze(ids: &[String], until: &str, json: bool) -> Result<()> {
+    let wake = date::parse_future(until, chrono::Local::now())?;
+    let label = format!("{}{}", SNOOZE_LABEL_PREFIX, wake.format(SNOOZE_TIME_FORMAT));
+    let client = get_client().await?;
+    let label_id = client.resolve_label_for_add(&label).await?;
+    client.batch_modify(ids, &[&label_id], &["INBOX"]).await?;
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "ids": ids, "until": wake.to_rfc3339(), "label": label })
+        );
+    } else {
+        println!(
+            "Snoozed {} until {}",
+            describe_ids(ids),
+            wake.format(SNOOZE_TIME_FORMAT)
+        );
+    }
+    Ok(())
+}
+
+/// Snooze labels with their wake-up times, soonest first.
+async fn snooze_labels(
+    client: &api::Client,
+) -> Result<Vec<(chrono::DateTime<chrono::Local>, api::Label)>> {
+    let mut snoozed: Vec<_> = client
+        .list_labels()
+        .await?
+        .labels
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|label| {
+            let time = label.name.strip_prefix(SNOOZE_LABEL_PREFIX)?;
+            let wake = date::parse_local(time).ok()?;
+            Some((wake, label))
+        })
+        .collect();
+    snoozed.sort_by_key(|(wake, _)| *wake);
+    Ok(snoozed)
+}
+
+async fn run_snooze_command(command: SnoozeCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    let snoozed = snooze_labels(&client).await?;
+    match command {
+        SnoozeCommands::List => {
+            let mut items = Vec::new();
+            for (wake, label) in &snoozed {
+                let ids = client.list_all_message_ids(None, &label.id).await?;
+                items.push((wake, ids));
+            }
+            if json {
+                let items: Vec<_> = items
+                    .iter()
+                    .map(
+                        |(wake, ids)| serde_json::json!({ "until": wake.to_rfc3339(), "ids": ids }),
+                    )
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&items)?);
+            } else if items.is_empty() {
+                println!("Nothing is snoozed");
+            } else {
+                let mut table = Table::new(["UNTIL", "MESSAGES"]);
+                for (wake, ids) in &items {
+                    table.add_row([wake.format(SNOOZE_TIME_FORMAT).to_string(), ids.join(" ")]);
+                }
+                print!("{}", table.render(table::terminal_width()));
+            }
+        }
+        SnoozeCommands::Wake => {
+            let now = chrono::Local::now();
+            let mut woken = Vec::new();
+            for (_, label) in snoozed.iter().filter(|(wake, _)| *wake <= now) {
+                let ids = client.list_all_message_ids(None, &label.id).await?;
+                if !ids.is_empty() {
+                    client
+                        .batch_modify(&ids, &["INBOX", "UNREAD"], &[&label.id])
+                        .await?;
+                }
+                // Each wake-up time has its own label; drop it once it's empty
+                client.delete_label(&label.id).await?;
+                woken.extend(ids);
+            }
+            if json {
+                println!("{}", serde_json::json!({ "woken": woken }));
+            } else if !woken.is_empty() {
+                println!("Returned {} to the inbox", describe_ids(&woken));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_rules_command(command: RulesCommands, json: bool) -> Result<()> {
+    use gmail::rules::{Action, RuleSet};
+
+    let load = |file: Option<PathBuf>| RuleSet::load(&file.unwrap_or_else(config::rules_path));
+    match command {
+        RulesCommands::List { file } => {
+            let set = load(file)?;
+            if json {
+                let items: Vec<_> = set
+                    .rules
+                    .iter()
+                    .enumerate()
+                    .map(|(i, rule)| {
+                        serde_json::json!({ "name": rule.display_name(i), "query": rule.query() })
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&items)?);
+                return Ok(());
+            }
+            let mut table = Table::new(["NAME", "QUERY"]);
+            for (i, rule) in set.rules.iter().enumerate() {
+                table.add_row([rule.display_name(i), rule.query()]);
+            }
+            print!("{}", table.render(table::terminal_width()));
+        }
+        RulesCommands::Apply {
+            file,
+            rule,
+            all_mail,
+        } => {
+            let set = load(file)?;
+            let rules: Vec<_> = set
+                .rules
+                .iter()
+                .enumerate()
+                .filter(|(i, r)| rule.is_none() || rule.as_ref() == Some(&r.display_name(*i)))
+                .collect();
+            if rules.is_empty() {
+                match rule {
+                    Some(name) => anyhow::bail!("No rule named '{}'", name),
+                    None => anyhow::bail!("No rules to apply"),
+                }
+            }
+
+            let client = get_client().await?;
+            let scope = if all_mail { "" } else { "INBOX" };
+            // Match everything before changing anything, so one confirmation covers all rules
+            let mut matches = Vec::new();
+            for (i, rule) in &rules {
+                let ids = client
+                    .list_all_message_ids(Some(&rule.query()), scope)
+                    .await?;
+                matches.push((rule.display_name(*i), *rule, ids));
+            }
+            let total: usize = matches.iter().map(|(_, _, ids)| ids.len()).sum();
+            if total > 0 {
+                confirm(&format!(
+                    "Apply {} rules to {} messages?",
+                    rules.len(),
+                    format_count(total)
+                ))?;
+            }
+
+            let mut results = Vec::new();
+            for (name, rule, ids) in &matches {
+                if !ids.is_empty() {
+                    let (mut add, remove) = rule.label_changes();
+                    let mut label_ids = Vec::new();
+                    if rule.actions.contains(&Action::Label) {
+                        for label in &rule.label {
+                            label_ids.push(
+                                client
+                                    .resolve_label_for_add(&normalize_label(label))
+                                    .await?,
+                            );
+                        }
+                    }
+                    add.extend(label_ids.iter().map(String::as_str));
+                    client.batch_modify(ids, &add, &remove).await?;
+                }
+                if !json {
+                    println!("{}: {} messages", name, format_count(ids.len()));
+                }
+                results.push(serde_json::json!({ "rule": name, "count": ids.len() }));
+            }
+            if json {
+                println!("{}", serde_json::to_string(&results)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_sync(full: bool, bodies: bool, concurrency: u32, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    // Syncing fills the cache even when `cache` is off for other commands
+    let client = match client.cache() {
+        Some(_) => client,
+        None => client.with_cache(gmail::cache::Cache::open(&config::cache_path())?),
+    };
+    let cache = client.cache().expect("cache was just set");
+
+    let mut added = 0;
+    let mut updated = 0;
+    let mut deleted = 0;
+    let start = if full { None } else { cache.history_id()? };
+    let history = match start {
+        Some(start) => match client.list_history(&start, None).await {
+            Ok(history) => Some(history),
+            // Gmail keeps about a week of history; start over when it's gone
+            Err(e) if e.is_not_found() => {
+                eprintln!("History {} has expired, fetching everything again", start);
+                None
+            }
+            Err(e) => return Err(e.into()),
+        },
+        None => None,
+    };
+
+    let history_id = match history {
+        Some(history) => {
+            let mut to_fetch = std::collections::BTreeSet::new();
+            for record in &history.history {
+                for m in &record.messages_added {
+                    to_fetch.insert(m.message.id.clone());
+                }
+                for m in &record.messages_deleted {
+                    to_fetch.remove(&m.message.id);
+                    deleted += cache.forget(std::slice::from_ref(&m.message.id))?;
+                }
+                for change in &record.labels_added {
+                    if !cache.change_labels(&change.message.id, &change.label_ids, &[])? {
+                        to_fetch.insert(change.message.id.clone());
+                    } else {
+                        updated += 1;
+                    }
+                }
+                for change in &record.labels_removed {
+                    if !cache.change_labels(&change.message.id, &[], &change.label_ids)? {
+                        to_fetch.insert(change.message.id.clone());
+                    } else {
+                        updated += 1;
+                    }
+                }
+            }
+            let ids: Vec<String> = to_fetch.into_iter().collect();
+            added += sync_messages(&client, &ids, bodies, concurrency).await?;
+            match history.history_id {
+                Some(id) => id,
+                None => client.get_profile().await?.history_id,
+            }
+        }
+        None => {
+            // Taken before listing so changes made during the pull are picked up next time
+            let history_id = client.get_profile().await?.history_id;
+            let spinner = progress_spinner("Listing messages...");
+            let ids = client.list_all_message_ids(None, "").await?;
+            spinner.finish_and_clear();
+            added += sync_messages(&client, &ids, bodies, concurrency).await?;
+            history_id
+        }
+    };
+    cache.set_history_id(&history_id)?;
+
+    let total = cache.count()?;
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "fetched": added,
+                "updated": updated,
+                "deleted": deleted,
+                "cached": total,
+                "historyId": history_id,
+            })
+        );
+    } else {
+        println!(
+            "Fetched {}, updated {}, deleted {} ({} messages cached)",
+            format_count(added),
+            format_count(updated),
+            format_count(deleted),
+            format_count(total)
+        );
+    }
+    Ok(())
+}
+
+/// Fetch `ids` into the cache, skipping messages it already has and ones
+/// deleted since they were listed. Returns how many were fetched.
+async fn sync_messages(
+    client: &api::Client,
+    ids: &[String],
+    bodies: bool,
+    concurrency: u32,
+) -> Result<usize> {
+    use gmail::cache::Format;
+
+    let cache = client.cache().expect("sync needs a cache");
+    let format = if bodies {
+        Format::Full
+    } else {
+        Format::Metadata(LIST_HEADERS)
+    };
+    let mut missing = Vec::new();
+    for id in ids {
+        if cache.get(id, format)?.is_none() {
+            missing.push(id);
+        }
+    }
+    if missing.is_empty() {
+        return Ok(0);
+    }
+
+    let bar = progress_bar(missing.len());
+    let mut fetched = 0;
+    let mut results = stream::iter(&missing)
+        .map(|id| async move {
+            match format {
+                Format::Full => client.get_message(id).await,
+                Format::Metadata(headers) => client.get_message_metadata(id, headers).await,
+            }
+        })
+        .buffer_unordered(concurrency as usize);
+    while let Some(result) = results.next().await {
+        match result {
+            Ok(_) => fetched += 1,
+            Err(e) if e.is_not_found() => {}
+            Err(e) => return Err(e.into()),
+        }
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+    Ok(fetched)
+}
+
+async fn run_undo(count: usize, list: bool, json: bool) -> Result<()> {
+    use gmail::journal::{self, Target};
+
+    let path = config::journal_path();
+    let entries = journal::read(&path)?;
+    let changes = journal::changes(&entries);
+    let recent = &changes[changes.len().saturating_sub(count)..];
+
+    if list {
+        if json {
+            let start = recent.first().map_or(entries.len(), |change| change.start);
+            println!("{}", serde_json::to_string_pretty(&entries[start..])?);
+            return Ok(());
+        }
+        if recent.is_empty() {
+            println!("Nothing to undo");
+            return Ok(());
+        }
+        let mut table = Table::new(["TIME", "COUNT", "TARGET", "CHANGE"]);
+        for change in recent.iter().rev() {
+            let change = &entries[change.clone()];
+            let entry = &change[0];
+            let time = chrono::DateTime::parse_from_rfc3339(&entry.time)
+                .map(|t| date::format_short(t.with_timezone(&chrono::Local)))
+                .unwrap_or_else(|_| entry.time.clone());
+            let target = match entry.target {
+                Target::Messages => "messages",
+                Target::Threads => "threads",
+            };
+            let mut labels: Vec<String> = Vec::new();
+            for entry in change {
+                let entry_labels = entry
+                    .add
+                    .iter()
+                    .map(|l| format!("+{}", l))
+                    .chain(entry.remove.iter().map(|l| format!("-{}", l)));
+                for label in entry_labels {
+                    if !labels.contains(&label) {
+                        labels.push(label);
+                    }
+                }
+            }
+            table.add_row([
+                time,
+                format_count(change.iter().map(|e| e.ids.len()).sum()),
+                target.to_string(),
+                labels.join(" "),
+            ]);
+        }
+        print!("{}", table.render(table::terminal_width()));
+        return Ok(());
+    }
+
+    if recent.is_empty() {
+        anyhow::bail!("Nothing to undo");
+    }
+    // Undoing must not itself land in the journal
+    let client = get_client().await?.without_journal();
+    let mut undone = 0;
+    for change in recent.iter().rev() {
+        for entry in entries[change.clone()].iter().rev() {
+            undo_entry(&client, entry).await?;
+        }
+        if !DRY_RUN.load(Ordering::Relaxed) {
+            journal::truncate(&path, change.start)?;
+        }
+        undone += 1;
+    }
+
+    if json {
+        println!("{}", serde_json::json!({ "undone": undone }));
+    } else if undone == 1 {
+        println!("Undid 1 change");
+    } else {
+        println!("Undid {} changes", undone);
+    }
+    Ok(())
+}
+
+async fn undo_entry(client: &api::Client, entry: &gmail::journal::Entry) -> Result<()> {
+    use gmail::journal::Target;
+
+    let inverse = entry.inverse();
+    let add: Vec<&str> = inverse.add.iter().map(String::as_str).collect();
+    let remove: Vec<&str> = inverse.remove.iter().map(String::as_str).collect();
+    // Trash is left through its own endpoint, which also restores what trashing removed
+    let untrash = remove == ["TRASH"] && add.is_empty();
+    match inverse.target {
+        Target::Messages if untrash => {
+            for id in &inverse.ids {
+                client.untrash(id).await?;
+            }
+        }
+        Target::Messages => client.batch_modify(&inverse.ids, &add, &remove).await?,
+        Target::Threads => {
+            for id in &inverse.ids {
+                if untrash {
+                    client.untrash_thread(id).await?;
+                } else {
+                    client.modify_thread_labels(id, &add, &remove).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_draft_command(command: DraftCommands, json: bool) -> Result<()> {
+    let client = get_client().await?;
+    match command {
+        DraftCommands::Create {
+            to,
+            cc,
+            bcc,
+            subject,
+            body,
+            file,
+            attach,
+        } => {
+            let body = read_body(body, file.as_deref())?;
+            let email = mime::Email {
+                to,
+                cc,
+                bcc,
+                subject,
+                body,
+                attachments: load_attachments(&attach)?,
+                ..Default::default()
+            };
+            let draft = client
+                .create_draft(email.to_rfc822().as_bytes(), None)
+                .await?;
+            if json {
+                println!("{}", serde_json::json!({ "id": draft.id }));
+            } else {
+                println!("Created draft {}", draft.id);
+            }
+        }
+        DraftCommands::List { max } => {
+            let drafts = client.list_drafts(max).await?.drafts.unwrap_or_default();
+            if drafts.is_empty() && !json {
+                println!("No drafts found.");
+            }
+            let mut items = Vec::new();
+            for draft_ref in drafts {
+                let draft = client.get_draft(&draft_ref.id).await?;
+                let Some(msg) = draft.message else { continue };
+                if json {
+                    items.push(serde_json::json!({
+                        "id": draft.id,
+                        "messageId": msg.id,
+                        "to": msg.get_header("To"),
+                        "subject": msg.get_header("Subject"),
+                        "snippet": msg.snippet,
+                    }));
+                } else {
+                    let to = msg.get_header("To").unwrap_or("(no recipients)");
+                    let subject = msg.get_header("Subject").unwrap_or("(no subject)");
+                    println!("{} | {} | {}", draft.id, to, subject);
+                }
+            }
+            if json {
+                println!("{}", serde_json::to_string(&items)?);
+            }
+        }
+        DraftCommands::Show { id } => {
+            let draft = client.get_draft(&id).await?;
+            let msg = draft
+                .message
+                .ok_or_else(|| anyhow::anyhow!("Draft {} has no message", id))?;
+            print_message(&msg, json)?;
+        }
+        DraftCommands::Edit {
+            id,
+            to,
+            cc,
+            subject,
+            body,
+            file,
+        } => {
+            let draft = client.get_draft(&id).await?;
+            let msg = draft
+                .message
+                .ok_or_else(|| anyhow::anyhow!("Draft {} has no message", id))?;
+            // The draft is rebuilt as plain text, which would drop HTML and attachments
+            let mime_type = msg
+                .payload
+                .as_ref()
+                .and_then(|p| p.mime_type.as_deref())
+                .unwrap_or("text/plain");
+            if !mime_type.eq_ignore_ascii_case("text/plain") {
+                anyhow::bail!(
+                    "Draft {} is {} (HTML or attachments), which editing here would lose; edit it in Gmail instead",
+                    id,
+                    mime_type
+                );
+            }
+            let body = match (body, file) {
+                (None, None) => msg.get_body_text().unwrap_or_default(),
+                (body, file) => read_body(body, file.as_deref())?,
+            };
+            let email = mime::Email {
+                from: msg.get_header("From").map(|h| h.to_string()),
+                to: if to.is_empty() {
+                    mime::split_address_list(msg.get_header("To").unwrap_or(""))
+                } else {
+                    to
+                },
+                cc: if cc.is_empty() {
+                    mime::split_address_list(msg.get_header("Cc").unwrap_or(""))
+                } else {
+                    cc
+                },
+                bcc: mime::split_address_list(msg.get_header("Bcc").unwrap_or("")),
+                subject: subject
+                    .unwrap_or_else(|| msg.get_header("Subject").unwrap_or("").to_string()),
+                body,
+                in_reply_to: msg.get_header("In-Reply-To").map(|h| h.to_string()),
+                references: msg.get_header("References").map(|h| h.to_string()),
+                ..Default::default()
+            };
+            client
+                .update_draft(&id, email.to_rfc822().as_bytes(), msg.thread_id.as_deref())
+                .await?;
+            println!("Updated draft {}", id);
+        }
+        DraftCommands::Send { id } => {
+            let sent = client.send_draft(&id).await?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "id": sent.id, "threadId": sent.thread_id })
+                );
+            } else {
+                println!("Sent draft {} as {}", id, sent.id);
+            }
+        }
+        DraftCommands::Delete { id } => {
+            client.delete_draft(&id).await?;
+            println!("Deleted draft {}", id);
+        }
+    }
+    Ok(())
+}
+
+/// A bar with counts, rate and ETA for `len` steps, hidden when progress isn't shown.
+fn progress_bar(len: usize) -> indicatif::ProgressBar {
+    if !SHOW_PROGRESS.load(Ordering::Relaxed) {
+        return indicatif::ProgressBar::hidden();
+    }
+    let bar = indicatif::ProgressBar::new(len as u64);
+    bar.set_style(
+        indicatif::ProgressStyle::with_template("{bar:40} {pos}/{len} ({per_sec}, {eta})")
+            .expect("valid progress template"),
+    );
+    bar
+}
+
+/// A ticking spinner showing `message`, hidden when progress isn't shown.
+fn progress_spinner(message: &'static str) -> indicatif::ProgressBar {
+    if !SHOW_PROGRESS.load(Ordering::Relaxed) {
+        return indicatif::ProgressBar::hidden();
+    }
+    let spinner = indicatif::ProgressBar::new_spinner();
+    spinner.set_message(message);
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+    spinner
+}
+
+/// The browser `login` opens: `--browser`, else the `browser` setting, else `$BROWSER`.
+fn login_browser(flag: Option<String>, no_browser: bool, cfg: &config::Config) -> auth::Browser {
+    if no_browser {
+        return auth::Browser::None;
+    }
+    // $BROWSER may list several, separated by colons
+    let from_env = std::env::var("BROWSER")
+        .ok()
+        .and_then(|list| list.split(':').next().map(str::to_string))
+        .filter(|command| !command.is_empty());
+    match flag.or(cfg.browser.clone()).or(from_env) {
+        Some(command) => auth::Browser::Command(command),
+        None => auth::Browser::System,
     }
 }
 
+/// The HTTP client for API requests, with `--timeout` applied.
+fn http_client() -> reqwest::Client {
+    api::http_client(api::Timeouts {
+        read: std::time::Duration::from_secs(TIMEOUT.load(Ordering::Relaxed)),
+        ..Default::default()
+    })
+}
+
 async fn get_client() -> Result<api::Client> {
     let cfg = config::load_config()?;
     let client_id = cfg.client_id();
     let client_secret = cfg.client_secret();
+    // Shared by the client and its token refreshes
+    let http = http_client();
+
+    let daemon = config::daemon_socket_path();
+    let client = if USE_DAEMON.load(Ordering::Relaxed)
+        && config::env_tokens().is_none()
+        && gmail::daemon::is_running(&daemon)
+    {
+        tracing::debug!("sending requests through {}", daemon.display());
+        api::Client::via_daemon(http, &daemon)
+    } else if let Some(tokens) = config::env_tokens() {
+        // Tokens from the environment are never written to the token file
+        if tokens.refresh_token.is_empty() {
+            api::Client::with_http_client(http, &tokens.access_token)
+        } else {
+            let provider = auth::RefreshingToken::oauth(
+                http.clone(),
+                &tokens.access_token,
+                client_id,
+                client_secret,
+                &tokens.refresh_token,
+            )
+            .without_saving();
+            api::Client::with_token_provider(http, provider)
+        }
+    } else if let Some(account) = cfg.service_account.clone() {
+        let tokens = match config::load_tokens() {
+            Ok(t) => t,
+            Err(_) if !config::tokens_path().exists() => {
+                let tokens = auth::service_account_token(&http, &account).await?;
+                config::save_tokens(&tokens)?;
+                tokens
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let scopes = account.scopes.clone();
+        let tokens =
+            auth::RefreshingToken::service_account(http.clone(), &tokens.access_token, account);
+        api::Client::with_token_provider(http, tokens).with_scopes(scopes)
+    } else {
+        let tokens = match config::load_tokens() {
+            Ok(t) => t,
+            Err(_) if !config::tokens_path().exists() => {
+                return Err(gmail::GmailError::Auth(
+                    "Not logged in. Run 'gmail login' first".to_string(),
+                )
+                .into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Expired tokens are refreshed by the client on the first 401
+        let provider = auth::RefreshingToken::oauth(
+            http.clone(),
+            &tokens.access_token,
+            client_id,
+            client_secret,
+            &tokens.refresh_token,
+        );
+        api::Client::with_token_provider(http, provider).with_scopes(tokens.scopes)
+    };
+
+    let client = client
+        .with_dry_run(DRY_RUN.load(Ordering::Relaxed))
+        .with_max_qps(MAX_QPS.load(Ordering::Relaxed))
+        .with_journal(config::journal_path())
+        .with_label_names(config::label_names_path());
+    if !cfg.cache {
+        return Ok(client);
+    }
+    Ok(client.with_cache(gmail::cache::Cache::open(&config::cache_path())?))
+}
 
-    let tokens = match config::load_tokens() {
-        Ok(t) => t,
-        Err(_) => anyhow::bail!("Not logged in. Run 'gmail login' first"),
+/// Label names for shell completion, from the last label list fetched.
+fn label_candidates() -> Vec<clap_complete::CompletionCandidate> {
+    config::cached_label_names()
+        .into_iter()
+        .map(clap_complete::CompletionCandidate::new)
+        .collect()
+}
+
+/// Log to stderr at the level `-v` asks for, or as `RUST_LOG` says without it.
+fn init_logging(verbose: u8) {
+    let filter = match verbose {
+        0 => tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("warn")),
+        1 => tracing_subscriber::EnvFilter::new("gmail=debug"),
+        _ => tracing_subscriber::EnvFilter::new("gmail=trace"),
     };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
 
-    // Try to use existing token, refresh if needed
-    let client = api::Client::new(&tokens.access_token);
+/// Set from `--dry-run` before any client is created
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+/// Set from `--yes`
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+/// Set from `--max-qps`
+static MAX_QPS: AtomicU32 = AtomicU32::new(gmail::quota::PER_USER_LIMIT);
+/// Cleared by `--json` and when stdout isn't a terminal
+static SHOW_PROGRESS: AtomicBool = AtomicBool::new(true);
+/// Cleared by `--no-daemon` and for `gmail daemon` itself
+static USE_DAEMON: AtomicBool = AtomicBool::new(true);
+/// Set from `--json` or the `format` setting, so errors are reported as JSON too
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+/// Set from `--timeout`, in seconds
+static TIMEOUT: AtomicU64 = AtomicU64::new(30);
 
-    // Test if token works by making a simple request
-    match client.list_messages(None, "INBOX", 1).await {
-        Ok(_) => Ok(client),
-        Err(_) => {
-            // Token expired, try refresh
-            let new_tokens =
-                auth::refresh_token(client_id, client_secret, &tokens.refresh_token).await?;
-            Ok(api::Client::new(&new_tokens.access_token))
+#[tokio::main]
+async fn main() -> std::process::ExitCode {
+    // Answers the shell when called back by a `gmail completions` script
+    clap_complete::CompleteEnv::with_factory(Cli::command).complete();
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        // `--json` isn't parsed yet, but a script asking for it wants JSON errors
+        Err(e) if e.use_stderr() && std::env::args_os().any(|arg| arg == "--json") => {
+            JSON_OUTPUT.store(true, Ordering::Relaxed);
+            return report_error(&e.into());
         }
+        Err(e) => e.exit(),
+    };
+    init_logging(cli.verbose);
+    JSON_OUTPUT.store(cli.json, Ordering::Relaxed);
+    DRY_RUN.store(cli.dry_run, Ordering::Relaxed);
+    MAX_QPS.store(cli.max_qps, Ordering::Relaxed);
+    TIMEOUT.store(cli.timeout, Ordering::Relaxed);
+    ASSUME_YES.store(cli.yes, Ordering::Relaxed);
+    // The daemon itself has to talk to Gmail
+    USE_DAEMON.store(
+        !cli.no_daemon && !matches!(cli.command, Commands::Daemon),
+        Ordering::Relaxed,
+    );
+    let result = match run(cli).await {
+        Err(e)
+            if e.downcast_ref::<gmail::GmailError>()
+                .is_some_and(|e| e.is_dry_run()) =>
+        {
+            eprintln!("{}", e);
+            Ok(())
+        }
+        result => {
+            if DRY_RUN.load(Ordering::Relaxed) {
+                eprintln!("Dry run: nothing was changed");
+            }
+            result
+        }
+    };
+    match result {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => report_error(&e),
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// What went wrong, for scripts: a stable name and exit code per kind of failure.
+fn error_kind(e: &anyhow::Error) -> (&'static str, u8) {
+    // The code clap exits with for invalid arguments
+    if e.downcast_ref::<clap::Error>().is_some() {
+        return ("usage", 2);
+    }
+    let Some(err) = gmail_error(e) else {
+        return ("error", 1);
+    };
+    if err.is_not_found() {
+        ("not_found", 6)
+    } else if err.is_unauthorized() {
+        ("auth_required", 3)
+    } else if err.is_rate_limited() {
+        ("rate_limited", 4)
+    } else if err.is_network() {
+        ("network", 5)
+    } else {
+        ("error", 1)
+    }
+}
+
+/// Print an error to stderr, as JSON with `--json`, and pick the exit code for it.
+fn report_error(e: &anyhow::Error) -> std::process::ExitCode {
+    let (kind, code) = error_kind(e);
+    if JSON_OUTPUT.load(Ordering::Relaxed) {
+        let api = gmail_error(e);
+        let error = serde_json::json!({
+            "kind": kind,
+            "message": format!("{:#}", e).trim_end(),
+            "status": api.and_then(|err| err.status()),
+            "reason": api.and_then(|err| err.reason()),
+            "exitCode": code,
+        });
+        eprintln!("{}", serde_json::json!({ "error": error }));
+    } else {
+        eprintln!("Error: {:?}", e);
+    }
+    std::process::ExitCode::from(code)
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    if let Some(dir) = &cli.config_dir {
+        config::set_config_dir(dir)?;
+    }
+    if let Some(account) = &cli.account {
+        config::set_account(account)?;
+    }
+    // A broken config shouldn't lock out `gmail config edit`, so fall back to defaults
+    let cfg = config::load_config().unwrap_or_else(|e| {
+        eprintln!("Warning: {:#}", e);
+        config::Config::default()
+    });
+    let json = cli.json || cfg.json_output();
+    JSON_OUTPUT.store(json, Ordering::Relaxed);
+    SHOW_PROGRESS.store(
+        !json && std::io::IsTerminal::is_terminal(&std::io::stdout()),
+        Ordering::Relaxed,
+    );
+    let color = cli
+        .color
+        .or_else(|| ColorChoice::from_str(cfg.color.as_deref()?, true).ok())
+        .unwrap_or(ColorChoice::Auto);
+    if color.enabled()
+        && let Err(e) = style::enable(&cfg.theme)
+    {
+        eprintln!("Warning: {:#}", e);
+    }
 
     match cli.command {
-        Commands::Config { client_id } => {
-            let cfg = config::Config {
-                client_id: Some(client_id),
-                client_secret: None,
-            };
-            config::save_config(&cfg)?;
-            println!("Custom client ID saved to {:?}", config::config_dir());
-        }
-        Commands::Login => {
+        Commands::Config { command } => run_config_command(command, json)?,
+        Commands::Login {
+            full_access,
+            readonly,
+            pubsub,
+            contacts,
+            device,
+            manual,
+            browser,
+            no_browser,
+            port,
+            bind,
+            force,
+            service_account,
+            impersonate,
+        } => {
             let cfg = config::load_config()?;
-            let client_id = cfg.client_id();
-            let client_secret = cfg.client_secret();
+            // A running daemon would keep using the login it started with
+            gmail::daemon::stop(&config::daemon_socket_path()).await?;
+
+            let mut scopes = if readonly || (cfg.readonly && !full_access) {
+                vec![auth::SCOPE_READONLY]
+            } else {
+                auth::DEFAULT_SCOPES.to_vec()
+            };
+            if full_access {
+                scopes.push(auth::SCOPE_FULL);
+            }
+            if pubsub {
+                scopes.push(auth::SCOPE_PUBSUB);
+            }
+            if contacts {
+                scopes.push(auth::SCOPE_CONTACTS);
+            }
+            if let (Some(key_file), Some(subject)) = (service_account, impersonate) {
+                let account = config::ServiceAccount {
+                    key_file: std::fs::canonicalize(&key_file)
+                        .with_context(|| format!("Key file not found: {}", key_file.display()))?,
+                    subject,
+                    scopes: scopes.iter().map(|s| s.to_string()).collect(),
+                };
+                let tokens = auth::service_account_token(&http_client(), &account).await?;
+                config::save_tokens(&tokens)?;
+                println!("Service account authorized for {}", account.subject);
+                let mut profile = config::load_profile_config()?;
+                profile.service_account = Some(account);
+                config::save_config(&profile)?;
+                return Ok(());
+            }
 
-            auth::login(client_id, client_secret).await?;
+            let (client_id, client_secret) = (cfg.client_id(), cfg.client_secret());
+            if device {
+                auth::login_device(client_id, client_secret, &scopes).await?;
+            } else if manual {
+                auth::login_manual(client_id, client_secret, &scopes, force).await?;
+            } else {
+                let options = auth::LoginOptions {
+                    browser: login_browser(browser, no_browser, &cfg),
+                    bind,
+                    port: port.unwrap_or(0),
+                    force,
+                    ..Default::default()
+                };
+                auth::login(client_id, client_secret, &scopes, &options).await?;
+            }
+            let mut profile = config::load_profile_config()?;
+            if profile.service_account.take().is_some() {
+                config::save_config(&profile)?;
+            }
             println!("Login successful! Tokens saved.");
         }
-        Commands::Labels => {
-            let client = get_client().await?;
-            let labels = client.list_labels().await?;
-
-            if let Some(labels) = labels.labels {
-                if cli.json {
-                    println!("{}", serde_json::to_string(&labels)?);
-                } else {
-                    let mut system: Vec<_> = labels
-                        .iter()
-                        .filter(|l| l.label_type.as_deref() == Some("system"))
-                        .collect();
-                    let mut user: Vec<_> = labels
-                        .iter()
-                        .filter(|l| l.label_type.as_deref() != Some("system"))
-                        .collect();
-
-                    system.sort_by(|a, b| a.name.cmp(&b.name));
-                    user.sort_by(|a, b| a.name.cmp(&b.name));
-
-                    println!("System labels:");
-                    for label in system {
-                        println!("  {} ({})", label.name, label.id);
-                    }
-                    if !user.is_empty() {
-                        println!("\nUser labels:");
-                        for label in user {
-                            println!("  {} ({})", label.name, label.id);
-                        }
-                    }
+        Commands::Completions { shell } => {
+            let shells = clap_complete::env::Shells::builtins();
+            let completer = shells
+                .completer(&shell)
+                .expect("clap only accepts built-in shells");
+            completer.write_registration(
+                "COMPLETE",
+                "gmail",
+                "gmail",
+                "gmail",
+                &mut std::io::stdout(),
+            )?;
+        }
+        Commands::Man => clap_mangen::Man::new(Cli::command()).render(&mut std::io::stdout())?,
+        Commands::Logout => {
+            let cfg = config::load_config()?;
+            gmail::daemon::stop(&config::daemon_socket_path()).await?;
+            let tokens = match config::load_tokens() {
+                Ok(t) => t,
+                Err(_) if !config::tokens_path().exists() => {
+                    println!("Not logged in");
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // Service account tokens can't be revoked; they expire within the hour
+            if cfg.service_account.is_none() {
+                auth::revoke_token(&tokens.access_token).await?;
+                if !tokens.refresh_token.is_empty() {
+                    auth::revoke_token(&tokens.refresh_token).await?;
                 }
             }
+            config::delete_tokens()?;
+            match config::active_account() {
+                Some(account) => println!("Logged out of {}", account),
+                None => println!("Logged out"),
+            }
+        }
+        Commands::Count {
+            label,
+            unread,
+            query,
+        } => {
+            let client = get_client().await?;
+            let count = count_messages(&client, &normalize_label(&label), unread, query).await?;
+            if json {
+                println!("{}", serde_json::json!({ "count": count }));
+            } else {
+                println!("{}", count);
+            }
+        }
+        Commands::Doctor => run_doctor(json).await?,
+        Commands::Daemon => {
+            let client = get_client().await?;
+            let socket = config::daemon_socket_path();
+            eprintln!("Listening on {}", socket.display());
+            gmail::daemon::serve(client, &socket).await?;
+        }
+        Commands::Profile => {
+            let client = get_client().await?;
+            let profile = client.get_profile().await?;
+            if json {
+                println!("{}", serde_json::to_string(&profile)?);
+            } else {
+                println!("Email: {}", profile.email_address);
+                println!("Messages: {}", profile.messages_total);
+                println!("Threads: {}", profile.threads_total);
+                println!("History ID: {}", profile.history_id);
+            }
+        }
+        Commands::Labels {
+            command: Some(command),
+            ..
+        } => run_labels_command(command, json).await?,
+        Commands::Labels {
+            command: None,
+            counts,
+            tree,
+            format,
+        } => {
+            let client = get_client().await?;
+            let format = OutputFormat::resolve(format, json);
+            list_labels(&client, counts, tree, format).await?;
         }
         Commands::List {
             max,
             query,
+            saved,
             label,
             unread,
+            threads,
+            all,
+            concurrency,
+            columns,
+            relative,
+            format,
+            ndjson,
+            search,
         } => {
+            let query = search.to_query(query)?;
+            let format = OutputFormat::resolve(format, json);
+            let json = format == OutputFormat::Json;
             let client = get_client().await?;
-            let label_id = normalize_label(&label);
+            let max = max.or(cfg.max_results).unwrap_or(100);
+            let label = label.or_else(|| cfg.label.clone());
+            let label_id = normalize_label(label.as_deref().unwrap_or("inbox"));
+            // A saved search combines with -q, e.g. `--saved receipts -q newer_than:30d`
+            let query = match saved {
+                Some(name) => {
+                    let saved = cfg.saved_query(&name)?;
+                    Some(match query {
+                        Some(q) => format!("{} {}", saved, q),
+                        None => saved.to_string(),
+                    })
+                }
+                None => query,
+            };
             let query = if unread {
                 Some(match query {
                     Some(q) => format!("is:unread {}", q),
@@ -222,132 +5972,803 @@ async fn main() -> Result<()> {
             } else {
                 query
             };
-            let list = client
-                .list_messages(query.as_deref(), &label_id, max)
+            if threads {
+                list_threads(
+                    &client,
+                    query.as_deref(),
+                    &label_id,
+                    max,
+                    format,
+                    relative,
+                    concurrency,
+                )
                 .await?;
-
-            if let Some(messages) = list.messages {
-                if cli.json {
-                    let mut items = Vec::new();
-                    for msg_ref in messages {
-                        let msg = client.get_message(&msg_ref.id).await?;
-                        items.push(serde_json::json!({
-                            "id": msg.id,
-                            "from": msg.get_header("From"),
-                            "to": msg.get_header("To"),
-                            "subject": msg.get_header("Subject"),
-                            "date": msg.get_header("Date"),
-                            "snippet": msg.snippet,
-                        }));
+                return Ok(());
+            }
+            if ndjson {
+                let limit = (!all).then_some(max);
+                stream_ndjson(&client, query.as_deref(), &label_id, limit, concurrency).await?;
+                return Ok(());
+            }
+            let mut cached = None;
+            let ids = if all {
+                let spinner = progress_spinner("Searching...");
+                let ids = client
+                    .list_all_message_ids(query.as_deref(), &label_id)
+                    .await?;
+                spinner.finish_and_clear();
+                ids
+            } else {
+                match client.list_messages(query.as_deref(), &label_id, max).await {
+                    Ok(list) => list
+                        .messages
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|m| m.id)
+                        .collect(),
+                    // Offline: fall back to what the cache has seen with this label
+                    Err(e) if query.is_none() && e.is_network() => {
+                        let Some(cache) = client.cache() else {
+                            return Err(e.into());
+                        };
+                        eprintln!("Offline: showing cached messages");
+                        cached = Some(cache.recent(&label_id, max)?);
+                        Vec::new()
                     }
+                    Err(e) => return Err(e.into()),
+                }
+            };
+
+            let messages = match cached {
+                Some(messages) => messages,
+                None if ids.is_empty() => Vec::new(),
+                None => fetch_messages(&client, &ids, concurrency).await?,
+            };
+            if !messages.is_empty() {
+                if json {
+                    let items: Vec<_> = messages.iter().map(list_item_json).collect();
                     println!("{}", serde_json::to_string(&items)?);
                 } else {
-                    for msg_ref in messages {
-                        let msg = client.get_message(&msg_ref.id).await?;
-                        let from = msg.get_header("From").unwrap_or("Unknown");
-                        let subject = msg.get_header("Subject").unwrap_or("(no subject)");
-                        println!("{} | {} | {}", msg.id, from, subject);
-                    }
+                    format.print_table(&message_table(&messages, &columns, relative));
                 }
-            } else if !cli.json {
+            } else if json {
+                println!("[]");
+            } else if format == OutputFormat::Text {
                 println!("No messages found.");
             } else {
-                println!("[]");
+                format.print_table(&Table::new(columns.iter().map(|c| c.header())));
             }
         }
-        Commands::Read { id } => {
+        Commands::History { since, label } => {
+            let client = get_client().await?;
+            let label_id = label.as_deref().map(normalize_label);
+            let history = client
+                .list_history(&since, label_id.as_deref())
+                .await
+                .context("Failed to fetch history (IDs older than about a week expire)")?;
+            print_history(&history, json)?;
+        }
+        Commands::Read {
+            id,
+            rfc822_id,
+            raw,
+            html,
+            header,
+            full_headers,
+            no_pager,
+        } => {
+            let client = get_client().await?;
+            let id = message_id_arg(&client, id, rfc822_id.as_deref()).await?;
+            if raw {
+                write_raw_message(&client, &id, None).await?;
+            } else if html {
+                let msg = client.get_message(&id).await?;
+                let html = msg
+                    .get_body_html()
+                    .ok_or_else(|| anyhow::anyhow!("Message {} has no HTML body", id))?;
+                println!("{}", html);
+            } else {
+                let msg = client.get_message(&id).await?;
+                let shown = ShownHeaders::new(header, full_headers);
+                if json {
+                    let mut value = message_json(&msg);
+                    if let Some(headers) = shown.select(&msg) {
+                        value["headers"] = serde_json::json!(headers);
+                    }
+                    println!("{}", serde_json::to_string(&value)?);
+                } else if no_pager {
+                    print!("{}", format_message(&msg, &shown));
+                } else {
+                    page(&format_message(&msg, &shown))?;
+                }
+            }
+        }
+        Commands::Urls { id } => {
             let client = get_client().await?;
             let msg = client.get_message(&id).await?;
-
-            if cli.json {
+            let mut found = msg
+                .get_body_html()
+                .map(|html| links::from_html(&html))
+                .unwrap_or_default();
+            if let Some(text) = msg.get_body_text() {
+                found.extend(links::from_text(&text));
+            }
+            let found = links::dedup(found);
+            if json {
+                println!("{}", serde_json::to_string(&found)?);
+            } else {
+                for link in &found {
+                    print_link(link);
+                }
+            }
+        }
+        Commands::Otp { from, max, copy } => {
+            let client = get_client().await?;
+            let mut query = "newer_than:1d".to_string();
+            if let Some(from) = &from {
+                query.push_str(&format!(" from:{}", from));
+            }
+            let refs = client
+                .list_messages(Some(&query), "", max)
+                .await?
+                .messages
+                .unwrap_or_default();
+            // Results come newest first, so the first code found is the latest
+            let mut found = None;
+            for msg_ref in refs {
+                let msg = client.get_message(&msg_ref.id).await?;
+                let subject = msg.get_header("Subject").unwrap_or_default();
+                let body = msg.get_readable_body(HTML_RENDER_WIDTH).unwrap_or_default();
+                if let Some(code) = otp::find_code(subject, &body) {
+                    found = Some((code, msg));
+                    break;
+                }
+            }
+            let Some((code, msg)) = found else {
+                anyhow::bail!("No verification code in the last {} messages", max);
+            };
+            if copy {
+                copy_to_clipboard(&code)?;
+            }
+            if json {
                 println!(
                     "{}",
-                    serde_json::to_string(&serde_json::json!({
+                    serde_json::json!({
+                        "code": code,
                         "id": msg.id,
                         "from": msg.get_header("From"),
-                        "to": msg.get_header("To"),
                         "subject": msg.get_header("Subject"),
-                        "date": msg.get_header("Date"),
-                        "body": msg.get_body_text(),
-                        "snippet": msg.snippet,
-                    }))?
+                    })
                 );
             } else {
-                println!("From: {}", msg.get_header("From").unwrap_or("Unknown"));
-                println!("To: {}", msg.get_header("To").unwrap_or("Unknown"));
+                println!("{}", code);
+            }
+        }
+        Commands::Ics { id, out } => {
+            let client = get_client().await?;
+            let msg = client.get_message(&id).await?;
+            let data = match msg.calendar_part() {
+                Some(part) => client.get_part_data(&id, part).await?,
+                // A message that is nothing but the invite
+                None => msg
+                    .get_body_calendar()
+                    .ok_or_else(|| anyhow::anyhow!("Message {} has no calendar invite", id))?
+                    .into_bytes(),
+            };
+            match out {
+                Some(path) => {
+                    std::fs::write(&path, &data)
+                        .with_context(|| format!("Failed to write {}", path.display()))?;
+                    println!("Saved invite to {}", path.display());
+                }
+                None => std::io::Write::write_all(&mut std::io::stdout(), &data)?,
+            }
+        }
+        Commands::FindId { message_id } => {
+            let client = get_client().await?;
+            let ids = client.find_by_rfc822_id(&message_id).await?;
+            if json {
                 println!(
-                    "Subject: {}",
-                    msg.get_header("Subject").unwrap_or("(no subject)")
+                    "{}",
+                    serde_json::json!({ "messageId": message_id, "ids": ids })
                 );
-                println!("Date: {}", msg.get_header("Date").unwrap_or("Unknown"));
-                println!("---");
-
-                if let Some(body) = msg.get_body_text() {
-                    println!("{}", body);
-                } else if let Some(snippet) = &msg.snippet {
-                    println!("{}", snippet);
+            } else if ids.is_empty() {
+                anyhow::bail!("No message with Message-ID {}", message_id);
+            } else {
+                for id in ids {
+                    println!("{}", id);
                 }
             }
         }
-        Commands::Archive { id } => {
+        Commands::Archive { ids } => {
+            let ids = read_ids(ids)?;
             let client = get_client().await?;
-            client.archive(&id).await?;
-            println!("Archived {}", id);
+            client.batch_modify(&ids, &[], &["INBOX"]).await?;
+            println!("Archived {}", describe_ids(&ids));
         }
-        Commands::Spam { id } => {
+        Commands::Spam { ids } => {
+            let ids = read_ids(ids)?;
+            confirm(&format!("Really mark {} as spam?", describe_ids(&ids)))?;
             let client = get_client().await?;
-            // Try to unsubscribe first, ignore errors (not all messages have unsubscribe)
-            let _ = client.unsubscribe(&id).await;
-            client.mark_spam(&id).await?;
-            println!("Marked as spam {}", id);
+            for id in &ids {
+                // Try to unsubscribe first, ignore errors (not all messages have unsubscribe);
+                // only a one-click POST is sent: no mail goes out and no browser is opened
+                let _ = client.unsubscribe(id).await;
+            }
+            client.batch_modify(&ids, &["SPAM"], &["INBOX"]).await?;
+            println!("Marked as spam {}", describe_ids(&ids));
         }
-        Commands::Unspam { id } => {
+        Commands::Unspam { ids } => {
+            let ids = read_ids(ids)?;
             let client = get_client().await?;
-            client.unspam(&id).await?;
-            println!("Moved to inbox {}", id);
+            client.batch_modify(&ids, &["INBOX"], &["SPAM"]).await?;
+            println!("Moved to inbox {}", describe_ids(&ids));
         }
-        Commands::Label { id, label } => {
+        Commands::Label { ids, label } => {
+            let ids = read_ids(ids)?;
             let client = get_client().await?;
             let label_id = normalize_label(&label);
-            client.add_label(&id, &label_id).await?;
-            println!("Added label {} to {}", label, id);
+            client.batch_add_label(&ids, &label_id).await?;
+            println!("Added label {} to {}", label, describe_ids(&ids));
         }
-        Commands::Unlabel { id, label } => {
+        Commands::Unlabel { ids, label } => {
+            let ids = read_ids(ids)?;
             let client = get_client().await?;
             let label_id = normalize_label(&label);
-            client.remove_label(&id, &label_id).await?;
-            println!("Removed label {} from {}", label, id);
+            client.batch_remove_label(&ids, &label_id).await?;
+            println!("Removed label {} from {}", label, describe_ids(&ids));
+        }
+        Commands::Delete { ids, permanent } => {
+            let ids = read_ids(ids)?;
+            if permanent {
+                let client = get_client().await?;
+                return delete_permanently(&client, &ids, json, confirm_permanent).await;
+            }
+            confirm(&format!("Really trash {}?", describe_ids(&ids)))?;
+            let client = get_client().await?;
+            client.batch_trash(&ids).await?;
+            println!("Moved to trash {}", describe_ids(&ids));
+        }
+        Commands::MarkRead { ids } => {
+            let ids = read_ids(ids)?;
+            let client = get_client().await?;
+            client.batch_modify(&ids, &[], &["UNREAD"]).await?;
+            println!("Marked as read {}", describe_ids(&ids));
+        }
+        Commands::MarkUnread { ids } => {
+            let ids = read_ids(ids)?;
+            let client = get_client().await?;
+            client.batch_modify(&ids, &["UNREAD"], &[]).await?;
+            println!("Marked as unread {}", describe_ids(&ids));
+        }
+        Commands::Star { ids } => {
+            let ids = read_ids(ids)?;
+            let client = get_client().await?;
+            client.batch_modify(&ids, &["STARRED"], &[]).await?;
+            println!("Starred {}", describe_ids(&ids));
+        }
+        Commands::Unstar { ids } => {
+            let ids = read_ids(ids)?;
+            let client = get_client().await?;
+            client.batch_modify(&ids, &[], &["STARRED"]).await?;
+            println!("Unstarred {}", describe_ids(&ids));
         }
-        Commands::Delete { id } => {
+        Commands::Important { ids } => {
+            let ids = read_ids(ids)?;
+            let client = get_client().await?;
+            client.batch_modify(&ids, &["IMPORTANT"], &[]).await?;
+            println!("Marked as important {}", describe_ids(&ids));
+        }
+        Commands::Unimportant { ids } => {
+            let ids = read_ids(ids)?;
+            let client = get_client().await?;
+            client.batch_modify(&ids, &[], &["IMPORTANT"]).await?;
+            println!("Marked as not important {}", describe_ids(&ids));
+        }
+        Commands::ClearLabels { ids } => {
+            let ids = read_ids(ids)?;
+            let client = get_client().await?;
+            for id in &ids {
+                let removed = client.clear_labels(id).await?;
+                if removed.is_empty() {
+                    println!("No user labels to remove from {}", id);
+                } else {
+                    println!("Removed {} labels from {}", removed.len(), id);
+                }
+            }
+        }
+        Commands::Open { id, print } => {
+            let client = get_client().await?;
+            let profile = client.get_profile().await?;
+            let msg = client.get_message_metadata(&id, &[]).await?;
+            let url = api::web_url(
+                &profile.email_address,
+                msg.thread_id.as_deref().unwrap_or(&msg.id),
+            );
+            if json {
+                println!("{}", serde_json::json!({ "id": id, "url": url }));
+            } else if print {
+                println!("{}", url);
+            } else {
+                open::that(&url).context("Failed to open the browser")?;
+                println!("Opened {}", url);
+            }
+        }
+        Commands::Unsubscribe {
+            id,
+            from,
+            query,
+            max,
+        } => {
             let client = get_client().await?;
-            client.trash(&id).await?;
-            println!("Moved to trash {}", id);
+            let Some(id) = id else {
+                let query = match from {
+                    Some(from) => format!("from:({})", from),
+                    None => query.unwrap_or_default(),
+                };
+                return run_bulk_unsubscribe(&client, &query, max, json).await;
+            };
+            if DRY_RUN.load(Ordering::Relaxed) {
+                return print_unsubscribe_links(&client, &id, json).await;
+            }
+            let (method, target) = match client.unsubscribe(&id).await? {
+                api::Unsubscribed::OneClick(url) => ("one-click", url),
+                api::Unsubscribed::Mailto(uri) => {
+                    let email = api::mailto_email(&uri)?;
+                    confirm(&format!(
+                        "Send an unsubscribe email to {}?",
+                        describe_unsubscribe_email(&email)
+                    ))?;
+                    client
+                        .send_message(email.to_rfc822().as_bytes(), None)
+                        .await?;
+                    ("mailto", email.to[0].clone())
+                }
+                api::Unsubscribed::Manual(url) => {
+                    if !json {
+                        open::that(&url).context("Failed to open the browser")?;
+                    }
+                    ("browser", url)
+                }
+            };
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "id": id, "method": method, "target": target })
+                );
+            } else {
+                match method {
+                    "one-click" => println!("Unsubscribed from {} (one-click)", id),
+                    "mailto" => println!("Sent an unsubscribe request to {}", target),
+                    _ => println!("Opened {}; finish unsubscribing in the browser", target),
+                }
+            }
         }
-        Commands::MarkRead { id } => {
+        Commands::Send {
+            from,
+            mut to,
+            mut cc,
+            mut bcc,
+            subject,
+            body,
+            file,
+            attach,
+            template,
+            var,
+            at,
+            edit,
+        } => {
+            // Under --edit the body is written in the editor, not read from stdin,
+            // unless a template provides it
+            let body = match (body, &file) {
+                (None, None) if edit && template.is_none() => Some(String::new()),
+                (body, _) => body,
+            };
+            let at = at
+                .map(|at| date::parse_future(&at, chrono::Local::now()))
+                .transpose()?;
+            let (subject, body) = match template {
+                Some(name) => {
+                    let (template_subject, template_body) =
+                        find_template(&cfg, &name)?.fill(&var.into_iter().collect())?;
+                    // An explicit subject or body wins over the template's
+                    let subject = if subject.is_empty() {
+                        template_subject
+                    } else {
+                        subject
+                    };
+                    let body = if body.is_none() && file.is_none() {
+                        template_body
+                    } else {
+                        read_body(body, file.as_deref())?
+                    };
+                    (subject, body)
+                }
+                None => (subject, read_body(body, file.as_deref())?),
+            };
+            if to
+                .iter()
+                .chain(&cc)
+                .chain(&bcc)
+                .any(|r| contacts::needs_lookup(r))
+            {
+                let client = get_client().await?;
+                for recipients in [&mut to, &mut cc, &mut bcc] {
+                    resolve_recipients(&client, recipients).await?;
+                }
+            }
+            let mut email = mime::Email {
+                from,
+                to,
+                cc,
+                bcc,
+                subject,
+                body,
+                attachments: load_attachments(&attach)?,
+                ..Default::default()
+            };
+            if edit {
+                edit_email(&mut email)?;
+            }
+            if let Some(at) = at {
+                return queue_message(at, email.to_rfc822().as_bytes(), json);
+            }
             let client = get_client().await?;
-            client.mark_read(&id).await?;
-            println!("Marked as read {}", id);
+            let sent = client
+                .send_message(email.to_rfc822().as_bytes(), None)
+                .await?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "id": sent.id, "threadId": sent.thread_id })
+                );
+            } else {
+                println!("Sent {}", sent.id);
+            }
         }
-        Commands::MarkUnread { id } => {
+        Commands::Reply {
+            id,
+            rfc822_id,
+            from,
+            body,
+            file,
+            attach,
+            edit,
+        } => {
+            let body = match (body, file) {
+                (None, None) if edit => String::new(),
+                (body, file) => read_body(body, file.as_deref())?,
+            };
+            let attachments = load_attachments(&attach)?;
             let client = get_client().await?;
-            client.mark_unread(&id).await?;
-            println!("Marked as unread {}", id);
+            let id = message_id_arg(&client, id, rfc822_id.as_deref()).await?;
+            let original = client.get_message(&id).await?;
+
+            let to = original
+                .get_header("Reply-To")
+                .or_else(|| original.get_header("From"))
+                .ok_or_else(|| anyhow::anyhow!("Message {} has no sender to reply to", id))?;
+            let message_id = original.get_header("Message-ID");
+            let body = if edit {
+                let attribution = match original.get_header("Date") {
+                    Some(date) => format!("On {}, {} wrote:", date, to),
+                    None => format!("{} wrote:", to),
+                };
+                let original_body = original
+                    .get_readable_body(HTML_RENDER_WIDTH)
+                    .or_else(|| original.snippet.clone())
+                    .unwrap_or_default();
+                body + &mime::reply_quote(&attribution, &original_body)
+            } else {
+                body
+            };
+            let mut email = mime::Email {
+                from,
+                to: vec![to.to_string()],
+                subject: mime::reply_subject(original.get_header("Subject").unwrap_or("")),
+                body,
+                in_reply_to: message_id.map(|m| m.to_string()),
+                references: message_id
+                    .map(|m| mime::reply_references(original.get_header("References"), m)),
+                attachments,
+                ..Default::default()
+            };
+            if edit {
+                edit_email(&mut email)?;
+            }
+
+            let sent = client
+                .send_message(email.to_rfc822().as_bytes(), original.thread_id.as_deref())
+                .await?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "id": sent.id, "threadId": sent.thread_id })
+                );
+            } else {
+                println!("Replied to {} with {}", id, sent.id);
+            }
         }
-        Commands::ClearLabels { id } => {
+        Commands::Forward {
+            id,
+            to,
+            cc,
+            body,
+            file,
+        } => {
+            let note = match (body, file) {
+                (None, None) => String::new(),
+                (body, file) => read_body(body, file.as_deref())?,
+            };
             let client = get_client().await?;
-            let removed = client.clear_labels(&id).await?;
-            if removed.is_empty() {
-                println!("No user labels to remove from {}", id);
+            let original = client.get_message(&id).await?;
+
+            let headers: Vec<(&str, &str)> = ["From", "Date", "Subject", "To", "Cc"]
+                .into_iter()
+                .filter_map(|name| original.get_header(name).map(|value| (name, value)))
+                .collect();
+            let original_body = original
+                .get_readable_body(HTML_RENDER_WIDTH)
+                .or_else(|| original.snippet.clone())
+                .unwrap_or_default();
+
+            let mut attachments = Vec::new();
+            for part in original.attachments() {
+                attachments.push(mime::Attachment {
+                    filename: part.filename.clone().unwrap_or_default(),
+                    mime_type: part.mime_type.clone(),
+                    data: client.get_part_data(&id, part).await?,
+                });
+            }
+
+            let email = mime::Email {
+                to,
+                cc,
+                subject: mime::forward_subject(original.get_header("Subject").unwrap_or("")),
+                body: mime::forward_body(&note, &headers, &original_body),
+                attachments,
+                ..Default::default()
+            };
+            let sent = client
+                .send_message(email.to_rfc822().as_bytes(), None)
+                .await?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "id": sent.id, "threadId": sent.thread_id })
+                );
             } else {
-                println!("Removed {} labels from {}", removed.len(), id);
+                println!("Forwarded {} as {}", id, sent.id);
             }
         }
-        Commands::Unsubscribe { id } => {
+        Commands::Attachment { id, part, out } => {
             let client = get_client().await?;
-            client.unsubscribe(&id).await?;
-            println!("Unsubscribed from {}", id);
+            let msg = client.get_message(&id).await?;
+            let attachments: Vec<_> = msg
+                .attachments()
+                .into_iter()
+                .filter(|p| part.is_none() || p.part_id == part)
+                .collect();
+            if attachments.is_empty() {
+                match part {
+                    Some(part) => anyhow::bail!("No attachment with part ID {} in {}", part, id),
+                    None => anyhow::bail!("Message {} has no attachments", id),
+                }
+            }
+
+            std::fs::create_dir_all(&out)
+                .with_context(|| format!("Failed to create {}", out.display()))?;
+            let mut saved = Vec::new();
+            // Two parts named `image.png` are saved as `image.png` and `image-2.png`
+            let mut taken = HashSet::new();
+            for attachment in attachments {
+                let data = client.get_part_data(&id, attachment).await?;
+                let path = out.join(unique_filename(
+                    safe_filename(attachment.filename.as_deref().unwrap_or_default()),
+                    &mut taken,
+                ));
+                std::fs::write(&path, &data)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                if !json {
+                    println!(
+                        "Saved {} ({})",
+                        path.display(),
+                        format_size(data.len() as u64)
+                    );
+                }
+                saved.push(path);
+            }
+            if json {
+                println!("{}", serde_json::to_string(&saved)?);
+            }
+        }
+        Commands::Sweep {
+            query,
+            search,
+            action,
+            label,
+        } => {
+            let query = search.to_query(query)?.ok_or_else(|| {
+                anyhow::anyhow!("Give --query or a search flag such as --from or --before")
+            })?;
+            run_sweep(&query, action, label.as_deref(), json).await?
+        }
+        Commands::Block {
+            sender,
+            trash_existing,
+        } => run_block(&sender, trash_existing, json).await?,
+        Commands::EmptyTrash => empty_label("TRASH", "Trash", json).await?,
+        Commands::EmptySpam => empty_label("SPAM", "Spam", json).await?,
+        Commands::Purge {
+            query,
+            yes_permanently_delete,
+        } => run_purge(&query, yes_permanently_delete, json).await?,
+        Commands::Attachments { command } => run_attachments_command(command, json).await?,
+        Commands::Thread { command } => run_thread_command(command, json).await?,
+        Commands::Filter { command } => run_filter_command(command, json).await?,
+        Commands::Vacation { command } => run_vacation_command(command, json).await?,
+        Commands::Alias { command } => run_alias_command(command, json).await?,
+        Commands::Forwarding { command } => run_forwarding_command(command, json).await?,
+        Commands::Settings { command } => run_settings_command(command, json).await?,
+        Commands::Watch {
+            command,
+            interval,
+            query,
+            label,
+            exec,
+        } => match command {
+            Some(command) => run_watch_command(command, json).await?,
+            None => {
+                let label_id = normalize_label(&label);
+                run_poll_watch(interval, query.as_deref(), &label_id, exec.as_deref(), json).await?
+            }
+        },
+        Commands::Accounts { command } => run_accounts_command(command, json).await?,
+        Commands::Triage { query, max } => run_triage(&query, max).await?,
+        Commands::Import {
+            files,
+            mbox,
+            label,
+            date_source,
+            never_mark_spam,
+        } => run_import(&files, mbox, &label, date_source, never_mark_spam, json).await?,
+        Commands::Export { command } => run_export_command(command, json).await?,
+        Commands::Sync {
+            full,
+            bodies,
+            concurrency,
+        } => run_sync(full, bodies, concurrency, json).await?,
+        Commands::Template { command } => run_template_command(command, &cfg, json)?,
+        Commands::Stats { command } => run_stats_command(command, json).await?,
+        Commands::Contacts { command } => run_contacts_command(command, json).await?,
+        Commands::Outbox { command } => run_outbox_command(command, json).await?,
+        Commands::Snooze {
+            command,
+            ids,
+            until,
+        } => match command {
+            Some(command) => run_snooze_command(command, json).await?,
+            None => {
+                let Some(until) = until else {
+                    anyhow::bail!("Missing --until (e.g. --until \"monday 9am\")");
+                };
+                if ids.is_empty() {
+                    anyhow::bail!("No message IDs given");
+                }
+                snooze(&read_ids(ids)?, &until, json).await?
+            }
+        },
+        Commands::Rules { command } => run_rules_command(command, json).await?,
+        Commands::SearchLocal {
+            query,
+            max,
+            columns,
+        } => {
+            let cache = gmail::cache::Cache::open(&config::cache_path())?;
+            let messages = cache.search(&query.join(" "), max)?;
+            if json {
+                let items: Vec<_> = messages.iter().map(list_item_json).collect();
+                println!("{}", serde_json::to_string(&items)?);
+            } else if messages.is_empty() {
+                println!("No messages found.");
+            } else {
+                let table = message_table(&messages, &columns, false);
+                print!("{}", table.render(table::terminal_width()));
+            }
         }
+        Commands::Undo { count, list } => run_undo(count, list, json).await?,
+        Commands::Draft { command } => run_draft_command(command, json).await?,
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_filename() {
+        assert_eq!(safe_filename("invoice.pdf"), "invoice.pdf");
+        assert_eq!(
+            safe_filename("../../.ssh/authorized_keys"),
+            "authorized_keys"
+        );
+        assert_eq!(safe_filename("C:\\Users\\me\\report.doc"), "report.doc");
+        assert_eq!(safe_filename("dir/"), "attachment");
+        assert_eq!(safe_filename(".."), "attachment");
+        assert_eq!(safe_filename(""), "attachment");
+    }
+
+    #[test]
+    fn test_unique_filename() {
+        let mut taken = HashSet::new();
+        assert_eq!(unique_filename("a.png".to_string(), &mut taken), "a.png");
+        assert_eq!(unique_filename("a.png".to_string(), &mut taken), "a-2.png");
+        assert_eq!(unique_filename("a.png".to_string(), &mut taken), "a-3.png");
+        assert_eq!(unique_filename(".env".to_string(), &mut taken), ".env");
+        assert_eq!(unique_filename(".env".to_string(), &mut taken), ".env-2");
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024 * 1024), "3072.0 GB");
+    }
+
+    #[test]
+    fn test_purge_query() {
+        assert_eq!(purge_query("older_than:1y").unwrap(), "older_than:1y");
+        assert!(purge_query("").is_err());
+        assert!(purge_query("   ").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_permanently_needs_confirmation() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/gmail/v1/users/me/messages/batchDelete"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = api::Client::new("test-token").with_base_url(&server.uri());
+        let ids = ["18c1".to_string()];
+
+        let refused = delete_permanently(&client, &ids, true, |_| anyhow::bail!("Aborted"));
+        assert!(refused.await.is_err());
+        let nothing = delete_permanently(&client, &[], true, |_| panic!("asked about nothing"));
+        nothing.await.unwrap();
+        delete_permanently(&client, &ids, true, |_| Ok(()))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_list_max_at_least_one() {
+        assert!(Cli::try_parse_from(["gmail", "list", "-n", "0"]).is_err());
+        assert!(Cli::try_parse_from(["gmail", "list", "-n", "1"]).is_ok());
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let not_found = anyhow::Error::from(gmail::GmailError::from_response(404, "Not Found"))
+            .context("Failed to get message");
+        assert_eq!(error_kind(&not_found), ("not_found", 6));
+        let expired = anyhow::Error::from(gmail::GmailError::from_response(401, "expired"));
+        assert_eq!(error_kind(&expired), ("auth_required", 3));
+        assert_eq!(error_kind(&anyhow::anyhow!("No such label")), ("error", 1));
+
+        let usage = match Cli::try_parse_from(["gmail", "--no-such-flag"]) {
+            Err(e) => e,
+            Ok(_) => panic!("parsed an unknown flag"),
+        };
+        assert_eq!(error_kind(&usage.into()), ("usage", 2));
+    }
+}
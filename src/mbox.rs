@@ -0,0 +1,130 @@
+use std::io::BufRead;
+
+/// Split an mbox archive into its individual RFC 822 messages.
+///
+/// A message starts at a `From ` line at the top of the file or after a blank
+/// line. The separator line itself is dropped, as is the blank line before the
+/// next one, and mboxrd `>From ` quoting is undone.
+pub fn split(data: &[u8]) -> Vec<Vec<u8>> {
+    Reader::new(data)
+        .collect::<std::io::Result<_>>()
+        .expect("reading from memory can't fail")
+}
+
+/// The messages of an mbox archive read one at a time, as [`split`] would
+/// find them, so an archive of any size never has to fit in memory.
+pub struct Reader<R> {
+    input: R,
+    current: Option<Vec<u8>>,
+    after_blank: bool,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            current: None,
+            after_blank: true,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match self.input.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            if self.after_blank && line.starts_with(b"From ") {
+                self.after_blank = false;
+                if let Some(message) = self.current.replace(Vec::new()) {
+                    return Some(Ok(finish(message)));
+                }
+                continue;
+            }
+            self.after_blank = is_blank(&line);
+            if let Some(message) = self.current.as_mut() {
+                message.extend_from_slice(unquote(&line));
+            }
+        }
+        self.current.take().map(|message| Ok(finish(message)))
+    }
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    line == b"\n" || line == b"\r\n"
+}
+
+// ">From " -> "From ", ">>From " -> ">From ", ...
+fn unquote(line: &[u8]) -> &[u8] {
+    let quotes = line.iter().take_while(|&&b| b == b'>').count();
+    if quotes > 0 && line[quotes..].starts_with(b"From ") {
+        &line[1..]
+    } else {
+        line
+    }
+}
+
+// Drop the blank line that separates a message from the next one
+fn finish(mut message: Vec<u8>) -> Vec<u8> {
+    if message.ends_with(b"\r\n\r\n") {
+        message.truncate(message.len() - 2);
+    } else if message.ends_with(b"\n\n") {
+        message.truncate(message.len() - 1);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let data = b"From a@example.com Mon Jan  1 00:00:00 2024\n\
+Subject: one\n\
+\n\
+>From the start\n\
+>>From quoted twice\n\
+\n\
+From b@example.com Tue Jan  2 00:00:00 2024\n\
+Subject: two\n\
+\n\
+Body From here\n";
+        let messages = split(data);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0],
+            b"Subject: one\n\nFrom the start\n>From quoted twice\n"
+        );
+        assert_eq!(messages[1], b"Subject: two\n\nBody From here\n");
+    }
+
+    #[test]
+    fn test_split_ignores_unseparated_from() {
+        let data = b"From x Mon Jan  1 00:00:00 2024\nSubject: a\n\nline\nFrom inside body\n";
+        let messages = split(data);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].ends_with(b"From inside body\n"));
+    }
+
+    #[test]
+    fn test_reader_yields_split_messages() {
+        let data = b"From a Mon Jan  1 00:00:00 2024\nSubject: one\n\nx\n\n\
+From b Tue Jan  2 00:00:00 2024\nSubject: two\n\ny\n";
+        let read: Vec<Vec<u8>> = Reader::new(&data[..]).map(Result::unwrap).collect();
+        assert_eq!(read, split(data));
+        assert_eq!(read.len(), 2);
+    }
+
+    #[test]
+    fn test_split_not_mbox() {
+        assert!(split(b"Subject: plain eml\n\nbody\n").is_empty());
+    }
+}
@@ -0,0 +1,698 @@
+use base64::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{Result, bail, format_err};
+
+/// An outgoing message, rendered to RFC 822 by [`Email::to_rfc822`].
+#[derive(Debug, Default)]
+pub struct Email {
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub bcc: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    pub in_reply_to: Option<String>,
+    pub references: Option<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Debug)]
+pub struct Attachment {
+    pub filename: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Email {
+    pub fn to_rfc822(&self) -> String {
+        let mut out = String::new();
+
+        if let Some(from) = &self.from {
+            push_header(&mut out, "From", &encode_address(from));
+        }
+        push_address_list(&mut out, "To", &self.to);
+        push_address_list(&mut out, "Cc", &self.cc);
+        push_address_list(&mut out, "Bcc", &self.bcc);
+        push_header(&mut out, "Subject", &encode_header_value(&self.subject));
+        if let Some(in_reply_to) = &self.in_reply_to {
+            push_header(&mut out, "In-Reply-To", in_reply_to);
+        }
+        if let Some(references) = &self.references {
+            push_header(&mut out, "References", references);
+        }
+        push_header(&mut out, "MIME-Version", "1.0");
+
+        if self.attachments.is_empty() {
+            push_text_part(&mut out, &self.body);
+            return out;
+        }
+
+        let boundary = new_boundary();
+        push_header(
+            &mut out,
+            "Content-Type",
+            &format!("multipart/mixed; boundary=\"{}\"", boundary),
+        );
+        out.push_str("\r\n");
+
+        out.push_str(&format!("--{}\r\n", boundary));
+        push_text_part(&mut out, &self.body);
+        out.push_str("\r\n");
+
+        for attachment in &self.attachments {
+            out.push_str(&format!("--{}\r\n", boundary));
+            push_attachment_part(&mut out, attachment);
+        }
+        out.push_str(&format!("--{}--\r\n", boundary));
+
+        out
+    }
+
+    /// The headers a user may change, a blank line, then the body, for editing in `$EDITOR`.
+    pub fn to_editable(&self) -> String {
+        format!(
+            "From: {}\nTo: {}\nCc: {}\nBcc: {}\nSubject: {}\n\n{}",
+            self.from.as_deref().unwrap_or_default(),
+            self.to.join(", "),
+            self.cc.join(", "),
+            self.bcc.join(", "),
+            self.subject,
+            self.body
+        )
+    }
+
+    /// Take the headers and body back from text written by `to_editable`.
+    pub fn apply_edited(&mut self, text: &str) -> Result<()> {
+        let (head, body) = match text.split_once("\n\n") {
+            Some((head, body)) => (head, body),
+            None => (text.trim_end_matches('\n'), ""),
+        };
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for line in head.lines() {
+            if line.starts_with([' ', '\t']) {
+                if let Some((_, value)) = headers.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+                continue;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| format_err!("Expected a header, found '{}'", line))?;
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+
+        for (name, value) in headers {
+            match name.as_str() {
+                "from" => self.from = (!value.is_empty()).then_some(value),
+                "to" => self.to = split_address_list(&value),
+                "cc" => self.cc = split_address_list(&value),
+                "bcc" => self.bcc = split_address_list(&value),
+                "subject" => self.subject = value,
+                _ => bail!(
+                    "Unknown header '{}' (From, To, Cc, Bcc and Subject can be edited)",
+                    name
+                ),
+            }
+        }
+        if self.to.is_empty() && self.cc.is_empty() && self.bcc.is_empty() {
+            bail!("The message has no recipients");
+        }
+        self.body = body.to_string();
+        Ok(())
+    }
+}
+
+impl Attachment {
+    /// Read a file from disk, guessing its MIME type from the extension.
+    pub fn from_path(path: &std::path::Path) -> std::io::Result<Self> {
+        let filename = path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "attachment".to_string());
+        Ok(Self {
+            mime_type: guess_mime_type(&filename).to_string(),
+            filename,
+            data: std::fs::read(path)?,
+        })
+    }
+}
+
+fn push_text_part(out: &mut String, body: &str) {
+    push_header(out, "Content-Type", "text/plain; charset=utf-8");
+    if body.is_ascii() {
+        push_header(out, "Content-Transfer-Encoding", "7bit");
+        out.push_str("\r\n");
+        out.push_str(&normalize_newlines(body));
+    } else {
+        push_header(out, "Content-Transfer-Encoding", "base64");
+        out.push_str("\r\n");
+        out.push_str(&wrap_base64(body.as_bytes()));
+    }
+}
+
+fn push_attachment_part(out: &mut String, attachment: &Attachment) {
+    let filename = encode_header_value(&attachment.filename).replace('"', "\\\"");
+    push_header(
+        out,
+        "Content-Type",
+        &format!("{}; name=\"{}\"", attachment.mime_type, filename),
+    );
+    push_header(
+        out,
+        "Content-Disposition",
+        &format!("attachment; filename=\"{}\"", filename),
+    );
+    push_header(out, "Content-Transfer-Encoding", "base64");
+    out.push_str("\r\n");
+    out.push_str(&wrap_base64(&attachment.data));
+}
+
+fn new_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    // "=_" can never occur in base64 output, so encoded parts can't collide with it
+    format!("=_gmail_{:x}", nanos)
+}
+
+/// Guess a MIME type from a filename extension, falling back to octet-stream.
+pub fn guess_mime_type(filename: &str) -> &'static str {
+    let ext = filename
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "ics" => "text/calendar",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "json" => "application/json",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xls" => "application/vnd.ms-excel",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "eml" => "message/rfc822",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Prefix a subject with `Fwd:` unless it already has a forward prefix.
+pub fn forward_subject(subject: &str) -> String {
+    let trimmed = subject.trim_start();
+    let lower = trimmed.to_ascii_lowercase();
+    if lower.starts_with("fwd:") || lower.starts_with("fw:") {
+        trimmed.to_string()
+    } else {
+        format!("Fwd: {}", trimmed)
+    }
+}
+
+/// Build a forward body: an optional note, then the original headers block and body.
+pub fn forward_body(note: &str, headers: &[(&str, &str)], original_body: &str) -> String {
+    let mut out = String::new();
+    if !note.is_empty() {
+        out.push_str(note.trim_end());
+        out.push_str("\n\n");
+    }
+    out.push_str("---------- Forwarded message ---------\n");
+    for (name, value) in headers {
+        out.push_str(&format!("{}: {}\n", name, value));
+    }
+    out.push('\n');
+    out.push_str(original_body);
+    out
+}
+
+/// Quote an original body for a reply, under an attribution line.
+pub fn reply_quote(attribution: &str, original_body: &str) -> String {
+    let mut out = format!("\n\n{}\n", attribution);
+    for line in original_body.trim_end().lines() {
+        if line.is_empty() {
+            out.push_str(">\n");
+        } else {
+            out.push_str(&format!("> {}\n", line));
+        }
+    }
+    out
+}
+
+/// Prefix a subject with `Re:` unless it already has one.
+pub fn reply_subject(subject: &str) -> String {
+    let trimmed = subject.trim_start();
+    if trimmed
+        .get(..3)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("re:"))
+    {
+        trimmed.to_string()
+    } else {
+        format!("Re: {}", trimmed)
+    }
+}
+
+/// Build the References header for a reply: the parent's references followed by its Message-ID.
+pub fn reply_references(parent_references: Option<&str>, parent_message_id: &str) -> String {
+    match parent_references {
+        Some(refs) if !refs.trim().is_empty() => format!("{} {}", refs.trim(), parent_message_id),
+        _ => parent_message_id.to_string(),
+    }
+}
+
+/// Split an address list header on commas, ignoring commas inside quotes or angle brackets.
+pub fn split_address_list(header: &str) -> Vec<String> {
+    let mut addrs = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut in_angle = false;
+    for c in header.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_angle = true,
+            '>' if !in_quotes => in_angle = false,
+            ',' if !in_quotes && !in_angle => {
+                addrs.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    addrs.push(current);
+    addrs
+        .into_iter()
+        .map(|a| a.trim().to_string())
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+/// The bare address of a mailbox such as `"Jane Doe" <jane@example.com>`.
+pub fn address_of(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => mailbox[start + 1..end].trim(),
+        _ => mailbox.trim(),
+    }
+}
+
+/// A line break in `value` would start a new header, so each one becomes a space.
+fn push_header(out: &mut String, name: &str, value: &str) {
+    out.push_str(name);
+    out.push_str(": ");
+    out.extend(
+        value
+            .chars()
+            .map(|c| if is_line_break(c) { ' ' } else { c }),
+    );
+    out.push_str("\r\n");
+}
+
+fn is_line_break(c: char) -> bool {
+    c == '\r' || c == '\n'
+}
+
+fn push_address_list(out: &mut String, name: &str, addrs: &[String]) {
+    if addrs.is_empty() {
+        return;
+    }
+    let encoded: Vec<String> = addrs.iter().map(|a| encode_address(a)).collect();
+    push_header(out, name, &encoded.join(", "));
+}
+
+/// Encode a header value as an RFC 2047 encoded-word if it isn't plain ASCII
+/// or contains a line break.
+pub fn encode_header_value(value: &str) -> String {
+    if value.is_ascii() && !value.contains(is_line_break) {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(value))
+    }
+}
+
+/// Encode only the display name of `Name <addr>` so the address stays parseable.
+fn encode_address(addr: &str) -> String {
+    match addr.rfind('<') {
+        Some(pos) if !addr.is_ascii() => {
+            let name = addr[..pos].trim().trim_matches('"');
+            format!("{} {}", encode_header_value(name), &addr[pos..])
+        }
+        _ => addr.to_string(),
+    }
+}
+
+/// Convert a text part to UTF-8 using the charset named in its Content-Type.
+///
+/// Missing or unknown charsets are read as UTF-8, and invalid bytes become U+FFFD
+/// instead of losing the whole body.
+pub fn decode_text(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding = content_type
+        .and_then(charset)
+        .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+    let (text, _, _) = encoding.decode(bytes);
+    text.into_owned()
+}
+
+/// A header from the header block of an RFC 822 message, unfolded and with
+/// encoded words decoded.
+pub fn raw_header(raw: &str, name: &str) -> Option<String> {
+    let mut value: Option<String> = None;
+    for line in raw.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            // A continuation belongs to the header before it
+            if let Some(value) = value.as_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if value.is_some() {
+            break;
+        }
+        if let Some((key, rest)) = line.split_once(':')
+            && key.eq_ignore_ascii_case(name)
+        {
+            value = Some(rest.trim().to_string());
+        }
+    }
+    value.map(|v| decode_header_value(&v))
+}
+
+/// Decode RFC 2047 encoded words (`=?UTF-8?B?...?=`, `=?iso-8859-1?Q?...?=`).
+/// Words that don't decode are left as they are.
+pub fn decode_header_value(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match decode_word(candidate) {
+            Some((text, len)) => {
+                // Whitespace between two encoded words is not part of the text
+                if !(after_word && before.trim().is_empty()) {
+                    out.push_str(before);
+                }
+                out.push_str(&text);
+                rest = &candidate[len..];
+                after_word = true;
+            }
+            None => {
+                out.push_str(before);
+                out.push_str("=?");
+                rest = &candidate[2..];
+                after_word = false;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// Decode one encoded word at the start of `s`, returning the text and its length
+fn decode_word(s: &str) -> Option<(String, usize)> {
+    let inner = s.strip_prefix("=?")?;
+    let (charset, inner) = inner.split_once('?')?;
+    let (encoding, inner) = inner.split_once('?')?;
+    let end = inner.find("?=")?;
+    let text = &inner[..end];
+    let bytes = match encoding {
+        "B" | "b" => BASE64_STANDARD.decode(text).ok()?,
+        "Q" | "q" => decode_q(text)?,
+        _ => return None,
+    };
+    let encoding = encoding_rs::Encoding::for_label(charset.as_bytes())?;
+    let (decoded, _, _) = encoding.decode(&bytes);
+    let len = 2 + charset.len() + 1 + 1 + 1 + end + 2;
+    Some((decoded.into_owned(), len))
+}
+
+fn decode_q(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'_' => out.push(b' '),
+            b'=' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            _ => out.push(b),
+        }
+    }
+    Some(out)
+}
+
+fn charset(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+fn normalize_newlines(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+fn wrap_base64(data: &[u8]) -> String {
+    let encoded = BASE64_STANDARD.encode(data);
+    let mut out = String::with_capacity(encoded.len() + encoded.len() / 76 * 2 + 2);
+    for chunk in encoded.as_bytes().chunks(76) {
+        // Base64 output is always ASCII
+        out.push_str(std::str::from_utf8(chunk).unwrap());
+        out.push_str("\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_round_trip() {
+        let mut email = Email {
+            to: vec!["a@example.com".to_string()],
+            subject: "Hi".to_string(),
+            body: "Hello".to_string(),
+            ..Default::default()
+        };
+        let text = email.to_editable();
+        assert_eq!(
+            text,
+            "From: \nTo: a@example.com\nCc: \nBcc: \nSubject: Hi\n\nHello"
+        );
+
+        let edited = text
+            .replace("Cc: ", "Cc: \"Doe, J\" <j@example.com>,\n b@example.com")
+            .replace("Hello", "Hello\n\nBye\n");
+        email.apply_edited(&edited).unwrap();
+        assert_eq!(email.from, None);
+        assert_eq!(
+            email.cc,
+            vec!["\"Doe, J\" <j@example.com>", "b@example.com"]
+        );
+        assert_eq!(email.body, "Hello\n\nBye\n");
+    }
+
+    #[test]
+    fn test_reply_quote() {
+        assert_eq!(
+            reply_quote("Alice wrote:", "Hi\n\nthere\n"),
+            "\n\nAlice wrote:\n> Hi\n>\n> there\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_edited_errors() {
+        let mut email = Email::default();
+        assert!(email.apply_edited("To: \nSubject: x\n\nbody").is_err());
+        assert!(
+            email
+                .apply_edited("To: a@example.com\nX-Foo: 1\n\n")
+                .is_err()
+        );
+        assert!(email.apply_edited("just some text\n\n").is_err());
+    }
+
+    #[test]
+    fn test_raw_header() {
+        let raw =
+            "To: a@example.com\r\nSubject: =?UTF-8?B?Q2Fmw6k=?=\r\n and more\r\n\r\nSubject: body";
+        assert_eq!(raw_header(raw, "subject").as_deref(), Some("Café and more"));
+        assert_eq!(raw_header(raw, "To").as_deref(), Some("a@example.com"));
+        assert_eq!(raw_header(raw, "Cc"), None);
+    }
+
+    #[test]
+    fn test_decode_header_value() {
+        assert_eq!(
+            decode_header_value("=?iso-8859-1?Q?Caf=E9_au?= =?UTF-8?B?bGFpdA==?= now"),
+            "Café aulait now"
+        );
+        assert_eq!(decode_header_value("plain =?bogus"), "plain =?bogus");
+    }
+
+    #[test]
+    fn test_plain_ascii_message() {
+        let email = Email {
+            to: vec!["a@example.com".to_string(), "b@example.com".to_string()],
+            subject: "Hello".to_string(),
+            body: "Line 1\nLine 2".to_string(),
+            ..Default::default()
+        };
+        let raw = email.to_rfc822();
+
+        assert!(raw.contains("To: a@example.com, b@example.com\r\n"));
+        assert!(raw.contains("Subject: Hello\r\n"));
+        assert!(raw.contains("Content-Transfer-Encoding: 7bit\r\n"));
+        assert!(raw.ends_with("\r\n\r\nLine 1\r\nLine 2"));
+        assert!(!raw.contains("Cc:"));
+    }
+
+    #[test]
+    fn test_header_line_breaks() {
+        let email = Email {
+            to: vec!["a@example.com\r\nBcc: x@example.com".to_string()],
+            subject: "Hi\r\nBcc: y@example.com".to_string(),
+            in_reply_to: Some("<1@example.com>\nBcc: z@example.com".to_string()),
+            ..Default::default()
+        };
+        let raw = email.to_rfc822();
+
+        assert!(!raw.contains("\nBcc:"));
+        assert!(raw.contains("To: a@example.com  Bcc: x@example.com\r\n"));
+        assert!(raw.contains("Subject: =?UTF-8?B?"));
+        assert_eq!(
+            decode_header_value(&encode_header_value("Hi\r\nthere")),
+            "Hi\r\nthere"
+        );
+    }
+
+    #[test]
+    fn test_non_ascii_subject_and_body() {
+        let email = Email {
+            to: vec!["José <jose@example.com>".to_string()],
+            subject: "Café".to_string(),
+            body: "Olá".to_string(),
+            ..Default::default()
+        };
+        let raw = email.to_rfc822();
+
+        assert!(raw.contains("Subject: =?UTF-8?B?Q2Fmw6k=?=\r\n"));
+        assert!(raw.contains("To: =?UTF-8?B?Sm9zw6k=?= <jose@example.com>\r\n"));
+        assert!(raw.contains("Content-Transfer-Encoding: base64\r\n"));
+        assert!(raw.ends_with("T2zDoQ==\r\n"));
+    }
+
+    #[test]
+    fn test_multipart_with_attachment() {
+        let email = Email {
+            to: vec!["a@example.com".to_string()],
+            subject: "Report".to_string(),
+            body: "See attached".to_string(),
+            attachments: vec![Attachment {
+                filename: "report.txt".to_string(),
+                mime_type: "text/plain".to_string(),
+                data: b"hello".to_vec(),
+            }],
+            ..Default::default()
+        };
+        let raw = email.to_rfc822();
+
+        let boundary = raw
+            .split("boundary=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+        assert_eq!(raw.matches(&format!("--{}\r\n", boundary)).count(), 2);
+        assert!(raw.contains("Content-Disposition: attachment; filename=\"report.txt\"\r\n"));
+        assert!(raw.contains("aGVsbG8=\r\n"));
+        assert!(raw.ends_with(&format!("--{}--\r\n", boundary)));
+    }
+
+    #[test]
+    fn test_guess_mime_type() {
+        assert_eq!(guess_mime_type("Report.PDF"), "application/pdf");
+        assert_eq!(guess_mime_type("photo.jpeg"), "image/jpeg");
+        assert_eq!(guess_mime_type("noext"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_forward_subject() {
+        assert_eq!(forward_subject("Hello"), "Fwd: Hello");
+        assert_eq!(forward_subject("FW: Hello"), "FW: Hello");
+        assert_eq!(forward_subject("fwd: Hello"), "fwd: Hello");
+    }
+
+    #[test]
+    fn test_forward_body() {
+        let body = forward_body("FYI", &[("From", "a@x"), ("Subject", "Hi")], "Original");
+        assert_eq!(
+            body,
+            "FYI\n\n---------- Forwarded message ---------\nFrom: a@x\nSubject: Hi\n\nOriginal"
+        );
+    }
+
+    #[test]
+    fn test_split_address_list() {
+        assert_eq!(
+            split_address_list("\"Doe, Jane\" <jane@x>, bob@x,"),
+            vec!["\"Doe, Jane\" <jane@x>", "bob@x"]
+        );
+        assert!(split_address_list("").is_empty());
+    }
+
+    #[test]
+    fn test_address_of() {
+        assert_eq!(address_of("\"Doe, Jane\" <jane@x>"), "jane@x");
+        assert_eq!(address_of(" bob@x "), "bob@x");
+    }
+
+    #[test]
+    fn test_reply_subject() {
+        assert_eq!(reply_subject("Hello"), "Re: Hello");
+        assert_eq!(reply_subject("Re: Hello"), "Re: Hello");
+        assert_eq!(reply_subject("RE: Hello"), "RE: Hello");
+        assert_eq!(reply_subject(""), "Re: ");
+        assert_eq!(reply_subject("ab日本"), "Re: ab日本");
+    }
+
+    #[test]
+    fn test_reply_references() {
+        assert_eq!(reply_references(None, "<b@x>"), "<b@x>");
+        assert_eq!(reply_references(Some("<a@x>"), "<b@x>"), "<a@x> <b@x>");
+        assert_eq!(reply_references(Some("  "), "<b@x>"), "<b@x>");
+    }
+
+    #[test]
+    fn test_decode_text() {
+        assert_eq!(
+            decode_text(b"Gr\xfc\xdfe", Some("text/plain; charset=iso-8859-1")),
+            "Grüße"
+        );
+        assert_eq!(
+            decode_text(
+                b"\x93quoted\x94",
+                Some("text/plain; format=flowed; CHARSET=\"windows-1252\"")
+            ),
+            "\u{201c}quoted\u{201d}"
+        );
+        assert_eq!(decode_text("héllo".as_bytes(), None), "héllo");
+        assert_eq!(
+            decode_text(b"bad \xff", Some("text/plain; charset=bogus")),
+            "bad \u{fffd}"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+/// Words that announce a one-time code, matched case-insensitively.
+const KEYWORDS: &[&str] = &[
+    "code",
+    "otp",
+    "passcode",
+    "pin",
+    "verification",
+    "verify",
+    "one-time",
+    "one time",
+    "security",
+    "confirm",
+    "login",
+    "log in",
+    "sign-in",
+    "sign in",
+    "token",
+];
+// How far around a code to look for a keyword, in bytes
+const BEFORE: usize = 80;
+const AFTER: usize = 40;
+
+/// The verification code in a message: the first 4-8 digit number (possibly
+/// split as `123 456` or `123-456`) or 6-8 character mixed letters-and-digits
+/// token near a word like "code" or "verification". The subject is searched first.
+pub fn find_code(subject: &str, body: &str) -> Option<String> {
+    [subject, body].into_iter().find_map(find_in)
+}
+
+fn find_in(text: &str) -> Option<String> {
+    let lower = text.to_ascii_lowercase();
+    candidates(text).into_iter().find_map(|(start, end, code)| {
+        let from = floor_char_boundary(&lower, start.saturating_sub(BEFORE));
+        let to = floor_char_boundary(&lower, (end + AFTER).min(lower.len()));
+        let near = &lower[from..to];
+        KEYWORDS
+            .iter()
+            .any(|k| contains_word(near, k))
+            .then_some(code)
+    })
+}
+
+/// Whether `word` appears in `text` on its own, e.g. "pin" but not in "shipping".
+fn contains_word(text: &str, word: &str) -> bool {
+    text.match_indices(word).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + word.len()..].chars().next();
+        !before.is_some_and(char::is_alphabetic) && !after.is_some_and(char::is_alphabetic)
+    })
+}
+
+/// Code-like tokens with their byte span, in order of appearance.
+fn candidates(text: &str) -> Vec<(usize, usize, String)> {
+    let bytes = text.as_bytes();
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_alphanumeric() || (i > 0 && is_word_byte(bytes[i - 1])) {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end < bytes.len() && bytes[end].is_ascii_alphanumeric() {
+            end += 1;
+        }
+        let word = &text[i..end];
+        let digits_only = word.bytes().all(|b| b.is_ascii_digit());
+        // "123 456" and "123-456" are one code
+        let mut code = word.to_string();
+        let mut span_end = end;
+        if digits_only
+            && word.len() == 3
+            && end + 4 <= bytes.len()
+            && matches!(bytes[end], b' ' | b'-')
+            && bytes[end + 1..end + 4].iter().all(u8::is_ascii_digit)
+            && bytes.get(end + 4).is_none_or(|b| !is_word_byte(*b))
+        {
+            code.push_str(&text[end + 1..end + 4]);
+            span_end = end + 4;
+        }
+        let next = bytes.get(span_end).copied();
+        let prev = i.checked_sub(1).map(|p| bytes[p]);
+        // Amounts, dates, times, versions and the like aren't codes
+        let embedded = matches!(prev, Some(b'$' | b'#' | b'/' | b':' | b'.' | b'+'))
+            || matches!(next, Some(b'/' | b':' | b'%'))
+            || (matches!(next, Some(b'.' | b','))
+                && bytes.get(span_end + 1).is_some_and(u8::is_ascii_digit));
+        let is_code = if code.bytes().all(|b| b.is_ascii_digit()) {
+            (4..=8).contains(&code.len()) && !is_year(&code)
+        } else {
+            (6..=8).contains(&code.len())
+                && code.bytes().any(|b| b.is_ascii_digit())
+                && code
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || b.is_ascii_uppercase())
+        };
+        if is_code && !embedded {
+            found.push((i, span_end, code));
+        }
+        i = span_end;
+    }
+    found
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+fn is_year(code: &str) -> bool {
+    code.len() == 4 && (code.starts_with("19") || code.starts_with("20"))
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_code() {
+        assert_eq!(
+            find_code(
+                "Your verification code",
+                "Use 482913 to sign in. It expires in 10 minutes."
+            ),
+            Some("482913".to_string())
+        );
+        assert_eq!(
+            find_code("123 456 is your Example code", ""),
+            Some("123456".to_string())
+        );
+        assert_eq!(
+            find_code("Sign in", "Your one-time passcode: K7QX2M"),
+            Some("K7QX2M".to_string())
+        );
+        // Amounts, years, times and order numbers without a keyword don't count
+        assert_eq!(
+            find_code("Shipping update", "Parcel 88213 is on its way"),
+            None
+        );
+        assert_eq!(
+            find_code(
+                "Receipt",
+                "Order 55512345 total $1234.00 on 2025-07-01 at 10:30"
+            ),
+            None
+        );
+        assert_eq!(
+            find_code("Welcome", "Thanks for joining in 2025! Your code is 7731."),
+            Some("7731".to_string())
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use chrono::{DateTime, Local};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::error::{Context, Result, format_err};
+
+/// Messages waiting to be sent at a later time, one RFC 822 file each.
+///
+/// File names start with the send time in seconds since the epoch
+/// (`<secs>-<nanos>.eml`), so the queue needs no separate index.
+#[derive(Debug)]
+pub struct Outbox {
+    dir: PathBuf,
+}
+
+/// A queued message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Queued {
+    /// File name without the extension, used to cancel it
+    pub id: String,
+    pub at: DateTime<Local>,
+    pub path: PathBuf,
+}
+
+impl Queued {
+    pub fn read(&self) -> Result<Vec<u8>> {
+        fs::read(&self.path).with_context(|| format!("Failed to read {}", self.path.display()))
+    }
+
+    /// Take the message out of the queue.
+    pub fn remove(&self) -> Result<()> {
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove {}", self.path.display()))
+    }
+
+    /// Set the message aside to send it, so a concurrent flush skips it.
+    /// `None` if another flush or a cancel got to it first.
+    pub fn claim(&self) -> Result<Option<Queued>> {
+        let sending = self.path.with_extension(SENDING);
+        match fs::rename(&self.path, &sending) {
+            Ok(()) => Ok(Some(Queued {
+                path: sending,
+                ..self.clone()
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to claim {}", self.path.display())),
+        }
+    }
+
+    /// Put a claimed message back in the queue, e.g. after it failed to send.
+    pub fn release(&self) -> Result<()> {
+        let queued = self.path.with_extension("eml");
+        fs::rename(&self.path, &queued)
+            .with_context(|| format!("Failed to requeue {}", self.path.display()))
+    }
+}
+
+// Extension of a message a flush is sending; `list` doesn't see these
+const SENDING: &str = "sending";
+
+impl Outbox {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Queue `raw` to be sent at `at`.
+    pub fn queue(&self, at: DateTime<Local>, raw: &[u8]) -> Result<Queued> {
+        config::ensure_dir(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let created = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let id = format!("{}-{}", at.timestamp(), created);
+        let path = self.dir.join(format!("{}.eml", id));
+        // Written aside first so a flush never sees half a message
+        let tmp = self.dir.join(format!(".{}.tmp", id));
+        config::write_secure(&tmp, raw)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(parse_entry(&path).expect("queued file names always parse"))
+    }
+
+    /// Every queued message, soonest first. A missing outbox is empty.
+    pub fn list(&self) -> Result<Vec<Queued>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.dir.display()));
+            }
+        };
+        let mut queued = Vec::new();
+        for entry in entries {
+            if let Some(item) = parse_entry(&entry?.path()) {
+                queued.push(item);
+            }
+        }
+        queued.sort_by(|a, b| (a.at, &a.id).cmp(&(b.at, &b.id)));
+        Ok(queued)
+    }
+
+    /// Queued messages whose send time is at or before `now`.
+    pub fn due(&self, now: DateTime<Local>) -> Result<Vec<Queued>> {
+        let mut queued = self.list()?;
+        queued.retain(|item| item.at <= now);
+        Ok(queued)
+    }
+
+    pub fn find(&self, id: &str) -> Result<Queued> {
+        self.list()?
+            .into_iter()
+            .find(|item| item.id == id)
+            .ok_or_else(|| format_err!("No queued message '{}' (see 'gmail outbox list')", id))
+    }
+}
+
+fn parse_entry(path: &Path) -> Option<Queued> {
+    if path.extension()? != "eml" {
+        return None;
+    }
+    let id = path.file_stem()?.to_str()?.to_string();
+    let (secs, _) = id.split_once('-')?;
+    let at = DateTime::from_timestamp(secs.parse().ok()?, 0)?.with_timezone(&Local);
+    Some(Queued {
+        id,
+        at,
+        path: path.to_path_buf(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::date::parse_local;
+
+    #[test]
+    fn test_queue_and_due() {
+        let dir = std::env::temp_dir().join(format!("gmail-outbox-test-{}", std::process::id()));
+        let outbox = Outbox::new(&dir);
+        assert!(outbox.list().unwrap().is_empty());
+
+        let later = outbox
+            .queue(
+                parse_local("2025-07-02 09:00").unwrap(),
+                b"Subject: later\r\n\r\n",
+            )
+            .unwrap();
+        let sooner = outbox
+            .queue(
+                parse_local("2025-07-01 09:00").unwrap(),
+                b"Subject: sooner\r\n\r\n",
+            )
+            .unwrap();
+        assert_eq!(outbox.list().unwrap(), vec![sooner.clone(), later.clone()]);
+        let mode = |p: &Path| {
+            use std::os::unix::fs::PermissionsExt;
+            fs::metadata(p).unwrap().permissions().mode() & 0o777
+        };
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&later.path), 0o600);
+
+        let due = outbox
+            .due(parse_local("2025-07-01 12:00").unwrap())
+            .unwrap();
+        assert_eq!(due, vec![sooner.clone()]);
+        assert_eq!(due[0].read().unwrap(), b"Subject: sooner\r\n\r\n");
+
+        let claimed = due[0].claim().unwrap().unwrap();
+        assert!(due[0].claim().unwrap().is_none());
+        assert_eq!(outbox.list().unwrap(), vec![later.clone()]);
+        assert_eq!(claimed.read().unwrap(), b"Subject: sooner\r\n\r\n");
+        claimed.release().unwrap();
+        assert_eq!(outbox.list().unwrap(), vec![sooner.clone(), later.clone()]);
+
+        due[0].claim().unwrap().unwrap().remove().unwrap();
+        assert_eq!(outbox.find(&later.id).unwrap(), later);
+        assert!(outbox.find(&sooner.id).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
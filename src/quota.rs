@@ -0,0 +1,116 @@
+use reqwest::Method;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Gmail's per-user limit, in quota units per second.
+pub const PER_USER_LIMIT: u32 = 250;
+
+/// Quota units Gmail charges for a request, from its method and URL path.
+///
+/// Requests to other Google APIs (Pub/Sub, People) count as one unit so they're still paced.
+pub fn units(method: &Method, path: &str) -> u32 {
+    let Some((_, rest)) = path.split_once("/users/me/") else {
+        return 1;
+    };
+    let parts: Vec<&str> = rest.split('/').collect();
+    let get = method == Method::GET;
+    let delete = method == Method::DELETE;
+    match parts.as_slice() {
+        ["profile"] => 1,
+        ["history"] => 2,
+        ["watch"] => 100,
+        ["stop"] => 50,
+        ["labels", ..] if get => 1,
+        ["labels", ..] => 5,
+        ["settings", ..] if get => 1,
+        [
+            "settings",
+            "sendAs" | "forwardingAddresses" | "delegates",
+            ..,
+        ] => 100,
+        ["settings", ..] => 5,
+        ["messages", "send"] | ["drafts", "send"] => 100,
+        ["messages", "batchModify" | "batchDelete"] => 50,
+        ["messages"] if !get => 25,
+        ["messages", "import"] => 25,
+        ["messages", _] if delete => 10,
+        ["threads", _] if delete => 20,
+        ["threads", ..] => 10,
+        ["drafts"] if !get => 10,
+        ["drafts", _] if delete => 10,
+        ["drafts", _] if method == Method::PUT => 15,
+        _ => 5,
+    }
+}
+
+/// Token bucket holding up to one second of quota units.
+///
+/// Requests take their units up front; one that overdraws the bucket waits until
+/// it has refilled, so bursts go out at once and sustained load settles at the rate.
+pub struct TokenBucket {
+    rate: f64,
+    // Units available and when that was last brought up to date
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(units_per_second: u32) -> Self {
+        let rate = f64::from(units_per_second.max(1));
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Take `units` and return how long to wait before sending the request.
+    pub fn reserve(&self, units: u32, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (available, updated) = *state;
+        let refilled = (available
+            + now.saturating_duration_since(updated).as_secs_f64() * self.rate)
+            .min(self.rate);
+        let left = refilled - f64::from(units);
+        *state = (left, now.max(updated));
+        if left >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-left / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        let path = |rest: &str| format!("/gmail/v1/users/me/{}", rest);
+        assert_eq!(units(&Method::GET, &path("messages/abc")), 5);
+        assert_eq!(units(&Method::DELETE, &path("messages/abc")), 10);
+        assert_eq!(units(&Method::POST, &path("messages/batchModify")), 50);
+        assert_eq!(units(&Method::POST, &path("messages/send")), 100);
+        assert_eq!(
+            units(&Method::POST, "/upload/gmail/v1/users/me/messages"),
+            25
+        );
+        assert_eq!(units(&Method::GET, &path("threads/abc")), 10);
+        assert_eq!(units(&Method::GET, &path("labels")), 1);
+        assert_eq!(units(&Method::POST, &path("settings/filters")), 5);
+        assert_eq!(units(&Method::GET, "/v1/people:searchContacts"), 1);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(100);
+        let start = Instant::now();
+        assert_eq!(bucket.reserve(60, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(40, start), Duration::ZERO);
+        // Empty: 50 units take half a second to come back
+        assert_eq!(bucket.reserve(50, start), Duration::from_millis(500));
+        // Refills are capped at one second's worth
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.reserve(100, later), Duration::ZERO);
+        assert!(bucket.reserve(1, later) > Duration::ZERO);
+    }
+}
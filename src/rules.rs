@@ -0,0 +1,169 @@
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::error::{Context, Result, bail};
+
+/// Rules from a TOML file, each a `[[rule]]` table:
+///
+/// ```toml
+/// [[rule]]
+/// name = "Old newsletters"
+/// from = "news@example.com"
+/// older_than = "30d"
+/// actions = ["archive", "mark-read", "label"]
+/// label = ["Newsletters"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct RuleSet {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: Option<String>,
+    /// Sender address or name
+    pub from: Option<String>,
+    /// Words in the subject
+    pub subject: Option<String>,
+    /// Any other Gmail search terms
+    pub query: Option<String>,
+    /// Minimum age, e.g. "7d", "2m", "1y"
+    pub older_than: Option<String>,
+    pub actions: Vec<Action>,
+    /// Labels added by the `label` action
+    #[serde(default)]
+    pub label: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Archive,
+    Label,
+    Delete,
+    MarkRead,
+}
+
+impl RuleSet {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read rules from {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid rules in {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let set: Self = toml::from_str(content)?;
+        for (i, rule) in set.rules.iter().enumerate() {
+            rule.validate()
+                .with_context(|| format!("Rule '{}'", rule.display_name(i)))?;
+        }
+        Ok(set)
+    }
+}
+
+impl Rule {
+    /// The rule's name, or its position in the file when unnamed.
+    pub fn display_name(&self, index: usize) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("#{}", index + 1))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.from.is_none()
+            && self.subject.is_none()
+            && self.query.is_none()
+            && self.older_than.is_none()
+        {
+            bail!("needs at least one of from, subject, query or older_than");
+        }
+        if self.actions.is_empty() {
+            bail!("has no actions");
+        }
+        if self.actions.contains(&Action::Label) && self.label.is_empty() {
+            bail!("uses the label action but sets no label");
+        }
+        if let Some(age) = &self.older_than {
+            let (n, unit) = age.split_at(age.len().saturating_sub(1));
+            if n.parse::<u32>().is_err() || !matches!(unit, "d" | "m" | "y") {
+                bail!("older_than must look like 7d, 2m or 1y, not '{}'", age);
+            }
+        }
+        Ok(())
+    }
+
+    /// The Gmail search query selecting the messages this rule applies to.
+    pub fn query(&self) -> String {
+        let mut terms = Vec::new();
+        if let Some(from) = &self.from {
+            terms.push(format!("from:({})", from));
+        }
+        if let Some(subject) = &self.subject {
+            terms.push(format!("subject:({})", subject));
+        }
+        if let Some(age) = &self.older_than {
+            terms.push(format!("older_than:{}", age));
+        }
+        if let Some(query) = &self.query {
+            terms.push(query.clone());
+        }
+        terms.join(" ")
+    }
+
+    /// System label IDs to add and remove; user labels from `label` are resolved separately.
+    pub fn label_changes(&self) -> (Vec<&'static str>, Vec<&'static str>) {
+        let mut add = Vec::new();
+        let mut remove = Vec::new();
+        for action in &self.actions {
+            match action {
+                Action::Archive => remove.push("INBOX"),
+                Action::Delete => add.push("TRASH"),
+                Action::MarkRead => remove.push("UNREAD"),
+                Action::Label => {}
+            }
+        }
+        (add, remove)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_query() {
+        let set = RuleSet::parse(
+            r#"
+            [[rule]]
+            name = "News"
+            from = "news@example.com"
+            subject = "weekly digest"
+            older_than = "30d"
+            query = "-is:starred"
+            actions = ["archive", "mark-read", "label"]
+            label = ["Newsletters"]
+            "#,
+        )
+        .unwrap();
+        let rule = &set.rules[0];
+        assert_eq!(
+            rule.query(),
+            "from:(news@example.com) subject:(weekly digest) older_than:30d -is:starred"
+        );
+        assert_eq!(rule.label_changes(), (vec![], vec!["INBOX", "UNREAD"]));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let err = |toml: &str| format!("{:#}", RuleSet::parse(toml).unwrap_err());
+        assert!(err("[[rule]]\nactions = [\"archive\"]").contains("at least one of"));
+        assert!(err("[[rule]]\nfrom = \"a\"\nactions = [\"label\"]").contains("no label"));
+        assert!(
+            err("[[rule]]\nfrom = \"a\"\nolder_than = \"3w\"\nactions = [\"delete\"]")
+                .contains("older_than")
+        );
+        assert!(err("[[rule]]\nfrom = \"a\"\nactions = [\"explode\"]").contains("explode"));
+    }
+}
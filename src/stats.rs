@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use crate::mime;
+
+/// Message count and total size for one key of a report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tally {
+    pub key: String,
+    pub count: usize,
+    pub bytes: u64,
+}
+
+/// Add up `(key, size)` pairs, largest count first (then largest size, then key).
+pub fn tally(items: impl IntoIterator<Item = (String, u64)>) -> Vec<Tally> {
+    let mut totals: HashMap<String, (usize, u64)> = HashMap::new();
+    for (key, bytes) in items {
+        let entry = totals.entry(key).or_default();
+        entry.0 += 1;
+        entry.1 += bytes;
+    }
+    let mut tallies: Vec<Tally> = totals
+        .into_iter()
+        .map(|(key, (count, bytes))| Tally { key, count, bytes })
+        .collect();
+    tallies.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then(b.bytes.cmp(&a.bytes))
+            .then(a.key.cmp(&b.key))
+    });
+    tallies
+}
+
+/// Total size of `total` messages given the sizes of a sample of them.
+///
+/// A sample covering every message is summed as is; a partial one is scaled up by its mean.
+pub fn estimate_total(total: u64, sample: &[u64]) -> u64 {
+    let sum: u64 = sample.iter().sum();
+    if sample.is_empty() || sample.len() as u64 >= total {
+        return sum;
+    }
+    (sum as f64 / sample.len() as f64 * total as f64).round() as u64
+}
+
+/// Gmail `larger:` thresholds tried from the top when looking for the biggest messages
+pub const SIZE_THRESHOLDS: &[&str] = &["25M", "10M", "5M", "1M", "100K"];
+
+/// The lowercased sender address of a From header, or just its domain.
+pub fn sender_key(from: &str, by_domain: bool) -> String {
+    let address = mime::address_of(from).trim().to_lowercase();
+    if by_domain {
+        match address.rsplit_once('@') {
+            Some((_, domain)) => domain.to_string(),
+            None => address,
+        }
+    } else {
+        address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sender_key() {
+        assert_eq!(
+            sender_key("Shop <Deals@News.Shop.com>", false),
+            "deals@news.shop.com"
+        );
+        assert_eq!(sender_key("deals@news.shop.com", true), "news.shop.com");
+    }
+
+    #[test]
+    fn test_estimate_total() {
+        assert_eq!(estimate_total(2, &[10, 20]), 30);
+        assert_eq!(estimate_total(10, &[10, 20]), 150);
+        assert_eq!(estimate_total(10, &[]), 0);
+    }
+
+    #[test]
+    fn test_tally() {
+        let tallies = tally([
+            ("a".to_string(), 10),
+            ("b".to_string(), 5),
+            ("a".to_string(), 20),
+            ("c".to_string(), 50),
+        ]);
+        let keys: Vec<_> = tallies.iter().map(|t| t.key.as_str()).collect();
+        assert_eq!(keys, ["a", "c", "b"]);
+        assert_eq!(tallies[0].count, 2);
+        assert_eq!(tallies[0].bytes, 30);
+    }
+}
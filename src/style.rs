@@ -0,0 +1,143 @@
+use std::sync::OnceLock;
+
+use crate::config::Theme;
+use crate::error::{Result, format_err};
+
+/// An ANSI text style such as "bold cyan", kept as SGR parameters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Style {
+    codes: Vec<&'static str>,
+}
+
+impl Style {
+    /// Parse space-separated attributes and colors, e.g. "bold", "dim", "bright-red underline".
+    /// An empty spec or "none" means no styling.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut codes = Vec::new();
+        for word in spec.split_whitespace() {
+            if word == "none" {
+                continue;
+            }
+            let code = sgr_code(word).ok_or_else(|| {
+                format_err!(
+                    "Unknown style '{}' (use bold, dim, italic, underline, a color like red or bright-blue, or none)",
+                    word
+                )
+            })?;
+            codes.push(code);
+        }
+        Ok(Self { codes })
+    }
+
+    /// This style with `other` applied on top.
+    pub fn with(&self, other: &Style) -> Style {
+        let mut codes = self.codes.clone();
+        codes.extend(&other.codes);
+        Style { codes }
+    }
+
+    pub fn paint(&self, text: &str) -> String {
+        if self.codes.is_empty() || text.is_empty() {
+            return text.to_string();
+        }
+        format!("\x1b[{}m{}\x1b[0m", self.codes.join(";"), text)
+    }
+}
+
+fn sgr_code(word: &str) -> Option<&'static str> {
+    Some(match word {
+        "bold" => "1",
+        "dim" => "2",
+        "italic" => "3",
+        "underline" => "4",
+        "reverse" => "7",
+        "black" => "30",
+        "red" => "31",
+        "green" => "32",
+        "yellow" => "33",
+        "blue" => "34",
+        "magenta" => "35",
+        "cyan" => "36",
+        "white" => "37",
+        "bright-black" | "gray" | "grey" => "90",
+        "bright-red" => "91",
+        "bright-green" => "92",
+        "bright-yellow" => "93",
+        "bright-blue" => "94",
+        "bright-magenta" => "95",
+        "bright-cyan" => "96",
+        "bright-white" => "97",
+        _ => return None,
+    })
+}
+
+/// Styles for each part of the output that gets colored.
+#[derive(Debug, Clone, Default)]
+pub struct Palette {
+    pub unread: Style,
+    pub label: Style,
+    pub header: Style,
+}
+
+impl Palette {
+    /// The palette for `theme`, filling unset roles with the defaults.
+    pub fn from_theme(theme: &Theme) -> Result<Self> {
+        let role =
+            |spec: &Option<String>, default: &str| Style::parse(spec.as_deref().unwrap_or(default));
+        Ok(Self {
+            unread: role(&theme.unread, "bold")?,
+            label: role(&theme.label, "cyan")?,
+            header: role(&theme.header, "dim")?,
+        })
+    }
+}
+
+/// Palette chosen at startup; colors are off until `enable` is called
+static PALETTE: OnceLock<Palette> = OnceLock::new();
+
+/// Turn on colored output using `theme`.
+pub fn enable(theme: &Theme) -> Result<()> {
+    let palette = Palette::from_theme(theme)?;
+    let _ = PALETTE.set(palette);
+    Ok(())
+}
+
+/// The active palette; every style is empty when colors are off.
+pub fn palette() -> &'static Palette {
+    static PLAIN: OnceLock<Palette> = OnceLock::new();
+    PALETTE
+        .get()
+        .unwrap_or_else(|| PLAIN.get_or_init(Palette::default))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_paint() {
+        let style = Style::parse("bold bright-cyan").unwrap();
+        assert_eq!(style.paint("hi"), "\x1b[1;96mhi\x1b[0m");
+        assert_eq!(Style::parse("none").unwrap().paint("hi"), "hi");
+        assert!(Style::parse("sparkly").is_err());
+    }
+
+    #[test]
+    fn test_with() {
+        let style = Style::parse("bold")
+            .unwrap()
+            .with(&Style::parse("red").unwrap());
+        assert_eq!(style.paint("x"), "\x1b[1;31mx\x1b[0m");
+    }
+
+    #[test]
+    fn test_palette_defaults() {
+        let theme = Theme {
+            label: Some("magenta".to_string()),
+            ..Default::default()
+        };
+        let palette = Palette::from_theme(&theme).unwrap();
+        assert_eq!(palette.unread, Style::parse("bold").unwrap());
+        assert_eq!(palette.label, Style::parse("magenta").unwrap());
+    }
+}
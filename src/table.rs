@@ -0,0 +1,227 @@
+use std::io::IsTerminal;
+
+use crate::style::Style;
+
+// Columns are never squeezed below this many characters
+const MIN_COLUMN_WIDTH: usize = 6;
+const COLUMN_GAP: &str = "  ";
+
+/// A plain-text table with aligned columns, shrunk to fit a maximum width.
+#[derive(Debug, Default)]
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    header_style: Style,
+    row_styles: Vec<Style>,
+    column_styles: Vec<Style>,
+}
+
+impl Table {
+    pub fn new<S: Into<String>>(headers: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            headers: headers.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn add_row<S: Into<String>>(&mut self, row: impl IntoIterator<Item = S>) {
+        self.add_styled_row(row, Style::default());
+    }
+
+    /// Add a row drawn in `style` when rendered as text.
+    pub fn add_styled_row<S: Into<String>>(
+        &mut self,
+        row: impl IntoIterator<Item = S>,
+        style: Style,
+    ) {
+        self.rows.push(
+            row.into_iter()
+                .map(|cell| clean_cell(&cell.into()))
+                .collect(),
+        );
+        self.row_styles.push(style);
+    }
+
+    pub fn style_header(&mut self, style: Style) {
+        self.header_style = style;
+    }
+
+    /// Style the cells of one column, on top of their row's style.
+    pub fn style_column(&mut self, index: usize, style: Style) {
+        if self.column_styles.len() <= index {
+            self.column_styles.resize(index + 1, Style::default());
+        }
+        self.column_styles[index] = style;
+    }
+
+    /// Render the table, truncating the widest columns first if it exceeds `max_width`.
+    pub fn render(&self, max_width: Option<usize>) -> String {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.chars().count()).collect();
+        for row in &self.rows {
+            for (i, cell) in row.iter().enumerate() {
+                if let Some(width) = widths.get_mut(i) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+        }
+        if let Some(max_width) = max_width {
+            fit_widths(&mut widths, max_width);
+        }
+
+        let mut out = String::new();
+        push_line(&mut out, &self.headers, &widths, |_| {
+            self.header_style.clone()
+        });
+        for (row, row_style) in self.rows.iter().zip(&self.row_styles) {
+            push_line(&mut out, row, &widths, |i| {
+                match self.column_styles.get(i) {
+                    Some(column_style) => row_style.with(column_style),
+                    None => row_style.clone(),
+                }
+            });
+        }
+        out
+    }
+}
+
+impl Table {
+    /// Render as delimiter-separated values with a header row.
+    ///
+    /// CSV cells are quoted (RFC 4180) when they contain a comma or quote; TSV cells
+    /// never need quoting since tabs are already collapsed to spaces.
+    pub fn render_delimited(&self, delimiter: char) -> String {
+        let mut out = String::new();
+        for row in std::iter::once(&self.headers).chain(&self.rows) {
+            let cells: Vec<String> = row.iter().map(|c| quote_cell(c, delimiter)).collect();
+            out.push_str(&cells.join(&delimiter.to_string()));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn quote_cell(cell: &str, delimiter: char) -> String {
+    if cell.contains(delimiter) || (delimiter == ',' && cell.contains('"')) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+/// Width of the terminal stdout is attached to, or None when output is piped.
+pub fn terminal_width() -> Option<usize> {
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    terminal_size::terminal_size().map(|(width, _)| width.0 as usize)
+}
+
+fn fit_widths(widths: &mut [usize], max_width: usize) {
+    let gaps = COLUMN_GAP.len() * widths.len().saturating_sub(1);
+    while widths.iter().sum::<usize>() + gaps > max_width {
+        let Some(widest) = widths
+            .iter_mut()
+            .filter(|w| **w > MIN_COLUMN_WIDTH)
+            .max_by_key(|w| **w)
+        else {
+            break;
+        };
+        *widest -= 1;
+    }
+}
+
+fn push_line(out: &mut String, cells: &[String], widths: &[usize], style: impl Fn(usize) -> Style) {
+    let last = widths.len().saturating_sub(1);
+    let mut line = String::new();
+    for (i, width) in widths.iter().enumerate() {
+        let cell = truncate(cells.get(i).map(String::as_str).unwrap_or(""), *width);
+        // Pad outside the escape codes so they don't count towards the width
+        line.push_str(&style(i).paint(&cell));
+        if i != last {
+            // No trailing padding on the last column
+            let padding = width - cell.chars().count();
+            line.push_str(&format!("{:padding$}{}", "", COLUMN_GAP, padding = padding));
+        }
+    }
+    out.push_str(line.trim_end());
+    out.push('\n');
+}
+
+/// Shorten `s` to `width` characters, marking the cut with an ellipsis.
+pub fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_string();
+    }
+    if width == 0 {
+        return String::new();
+    }
+    let mut cut: String = s.chars().take(width - 1).collect();
+    cut.push('…');
+    cut
+}
+
+// Keep every row on one line
+fn clean_cell(cell: &str) -> String {
+    cell.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_aligns_columns() {
+        let mut table = Table::new(["ID", "SUBJECT"]);
+        table.add_row(["1", "Hello"]);
+        table.add_row(["12345", "Multi\nline"]);
+        assert_eq!(
+            table.render(None),
+            "ID     SUBJECT\n1      Hello\n12345  Multi line\n"
+        );
+    }
+
+    #[test]
+    fn test_render_truncates_widest_column() {
+        let mut table = Table::new(["ID", "SUBJECT"]);
+        table.add_row(["abc", "A rather long subject line"]);
+        let out = table.render(Some(20));
+        assert!(out.lines().all(|l| l.chars().count() <= 20));
+        assert!(out.contains("A rather long …"));
+    }
+
+    #[test]
+    fn test_render_delimited() {
+        let mut table = Table::new(["ID", "SUBJECT"]);
+        table.add_row(["1", "Re: a, b | \"c\""]);
+        table.add_row(["2", "tab\there"]);
+        assert_eq!(
+            table.render_delimited(','),
+            "ID,SUBJECT\n1,\"Re: a, b | \"\"c\"\"\"\n2,tab here\n"
+        );
+        assert_eq!(
+            table.render_delimited('\t'),
+            "ID\tSUBJECT\n1\tRe: a, b | \"c\"\n2\ttab here\n"
+        );
+    }
+
+    #[test]
+    fn test_render_styled() {
+        let mut table = Table::new(["ID", "LABELS"]);
+        table.style_header(Style::parse("dim").unwrap());
+        table.style_column(1, Style::parse("cyan").unwrap());
+        table.add_styled_row(["1", "INBOX"], Style::parse("bold").unwrap());
+        table.add_row(["22", ""]);
+        assert_eq!(
+            table.render(None),
+            "\x1b[2mID\x1b[0m  \x1b[2mLABELS\x1b[0m\n\
+             \x1b[1m1\x1b[0m   \x1b[1;36mINBOX\x1b[0m\n\
+             22\n"
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("héllo", 10), "héllo");
+        assert_eq!(truncate("héllo", 3), "hé…");
+    }
+}
@@ -0,0 +1,320 @@
+use futures::future::BoxFuture;
+use futures::{StreamExt, TryStreamExt};
+use gmail::Client;
+use gmail::TokenProvider;
+use std::os::unix::fs::DirBuilderExt;
+use wiremock::matchers::{bearer_token, body_string_contains, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn client(server: &MockServer) -> Client {
+    Client::new("test-token").with_base_url(&server.uri())
+}
+
+#[tokio::test]
+async fn test_list_all_message_ids_follows_pages() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/messages"))
+        .and(query_param("q", "from:news@example.com"))
+        .and(query_param("pageToken", "p2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "messages": [{ "id": "b", "threadId": "t" }]
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/messages"))
+        .and(bearer_token("test-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "messages": [{ "id": "a", "threadId": "t" }],
+            "nextPageToken": "p2"
+        })))
+        .mount(&server)
+        .await;
+
+    let ids = client(&server)
+        .list_all_message_ids(Some("from:news@example.com"), "")
+        .await
+        .unwrap();
+    assert_eq!(ids, ["a", "b"]);
+}
+
+#[tokio::test]
+async fn test_messages_stream_fetches_pages_lazily() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/messages"))
+        .and(query_param("pageToken", "p2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+        .expect(0)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/messages"))
+        .and(query_param("labelIds", "INBOX"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "messages": [{ "id": "a" }, { "id": "b" }],
+            "nextPageToken": "p2"
+        })))
+        .mount(&server)
+        .await;
+
+    let client = client(&server);
+    let mut stream = std::pin::pin!(client.messages_stream(None, "INBOX"));
+    let mut ids = Vec::new();
+    while let Some(m) = stream.next().await {
+        ids.push(m.unwrap().id);
+        if ids.len() == 2 {
+            break;
+        }
+    }
+    assert_eq!(ids, ["a", "b"]);
+
+    let first: Vec<_> = client
+        .messages_stream(None, "INBOX")
+        .take(1)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(first[0].id, "a");
+}
+
+#[tokio::test]
+async fn test_api_errors_are_typed() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/messages/gone"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(serde_json::json!({
+            "error": {
+                "code": 404,
+                "message": "Requested entity was not found.",
+                "errors": [{ "reason": "notFound" }]
+            }
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/profile"))
+        .respond_with(ResponseTemplate::new(429).set_body_string("slow down"))
+        .mount(&server)
+        .await;
+
+    let client = client(&server);
+    let err = client.get_message("gone").await.unwrap_err();
+    assert!(err.is_not_found());
+    assert_eq!(err.reason(), Some("notFound"));
+    let err = client.get_profile().await.unwrap_err();
+    assert!(err.is_rate_limited());
+}
+
+#[tokio::test]
+async fn test_get_messages_metadata_batches() {
+    let server = MockServer::start().await;
+    let body = "--batch_x\r\n\
+        Content-Type: application/http\r\n\
+        Content-ID: <response-item1>\r\n\r\n\
+        HTTP/1.1 404 Not Found\r\n\r\n\
+        {\"error\": {\"code\": 404, \"message\": \"Not Found\"}}\r\n\
+        --batch_x\r\n\
+        Content-Type: application/http\r\n\
+        Content-ID: <response-item0>\r\n\r\n\
+        HTTP/1.1 200 OK\r\n\r\n\
+        {\"id\": \"a\", \"labelIds\": [\"INBOX\"]}\r\n\
+        --batch_x--\r\n";
+    Mock::given(method("POST"))
+        .and(path("/batch/gmail/v1"))
+        .and(body_string_contains(
+            "GET /gmail/v1/users/me/messages/b?format=metadata",
+        ))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_raw(body, "multipart/mixed; boundary=batch_x"),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let results = client(&server)
+        .get_messages_metadata(&["a".to_string(), "b".to_string()], &["From"])
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].as_ref().unwrap().id, "a");
+    assert!(results[1].as_ref().unwrap_err().is_not_found());
+}
+
+#[tokio::test]
+async fn test_dry_run_sends_nothing() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    client(&server)
+        .with_dry_run(true)
+        .batch_modify(&["a".to_string()], &[], &["UNREAD"])
+        .await
+        .unwrap();
+}
+
+/// Hands out "old" until asked to refresh, then "new".
+struct StubTokens(std::sync::Mutex<&'static str>);
+
+impl TokenProvider for StubTokens {
+    fn token(&self) -> BoxFuture<'_, gmail::error::Result<String>> {
+        Box::pin(async move { Ok(self.0.lock().unwrap().to_string()) })
+    }
+
+    fn refresh<'a>(
+        &'a self,
+        _stale: &'a str,
+    ) -> BoxFuture<'a, gmail::error::Result<Option<String>>> {
+        Box::pin(async move {
+            *self.0.lock().unwrap() = "new";
+            Ok(Some("new".to_string()))
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_token_provider_refreshes_on_401() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/profile"))
+        .and(bearer_token("old"))
+        .respond_with(ResponseTemplate::new(401).set_body_string("expired"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/profile"))
+        .and(bearer_token("new"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "emailAddress": "me@example.com",
+            "messagesTotal": 1,
+            "threadsTotal": 1,
+            "historyId": "1"
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let client = Client::with_token_provider(
+        gmail::api::http_client(Default::default()),
+        StubTokens(std::sync::Mutex::new("old")),
+    )
+    .with_base_url(&server.uri());
+    client.get_profile().await.unwrap();
+    // The renewed token is used from then on
+    client.get_profile().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_daemon_forwards_requests_with_its_login() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/labels"))
+        .and(bearer_token("daemon-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "labels": [{ "id": "INBOX", "name": "INBOX" }]
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/messages/gone"))
+        .and(bearer_token("daemon-token"))
+        .respond_with(ResponseTemplate::new(404).set_body_string("Not Found"))
+        .mount(&server)
+        .await;
+
+    // The daemon refuses to listen in a directory other users can enter
+    let dir = std::env::temp_dir().join(format!("gmail-daemon-test-{}", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+    let socket = dir.join("daemon.sock");
+    let daemon = Client::new("daemon-token").with_base_url(&server.uri());
+    let serving = tokio::spawn({
+        let socket = socket.clone();
+        async move { gmail::daemon::serve(daemon, &socket).await }
+    });
+    for _ in 0..100 {
+        if gmail::daemon::is_running(&socket) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    let client = Client::via_daemon(reqwest::Client::new(), &socket).with_base_url(&server.uri());
+    // The second label list is answered from the daemon's memory
+    for _ in 0..2 {
+        let labels = client.list_labels().await.unwrap().labels.unwrap();
+        assert_eq!(labels[0].id, "INBOX");
+    }
+    let err = client.get_message("gone").await.unwrap_err();
+    assert!(err.is_not_found());
+
+    // The daemon's token only goes to the API it was started for
+    let elsewhere =
+        Client::via_daemon(reqwest::Client::new(), &socket).with_base_url("http://127.0.0.1:1");
+    let err = elsewhere.list_labels().await.unwrap_err();
+    assert!(err.to_string().contains("outside the Gmail API"));
+
+    assert!(gmail::daemon::stop(&socket).await.unwrap());
+    serving.await.unwrap().unwrap();
+    assert!(!socket.exists());
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_create_label_path_keeps_parent_case() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/gmail/v1/users/me/labels"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "labels": [{ "id": "Label_1", "name": "Work" }]
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/gmail/v1/users/me/labels"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "id": "Label_2", "name": "new" })),
+        )
+        .mount(&server)
+        .await;
+
+    let client = client(&server);
+    client
+        .create_label_path("clients/acme/Invoices", None)
+        .await
+        .unwrap();
+    client
+        .create_label_path("work/Reports", None)
+        .await
+        .unwrap();
+
+    let posted: Vec<String> = server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|r| r.method.as_str() == "POST")
+        .map(|r| {
+            r.body_json::<serde_json::Value>().unwrap()["name"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    assert_eq!(
+        posted,
+        [
+            "clients",
+            "clients/acme",
+            "clients/acme/Invoices",
+            "Work/Reports"
+        ]
+    );
+}